@@ -51,9 +51,20 @@
 //!   blanket `#[derive(Serialize, Deserialize)]` on the enum is usually
 //!   sufficient).
 //!
-//! ---
-//! Currently `sourcerer-derive` only provides the `Event` macro. More helpers
-//! may be added in the future.
+//! # `#[derive(Snapshot)]`
+//! The `Snapshot` derive implements the `sourcerer::Snapshot` trait for a
+//! struct or enum. Its schema version defaults to `1` and is set with
+//! `#[snapshot(version = N)]`:
+//!
+//! ```ignore
+//! #[derive(Clone, Debug, Serialize, Deserialize, Snapshot)]
+//! #[snapshot(version = 2)]
+//! struct AccountSnapshot {
+//!     balance: i64,
+//! }
+//!
+//! assert_eq!(AccountSnapshot::snapshot_version(), 2);
+//! ```
 use proc_macro::TokenStream;
 use proc_macro2::Span;
 use quote::quote;
@@ -81,17 +92,17 @@ pub fn event_derive(input: TokenStream) -> TokenStream {
             if let Some(key) = ident {
                 match key.as_str() {
                     "version" => {
-                        if let syn::Expr::Lit(expr_lit) = &nv.value {
-                            if let Lit::Int(li) = &expr_lit.lit {
-                                *version = Some(li.base10_parse::<u16>().expect("invalid int"));
-                            }
+                        if let syn::Expr::Lit(expr_lit) = &nv.value
+                            && let Lit::Int(li) = &expr_lit.lit
+                        {
+                            *version = Some(li.base10_parse::<u16>().expect("invalid int"));
                         }
                     }
                     "source" => {
-                        if let syn::Expr::Lit(expr_lit) = &nv.value {
-                            if let Lit::Str(ls) = &expr_lit.lit {
-                                *source = Some(ls.value());
-                            }
+                        if let syn::Expr::Lit(expr_lit) = &nv.value
+                            && let Lit::Str(ls) = &expr_lit.lit
+                        {
+                            *source = Some(ls.value());
                         }
                     }
                     _ => {}
@@ -183,3 +194,43 @@ pub fn event_derive(input: TokenStream) -> TokenStream {
 
     TokenStream::from(expanded)
 }
+
+/// Derives the `Snapshot` trait for a struct or enum.
+///
+/// The schema version returned by `snapshot_version` is read from
+/// `#[snapshot(version = N)]`, and defaults to `1`.
+#[proc_macro_derive(Snapshot, attributes(snapshot))]
+pub fn snapshot_derive(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+
+    let mut version: u16 = 1;
+    for attr in &input.attrs {
+        if attr.path().is_ident("snapshot") {
+            let parser = Punctuated::<MetaNameValue, Token![,]>::parse_terminated;
+            let list = attr
+                .parse_args_with(parser)
+                .expect("invalid snapshot attribute");
+            for nv in &list {
+                if nv.path.is_ident("version")
+                    && let syn::Expr::Lit(expr_lit) = &nv.value
+                    && let Lit::Int(li) = &expr_lit.lit
+                {
+                    version = li.base10_parse::<u16>().expect("invalid int");
+                }
+            }
+        }
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let expanded = quote! {
+        impl #impl_generics sourcerer::Snapshot for #name #ty_generics #where_clause {
+            fn snapshot_version() -> u16 {
+                #version
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
@@ -24,14 +24,84 @@
 //! A random UUID is generated for the CloudEvent `id` field and the `source`
 //! attribute defaults to `"urn:sourcerer:event"`. If you need more control
 //! build the underlying event manually via the `into_inner` method.
+//!
+//! Events read back from a store convert with [`CloudEvent::from_stored`]
+//! instead, which carries their stream context: the event ID as the `id`, the
+//! aggregate ID as the `subject`, the recording time as the `time`, and the
+//! aggregate type, aggregate version and event version as extensions. Events
+//! stored without an event ID, whose metadata holds the nil UUID, get an `id`
+//! derived from their aggregate type, aggregate ID and version instead. The
+//! `source` and `dataschema` can be set at runtime with
+//! [`CloudEventOptions`].
+//!
+//! Inbound CloudEvents convert the other way, into a [`RawStoredEvent`] or
+//! directly into a [`StoredEvent`], reading the same attributes back. The
+//! `eventversion` extension is required, so the payload can be upcast with
+//! [`UpcasterChain::decode`](crate::upcaster::UpcasterChain::decode) like
+//! any historical event. Malformed events are rejected with an
+//! [`Error::Validation`] naming the offending attribute.
 
-use crate::{Error, Event, Result};
-use cloudevents::event::{Data, Event as CeEvent, EventBuilder, EventBuilderV10};
+use std::collections::BTreeMap;
+
+use crate::{
+    Error, Event, EventMetadata, Result, StoredEvent,
+    metadata::legacy_event_id,
+    serializer::Format,
+    upcaster::{RawStoredEvent, UpcasterChain},
+};
+use chrono::Utc;
+use cloudevents::{
+    AttributesReader,
+    event::{Data, Event as CeEvent, EventBuilder, EventBuilderV10, ExtensionValue},
+};
 use serde::Serialize;
 use tracing::instrument;
 use url::Url;
 use uuid::Uuid;
 
+/// The extension holding the type name of the event's aggregate.
+pub const AGGREGATE_TYPE_EXTENSION: &str = "aggregatetype";
+
+/// The extension holding the version of the aggregate after the event.
+pub const AGGREGATE_VERSION_EXTENSION: &str = "aggregateversion";
+
+/// The extension holding the version of the event's schema.
+pub const EVENT_VERSION_EXTENSION: &str = "eventversion";
+
+/// The source used when an event's own source is not a valid URI.
+const DEFAULT_SOURCE: &str = "urn:sourcerer:event";
+
+/// Options for converting stored events with
+/// [`CloudEvent::from_stored_with`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CloudEventOptions {
+    source: Option<Url>,
+    dataschema: Option<Url>,
+}
+
+impl CloudEventOptions {
+    /// Creates the default options, which keep each event's own
+    /// [`Event::event_source`] and set no `dataschema`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the `source` of every event, in place of its own
+    /// [`Event::event_source`].
+    #[must_use]
+    pub fn with_source(mut self, source: Url) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Sets the `dataschema` URI describing the event data.
+    #[must_use]
+    pub fn with_dataschema(mut self, dataschema: Url) -> Self {
+        self.dataschema = Some(dataschema);
+        self
+    }
+}
+
 /// Newtype wrapper around `cloudevents_sdk::Event` so we can legally provide a
 /// blanket [`From`] implementation without violating Rust's orphan rules.
 #[derive(Debug, Clone)]
@@ -65,6 +135,183 @@ impl CloudEvent {
 
         Ok(Self(ce))
     }
+
+    /// Builds a [`CloudEvent`] from a stored event, with the default
+    /// [`CloudEventOptions`].
+    ///
+    /// The `id` is the event ID, or a UUIDv5 of the aggregate type, aggregate
+    /// ID and version for an event stored without one, so converting the same
+    /// stored event twice gives the same CloudEvent.
+    pub fn from_stored<E>(event: &StoredEvent<E>) -> Result<Self>
+    where
+        E: Event + Serialize,
+    {
+        Self::from_stored_with(event, &CloudEventOptions::default())
+    }
+
+    /// Builds a [`CloudEvent`] from a stored event, with the given options.
+    #[instrument(skip(event), fields(event_id = %event.metadata().event_id()))]
+    pub fn from_stored_with<E>(event: &StoredEvent<E>, options: &CloudEventOptions) -> Result<Self>
+    where
+        E: Event + Serialize,
+    {
+        let data_json = serde_json::to_vec(event.event())
+            .map_err(|e| Error::Validation(format!("failed to serialise event: {e}")))?;
+        let source = options
+            .source
+            .clone()
+            .unwrap_or_else(|| source_of(event.event()));
+
+        let id = match event.metadata().event_id() {
+            id if id.is_nil() => legacy_event_id(
+                event.aggregate_type(),
+                event.aggregate_id(),
+                event.version(),
+            ),
+            id => id,
+        };
+
+        let builder = EventBuilderV10::new()
+            .id(id.to_string())
+            .ty(event.event_type())
+            .source(source)
+            .subject(event.aggregate_id())
+            .time(event.metadata().recorded_at())
+            .extension(AGGREGATE_TYPE_EXTENSION, event.aggregate_type())
+            .extension(AGGREGATE_VERSION_EXTENSION, event.version())
+            .extension(EVENT_VERSION_EXTENSION, i64::from(event.event_version()));
+        let builder = match &options.dataschema {
+            Some(dataschema) => builder.data_with_schema(
+                "application/json",
+                dataschema.clone(),
+                Data::from(data_json),
+            ),
+            None => builder.data("application/json", Data::from(data_json)),
+        };
+        let ce = builder
+            .build()
+            .map_err(|e| Error::Validation(format!("failed to build CloudEvent: {e}")))?;
+
+        Ok(Self(ce))
+    }
+}
+
+impl TryFrom<CloudEvent> for RawStoredEvent {
+    type Error = Error;
+
+    /// Reads a CloudEvent into a raw event, with its JSON data as the
+    /// payload.
+    ///
+    /// The `id` must be a UUID, and becomes the event ID. The `subject` is
+    /// required and becomes the aggregate ID. The `eventversion` extension is
+    /// required, while the `aggregatetype` and `aggregateversion` extensions
+    /// default to an empty type and version 0. Integer extensions may also be
+    /// strings, as they are in binary mode. An event without a `time` is
+    /// recorded at the time of the conversion.
+    fn try_from(event: CloudEvent) -> Result<Self> {
+        let mut event = event.into_inner();
+        let event_id = Uuid::parse_str(event.id())
+            .map_err(|e| invalid("id", &format!("is not a UUID: {e}")))?;
+        let aggregate_id = event
+            .subject()
+            .ok_or_else(|| invalid("subject", "is missing"))?
+            .to_owned();
+        let event_version = integer_extension(&event, EVENT_VERSION_EXTENSION)?
+            .ok_or_else(|| invalid(EVENT_VERSION_EXTENSION, "is missing"))?;
+        let event_version = u16::try_from(event_version).map_err(|_| {
+            invalid(
+                EVENT_VERSION_EXTENSION,
+                &format!("{event_version} is out of range"),
+            )
+        })?;
+        let version = integer_extension(&event, AGGREGATE_VERSION_EXTENSION)?.unwrap_or(0);
+        let aggregate_type = match event.extension(AGGREGATE_TYPE_EXTENSION) {
+            Some(ExtensionValue::String(aggregate_type)) => aggregate_type.clone(),
+            Some(_) => return Err(invalid(AGGREGATE_TYPE_EXTENSION, "is not a string")),
+            None => String::new(),
+        };
+        let event_type = event.ty().to_owned();
+        let recorded_at = event.time().copied().unwrap_or_else(Utc::now);
+
+        let (content_type, _, data) = event.take_data();
+        if let Some(content_type) = content_type
+            && !is_json(&content_type)
+        {
+            return Err(invalid(
+                "datacontenttype",
+                &format!("`{content_type}` is not JSON"),
+            ));
+        }
+        let payload = match data.ok_or_else(|| invalid("data", "is missing"))? {
+            Data::Binary(bytes) => bytes,
+            Data::String(text) => text.into_bytes(),
+            Data::Json(value) => serde_json::to_vec(&value)
+                .map_err(|e| invalid("data", &format!("cannot be encoded: {e}")))?,
+        };
+
+        Ok(Self {
+            aggregate_type,
+            aggregate_id,
+            version,
+            event_version,
+            event_type,
+            format: Format::Json,
+            payload,
+            position: 0,
+            metadata: EventMetadata::from_parts(event_id, recorded_at, None, None, BTreeMap::new()),
+            hash: None,
+        })
+    }
+}
+
+impl<E: Event> TryFrom<CloudEvent> for StoredEvent<E> {
+    type Error = Error;
+
+    /// Reads a CloudEvent into a stored event, as a [`RawStoredEvent`] that
+    /// is deserialized without upcasting.
+    ///
+    /// Events that may use older schemas should be converted into a
+    /// [`RawStoredEvent`] and decoded with
+    /// [`UpcasterChain::decode`] instead.
+    fn try_from(event: CloudEvent) -> Result<Self> {
+        UpcasterChain::new().decode(RawStoredEvent::try_from(event)?)
+    }
+}
+
+/// Returns the error for an invalid attribute of an inbound CloudEvent.
+fn invalid(attribute: &str, problem: &str) -> Error {
+    Error::Validation(format!("CloudEvent attribute `{attribute}` {problem}"))
+}
+
+/// Reads an integer extension, which binary mode carries as a string.
+fn integer_extension(event: &CeEvent, name: &str) -> Result<Option<i64>> {
+    match event.extension(name) {
+        None => Ok(None),
+        Some(ExtensionValue::Integer(value)) => Ok(Some(*value)),
+        Some(ExtensionValue::String(value)) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| invalid(name, &format!("`{value}` is not an integer"))),
+        Some(ExtensionValue::Boolean(_)) => Err(invalid(name, "is not an integer")),
+    }
+}
+
+/// Returns whether a content type, ignoring its parameters, denotes JSON.
+fn is_json(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type == "application/json" || media_type.ends_with("+json")
+}
+
+/// Returns the event's own source, or the default source if it is not a
+/// valid URI.
+fn source_of<E: Event>(event: &E) -> Url {
+    Url::parse(event.event_source())
+        .unwrap_or_else(|_| Url::parse(DEFAULT_SOURCE).expect("default URN is valid"))
 }
 
 impl<E> From<E> for CloudEvent
@@ -72,11 +319,9 @@ where
     E: Event + Serialize,
 {
     fn from(event: E) -> Self {
-        let source_str = event.event_source();
-        let source = Url::parse(source_str)
-            .unwrap_or_else(|_| Url::parse("urn:sourcerer:event").expect("default URN is valid"));
+        let source = source_of(&event);
 
-        // Safe unwrap: if both parses failed we'd have panicked above.
+        // Safe unwrap: if both parses failed we'd have panicked in `source_of`.
         Self::from_event_with_source(event, source).expect("constructing CloudEvent cannot fail")
     }
 }
@@ -0,0 +1,197 @@
+//! Defines the opt-in compression of stored event and snapshot payloads.
+//!
+//! Stores that support compression take a [`Compression`] setting and record
+//! the [`Codec`] used for every payload, so data written uncompressed, or
+//! with another codec, keeps loading.
+//!
+//! zstd and lz4 are available behind the `zstd` and `lz4` features.
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// The compression applied to a stored payload.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// The payload is stored as is.
+    #[default]
+    None,
+    /// zstd, via the `zstd` crate. Requires the `zstd` feature.
+    Zstd,
+    /// LZ4, via `lz4_flex`. Requires the `lz4` feature.
+    Lz4,
+}
+
+impl Codec {
+    /// Returns the name under which the codec is recorded.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
+        }
+    }
+
+    /// Returns `true` for [`Codec::None`].
+    pub fn is_none(&self) -> bool {
+        *self == Self::None
+    }
+
+    /// Restores a payload compressed with this codec.
+    ///
+    /// Fails if the feature providing the codec is not enabled.
+    pub fn decompress(self, bytes: &[u8]) -> Result<Cow<'_, [u8]>> {
+        match self {
+            Self::None => Ok(Cow::Borrowed(bytes)),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::decode_all(bytes)
+                .map(Cow::Owned)
+                .map_err(|e| Error::Store(e.to_string())),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => lz4_flex::decompress_size_prepended(bytes)
+                .map(Cow::Owned)
+                .map_err(|e| Error::Store(e.to_string())),
+            #[allow(unreachable_patterns)]
+            codec => Err(Error::Store(format!(
+                "cannot decompress {codec} payload: codec not enabled"
+            ))),
+        }
+    }
+
+    /// Returns the tag identifying the codec in binary layouts.
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Zstd => 1,
+            Self::Lz4 => 2,
+        }
+    }
+
+    /// Looks a codec up by its binary tag.
+    pub(crate) fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Zstd),
+            2 => Ok(Self::Lz4),
+            _ => Err(Error::Store(format!("unknown compression tag {tag}"))),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            "lz4" => Ok(Self::Lz4),
+            _ => Err(Error::Store(format!("unknown compression codec `{s}`"))),
+        }
+    }
+}
+
+/// Controls how a store compresses the payloads it writes.
+///
+/// Payloads smaller than the threshold, or that would not shrink, are stored
+/// uncompressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    codec: Codec,
+    level: i32,
+    threshold: usize,
+}
+
+impl Default for Compression {
+    /// Leaves payloads uncompressed.
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl Compression {
+    /// The default threshold below which payloads are stored uncompressed.
+    pub const DEFAULT_THRESHOLD: usize = 256;
+
+    /// Stores every payload uncompressed.
+    pub fn none() -> Self {
+        Self {
+            codec: Codec::None,
+            level: 0,
+            threshold: Self::DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// Compresses payloads with zstd at the given level, where `0` selects
+    /// zstd's default.
+    #[cfg(feature = "zstd")]
+    pub fn zstd(level: i32) -> Self {
+        Self {
+            codec: Codec::Zstd,
+            level,
+            threshold: Self::DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// Compresses payloads with LZ4.
+    #[cfg(feature = "lz4")]
+    pub fn lz4() -> Self {
+        Self {
+            codec: Codec::Lz4,
+            level: 0,
+            threshold: Self::DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// Sets the size in bytes below which payloads are stored uncompressed.
+    #[must_use]
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Returns the codec applied to payloads at or above the threshold.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Returns the size in bytes below which payloads are stored
+    /// uncompressed.
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Compresses a payload if it is large enough and shrinks, returning the
+    /// codec that was applied.
+    pub(crate) fn compress(&self, bytes: Vec<u8>) -> Result<(Codec, Vec<u8>)> {
+        if self.codec.is_none() || bytes.len() < self.threshold {
+            return Ok((Codec::None, bytes));
+        }
+        let compressed: Vec<u8> = match self.codec {
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => {
+                zstd::bulk::compress(&bytes, self.level).map_err(|e| Error::Store(e.to_string()))
+            }
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(&bytes)),
+            // Only constructors for enabled codecs exist.
+            #[allow(unreachable_patterns)]
+            codec => Err(Error::Store(format!(
+                "cannot compress payload with {codec}: codec not enabled"
+            ))),
+        }?;
+        if compressed.len() < bytes.len() {
+            Ok((self.codec, compressed))
+        } else {
+            Ok((Codec::None, bytes))
+        }
+    }
+}
@@ -0,0 +1,503 @@
+//! Provides crypto-shredding: personal data is encrypted with a key per data
+//! subject, so deleting the key erases the data from every stored event.
+//!
+//! Fields holding personal data are declared as [`Encrypted<T>`]; a whole
+//! payload is encrypted by wrapping it in one, such as a variant
+//! `Registered(Encrypted<Registration>)`. A
+//! [`GenericRepository`](crate::repository::GenericRepository) configured
+//! with an [`Encryption`] seals these values with the subject's key from a
+//! [`KeyStore`] when saving, and opens them again when loading.
+//!
+//! Once [`KeyStore::forget`] has deleted a subject's key, their values load
+//! as shredded: [`Encrypted::value`] returns `None`, or the whole event is
+//! replaced by the [`Encryption`]'s placeholder if one is set.
+//!
+//! Values are encrypted with XChaCha20-Poly1305 and bound to their subject.
+//! Opening a payload requires a self-describing format, so bincode payloads
+//! cannot hold encrypted values. Snapshots are not encrypted: an
+//! `Encrypted` value that has not been sealed refuses to serialize, so it
+//! cannot leak into a snapshot or be appended to a store directly.
+use std::{
+    cell::Cell,
+    collections::{BTreeSet, HashMap, hash_map::Entry},
+    fmt,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, ser::Error as _};
+use serde_json::Value;
+
+use crate::{
+    Error, Event, Result,
+    serializer::{EventSerializer, Format, JsonSerializer},
+    upcaster::RawStoredEvent,
+};
+
+/// Tag of a value waiting to be sealed, or that has been opened.
+const PLAIN: &str = "$plain";
+/// Tag of an encrypted value.
+const SEALED: &str = "$sealed";
+/// Tag of a value whose key has been forgotten.
+const SHREDDED: &str = "$shredded";
+
+/// A 256-bit key encrypting the personal data of one subject.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    /// Generates a random key.
+    pub fn generate() -> Self {
+        Self(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    /// Creates a key from its bytes.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Returns the bytes of the key.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Returns the cipher keyed with this key.
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+impl TryFrom<&[u8]> for Key {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| Error::Store(format!("invalid key length {}", bytes.len())))
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// A key store holds the encryption key of every data subject.
+#[async_trait]
+pub trait KeyStore: Send + Sync {
+    /// Loads the key of a subject, if it has one.
+    async fn load(&self, subject: &str) -> Result<Option<Key>>;
+
+    /// Loads the key of a subject, creating one if it has none.
+    ///
+    /// Concurrent calls for the same subject must return the same key.
+    async fn load_or_create(&self, subject: &str) -> Result<Key>;
+
+    /// Deletes the key of a subject, so every value encrypted with it becomes
+    /// unreadable.
+    ///
+    /// Values saved for the subject afterwards are encrypted with a new key.
+    async fn forget(&self, subject: &str) -> Result<()>;
+}
+
+thread_local! {
+    /// Whether the current thread is serializing events to seal them.
+    static SEALING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Allows plain [`Encrypted`] values to serialize until dropped.
+struct Sealing;
+
+impl Sealing {
+    fn begin() -> Self {
+        SEALING.set(true);
+        Self
+    }
+}
+
+impl Drop for Sealing {
+    fn drop(&mut self) {
+        SEALING.set(false);
+    }
+}
+
+/// A value encrypted with the key of its data subject.
+///
+/// A value is plain when created or once opened by a repository, sealed when
+/// read straight from a store, and shredded once its subject's key has been
+/// forgotten. Its `Debug` output never shows the value.
+#[derive(Clone, PartialEq)]
+pub struct Encrypted<T> {
+    state: State<T>,
+}
+
+#[derive(Clone, PartialEq)]
+enum State<T> {
+    Plain { subject: String, value: T },
+    Sealed(Sealed),
+    Shredded { subject: String },
+}
+
+/// The stored form of an encrypted value.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct Sealed {
+    subject: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// The serialized form of an [`Encrypted`] value.
+#[derive(Serialize)]
+enum ReprRef<'a, T> {
+    #[serde(rename = "$plain")]
+    Plain { subject: &'a str, value: &'a T },
+    #[serde(rename = "$sealed")]
+    Sealed(&'a Sealed),
+    #[serde(rename = "$shredded")]
+    Shredded { subject: &'a str },
+}
+
+/// The deserialized form of an [`Encrypted`] value.
+#[derive(Deserialize)]
+enum Repr<T> {
+    #[serde(rename = "$plain")]
+    Plain { subject: String, value: T },
+    #[serde(rename = "$sealed")]
+    Sealed(Sealed),
+    #[serde(rename = "$shredded")]
+    Shredded { subject: String },
+}
+
+impl<T> Encrypted<T> {
+    /// Wraps the personal data of `subject`, to be encrypted with their key.
+    pub fn new(subject: impl Into<String>, value: T) -> Self {
+        Self {
+            state: State::Plain {
+                subject: subject.into(),
+                value,
+            },
+        }
+    }
+
+    /// Returns the subject whose key encrypts the value.
+    pub fn subject(&self) -> &str {
+        match &self.state {
+            State::Plain { subject, .. } | State::Shredded { subject } => subject,
+            State::Sealed(sealed) => &sealed.subject,
+        }
+    }
+
+    /// Returns the value, unless it is still sealed or has been shredded.
+    pub fn value(&self) -> Option<&T> {
+        match &self.state {
+            State::Plain { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the value, unless it is still sealed or has been shredded.
+    pub fn into_value(self) -> Option<T> {
+        match self.state {
+            State::Plain { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the subject's key has been forgotten.
+    pub fn is_shredded(&self) -> bool {
+        matches!(self.state, State::Shredded { .. })
+    }
+}
+
+impl<T> fmt::Debug for Encrypted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            State::Plain { .. } => "plain",
+            State::Sealed(_) => "sealed",
+            State::Shredded { .. } => "shredded",
+        };
+        f.debug_struct("Encrypted")
+            .field("subject", &self.subject())
+            .field("state", &state)
+            .finish()
+    }
+}
+
+impl<T: Serialize> Serialize for Encrypted<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let repr = match &self.state {
+            State::Plain { subject, value } => {
+                if !SEALING.get() {
+                    return Err(S::Error::custom(format!(
+                        "unsealed personal data of {subject}: encrypted values must be saved \
+                         through a repository with encryption"
+                    )));
+                }
+                ReprRef::Plain { subject, value }
+            }
+            State::Sealed(sealed) => ReprRef::Sealed(sealed),
+            State::Shredded { subject } => ReprRef::Shredded { subject },
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Encrypted<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let state = match Repr::deserialize(deserializer)? {
+            Repr::Plain { subject, value } => State::Plain { subject, value },
+            Repr::Sealed(sealed) => State::Sealed(sealed),
+            Repr::Shredded { subject } => State::Shredded { subject },
+        };
+        Ok(Self { state })
+    }
+}
+
+/// A replacement for events holding shredded values.
+type Placeholder<E> = Box<dyn Fn(&RawStoredEvent) -> E + Send + Sync>;
+
+/// Seals the [`Encrypted`] values of events with the keys of a [`KeyStore`],
+/// and opens them again.
+pub struct Encryption<E: Event> {
+    keys: Arc<dyn KeyStore>,
+    placeholder: Option<Placeholder<E>>,
+}
+
+/// A stored event whose sealed values have been opened.
+pub(crate) enum Opened {
+    /// Every value could be decrypted.
+    Readable(RawStoredEvent),
+    /// Some values are marked as shredded.
+    Shredded(RawStoredEvent),
+}
+
+impl<E: Event> Encryption<E> {
+    /// Creates an `Encryption` using the keys of `keys`.
+    pub fn new<K: KeyStore + 'static>(keys: Arc<K>) -> Self {
+        Self {
+            keys,
+            placeholder: None,
+        }
+    }
+
+    /// Sets the placeholder loaded instead of any event holding shredded
+    /// values.
+    ///
+    /// It receives the stored event, as JSON, with every shredded value
+    /// marked as such. Without a placeholder, such events load with their
+    /// shredded values reporting [`Encrypted::is_shredded`].
+    pub fn with_placeholder<F>(mut self, placeholder: F) -> Self
+    where
+        F: Fn(&RawStoredEvent) -> E + Send + Sync + 'static,
+    {
+        self.placeholder = Some(Box::new(placeholder));
+        self
+    }
+
+    /// Forgets the key of `subject`, shredding all of their personal data.
+    pub async fn forget(&self, subject: &str) -> Result<()> {
+        self.keys.forget(subject).await
+    }
+
+    /// Encrypts the plain [`Encrypted`] values of events, creating keys for
+    /// subjects that have none.
+    pub async fn seal(&self, events: Vec<E>) -> Result<Vec<E>> {
+        let mut keys = HashMap::new();
+        let mut sealed = Vec::with_capacity(events.len());
+        for event in events {
+            let mut payload = {
+                let _sealing = Sealing::begin();
+                serde_json::to_value(&event).map_err(|e| Error::Store(e.to_string()))?
+            };
+            let subjects = subjects(&payload, PLAIN)?;
+            if subjects.is_empty() {
+                sealed.push(event);
+                continue;
+            }
+            for subject in subjects {
+                if let Entry::Vacant(entry) = keys.entry(subject) {
+                    let key = self.keys.load_or_create(entry.key()).await?;
+                    entry.insert(key);
+                }
+            }
+
+            rewrite(&mut payload, PLAIN, &mut |content| {
+                let Plain { subject, value } = from_value(content)?;
+                let key = &keys[&subject];
+                let plaintext =
+                    serde_json::to_vec(&value).map_err(|e| Error::Store(e.to_string()))?;
+                let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let ciphertext = key
+                    .cipher()
+                    .encrypt(
+                        &nonce,
+                        Payload {
+                            msg: &plaintext,
+                            aad: subject.as_bytes(),
+                        },
+                    )
+                    .map_err(|_| Error::Store(format!("cannot encrypt value of {subject}")))?;
+                let sealed = Sealed {
+                    nonce: BASE64.encode(nonce),
+                    ciphertext: BASE64.encode(ciphertext),
+                    subject,
+                };
+                tagged(SEALED, &sealed)
+            })?;
+            sealed.push(from_value(payload)?);
+        }
+        Ok(sealed)
+    }
+
+    /// Decrypts the sealed values of a stored event, marking those whose key
+    /// has been forgotten as shredded.
+    pub(crate) async fn open(&self, event: RawStoredEvent) -> Result<Opened> {
+        if !event
+            .payload
+            .windows(SEALED.len())
+            .any(|window| window == SEALED.as_bytes())
+        {
+            return Ok(Opened::Readable(event));
+        }
+        let mut payload: Value = event.decode().map_err(|e| {
+            Error::Store(format!(
+                "cannot open {} payload of {}: {e}",
+                event.format, event.event_type
+            ))
+        })?;
+
+        let mut keys = HashMap::new();
+        for subject in subjects(&payload, SEALED)? {
+            let key = self.keys.load(&subject).await?;
+            keys.insert(subject, key);
+        }
+
+        let mut shredded = false;
+        rewrite(&mut payload, SEALED, &mut |content| {
+            let sealed: Sealed = from_value(content)?;
+            let Some(key) = &keys[&sealed.subject] else {
+                shredded = true;
+                return tagged(SHREDDED, &serde_json::json!({ "subject": sealed.subject }));
+            };
+            let corrupt = || Error::Store(format!("corrupt value of {}", sealed.subject));
+            let nonce = BASE64.decode(&sealed.nonce).map_err(|_| corrupt())?;
+            if nonce.len() != 24 {
+                return Err(corrupt());
+            }
+            let ciphertext = BASE64.decode(&sealed.ciphertext).map_err(|_| corrupt())?;
+            let plaintext = key
+                .cipher()
+                .decrypt(
+                    XNonce::from_slice(&nonce),
+                    Payload {
+                        msg: &ciphertext,
+                        aad: sealed.subject.as_bytes(),
+                    },
+                )
+                .map_err(|_| Error::Store(format!("cannot decrypt value of {}", sealed.subject)))?;
+            let value: Value =
+                serde_json::from_slice(&plaintext).map_err(|e| Error::Store(e.to_string()))?;
+            tagged(
+                PLAIN,
+                &Plain {
+                    subject: sealed.subject,
+                    value,
+                },
+            )
+        })?;
+
+        let event = RawStoredEvent {
+            format: Format::Json,
+            payload: JsonSerializer.serialize(&payload)?,
+            ..event
+        };
+        Ok(if shredded {
+            Opened::Shredded(event)
+        } else {
+            Opened::Readable(event)
+        })
+    }
+
+    /// Returns the placeholder for an event holding shredded values, if one
+    /// is set.
+    pub(crate) fn placeholder(&self, event: &RawStoredEvent) -> Option<E> {
+        self.placeholder
+            .as_ref()
+            .map(|placeholder| placeholder(event))
+    }
+}
+
+/// The content of a plain value.
+#[derive(Serialize, Deserialize)]
+struct Plain {
+    subject: String,
+    value: Value,
+}
+
+/// Deserializes a JSON value.
+fn from_value<T: serde::de::DeserializeOwned>(value: Value) -> Result<T> {
+    serde_json::from_value(value).map_err(|e| Error::Store(e.to_string()))
+}
+
+/// Wraps `content` in an object with the single key `tag`.
+fn tagged<T: Serialize>(tag: &str, content: &T) -> Result<Value> {
+    let content = serde_json::to_value(content).map_err(|e| Error::Store(e.to_string()))?;
+    Ok(Value::Object(
+        [(tag.to_string(), content)].into_iter().collect(),
+    ))
+}
+
+/// Returns the content of `value` if it is an object tagged `tag`.
+fn tag_content<'a>(value: &'a Value, tag: &str) -> Option<&'a Value> {
+    match value {
+        Value::Object(map) if map.len() == 1 => map.get(tag),
+        _ => None,
+    }
+}
+
+/// Collects the subjects of every value tagged `tag`.
+fn subjects(value: &Value, tag: &str) -> Result<BTreeSet<String>> {
+    fn visit(value: &Value, tag: &str, subjects: &mut BTreeSet<String>) -> Result<()> {
+        if let Some(content) = tag_content(value, tag) {
+            let subject = content
+                .get("subject")
+                .and_then(Value::as_str)
+                .ok_or_else(|| Error::Store(format!("`{tag}` value without a subject")))?;
+            subjects.insert(subject.to_string());
+            return Ok(());
+        }
+        match value {
+            Value::Object(map) => map.values().try_for_each(|v| visit(v, tag, subjects)),
+            Value::Array(items) => items.iter().try_for_each(|v| visit(v, tag, subjects)),
+            _ => Ok(()),
+        }
+    }
+
+    let mut subjects = BTreeSet::new();
+    visit(value, tag, &mut subjects)?;
+    Ok(subjects)
+}
+
+/// Replaces every value tagged `tag` with what `f` returns for its content.
+fn rewrite(value: &mut Value, tag: &str, f: &mut dyn FnMut(Value) -> Result<Value>) -> Result<()> {
+    if let Value::Object(map) = value
+        && map.len() == 1
+        && let Some(content) = map.get_mut(tag)
+    {
+        let content = content.take();
+        *value = f(content)?;
+        return Ok(());
+    }
+    match value {
+        Value::Object(map) => map.values_mut().try_for_each(|v| rewrite(v, tag, f)),
+        Value::Array(items) => items.iter_mut().try_for_each(|v| rewrite(v, tag, f)),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,321 @@
+//! Binds [`CloudEvent`]s to HTTP, for receiving and emitting them over
+//! webhooks.
+//!
+//! All three content modes of the CloudEvents HTTP protocol binding are
+//! supported:
+//!
+//! * **Binary** mode carries the attributes in `ce-*` headers and the data
+//!   as the body, with its own `content-type`.
+//! * **Structured** mode carries the whole event as an
+//!   `application/cloudevents+json` body.
+//! * **Batch** mode carries a JSON array of events as an
+//!   `application/cloudevents-batch+json` body.
+//!
+//! [`decode`], [`decode_batch`], [`encode`] and [`encode_batch`] convert
+//! between events and header maps and bodies, for use with any HTTP client.
+//! For axum, [`CloudEvent`] is an extractor accepting a single event in
+//! either binary or structured mode, and a responder in binary mode. The
+//! [`Binary`], [`Structured`] and [`Batch`] wrappers accept and respond in
+//! one mode only.
+//!
+//! # Example
+//!
+//! ```rust
+//! use axum::{Router, routing::post};
+//! use sourcerer::{CloudEvent, http::Batch};
+//!
+//! /// Echoes a single event back in binary mode.
+//! async fn echo(event: CloudEvent) -> CloudEvent {
+//!     event
+//! }
+//!
+//! /// Acknowledges a batch of events with the number received.
+//! async fn ingest(Batch(events): Batch) -> String {
+//!     events.len().to_string()
+//! }
+//!
+//! let app: Router = Router::new()
+//!     .route("/events", post(echo))
+//!     .route("/events/batch", post(ingest));
+//! ```
+
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use cloudevents::{
+    binding::http::{Builder, Serializer, to_event},
+    event::Event as CeEvent,
+    message::BinaryDeserializer,
+};
+
+use crate::{CloudEvent, Error, Result};
+
+/// The content type of structured mode.
+pub const STRUCTURED_CONTENT_TYPE: &str = "application/cloudevents+json";
+
+/// The content type of batch mode.
+pub const BATCH_CONTENT_TYPE: &str = "application/cloudevents-batch+json";
+
+/// A content mode of the CloudEvents HTTP protocol binding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Attributes in `ce-*` headers, data as the body.
+    Binary,
+    /// A single JSON-encoded event as the body.
+    Structured,
+    /// A JSON array of events as the body.
+    Batch,
+}
+
+impl Mode {
+    /// Returns the mode of a message from its `content-type` header.
+    ///
+    /// Messages that are neither structured nor batched are in binary mode,
+    /// whatever the content type of their data.
+    pub fn of(headers: &HeaderMap) -> Self {
+        let media_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase());
+        match media_type.as_deref() {
+            Some(STRUCTURED_CONTENT_TYPE) => Self::Structured,
+            Some(BATCH_CONTENT_TYPE) => Self::Batch,
+            _ => Self::Binary,
+        }
+    }
+}
+
+/// Decodes a single event in binary or structured mode.
+pub fn decode(headers: &HeaderMap, body: Vec<u8>) -> Result<CloudEvent> {
+    if Mode::of(headers) == Mode::Batch {
+        return Err(Error::Validation(
+            "expected a single CloudEvent, got a batch".into(),
+        ));
+    }
+    to_event(headers, body)
+        .map(CloudEvent)
+        .map_err(|e| Error::Validation(format!("invalid CloudEvent: {e}")))
+}
+
+/// Decodes a batch of events.
+pub fn decode_batch(headers: &HeaderMap, body: &[u8]) -> Result<Vec<CloudEvent>> {
+    if Mode::of(headers) != Mode::Batch {
+        return Err(Error::Validation(format!(
+            "expected a CloudEvents batch with content type {BATCH_CONTENT_TYPE}"
+        )));
+    }
+    let events: Vec<CeEvent> = serde_json::from_slice(body)
+        .map_err(|e| Error::Validation(format!("invalid CloudEvents batch: {e}")))?;
+    Ok(events.into_iter().map(CloudEvent).collect())
+}
+
+/// Encodes a single event in the given mode, as a batch of one in batch mode.
+pub fn encode(event: &CloudEvent, mode: Mode) -> Result<(HeaderMap, Vec<u8>)> {
+    match mode {
+        Mode::Binary => BinaryDeserializer::deserialize_binary(
+            event.0.clone(),
+            Serializer::new(BinaryParts::default()),
+        )
+        .map_err(|e| Error::Validation(format!("failed to encode CloudEvent: {e}"))),
+        Mode::Structured => {
+            let body = serde_json::to_vec(&event.0)
+                .map_err(|e| Error::Validation(format!("failed to encode CloudEvent: {e}")))?;
+            Ok((content_type(STRUCTURED_CONTENT_TYPE), body))
+        }
+        Mode::Batch => encode_batch(std::slice::from_ref(event)),
+    }
+}
+
+/// Encodes a batch of events.
+pub fn encode_batch(events: &[CloudEvent]) -> Result<(HeaderMap, Vec<u8>)> {
+    let events: Vec<&CeEvent> = events.iter().map(|event| &event.0).collect();
+    let body = serde_json::to_vec(&events)
+        .map_err(|e| Error::Validation(format!("failed to encode CloudEvents batch: {e}")))?;
+    Ok((content_type(BATCH_CONTENT_TYPE), body))
+}
+
+/// Returns headers holding only the given content type.
+fn content_type(value: &'static str) -> HeaderMap {
+    HeaderMap::from_iter([(CONTENT_TYPE, HeaderValue::from_static(value))])
+}
+
+/// Collects the headers and body of a binary-mode message.
+#[derive(Default)]
+struct BinaryParts {
+    headers: HeaderMap,
+}
+
+impl Builder<(HeaderMap, Vec<u8>)> for BinaryParts {
+    fn header(&mut self, key: &str, value: HeaderValue) {
+        // Keys are `ce-` followed by an attribute name, or `content-type`,
+        // which are all valid header names.
+        if let Ok(name) = HeaderName::try_from(key) {
+            self.headers.insert(name, value);
+        }
+    }
+
+    fn body(&mut self, bytes: Vec<u8>) -> cloudevents::message::Result<(HeaderMap, Vec<u8>)> {
+        Ok((std::mem::take(&mut self.headers), bytes))
+    }
+
+    fn finish(&mut self) -> cloudevents::message::Result<(HeaderMap, Vec<u8>)> {
+        self.body(Vec::new())
+    }
+}
+
+/// Rejects a request that does not hold the CloudEvents an extractor
+/// expects.
+#[derive(Debug, Clone)]
+pub struct CloudEventRejection {
+    status: StatusCode,
+    message: String,
+}
+
+impl CloudEventRejection {
+    /// Returns the status the request is rejected with: `415 Unsupported
+    /// Media Type` for the wrong content mode and `400 Bad Request` for a
+    /// malformed event.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns why the request was rejected.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn unsupported(mode: Mode) -> Self {
+        Self {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            message: format!("CloudEvents in {mode:?} mode are not accepted here"),
+        }
+    }
+
+    fn invalid(error: &Error) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for CloudEventRejection {
+    fn into_response(self) -> Response {
+        (self.status, self.message).into_response()
+    }
+}
+
+/// Reads the headers and body of a request in one of the `accepted` modes.
+async fn read<S: Send + Sync>(
+    req: Request,
+    state: &S,
+    accepted: &[Mode],
+) -> std::result::Result<(HeaderMap, Bytes), CloudEventRejection> {
+    let mode = Mode::of(req.headers());
+    if !accepted.contains(&mode) {
+        return Err(CloudEventRejection::unsupported(mode));
+    }
+    let headers = req.headers().clone();
+    let body = Bytes::from_request(req, state)
+        .await
+        .map_err(|e| CloudEventRejection {
+            status: e.status(),
+            message: e.body_text(),
+        })?;
+    Ok((headers, body))
+}
+
+/// Returns the response for an encoded message, or `500 Internal Server
+/// Error` if it could not be encoded.
+fn respond(encoded: Result<(HeaderMap, Vec<u8>)>) -> Response {
+    match encoded {
+        Ok((headers, body)) => (headers, body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequest<S> for CloudEvent {
+    type Rejection = CloudEventRejection;
+
+    async fn from_request(req: Request, state: &S) -> std::result::Result<Self, Self::Rejection> {
+        let (headers, body) = read(req, state, &[Mode::Binary, Mode::Structured]).await?;
+        decode(&headers, body.to_vec()).map_err(|e| CloudEventRejection::invalid(&e))
+    }
+}
+
+impl IntoResponse for CloudEvent {
+    fn into_response(self) -> Response {
+        respond(encode(&self, Mode::Binary))
+    }
+}
+
+/// A single CloudEvent in binary mode.
+#[derive(Debug, Clone)]
+pub struct Binary(pub CloudEvent);
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequest<S> for Binary {
+    type Rejection = CloudEventRejection;
+
+    async fn from_request(req: Request, state: &S) -> std::result::Result<Self, Self::Rejection> {
+        let (headers, body) = read(req, state, &[Mode::Binary]).await?;
+        decode(&headers, body.to_vec())
+            .map(Self)
+            .map_err(|e| CloudEventRejection::invalid(&e))
+    }
+}
+
+impl IntoResponse for Binary {
+    fn into_response(self) -> Response {
+        respond(encode(&self.0, Mode::Binary))
+    }
+}
+
+/// A single CloudEvent in structured mode.
+#[derive(Debug, Clone)]
+pub struct Structured(pub CloudEvent);
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequest<S> for Structured {
+    type Rejection = CloudEventRejection;
+
+    async fn from_request(req: Request, state: &S) -> std::result::Result<Self, Self::Rejection> {
+        let (headers, body) = read(req, state, &[Mode::Structured]).await?;
+        decode(&headers, body.to_vec())
+            .map(Self)
+            .map_err(|e| CloudEventRejection::invalid(&e))
+    }
+}
+
+impl IntoResponse for Structured {
+    fn into_response(self) -> Response {
+        respond(encode(&self.0, Mode::Structured))
+    }
+}
+
+/// A batch of CloudEvents in batch mode.
+#[derive(Debug, Clone, Default)]
+pub struct Batch(pub Vec<CloudEvent>);
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequest<S> for Batch {
+    type Rejection = CloudEventRejection;
+
+    async fn from_request(req: Request, state: &S) -> std::result::Result<Self, Self::Rejection> {
+        let (headers, body) = read(req, state, &[Mode::Batch]).await?;
+        decode_batch(&headers, &body)
+            .map(Self)
+            .map_err(|e| CloudEventRejection::invalid(&e))
+    }
+}
+
+impl IntoResponse for Batch {
+    fn into_response(self) -> Response {
+        respond(encode_batch(&self.0))
+    }
+}
@@ -0,0 +1,217 @@
+//! Provides the tamper-evident hash chain over event streams.
+//!
+//! Every store hashes each event it appends together with the hash of the
+//! event before it in the same stream, so editing, reordering or removing a
+//! stored event changes the hash of every event after it.
+//! [`EventStore::verify_stream`](crate::EventStore::verify_stream) and
+//! [`EventStore::verify_all`](crate::EventStore::verify_all) recompute the
+//! chain and report the first [`BrokenLink`].
+//!
+//! A hash is the SHA-256 of the event's aggregate type and ID, version, event
+//! type and schema version, metadata and payload. Payloads are hashed as JSON
+//! with sorted keys, so the hash does not depend on the payload format or on
+//! how a backend normalizes stored JSON. The global position is not covered,
+//! and events removed from the end of a stream leave no trace in the chain.
+use std::{collections::HashMap, fmt};
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    Error, Event, EventMetadata, Result, StoredEvent, serializer::Format, upcaster::RawStoredEvent,
+};
+
+/// The first event of a stream whose hash does not check out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokenLink {
+    /// The type name of the aggregate the event belongs to.
+    pub aggregate_type: String,
+    /// The ID of the aggregate the event belongs to.
+    pub aggregate_id: String,
+    /// The version of the event in its stream.
+    pub version: i64,
+    /// The position of the event in the store's global log.
+    pub position: i64,
+    /// What is wrong with the event.
+    pub problem: LinkProblem,
+}
+
+/// Describes how an event breaks its stream's hash chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkProblem {
+    /// The event was stored without a hash, for example before hashes were
+    /// recorded.
+    Unhashed,
+    /// The recorded hash differs from the one computed from the event and
+    /// its predecessor.
+    Mismatch {
+        /// The hash stored with the event.
+        recorded: String,
+        /// The hash computed when verifying.
+        computed: String,
+    },
+}
+
+impl fmt::Display for BrokenLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "event {} of {}/{} at position {} ",
+            self.version, self.aggregate_type, self.aggregate_id, self.position
+        )?;
+        match &self.problem {
+            LinkProblem::Unhashed => f.write_str("has no hash"),
+            LinkProblem::Mismatch { recorded, computed } => {
+                write!(f, "is recorded as {recorded} but hashes to {computed}")
+            }
+        }
+    }
+}
+
+impl<E: Event> StoredEvent<E> {
+    /// Sets the hash of this event, chained to the hash of the event before
+    /// it in the stream, if there is one.
+    pub(crate) fn chained(mut self, previous: Option<&str>) -> Result<Self> {
+        let payload =
+            serde_json::to_value(self.event()).map_err(|e| Error::Store(e.to_string()))?;
+        self.hash = Some(hash(
+            previous,
+            Header {
+                aggregate_type: self.aggregate_type(),
+                aggregate_id: self.aggregate_id(),
+                version: self.version(),
+                event_version: self.event_version(),
+                event_type: self.event_type(),
+                metadata: self.metadata(),
+            },
+            &payload,
+        )?);
+        Ok(self)
+    }
+
+    /// Sets the hash read back from a store, if the event was stored with
+    /// one.
+    pub(crate) fn with_recorded_hash(mut self, hash: Option<String>) -> Self {
+        self.hash = hash;
+        self
+    }
+}
+
+/// The hashed fields of an event besides its payload.
+#[derive(serde::Serialize)]
+struct Header<'a> {
+    aggregate_type: &'a str,
+    aggregate_id: &'a str,
+    version: i64,
+    event_version: u16,
+    event_type: &'a str,
+    metadata: &'a EventMetadata,
+}
+
+/// Computes the hash of an event from its predecessor's hash, its header and
+/// its payload.
+fn hash(previous: Option<&str>, header: Header<'_>, payload: &Value) -> Result<String> {
+    let header = serde_json::to_value(header).map_err(|e| Error::Store(e.to_string()))?;
+    let mut bytes = Vec::new();
+    canonical(
+        &serde_json::json!({
+            "previous": previous,
+            "header": header,
+            "payload": payload,
+        }),
+        &mut bytes,
+    );
+
+    let digest = Sha256::digest(&bytes);
+    let mut hex = String::with_capacity(digest.len() * 2);
+    for byte in digest {
+        hex.push_str(&format!("{byte:02x}"));
+    }
+    Ok(hex)
+}
+
+/// Writes a JSON value with the keys of every object sorted.
+fn canonical(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            out.push(b'{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                out.extend_from_slice(Value::String(key.clone()).to_string().as_bytes());
+                out.push(b':');
+                canonical(value, out);
+            }
+            out.push(b'}');
+        }
+        Value::Array(items) => {
+            out.push(b'[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                canonical(item, out);
+            }
+            out.push(b']');
+        }
+        scalar => out.extend_from_slice(scalar.to_string().as_bytes()),
+    }
+}
+
+/// Follows the hash chains of one or more streams, event by event.
+#[derive(Debug, Default)]
+pub(crate) struct Chains {
+    /// The hash of the last event checked in each stream.
+    previous: HashMap<String, String>,
+}
+
+impl Chains {
+    /// Checks the next event of its stream, returning the broken link if its
+    /// hash does not check out.
+    ///
+    /// Payloads are hashed as stored, so events that can no longer be
+    /// deserialized into `E` still verify, except for bincode payloads,
+    /// which are only readable as `E`.
+    pub(crate) fn check<E: Event>(&mut self, event: &RawStoredEvent) -> Result<Option<BrokenLink>> {
+        let payload: Value = match event.format {
+            Format::Bincode => serde_json::to_value(event.decode::<E>()?)
+                .map_err(|e| Error::Store(e.to_string()))?,
+            _ => event.decode()?,
+        };
+        let stream = format!("{}/{}", event.aggregate_type, event.aggregate_id);
+        let computed = hash(
+            self.previous.get(&stream).map(String::as_str),
+            Header {
+                aggregate_type: &event.aggregate_type,
+                aggregate_id: &event.aggregate_id,
+                version: event.version,
+                event_version: event.event_version,
+                event_type: &event.event_type,
+                metadata: &event.metadata,
+            },
+            &payload,
+        )?;
+
+        let problem = match &event.hash {
+            None => LinkProblem::Unhashed,
+            Some(recorded) if *recorded != computed => LinkProblem::Mismatch {
+                recorded: recorded.clone(),
+                computed,
+            },
+            Some(_) => {
+                self.previous.insert(stream, computed);
+                return Ok(None);
+            }
+        };
+        Ok(Some(BrokenLink {
+            aggregate_type: event.aggregate_type.clone(),
+            aggregate_id: event.aggregate_id.clone(),
+            version: event.version,
+            position: event.position,
+            problem,
+        }))
+    }
+}
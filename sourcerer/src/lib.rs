@@ -15,6 +15,9 @@
 //!   optimize loading.
 //! - **[`Repository`]**: A high-level API for loading aggregates, handling
 //!   commands, and saving events.
+//! - **[`Projection`]**: A read model built from the global event log, kept
+//!   up to date by a [`projection::ProjectionRunner`] that records progress
+//!   in a [`CheckpointStore`].
 //!
 //! ## Example
 //!
@@ -67,6 +70,7 @@
 //! // 2. Implement the Aggregate trait.
 //! #[sourcerer::async_trait]
 //! impl Aggregate for BankAccount {
+//!     const TYPE_NAME: &str = "bank-account";
 //!     type Id = Uuid;
 //!     type Event = BankAccountEvent;
 //!     type Command = BankAccountCommand;
@@ -135,27 +139,55 @@
 use std::fmt::Debug;
 
 pub use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt, stream::BoxStream};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use uuid::Uuid;
 
 pub mod cloudevent;
+pub mod compression;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod integrity;
+pub mod metadata;
+pub mod outbox;
+pub mod projection;
 pub mod repository;
+pub mod serializer;
 pub mod snapshot;
 pub mod store;
+pub mod subscription;
 pub mod upcaster;
 
+#[cfg(feature = "encryption")]
+pub use encryption::KeyStore;
+pub use outbox::{EventPublisher, Outbox};
+pub use projection::{CheckpointStore, Projection};
 pub use repository::Repository;
+pub use serializer::EventSerializer;
 pub use snapshot::SnapshotStore;
 
 pub use cloudevent::CloudEvent;
+pub use metadata::EventMetadata;
 
 /// The error type for this crate.
 #[derive(Debug, thiserror::Error, Clone)]
 pub enum Error {
     /// Occurs when an aggregate's expected version does not match the actual
     /// version, indicating a concurrency conflict.
-    #[error("aggregate conflict")]
-    Conflict,
+    #[error(
+        "concurrency conflict on aggregate {aggregate_id}: expected {expected}, found version {actual}"
+    )]
+    Conflict {
+        /// The ID of the aggregate whose stream was appended to.
+        aggregate_id: String,
+        /// The expectation passed to the append.
+        expected: ExpectedVersion,
+        /// The version the stream was actually at.
+        actual: i64,
+    },
     /// Occurs when an aggregate could not be found.
     #[error("aggregate not found")]
     NotFound,
@@ -165,11 +197,105 @@ pub enum Error {
     /// Occurs when a command fails a validation rule.
     #[error("validation error: {0}")]
     Validation(String),
+    /// Occurs when a stream fails hash chain verification.
+    #[error("hash chain broken: {0}")]
+    Integrity(Box<integrity::BrokenLink>),
 }
 
 /// A specialized `Result` type for this crate's operations.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The error returned when handling a command through a repository.
+///
+/// It keeps the aggregate's own error type intact, so callers can tell a
+/// command the domain rejected apart from a concurrency conflict or an
+/// infrastructure failure.
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError<E> {
+    /// The aggregate rejected the command.
+    #[error("command rejected: {0}")]
+    Domain(#[source] E),
+    /// The events could not be saved because the aggregate was changed
+    /// concurrently.
+    #[error(
+        "concurrency conflict on aggregate {aggregate_id}: expected {expected}, found version {actual}"
+    )]
+    Concurrency {
+        /// The ID of the aggregate whose stream was appended to.
+        aggregate_id: String,
+        /// The expectation passed to the append.
+        expected: ExpectedVersion,
+        /// The version the stream was actually at.
+        actual: i64,
+    },
+    /// Loading or saving the aggregate failed.
+    #[error(transparent)]
+    Storage(Error),
+}
+
+impl<E> From<Error> for CommandError<E> {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Conflict {
+                aggregate_id,
+                expected,
+                actual,
+            } => Self::Concurrency {
+                aggregate_id,
+                expected,
+                actual,
+            },
+            error => Self::Storage(error),
+        }
+    }
+}
+
+/// The optimistic concurrency expectation for an append.
+///
+/// Versions count the events in a stream, so a stream that does not exist yet
+/// has version `0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ExpectedVersion {
+    /// The append succeeds whatever the stream's current version.
+    Any,
+    /// The stream must not exist yet.
+    NoStream,
+    /// The stream must already contain at least one event.
+    StreamExists,
+    /// The stream must be at exactly this version.
+    Exact(i64),
+}
+
+impl ExpectedVersion {
+    /// Returns `true` if a stream at `current_version` satisfies this
+    /// expectation.
+    pub fn matches(self, current_version: i64) -> bool {
+        match self {
+            Self::Any => true,
+            Self::NoStream => current_version == 0,
+            Self::StreamExists => current_version > 0,
+            Self::Exact(expected) => current_version == expected,
+        }
+    }
+}
+
+impl std::fmt::Display for ExpectedVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Any => f.write_str("any version"),
+            Self::NoStream => f.write_str("no stream"),
+            Self::StreamExists => f.write_str("an existing stream"),
+            Self::Exact(version) => write!(f, "version {version}"),
+        }
+    }
+}
+
+impl From<i64> for ExpectedVersion {
+    fn from(version: i64) -> Self {
+        Self::Exact(version)
+    }
+}
+
 /// A marker trait for events.
 ///
 /// Events must be serializable, deserializable, clonable, and debuggable.
@@ -206,6 +332,14 @@ impl AggregateId for Uuid {
 /// of the domain model.
 #[async_trait]
 pub trait Aggregate: Default + Send + Sync + 'static {
+    /// The name of the aggregate type, such as `"order"`.
+    ///
+    /// Stores namespace every stream and snapshot by this name, so aggregates
+    /// of different types can share IDs and storage. It must be unique among
+    /// the aggregates sharing a store and must not change once events have
+    /// been stored. Stores keying streams by name reject names containing
+    /// `/`, which separates the type name from the aggregate ID.
+    const TYPE_NAME: &'static str;
     /// The type of the aggregate's unique identifier.
     type Id: AggregateId;
     /// The type of events that this aggregate produces.
@@ -248,8 +382,21 @@ pub trait Aggregate: Default + Send + Sync + 'static {
     }
 }
 
-/// A marker trait for snapshots.
-pub trait Snapshot: Serialize + DeserializeOwned + Clone + Debug + Send + Sync {}
+/// A trait for snapshots.
+///
+/// The `Snapshot` derive macro can be used to implement this trait, setting
+/// the schema version with `#[snapshot(version = N)]`.
+pub trait Snapshot: Serialize + DeserializeOwned + Clone + Debug + Send + Sync {
+    /// Returns the version of the snapshot's schema.
+    ///
+    /// Bump it whenever the snapshot's serialized shape changes. Stored
+    /// snapshots of another version are upcast by a
+    /// [`SnapshotUpcasterChain`](upcaster::SnapshotUpcasterChain), or
+    /// ignored by the repository in favour of a full replay.
+    fn snapshot_version() -> u16 {
+        1
+    }
+}
 
 /// Represents a stored event, including metadata.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -258,6 +405,9 @@ pub trait Snapshot: Serialize + DeserializeOwned + Clone + Debug + Send + Sync {
     deserialize = "E: serde::de::DeserializeOwned"
 ))]
 pub struct StoredEvent<E: Event> {
+    /// The type name of the aggregate this event belongs to.
+    #[serde(default)]
+    aggregate_type: String,
     /// The ID of the aggregate this event belongs to.
     aggregate_id: String,
     /// The version of the aggregate after this event was applied.
@@ -268,6 +418,15 @@ pub struct StoredEvent<E: Event> {
     event_type: String,
     /// The event payload itself.
     event: E,
+    /// The position of this event in the store's global, totally-ordered log.
+    #[serde(default)]
+    position: i64,
+    /// The metadata envelope recorded alongside the event.
+    #[serde(default)]
+    metadata: EventMetadata,
+    /// The hash of this event, chained to the previous event in the stream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
 }
 
 impl<E: Event> StoredEvent<E> {
@@ -280,14 +439,58 @@ impl<E: Event> StoredEvent<E> {
         event: E,
     ) -> Self {
         Self {
+            aggregate_type: String::new(),
             aggregate_id,
             version,
             event_version,
             event_type,
             event,
+            position: 0,
+            metadata: EventMetadata::default(),
+            hash: None,
         }
     }
 
+    /// Sets the type name of the aggregate this event belongs to.
+    ///
+    /// Stores call this on `append` with [`Aggregate::TYPE_NAME`].
+    #[must_use]
+    pub fn with_aggregate_type(mut self, aggregate_type: impl Into<String>) -> Self {
+        self.aggregate_type = aggregate_type.into();
+        self
+    }
+
+    /// Sets the metadata recorded alongside this event.
+    #[must_use]
+    pub fn with_metadata(mut self, metadata: EventMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Sets the global position of this event.
+    ///
+    /// Stores call this on `append` once the position has been assigned.
+    #[must_use]
+    pub fn with_position(mut self, position: i64) -> Self {
+        self.position = position;
+        self
+    }
+
+    /// Sets the hash recorded for this event.
+    ///
+    /// Stores call this when reading back events. Appended events are hashed
+    /// by the store; see [`integrity`].
+    #[must_use]
+    pub fn with_hash(mut self, hash: impl Into<String>) -> Self {
+        self.hash = Some(hash.into());
+        self
+    }
+
+    /// Returns the type name of the aggregate this event belongs to.
+    pub fn aggregate_type(&self) -> &str {
+        &self.aggregate_type
+    }
+
     /// Returns the ID of the aggregate this event belongs to.
     pub fn aggregate_id(&self) -> &str {
         &self.aggregate_id
@@ -304,6 +507,23 @@ impl<E: Event> StoredEvent<E> {
     pub fn event_type(&self) -> &str {
         &self.event_type
     }
+    /// Returns the position of this event in the store's global log.
+    ///
+    /// Positions start at `1` and increase monotonically across every
+    /// aggregate in the store, following the order in which events were
+    /// committed.
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+    /// Returns the metadata recorded alongside this event.
+    pub fn metadata(&self) -> &EventMetadata {
+        &self.metadata
+    }
+    /// Returns the hash of this event, chained to the previous event in the
+    /// stream, or `None` if it was stored without one.
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
     /// Returns the event payload itself.
     pub fn event(&self) -> &E {
         &self.event
@@ -319,16 +539,51 @@ impl<E: Event> StoredEvent<E> {
 pub trait EventStore<A: Aggregate>: Send + Sync {
     /// Appends a list of events to the event store for a given aggregate.
     ///
-    /// This operation must be atomic. It should fail if the `expected_version`
-    /// does not match the current version of the aggregate, preventing
-    /// optimistic concurrency conflicts.
+    /// This operation must be atomic. It should fail with [`Error::Conflict`]
+    /// if the current version of the aggregate does not satisfy
+    /// `expected_version`, preventing optimistic concurrency conflicts. New
+    /// events are numbered on from the current version.
+    ///
+    /// Every appended event is stored with a copy of `metadata`, stamped with
+    /// its own event ID and the time of the append.
     async fn append(
         &self,
         id: &A::Id,
-        expected_version: i64,
+        expected_version: ExpectedVersion,
         events: Vec<A::Event>,
+        metadata: EventMetadata,
     ) -> Result<Vec<StoredEvent<A::Event>>>;
 
+    /// Appends events to several streams atomically: either every entry of
+    /// `batch` is appended, or none is.
+    ///
+    /// Each entry names a stream, the version it is expected at and the
+    /// events to append, as in [`append`](EventStore::append). Entries are
+    /// applied in order, so a stream may appear more than once, each entry
+    /// expecting the version left by the one before. Every event is stored
+    /// with a copy of `metadata`, and the stored events are returned in batch
+    /// order.
+    ///
+    /// The default implementation only accepts batches touching a single
+    /// entry, which it passes to `append`; stores able to commit several
+    /// streams in one transaction override it.
+    async fn append_batch(
+        &self,
+        batch: Vec<(A::Id, ExpectedVersion, Vec<A::Event>)>,
+        metadata: EventMetadata,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        let mut batch = batch.into_iter();
+        match (batch.next(), batch.next()) {
+            (None, _) => Ok(Vec::new()),
+            (Some((id, expected_version, events)), None) => {
+                self.append(&id, expected_version, events, metadata).await
+            }
+            (Some(_), Some(_)) => Err(Error::Store(
+                "this store cannot append to several streams atomically".into(),
+            )),
+        }
+    }
+
     /// Loads the full event stream for a given aggregate.
     async fn load(&self, id: &A::Id) -> Result<Vec<StoredEvent<A::Event>>>;
 
@@ -346,4 +601,145 @@ pub trait EventStore<A: Aggregate>: Send + Sync {
         id: &A::Id,
         version: i64,
     ) -> Result<Vec<crate::upcaster::RawStoredEvent>>;
+
+    /// Streams the event stream for a given aggregate, starting after a
+    /// specific version.
+    ///
+    /// Unlike [`load_from`](EventStore::load_from), events are read
+    /// incrementally, so memory use stays bounded no matter how long the
+    /// stream is.
+    fn stream_from<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<StoredEvent<A::Event>>>;
+
+    /// Streams the full event stream for a given aggregate.
+    fn stream<'a>(&'a self, id: &'a A::Id) -> BoxStream<'a, Result<StoredEvent<A::Event>>> {
+        self.stream_from(id, 0)
+    }
+
+    /// Streams the raw event stream for a given aggregate, starting after a
+    /// specific version.
+    ///
+    /// This is the streaming counterpart of [`load_raw`](EventStore::load_raw)
+    /// and is what the `GenericRepository` folds over when loading aggregates.
+    fn stream_raw<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<crate::upcaster::RawStoredEvent>>;
+
+    /// Streams the raw events of a given aggregate after `after_version`, up
+    /// to and including `until_version`.
+    ///
+    /// This is what the `GenericRepository` folds over when loading an
+    /// aggregate as of a past version. The default implementation stops
+    /// [`stream_raw`](EventStore::stream_raw) at the bound; stores that can
+    /// bound their reads override it.
+    fn stream_raw_range<'a>(
+        &'a self,
+        id: &'a A::Id,
+        after_version: i64,
+        until_version: i64,
+    ) -> BoxStream<'a, Result<crate::upcaster::RawStoredEvent>> {
+        self.stream_raw(id, after_version)
+            .try_take_while(move |event| futures::future::ready(Ok(event.version <= until_version)))
+            .boxed()
+    }
+
+    /// Returns the version of the latest event of a given aggregate recorded
+    /// at or before `timestamp`, or `0` if there is none.
+    ///
+    /// The default implementation reads the whole stream.
+    async fn version_as_of(&self, id: &A::Id, timestamp: DateTime<Utc>) -> Result<i64> {
+        self.stream_raw(id, 0)
+            .try_fold(0, |version, event| {
+                futures::future::ready(Ok(if event.metadata.recorded_at() <= timestamp {
+                    version.max(event.version)
+                } else {
+                    version
+                }))
+            })
+            .await
+    }
+
+    /// Reads events across all aggregates of type `A` in global commit order.
+    ///
+    /// Returns at most `limit` events whose position is strictly greater than
+    /// `from_position`. Passing `0` reads from the beginning of the log, and
+    /// passing the position of the last processed event resumes right after
+    /// it. Positions are shared by every aggregate type in the same storage,
+    /// so they may skip the positions of other types' events.
+    async fn read_all(
+        &self,
+        from_position: i64,
+        limit: usize,
+    ) -> Result<Vec<StoredEvent<A::Event>>>;
+
+    /// Reads raw events across all aggregates of type `A` in global commit
+    /// order, like [`read_all`](EventStore::read_all).
+    ///
+    /// The default implementation encodes the events returned by `read_all`
+    /// as JSON. Stores that keep encoded payloads return them as stored.
+    async fn read_all_raw(
+        &self,
+        from_position: i64,
+        limit: usize,
+    ) -> Result<Vec<crate::upcaster::RawStoredEvent>> {
+        self.read_all(from_position, limit)
+            .await?
+            .iter()
+            .map(|e| crate::upcaster::RawStoredEvent::encode(e, &serializer::JsonSerializer))
+            .collect()
+    }
+
+    /// Lists the IDs of every stream of type `A`, in ascending order.
+    async fn list_aggregate_ids(&self) -> Result<Vec<String>>;
+
+    /// Verifies the hash chain of a stream, returning the first event whose
+    /// hash does not check out, or `None` if the stream is intact.
+    async fn verify_stream(&self, id: &A::Id) -> Result<Option<integrity::BrokenLink>> {
+        let mut chains = integrity::Chains::default();
+        let mut events = self.stream_raw(id, 0);
+        while let Some(event) = events.try_next().await? {
+            if let Some(broken) = chains.check::<A::Event>(&event)? {
+                return Ok(Some(broken));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Verifies the hash chains of every stream of type `A`, returning the
+    /// first event in global order whose hash does not check out, or `None`
+    /// if every stream is intact.
+    async fn verify_all(&self) -> Result<Option<integrity::BrokenLink>> {
+        let mut chains = integrity::Chains::default();
+        let mut position = 0;
+        loop {
+            let events = self
+                .read_all_raw(position, subscription::BATCH_SIZE)
+                .await?;
+            let Some(last) = events.last() else {
+                return Ok(None);
+            };
+            position = last.position;
+            for event in &events {
+                if let Some(broken) = chains.check::<A::Event>(event)? {
+                    return Ok(Some(broken));
+                }
+            }
+        }
+    }
+
+    /// Subscribes to the global log of aggregates of type `A`.
+    ///
+    /// The returned stream first replays every event after `from_position`
+    /// that passes `filter`, then yields new events as they are appended,
+    /// without polling the store.
+    async fn subscribe(
+        &self,
+        from_position: i64,
+        filter: crate::subscription::SubscriptionFilter,
+    ) -> Result<crate::subscription::EventSubscription<A::Event>>;
 }
@@ -0,0 +1,170 @@
+//! Defines the metadata envelope recorded alongside every stored event.
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The namespace of the event IDs derived by [`legacy_event_id`].
+const LEGACY_EVENT_ID_NAMESPACE: Uuid = Uuid::from_u128(0x5d0b_8f6e_3c1a_4e2b_9f7d_2a6c_8e4b_1f03);
+
+/// Derives the event ID of an event stored without one from its aggregate
+/// type, aggregate ID and version, which identify it just as stably.
+pub(crate) fn legacy_event_id(aggregate_type: &str, aggregate_id: &str, version: i64) -> Uuid {
+    let name = format!("{aggregate_type}/{aggregate_id}/{version}");
+    Uuid::new_v5(&LEGACY_EVENT_ID_NAMESPACE, name.as_bytes())
+}
+
+/// Metadata recorded alongside an event.
+///
+/// When passed to [`EventStore::append`](crate::EventStore::append), the
+/// correlation ID, causation ID and headers are copied onto every appended
+/// event, while each event is given its own event ID and recording time.
+///
+/// An idempotency key, if set, makes the append idempotent instead. It is not
+/// recorded on the stored events.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventMetadata {
+    /// The unique ID of the event.
+    event_id: Uuid,
+    /// When the event was recorded by the store.
+    recorded_at: DateTime<Utc>,
+    /// Groups every event that stems from the same originating request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<String>,
+    /// Identifies the message, usually a command or event, that caused this
+    /// event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    causation_id: Option<String>,
+    /// Free-form, user-defined headers.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    /// Deduplicates the append this metadata is passed to.
+    #[serde(skip)]
+    idempotency_key: Option<String>,
+}
+
+/// The metadata of events stored before metadata was recorded: the nil event
+/// ID, recorded at the Unix epoch.
+///
+/// Both are fixed so such events read back the same every time. Use
+/// [`EventMetadata::new`] for a fresh event ID and the current time.
+impl Default for EventMetadata {
+    fn default() -> Self {
+        Self {
+            event_id: Uuid::nil(),
+            recorded_at: DateTime::UNIX_EPOCH,
+            correlation_id: None,
+            causation_id: None,
+            headers: BTreeMap::new(),
+            idempotency_key: None,
+        }
+    }
+}
+
+impl EventMetadata {
+    /// Creates new metadata with a fresh event ID and the current time.
+    pub fn new() -> Self {
+        Self {
+            event_id: Uuid::new_v4(),
+            recorded_at: Utc::now(),
+            ..Self::default()
+        }
+    }
+
+    /// Sets the correlation ID.
+    #[must_use]
+    pub fn with_correlation_id(mut self, correlation_id: impl Into<String>) -> Self {
+        self.correlation_id = Some(correlation_id.into());
+        self
+    }
+
+    /// Sets the causation ID.
+    #[must_use]
+    pub fn with_causation_id(mut self, causation_id: impl Into<String>) -> Self {
+        self.causation_id = Some(causation_id.into());
+        self
+    }
+
+    /// Adds a header, replacing any existing header with the same name.
+    #[must_use]
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+
+    /// Sets the idempotency key of the append this metadata is passed to.
+    ///
+    /// Stores that support idempotent appends remember the events appended
+    /// under a key for a retention period. An append repeating a remembered
+    /// key, such as a retried request, stores nothing and returns the events
+    /// originally appended, without checking the expected version. Stores
+    /// that do not support them reject appends with a key.
+    #[must_use]
+    pub fn with_idempotency_key(mut self, idempotency_key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(idempotency_key.into());
+        self
+    }
+
+    /// Reassembles metadata read back from a store.
+    pub(crate) fn from_parts(
+        event_id: Uuid,
+        recorded_at: DateTime<Utc>,
+        correlation_id: Option<String>,
+        causation_id: Option<String>,
+        headers: BTreeMap<String, String>,
+    ) -> Self {
+        Self {
+            event_id,
+            recorded_at,
+            correlation_id,
+            causation_id,
+            headers,
+            idempotency_key: None,
+        }
+    }
+
+    /// Returns the unique ID of the event.
+    pub fn event_id(&self) -> Uuid {
+        self.event_id
+    }
+
+    /// Returns when the event was recorded by the store.
+    pub fn recorded_at(&self) -> DateTime<Utc> {
+        self.recorded_at
+    }
+
+    /// Returns the correlation ID, if any.
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+
+    /// Returns the causation ID, if any.
+    pub fn causation_id(&self) -> Option<&str> {
+        self.causation_id.as_deref()
+    }
+
+    /// Returns the user-defined headers.
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    /// Returns the idempotency key, if any.
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
+
+    /// Returns a copy of this metadata for a newly appended event, with a
+    /// fresh event ID and recorded at `now`, and without the idempotency key.
+    ///
+    /// The time is truncated to microseconds, the precision of Postgres
+    /// timestamps, so it reads back exactly as it was written in every store.
+    pub(crate) fn stamp(&self, now: DateTime<Utc>) -> Self {
+        Self {
+            event_id: Uuid::new_v4(),
+            recorded_at: now.trunc_subsecs(6),
+            idempotency_key: None,
+            ..self.clone()
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Provides a transactional outbox for publishing stored events.
+//!
+//! Publishing events after `save` loses them if the process stops in
+//! between. Instead, stores with an outbox enabled record every appended
+//! event in their [`Outbox`] as part of the append itself, and an
+//! [`OutboxRelay`] drains the outbox into an [`EventPublisher`].
+//!
+//! Delivery is at least once: a message is only removed from the outbox
+//! once published, so it is published again if the relay stops in between.
+//! Each message is published as a [`CloudEvent`] built by
+//! [`CloudEvent::from_stored_with`], whose `id` is the event ID, letting
+//! consumers drop the duplicates. A message that fails is retried
+//! with backoff, possibly after later messages, until the relay's
+//! [`RetryPolicy`] runs out of attempts and moves it to the dead letters.
+use std::{marker::PhantomData, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_timer::Delay;
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, warn};
+
+use crate::{
+    CloudEvent, Event, Result, StoredEvent, cloudevent::CloudEventOptions, repository::RetryPolicy,
+};
+
+/// An event waiting in an outbox, or dead-lettered.
+#[derive(Debug, Clone)]
+pub struct OutboxMessage<E: Event> {
+    event: StoredEvent<E>,
+    attempts: u32,
+    last_error: Option<String>,
+}
+
+impl<E: Event> OutboxMessage<E> {
+    /// Creates a message for a stored event.
+    pub fn new(event: StoredEvent<E>, attempts: u32, last_error: Option<String>) -> Self {
+        Self {
+            event,
+            attempts,
+            last_error,
+        }
+    }
+
+    /// Returns the global position of the event, which identifies the
+    /// message.
+    pub fn position(&self) -> i64 {
+        self.event.position()
+    }
+
+    /// Returns the stored event.
+    pub fn event(&self) -> &StoredEvent<E> {
+        &self.event
+    }
+
+    /// Returns the number of failed attempts to publish the message.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Returns the error of the last failed attempt, if any.
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+}
+
+/// The delivery state of a message, as kept by the stores.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Delivery {
+    pub attempts: u32,
+    /// When the message is next due for delivery.
+    pub due_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+impl Delivery {
+    /// The state of a message not attempted yet, due at `due_at`.
+    pub(crate) fn new(due_at: DateTime<Utc>) -> Self {
+        Self {
+            attempts: 0,
+            due_at,
+            last_error: None,
+        }
+    }
+
+    /// Records a failed attempt, due again at `retry_at`.
+    pub(crate) fn failed(&mut self, error: &str, retry_at: DateTime<Utc>) {
+        self.attempts += 1;
+        self.due_at = retry_at;
+        self.last_error = Some(error.to_owned());
+    }
+
+    /// Builds the message for `event` in this state.
+    pub(crate) fn message<E: Event>(self, event: StoredEvent<E>) -> OutboxMessage<E> {
+        OutboxMessage::new(event, self.attempts, self.last_error)
+    }
+}
+
+/// The messages appended events leave to be published.
+///
+/// Messages are identified by the global position of their event. Pending
+/// messages are due for delivery from when they are appended, or from the
+/// time set by the last failed attempt.
+#[async_trait]
+pub trait Outbox<E: Event>: Send + Sync {
+    /// Returns up to `limit` pending messages due for delivery at `now`, in
+    /// position order.
+    async fn pending(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<OutboxMessage<E>>>;
+
+    /// Removes a message that has been published.
+    async fn acknowledge(&self, position: i64) -> Result<()>;
+
+    /// Records a failed attempt to publish a message, which becomes due
+    /// again at `retry_at`.
+    async fn retry_later(&self, position: i64, error: &str, retry_at: DateTime<Utc>) -> Result<()>;
+
+    /// Records the last failed attempt to publish a message and moves it to
+    /// the dead letters, which are never delivered.
+    async fn dead_letter(&self, position: i64, error: &str) -> Result<()>;
+
+    /// Returns up to `limit` dead letters, in position order.
+    async fn dead_letters(&self, limit: usize) -> Result<Vec<OutboxMessage<E>>>;
+
+    /// Moves a dead letter back to the pending messages, due immediately and
+    /// with its attempts reset.
+    async fn requeue(&self, position: i64) -> Result<()>;
+}
+
+/// Publishes events to a message broker or any other consumer.
+#[async_trait]
+pub trait EventPublisher: Send + Sync {
+    /// Publishes a single event.
+    ///
+    /// An error leaves the event in the outbox, to be published again later.
+    async fn publish(&self, event: CloudEvent) -> Result<()>;
+}
+
+/// What a pass of an [`OutboxRelay`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayReport {
+    /// The number of messages published.
+    pub published: usize,
+    /// The number of messages that failed and will be retried.
+    pub retried: usize,
+    /// The number of messages moved to the dead letters.
+    pub dead_lettered: usize,
+}
+
+impl RelayReport {
+    /// Returns the number of messages the pass handled.
+    pub fn handled(&self) -> usize {
+        self.published + self.retried + self.dead_lettered
+    }
+}
+
+/// The retry policy of a relay unless set with [`OutboxRelay::with_retry`]:
+/// 30 attempts, backing off from a second, doubling up to five minutes, so a
+/// message outlasts a publisher outage of well over an hour before it is
+/// dead-lettered.
+fn default_retry() -> RetryPolicy {
+    RetryPolicy::new(30).with_backoff(Duration::from_secs(1), Duration::from_secs(5 * 60))
+}
+
+/// Drains an outbox into an event publisher.
+///
+/// Run a single relay per outbox: concurrent relays publish the same
+/// messages.
+pub struct OutboxRelay<E, O, P>
+where
+    E: Event,
+    O: Outbox<E>,
+    P: EventPublisher,
+{
+    outbox: Arc<O>,
+    publisher: P,
+    retry: RetryPolicy,
+    batch_size: usize,
+    cloud_events: CloudEventOptions,
+    _phantom: PhantomData<E>,
+}
+
+impl<E, O, P> OutboxRelay<E, O, P>
+where
+    E: Event,
+    O: Outbox<E>,
+    P: EventPublisher,
+{
+    /// Creates a new `OutboxRelay`.
+    ///
+    /// A failed message is attempted up to 30 times, backing off from a
+    /// second up to five minutes between attempts. This is far more patient
+    /// than the default [`RetryPolicy`], which is tuned for retrying
+    /// commands after a conflict.
+    pub fn new(outbox: Arc<O>, publisher: P) -> Self {
+        Self {
+            outbox,
+            publisher,
+            retry: default_retry(),
+            batch_size: 256,
+            cloud_events: CloudEventOptions::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Sets how many times a message is attempted before it is moved to the
+    /// dead letters, and the backoff between attempts.
+    #[must_use]
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the maximum number of messages read from the outbox at once.
+    #[must_use]
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Sets the options the published CloudEvents are built with, such as
+    /// their `source`.
+    #[must_use]
+    pub fn with_cloud_event_options(mut self, options: CloudEventOptions) -> Self {
+        self.cloud_events = options;
+        self
+    }
+
+    /// Returns the publisher.
+    pub fn publisher(&self) -> &P {
+        &self.publisher
+    }
+
+    /// Publishes every message due now, until none is left.
+    #[instrument(skip(self))]
+    pub async fn run_once(&self) -> Result<RelayReport> {
+        let mut report = RelayReport::default();
+        loop {
+            let now = Utc::now();
+            let batch = self.outbox.pending(now, self.batch_size).await?;
+            if batch.is_empty() {
+                return Ok(report);
+            }
+            for message in batch {
+                self.deliver(message, now, &mut report).await?;
+            }
+        }
+    }
+
+    /// Keeps publishing messages, checking the outbox again every
+    /// `poll_interval` once it is drained.
+    ///
+    /// This only returns if the outbox fails.
+    pub async fn run(&self, poll_interval: Duration) -> Result<()> {
+        loop {
+            self.run_once().await?;
+            Delay::new(poll_interval).await;
+        }
+    }
+
+    /// Publishes one message and records the outcome in the outbox.
+    async fn deliver(
+        &self,
+        message: OutboxMessage<E>,
+        now: DateTime<Utc>,
+        report: &mut RelayReport,
+    ) -> Result<()> {
+        let position = message.position();
+        let published = match CloudEvent::from_stored_with(message.event(), &self.cloud_events) {
+            Ok(cloud_event) => self.publisher.publish(cloud_event).await,
+            Err(e) => Err(e),
+        };
+        let error = match published {
+            Ok(()) => {
+                self.outbox.acknowledge(position).await?;
+                report.published += 1;
+                return Ok(());
+            }
+            Err(e) => e.to_string(),
+        };
+
+        let attempt = message.attempts() + 1;
+        if attempt >= self.retry.max_attempts() {
+            warn!(position, attempt, %error, "giving up on outbox message");
+            self.outbox.dead_letter(position, &error).await?;
+            report.dead_lettered += 1;
+        } else {
+            let backoff = chrono::Duration::from_std(self.retry.backoff(attempt))
+                .unwrap_or(chrono::Duration::MAX);
+            let retry_at = now.checked_add_signed(backoff).unwrap_or(now);
+            warn!(position, attempt, %error, %retry_at, "failed to publish outbox message");
+            self.outbox.retry_later(position, &error, retry_at).await?;
+            report.retried += 1;
+        }
+        Ok(())
+    }
+}
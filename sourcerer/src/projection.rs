@@ -0,0 +1,181 @@
+//! Provides projections for building read models from the global event log.
+//!
+//! A [`Projection`] consumes stored events in global order, and a
+//! [`CheckpointStore`] remembers how far each projection has got, so a
+//! [`ProjectionRunner`] can resume after a restart or rebuild a projection
+//! from scratch.
+use std::{marker::PhantomData, sync::Arc};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use tracing::instrument;
+
+use crate::{Aggregate, Event, EventStore, Result, StoredEvent, subscription::SubscriptionFilter};
+
+/// A read model built by folding over stored events.
+#[async_trait]
+pub trait Projection<E: Event>: Send + Sync {
+    /// Returns the unique name of the projection, used as its checkpoint key.
+    fn name(&self) -> &str;
+
+    /// Handles a single stored event.
+    ///
+    /// Events are delivered at least once: if the process stops before the
+    /// checkpoint is saved, the same events are handled again on restart.
+    async fn handle(&mut self, event: &StoredEvent<E>) -> Result<()>;
+
+    /// Clears all state built by the projection ahead of a rebuild.
+    async fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A checkpoint store persists the global position each projection has
+/// processed up to.
+#[async_trait]
+pub trait CheckpointStore: Send + Sync {
+    /// Loads the checkpoint of a projection, if it has one.
+    async fn load(&self, projection: &str) -> Result<Option<i64>>;
+
+    /// Saves the checkpoint of a projection.
+    ///
+    /// This should overwrite any existing checkpoint for the same projection.
+    async fn save(&self, projection: &str, position: i64) -> Result<()>;
+
+    /// Deletes the checkpoint of a projection, so it restarts from the
+    /// beginning of the log.
+    async fn delete(&self, projection: &str) -> Result<()>;
+}
+
+/// Feeds events from an event store to a projection and checkpoints its
+/// progress.
+pub struct ProjectionRunner<A, S, C, P>
+where
+    A: Aggregate,
+    S: EventStore<A>,
+    C: CheckpointStore,
+    P: Projection<A::Event>,
+{
+    store: Arc<S>,
+    checkpoints: Arc<C>,
+    projection: P,
+    batch_size: usize,
+    _phantom: PhantomData<A>,
+}
+
+impl<A, S, C, P> ProjectionRunner<A, S, C, P>
+where
+    A: Aggregate,
+    S: EventStore<A>,
+    C: CheckpointStore,
+    P: Projection<A::Event>,
+{
+    /// Creates a new `ProjectionRunner`.
+    pub fn new(store: Arc<S>, checkpoints: Arc<C>, projection: P) -> Self {
+        Self {
+            store,
+            checkpoints,
+            projection,
+            batch_size: 256,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Sets the maximum number of events handled between two checkpoints.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Returns the projection.
+    pub fn projection(&self) -> &P {
+        &self.projection
+    }
+
+    /// Consumes the runner and returns the projection.
+    pub fn into_projection(self) -> P {
+        self.projection
+    }
+
+    /// Returns the global position the projection has processed up to.
+    pub async fn position(&self) -> Result<i64> {
+        Ok(self
+            .checkpoints
+            .load(self.projection.name())
+            .await?
+            .unwrap_or(0))
+    }
+
+    /// Catches the projection up with the end of the log and returns the
+    /// number of events handled.
+    #[instrument(skip(self), fields(projection = self.projection.name()))]
+    pub async fn run_once(&mut self) -> Result<usize> {
+        let mut position = self.position().await?;
+        let mut handled = 0;
+
+        loop {
+            let batch = self.store.read_all(position, self.batch_size).await?;
+            let Some(last) = batch.last().map(StoredEvent::position) else {
+                return Ok(handled);
+            };
+
+            for event in &batch {
+                self.projection.handle(event).await?;
+            }
+            self.checkpoints.save(self.projection.name(), last).await?;
+
+            handled += batch.len();
+            position = last;
+        }
+    }
+
+    /// Catches the projection up and then keeps it up to date as events are
+    /// appended.
+    ///
+    /// This only returns once the store's subscription ends or fails.
+    #[instrument(skip(self), fields(projection = self.projection.name()))]
+    pub async fn run(&mut self) -> Result<()> {
+        let position = self.position().await?;
+        let mut batches = self
+            .store
+            .subscribe(position, SubscriptionFilter::new())
+            .await?
+            .ready_chunks(self.batch_size);
+
+        while let Some(batch) = batches.next().await {
+            let mut last = None;
+            let mut failure = None;
+            for event in batch {
+                match event {
+                    Ok(event) => {
+                        self.projection.handle(&event).await?;
+                        last = Some(event.position());
+                    }
+                    Err(e) => {
+                        failure = Some(e);
+                        break;
+                    }
+                }
+            }
+
+            // Checkpoint whatever was handled before surfacing an error.
+            if let Some(last) = last {
+                self.checkpoints.save(self.projection.name(), last).await?;
+            }
+            if let Some(e) = failure {
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resets the projection and rebuilds it from position zero, returning
+    /// the number of events handled.
+    #[instrument(skip(self), fields(projection = self.projection.name()))]
+    pub async fn rebuild(&mut self) -> Result<usize> {
+        self.projection.reset().await?;
+        self.checkpoints.delete(self.projection.name()).await?;
+        self.run_once().await
+    }
+}
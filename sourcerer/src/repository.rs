@@ -1,22 +1,151 @@
 //! Provides a generic repository for interacting with aggregates.
-use std::{marker::PhantomData, sync::Arc};
+use std::{marker::PhantomData, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
-use tracing::instrument;
+use chrono::{DateTime, Utc};
+use futures::{TryStreamExt, future::BoxFuture};
+use futures_timer::Delay;
+use tracing::{instrument, warn};
 
 use crate::{
-    Aggregate, Error, EventStore, Result, snapshot::SnapshotStore, upcaster::UpcasterChain,
+    Aggregate, CommandError, Error, Event, EventMetadata, EventStore, ExpectedVersion, Result,
+    StoredEvent,
+    integrity::Chains,
+    snapshot::{
+        EveryNEvents, SnapshotContext, SnapshotMark, SnapshotMarks, SnapshotPolicy, SnapshotStore,
+        StoredSnapshot,
+    },
+    upcaster::{RawStoredEvent, SnapshotUpcasterChain, UpcasterChain},
 };
 
+#[cfg(feature = "encryption")]
+use crate::encryption::{Encryption, Opened};
+
 /// Defines the standard interface for a repository.
 #[async_trait]
 pub trait Repository<A: Aggregate>: Send + Sync {
     /// Loads an aggregate instance from the store.
     async fn load(&self, id: &A::Id) -> Result<A>;
+    /// Loads an aggregate instance as it was at `version`, or as it is now
+    /// if it has not reached that version yet.
+    async fn load_at_version(&self, id: &A::Id, version: i64) -> Result<A>;
+    /// Loads an aggregate instance as it was at `timestamp`, from the events
+    /// recorded up to then.
+    async fn load_as_of(&self, id: &A::Id, timestamp: DateTime<Utc>) -> Result<A>;
     /// Saves a new list of events for an aggregate.
-    async fn save(&self, aggregate: &A, new_events: Vec<A::Event>) -> Result<()>;
+    async fn save(&self, aggregate: &A, new_events: Vec<A::Event>) -> Result<()> {
+        self.save_with_metadata(aggregate, new_events, EventMetadata::default())
+            .await
+    }
+    /// Saves a new list of events for an aggregate, recording `metadata`
+    /// alongside each of them.
+    ///
+    /// With an [idempotency key](EventMetadata::with_idempotency_key), a
+    /// retried save stores nothing and succeeds if the store still remembers
+    /// the key.
+    async fn save_with_metadata(
+        &self,
+        aggregate: &A,
+        new_events: Vec<A::Event>,
+        metadata: EventMetadata,
+    ) -> Result<()>;
+}
+
+/// Controls how often and how quickly [`GenericRepository::execute`] retries
+/// a command after a concurrency conflict, and an
+/// [`OutboxRelay`](crate::outbox::OutboxRelay) a message it failed to publish.
+///
+/// The delay before each retry grows exponentially from the initial backoff,
+/// capped at the maximum backoff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: u32,
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts, backing off from 10ms, doubling up to 1s.
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a policy making at most `max_attempts` attempts in total, with
+    /// the default backoff.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            ..Self::default()
+        }
+    }
+
+    /// Creates a policy that never retries.
+    pub fn no_retry() -> Self {
+        Self::new(1)
+    }
+
+    /// Sets the delay before the first retry and the cap on later delays.
+    #[must_use]
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Sets the factor the delay grows by after each retry.
+    #[must_use]
+    pub fn with_multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier.max(1);
+        self
+    }
+
+    /// Returns the maximum number of attempts, including the first one.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Returns the delay after the given failed attempt, counting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
 }
 
+/// A snapshot that could not be written.
+///
+/// Snapshots are written after the events they cover are committed, so a
+/// failure does not fail the save. It is passed to the handler set with
+/// [`GenericRepository::with_snapshot_failure_handler`] instead.
+#[derive(Debug)]
+pub struct SnapshotFailure {
+    /// The ID of the aggregate the snapshot was taken of.
+    pub aggregate_id: String,
+    /// The aggregate version the snapshot was taken at.
+    pub version: i64,
+    /// The error returned by the snapshot store.
+    pub error: Error,
+}
+
+/// Runs snapshot writes in the background.
+type Spawner = Box<dyn Fn(BoxFuture<'static, ()>) + Send + Sync>;
+
+/// Handles snapshots that could not be written.
+type FailureHandler = Arc<dyn Fn(SnapshotFailure) + Send + Sync>;
+
+/// How many aggregates a repository remembers the latest snapshot of by
+/// default.
+const SNAPSHOT_MARK_CAPACITY: usize = 10_000;
+
 /// A generic, high-level repository for loading and saving aggregates.
 ///
 /// This repository simplifies the common load-handle-save cycle by
@@ -30,7 +159,14 @@ where
     store: Arc<S>,
     snapshot_store: Option<Arc<SS>>,
     upcasters: UpcasterChain<A::Event>,
-    snapshot_frequency: Option<usize>,
+    snapshot_upcasters: SnapshotUpcasterChain<A::Snapshot>,
+    #[cfg(feature = "encryption")]
+    encryption: Option<Encryption<A::Event>>,
+    snapshot_policy: Option<Box<dyn SnapshotPolicy<A>>>,
+    snapshot_marks: Arc<SnapshotMarks>,
+    snapshot_spawner: Option<Spawner>,
+    snapshot_failure_handler: Option<FailureHandler>,
+    verify_hashes: bool,
     _phantom: PhantomData<A>,
 }
 
@@ -46,7 +182,14 @@ where
             store,
             snapshot_store,
             upcasters: UpcasterChain::new(),
-            snapshot_frequency: None,
+            snapshot_upcasters: SnapshotUpcasterChain::new(),
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            snapshot_policy: None,
+            snapshot_marks: Arc::new(SnapshotMarks::new(SNAPSHOT_MARK_CAPACITY)),
+            snapshot_spawner: None,
+            snapshot_failure_handler: None,
+            verify_hashes: false,
             _phantom: PhantomData,
         }
     }
@@ -57,14 +200,430 @@ where
         self
     }
 
+    /// Sets the upcaster chain applied to snapshots stored with an older
+    /// snapshot schema.
+    pub fn with_snapshot_upcasters(
+        mut self,
+        upcasters: SnapshotUpcasterChain<A::Snapshot>,
+    ) -> Self {
+        self.snapshot_upcasters = upcasters;
+        self
+    }
+
+    /// Sets the encryption sealing the personal data of saved events and
+    /// opening it on load.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption(mut self, encryption: Encryption<A::Event>) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
     /// Sets the frequency at which snapshots should be created.
     ///
     /// For example, a value of `Some(100)` means a snapshot will be created
-    /// every 100 events.
+    /// every 100 events. This is shorthand for
+    /// [`with_snapshot_policy`](Self::with_snapshot_policy) with
+    /// [`EveryNEvents`], and `None` disables snapshotting.
     pub fn with_snapshot_frequency(mut self, frequency: Option<usize>) -> Self {
-        self.snapshot_frequency = frequency;
+        self.snapshot_policy =
+            frequency.map(|n| Box::new(EveryNEvents::new(n)) as Box<dyn SnapshotPolicy<A>>);
+        self
+    }
+
+    /// Sets the policy deciding when `save` takes a snapshot.
+    ///
+    /// The policy only takes effect with a snapshot store.
+    pub fn with_snapshot_policy(mut self, policy: impl SnapshotPolicy<A> + 'static) -> Self {
+        self.snapshot_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Sets how many aggregates the repository remembers the latest snapshot
+    /// of, 10,000 by default.
+    ///
+    /// Snapshot policies are told about the latest snapshot the repository
+    /// has loaded or written. Beyond the capacity, the least recently loaded
+    /// or saved aggregates are forgotten until they are loaded again.
+    pub fn with_snapshot_mark_capacity(mut self, capacity: usize) -> Self {
+        self.snapshot_marks = Arc::new(SnapshotMarks::new(capacity));
+        self
+    }
+
+    /// Writes snapshots in the background, handing each write to `spawn`
+    /// instead of awaiting it in `save`.
+    ///
+    /// The crate does not depend on an async runtime, so `spawn` runs the
+    /// task on the application's, for example
+    /// `|task| { tokio::spawn(task); }`.
+    pub fn with_background_snapshots<F>(mut self, spawn: F) -> Self
+    where
+        F: Fn(BoxFuture<'static, ()>) + Send + Sync + 'static,
+    {
+        self.snapshot_spawner = Some(Box::new(spawn));
         self
     }
+
+    /// Sets the handler told about snapshots that could not be written.
+    ///
+    /// By default, failures are logged as warnings.
+    pub fn with_snapshot_failure_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(SnapshotFailure) + Send + Sync + 'static,
+    {
+        self.snapshot_failure_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Sets whether `load` verifies the hash chain of the stream it replays.
+    ///
+    /// When enabled, the stream is read from its first event even if a
+    /// snapshot covers part of it, and a broken link fails the load with
+    /// [`Error::Integrity`].
+    pub fn with_hash_verification(mut self, verify: bool) -> Self {
+        self.verify_hashes = verify;
+        self
+    }
+
+    /// Loads the aggregate's latest snapshot, or its latest one taken at or
+    /// before `at`, upcast to the current snapshot schema.
+    ///
+    /// A snapshot that cannot be loaded, upcast or deserialized is skipped
+    /// with a warning rather than failing the load. The returned flag is
+    /// `true` in that case, so the caller can replace it.
+    async fn load_snapshot(
+        &self,
+        id: &A::Id,
+        at: Option<i64>,
+    ) -> (Option<StoredSnapshot<A::Snapshot>>, bool) {
+        let Some(snapshot_store) = &self.snapshot_store else {
+            return (None, false);
+        };
+        let raw = match at {
+            Some(version) => snapshot_store.load_raw_at_or_before(id, version).await,
+            None => snapshot_store.load_raw(id).await,
+        };
+        let snapshot = match raw {
+            Ok(Some(raw)) => self.snapshot_upcasters.decode(raw),
+            Ok(None) => return (None, false),
+            Err(e) => Err(e),
+        };
+        match snapshot {
+            Ok(snapshot) => {
+                if at.is_none() {
+                    self.snapshot_marks.record(
+                        id.to_string(),
+                        SnapshotMark {
+                            version: snapshot.version(),
+                            taken_at: snapshot.taken_at(),
+                        },
+                    );
+                }
+                (Some(snapshot), false)
+            }
+            Err(e) => {
+                warn!(error = %e, "ignoring unusable snapshot, replaying the full stream");
+                (None, true)
+            }
+        }
+    }
+
+    /// Rebuilds the aggregate `id` from `snapshot`, if any, and the events
+    /// after it, up to and including version `until` if given.
+    async fn replay(
+        &self,
+        id: &A::Id,
+        snapshot: Option<StoredSnapshot<A::Snapshot>>,
+        until: Option<i64>,
+    ) -> Result<A> {
+        let (mut aggregate, starting_version, has_snapshot) = match snapshot {
+            Some(stored) => {
+                let v = stored.version();
+                (A::from_snapshot(stored.into_snapshot()), v, true)
+            }
+            None => (A::default(), 0, false),
+        };
+
+        // Fold all events that occurred after the snapshot (or from scratch)
+        // one at a time, so the stream is never held in memory as a whole.
+        // Verifying the hash chain needs every event from the start.
+        let mut chains = self.verify_hashes.then(Chains::default);
+        let from_version = if chains.is_some() {
+            0
+        } else {
+            starting_version
+        };
+        let mut raw_events = match until {
+            Some(until) => self.store.stream_raw_range(id, from_version, until),
+            None => self.store.stream_raw(id, from_version),
+        };
+        let mut found = has_snapshot;
+
+        while let Some(raw_event) = raw_events.try_next().await? {
+            if let Some(chains) = &mut chains
+                && let Some(broken) = chains.check::<A::Event>(&raw_event)?
+            {
+                return Err(Error::Integrity(Box::new(broken)));
+            }
+            if raw_event.version <= starting_version {
+                continue;
+            }
+            let event = self.decode(raw_event).await?;
+            aggregate.apply(&event);
+            found = true;
+        }
+
+        // Guard against loading a non-existing aggregate.
+        if !found {
+            return Err(Error::NotFound);
+        }
+        Ok(aggregate)
+    }
+
+    /// Opens, upcasts and decodes a stored event.
+    async fn decode(&self, raw_event: RawStoredEvent) -> Result<A::Event> {
+        #[cfg(feature = "encryption")]
+        let raw_event = match &self.encryption {
+            Some(encryption) => match encryption.open(raw_event).await? {
+                Opened::Readable(raw_event) => raw_event,
+                Opened::Shredded(raw_event) => match encryption.placeholder(&raw_event) {
+                    Some(event) => return Ok(event),
+                    None => raw_event,
+                },
+            },
+            None => raw_event,
+        };
+        self.upcasters.upcast(raw_event)?.decode()
+    }
+}
+
+impl<A, S, SS> GenericRepository<A, S, SS>
+where
+    A: Aggregate,
+    S: EventStore<A> + 'static,
+    SS: SnapshotStore<A> + 'static,
+{
+    /// Starts a unit of work, whose saves are committed together.
+    pub fn unit_of_work(&self) -> UnitOfWork<'_, A, S, SS> {
+        UnitOfWork {
+            repository: self,
+            pending: Vec::new(),
+            metadata: EventMetadata::default(),
+        }
+    }
+
+    /// Appends events for the aggregate `id`, which was at `version_before`
+    /// when they were produced, and takes a snapshot of `aggregate` if the
+    /// snapshot policy asks for one.
+    ///
+    /// Returns `false` if the store returned the events of an earlier append
+    /// with the same idempotency key instead of storing these.
+    async fn commit(
+        &self,
+        id: &A::Id,
+        version_before: i64,
+        aggregate: &A,
+        new_events: Vec<A::Event>,
+        metadata: EventMetadata,
+    ) -> Result<bool> {
+        if new_events.is_empty() {
+            return Ok(true);
+        }
+
+        let version_after = version_before + new_events.len() as i64;
+        let snapshot = self.planned_snapshot(id, version_before, aggregate, &new_events);
+        let new_events = self.seal(new_events).await?;
+        let stored_events = self
+            .store
+            .append(id, expected_version(version_before), new_events, metadata)
+            .await?;
+
+        if !reaches(&stored_events, id, version_after) {
+            return Ok(false);
+        }
+        if let Some(snapshot) = snapshot {
+            self.write_snapshot(id, version_after, snapshot).await;
+        }
+        Ok(true)
+    }
+
+    /// Takes a snapshot of `aggregate` if the snapshot policy asks for one
+    /// once `new_events` are saved.
+    fn planned_snapshot(
+        &self,
+        id: &A::Id,
+        version_before: i64,
+        aggregate: &A,
+        new_events: &[A::Event],
+    ) -> Option<A::Snapshot> {
+        let (Some(_), Some(policy)) = (&self.snapshot_store, &self.snapshot_policy) else {
+            return None;
+        };
+        let last_snapshot = self.snapshot_marks.get(&id.to_string());
+        let context = SnapshotContext::new(aggregate, version_before, new_events, last_snapshot);
+        policy
+            .should_snapshot(&context)
+            .then(|| aggregate.snapshot())
+    }
+
+    /// Seals the personal data of events about to be saved, if encryption is
+    /// set.
+    async fn seal(&self, new_events: Vec<A::Event>) -> Result<Vec<A::Event>> {
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = &self.encryption {
+            return encryption.seal(new_events).await;
+        }
+        Ok(new_events)
+    }
+
+    /// Saves a snapshot of the aggregate `id` at `version`, in the background
+    /// if a spawner is set, reporting failures to the failure handler.
+    async fn write_snapshot(&self, id: &A::Id, version: i64, snapshot: A::Snapshot) {
+        let Some(snapshot_store) = &self.snapshot_store else {
+            return;
+        };
+        let write = write_snapshot(
+            snapshot_store.clone(),
+            self.snapshot_marks.clone(),
+            self.snapshot_failure_handler.clone(),
+            id.clone(),
+            version,
+            snapshot,
+        );
+        match &self.snapshot_spawner {
+            Some(spawn) => spawn(Box::pin(write)),
+            None => write.await,
+        }
+    }
+
+    /// Loads the aggregate `id`, handles `command` and saves the resulting
+    /// events, returning the updated aggregate.
+    ///
+    /// An aggregate that does not exist yet starts from its default state, so
+    /// creation commands go through here too. A concurrency conflict is
+    /// reported as [`CommandError::Concurrency`]; use
+    /// [`execute`](Self::execute) to retry instead.
+    #[instrument(skip(self, command), fields(aggregate.id = ?id))]
+    pub async fn handle(
+        &self,
+        id: &A::Id,
+        command: A::Command,
+    ) -> std::result::Result<A, CommandError<A::Error>> {
+        self.attempt(id, command, EventMetadata::default()).await
+    }
+
+    /// Behaves like [`handle`](Self::handle), recording `metadata` alongside
+    /// each of the resulting events.
+    ///
+    /// With an [idempotency key](EventMetadata::with_idempotency_key), a
+    /// command handled again stores nothing if the store still remembers the
+    /// key, and the aggregate is returned as the first save left it.
+    #[instrument(skip(self, command, metadata), fields(aggregate.id = ?id))]
+    pub async fn handle_with_metadata(
+        &self,
+        id: &A::Id,
+        command: A::Command,
+        metadata: EventMetadata,
+    ) -> std::result::Result<A, CommandError<A::Error>> {
+        self.attempt(id, command, metadata).await
+    }
+
+    /// Loads the aggregate `id`, handles `command` and saves the resulting
+    /// events once, without retrying.
+    async fn attempt(
+        &self,
+        id: &A::Id,
+        command: A::Command,
+        metadata: EventMetadata,
+    ) -> std::result::Result<A, CommandError<A::Error>> {
+        let mut aggregate = match self.load(id).await {
+            Ok(aggregate) => aggregate,
+            Err(Error::NotFound) => A::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let version_before = aggregate.version();
+
+        let new_events = aggregate
+            .handle(command)
+            .await
+            .map_err(CommandError::Domain)?;
+        for event in &new_events {
+            aggregate.apply(event);
+        }
+
+        let stored = self
+            .commit(id, version_before, &aggregate, new_events, metadata)
+            .await?;
+        // A deduplicated save stored other events than those just applied.
+        if !stored {
+            aggregate = self.load(id).await?;
+        }
+        Ok(aggregate)
+    }
+}
+
+impl<A, S, SS> GenericRepository<A, S, SS>
+where
+    A: Aggregate,
+    A::Command: Clone,
+    S: EventStore<A> + 'static,
+    SS: SnapshotStore<A> + 'static,
+{
+    /// Loads the aggregate `id`, handles `command` and saves the resulting
+    /// events, returning the updated aggregate.
+    ///
+    /// Behaves like [`handle`](Self::handle), except that when the save hits
+    /// a concurrency conflict the aggregate is reloaded and the command
+    /// handled again, as allowed by `retry`.
+    #[instrument(skip(self, command), fields(aggregate.id = ?id))]
+    pub async fn execute(
+        &self,
+        id: &A::Id,
+        command: A::Command,
+        retry: RetryPolicy,
+    ) -> std::result::Result<A, CommandError<A::Error>> {
+        self.execute_with_metadata(id, command, retry, EventMetadata::default())
+            .await
+    }
+
+    /// Behaves like [`execute`](Self::execute), recording `metadata`
+    /// alongside each of the resulting events.
+    ///
+    /// With an [idempotency key](EventMetadata::with_idempotency_key), a
+    /// command executed again stores nothing if the store still remembers the
+    /// key, and the aggregate is returned as the first save left it.
+    #[instrument(skip(self, command, metadata), fields(aggregate.id = ?id))]
+    pub async fn execute_with_metadata(
+        &self,
+        id: &A::Id,
+        command: A::Command,
+        retry: RetryPolicy,
+        metadata: EventMetadata,
+    ) -> std::result::Result<A, CommandError<A::Error>> {
+        let mut attempt = 1;
+        loop {
+            match self.attempt(id, command.clone(), metadata.clone()).await {
+                Err(e @ CommandError::Concurrency { .. }) if attempt < retry.max_attempts() => {
+                    let delay = retry.backoff(attempt);
+                    warn!(
+                        attempt,
+                        max_attempts = retry.max_attempts(),
+                        ?delay,
+                        error = %e,
+                        "concurrency conflict, retrying command"
+                    );
+                    if !delay.is_zero() {
+                        Delay::new(delay).await;
+                    }
+                    attempt += 1;
+                }
+                Err(e @ CommandError::Concurrency { .. }) if retry.max_attempts() > 1 => {
+                    warn!(attempt, error = %e, "concurrency conflict, giving up on command");
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -78,62 +637,220 @@ where
     async fn load(&self, id: &A::Id) -> Result<A> {
         // Attempt to hydrate the aggregate from a snapshot first so we can
         // replay only the delta of events that occurred afterwards.
-        let (mut aggregate, starting_version, has_snapshot) =
-            if let Some(snapshot_store) = &self.snapshot_store {
-                if let Some(stored) = snapshot_store.load(id).await? {
-                    let v = stored.version();
-                    let snap = stored.into_snapshot();
-                    (A::from_snapshot(snap), v, true)
-                } else {
-                    (A::default(), 0, false)
-                }
-            } else {
-                (A::default(), 0, false)
-            };
+        let (snapshot, stale_snapshot) = self.load_snapshot(id, None).await;
+        let aggregate = self.replay(id, snapshot, None).await?;
 
-        // Load all events that occurred after the snapshot (or from scratch).
-        let raw_events = self.store.load_raw(id, starting_version).await?;
+        // Replace a snapshot that could not be used, so the next load does
+        // not replay the full stream again.
+        if stale_snapshot {
+            self.write_snapshot(id, aggregate.version(), aggregate.snapshot())
+                .await;
+        }
 
-        // Guard against loading a non-existing aggregate.
-        if raw_events.is_empty() && !has_snapshot {
+        Ok(aggregate)
+    }
+
+    #[instrument(skip(self), fields(aggregate.id = ?id))]
+    async fn load_at_version(&self, id: &A::Id, version: i64) -> Result<A> {
+        if version <= 0 {
             return Err(Error::NotFound);
         }
+        // Start from the latest snapshot the version has reached, if any. An
+        // unusable one is left for `load` to replace.
+        let (snapshot, _) = self.load_snapshot(id, Some(version)).await;
+        self.replay(id, snapshot, Some(version)).await
+    }
 
-        for raw_event in raw_events {
-            let upcasted_event = self.upcasters.upcast(raw_event)?;
-            let event = serde_json::from_value(upcasted_event.payload)
-                .map_err(|e| Error::Store(e.to_string()))?;
-            aggregate.apply(&event);
+    #[instrument(skip(self), fields(aggregate.id = ?id, %timestamp))]
+    async fn load_as_of(&self, id: &A::Id, timestamp: DateTime<Utc>) -> Result<A> {
+        let version = self.store.version_as_of(id, timestamp).await?;
+        self.load_at_version(id, version).await
+    }
+
+    #[instrument(skip(self, aggregate, new_events, metadata), fields(aggregate.id = ?aggregate.id()))]
+    async fn save_with_metadata(
+        &self,
+        aggregate: &A,
+        new_events: Vec<A::Event>,
+        metadata: EventMetadata,
+    ) -> Result<()> {
+        let version_before_save = aggregate.version() - new_events.len() as i64;
+        self.commit(
+            aggregate.id(),
+            version_before_save,
+            aggregate,
+            new_events,
+            metadata,
+        )
+        .await
+        .map(drop)
+    }
+}
+
+/// Saves a snapshot and records it as the aggregate's latest, or reports why
+/// it could not be saved.
+async fn write_snapshot<A, SS>(
+    snapshot_store: Arc<SS>,
+    marks: Arc<SnapshotMarks>,
+    on_failure: Option<FailureHandler>,
+    id: A::Id,
+    version: i64,
+    snapshot: A::Snapshot,
+) where
+    A: Aggregate,
+    SS: SnapshotStore<A>,
+{
+    match snapshot_store.save(&id, version, snapshot).await {
+        Ok(()) => marks.record(
+            id.to_string(),
+            SnapshotMark {
+                version,
+                taken_at: Some(Utc::now()),
+            },
+        ),
+        Err(error) => {
+            let failure = SnapshotFailure {
+                aggregate_id: id.to_string(),
+                version,
+                error,
+            };
+            match on_failure {
+                Some(handler) => handler(failure),
+                None => warn!(
+                    aggregate.id = %failure.aggregate_id,
+                    version,
+                    error = %failure.error,
+                    "failed to write snapshot"
+                ),
+            }
         }
+    }
+}
 
-        Ok(aggregate)
+/// Returns whether `stored_events` bring the aggregate `id` to `version`.
+///
+/// An append deduplicated by its idempotency key returns the events of the
+/// original append, which may not match the aggregate being saved; its
+/// snapshot must not be taken then.
+fn reaches<E: Event>(stored_events: &[StoredEvent<E>], id: &impl ToString, version: i64) -> bool {
+    let id = id.to_string();
+    stored_events
+        .iter()
+        .any(|e| e.version() == version && e.aggregate_id() == id)
+}
+
+/// The version a save expects the stream of an aggregate that was at
+/// `version_before` to be at.
+fn expected_version(version_before: i64) -> ExpectedVersion {
+    // A brand-new aggregate must not reuse the ID of an existing stream.
+    match version_before {
+        0 => ExpectedVersion::NoStream,
+        version => ExpectedVersion::Exact(version),
     }
+}
 
-    #[instrument(skip(self, aggregate, new_events), fields(aggregate.id = ?aggregate.id()))]
-    async fn save(&self, aggregate: &A, new_events: Vec<A::Event>) -> Result<()> {
+/// Saves to several aggregates that are committed together, atomically.
+///
+/// Created by [`GenericRepository::unit_of_work`]. Each [`save`](Self::save)
+/// is only recorded; [`commit`](Self::commit) then appends every recorded
+/// save in one [`EventStore::append_batch`], so either all of them are stored
+/// or, on a concurrency conflict or any other error, none is. Dropping a unit
+/// of work without committing it discards its saves.
+pub struct UnitOfWork<'a, A, S, SS>
+where
+    A: Aggregate,
+    S: EventStore<A>,
+    SS: SnapshotStore<A>,
+{
+    repository: &'a GenericRepository<A, S, SS>,
+    pending: Vec<Pending<A>>,
+    metadata: EventMetadata,
+}
+
+/// A save recorded by a [`UnitOfWork`].
+struct Pending<A: Aggregate> {
+    id: A::Id,
+    version_before: i64,
+    new_events: Vec<A::Event>,
+    snapshot: Option<A::Snapshot>,
+}
+
+impl<A, S, SS> UnitOfWork<'_, A, S, SS>
+where
+    A: Aggregate,
+    S: EventStore<A> + 'static,
+    SS: SnapshotStore<A> + 'static,
+{
+    /// Sets the metadata recorded alongside every event of the unit.
+    #[must_use]
+    pub fn with_metadata(mut self, metadata: EventMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Records `new_events` to be saved for `aggregate`, which they have
+    /// already been applied to.
+    ///
+    /// The same aggregate may be saved more than once, each save carrying the
+    /// events applied since the previous one.
+    pub fn save(&mut self, aggregate: &A, new_events: Vec<A::Event>) {
         if new_events.is_empty() {
-            return Ok(());
+            return;
         }
+        let version_before = aggregate.version() - new_events.len() as i64;
+        let snapshot = self.repository.planned_snapshot(
+            aggregate.id(),
+            version_before,
+            aggregate,
+            &new_events,
+        );
+        self.pending.push(Pending {
+            id: aggregate.id().clone(),
+            version_before,
+            new_events,
+            snapshot,
+        });
+    }
 
-        let version_before_save = aggregate.version() - new_events.len() as i64;
-        let num_new_events = new_events.len() as i64;
+    /// Returns the number of saves recorded.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
 
-        self.store
-            .append(aggregate.id(), version_before_save, new_events)
-            .await?;
+    /// Returns `true` if no save has been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
 
-        if let (Some(snapshot_store), Some(frequency)) =
-            (&self.snapshot_store, self.snapshot_frequency)
-        {
-            let version_after_save = version_before_save + num_new_events;
-            if version_after_save / frequency as i64 > version_before_save / frequency as i64 {
-                let snapshot = aggregate.snapshot();
-                snapshot_store
-                    .save(aggregate.id(), version_after_save, snapshot)
-                    .await?;
+    /// Appends the events of every recorded save atomically, then takes the
+    /// snapshots the snapshot policy asked for.
+    #[instrument(skip(self), fields(saves = self.pending.len()))]
+    pub async fn commit(self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let repository = self.repository;
+        let mut batch = Vec::with_capacity(self.pending.len());
+        let mut snapshots = Vec::new();
+        for pending in self.pending {
+            let version_after = pending.version_before + pending.new_events.len() as i64;
+            if let Some(snapshot) = pending.snapshot {
+                snapshots.push((pending.id.clone(), version_after, snapshot));
             }
+            batch.push((
+                pending.id,
+                expected_version(pending.version_before),
+                repository.seal(pending.new_events).await?,
+            ));
         }
+        let stored_events = repository.store.append_batch(batch, self.metadata).await?;
 
+        for (id, version, snapshot) in snapshots {
+            if reaches(&stored_events, &id, version) {
+                repository.write_snapshot(&id, version, snapshot).await;
+            }
+        }
         Ok(())
     }
 }
@@ -148,7 +865,26 @@ where
         (**self).load(aggregate_id).await
     }
 
+    async fn load_at_version(&self, aggregate_id: &A::Id, version: i64) -> Result<A> {
+        (**self).load_at_version(aggregate_id, version).await
+    }
+
+    async fn load_as_of(&self, aggregate_id: &A::Id, timestamp: DateTime<Utc>) -> Result<A> {
+        (**self).load_as_of(aggregate_id, timestamp).await
+    }
+
     async fn save(&self, aggregate: &A, events: Vec<A::Event>) -> Result<()> {
         (**self).save(aggregate, events).await
     }
+
+    async fn save_with_metadata(
+        &self,
+        aggregate: &A,
+        events: Vec<A::Event>,
+        metadata: EventMetadata,
+    ) -> Result<()> {
+        (**self)
+            .save_with_metadata(aggregate, events, metadata)
+            .await
+    }
 }
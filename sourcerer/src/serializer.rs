@@ -0,0 +1,196 @@
+//! Defines how event payloads are encoded by the persistent stores.
+//!
+//! Every persistent store is generic over an [`EventSerializer`], defaulting
+//! to [`JsonSerializer`]. The [`Format`] of each event is recorded alongside
+//! it, so a stream written with different serializers over time still loads:
+//! events are always decoded in the format they were written in.
+//!
+//! MessagePack, CBOR and bincode are available behind the `msgpack`, `cbor`
+//! and `bincode` features.
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{Error, Result};
+
+/// The encoding of a stored event payload.
+///
+/// Every format can be named, so events written by a serializer whose feature
+/// is not enabled are reported as such rather than misread.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// JSON, via `serde_json`.
+    #[default]
+    Json,
+    /// MessagePack, via `rmp-serde`. Requires the `msgpack` feature.
+    MessagePack,
+    /// CBOR, via `ciborium`. Requires the `cbor` feature.
+    Cbor,
+    /// bincode. Requires the `bincode` feature.
+    ///
+    /// bincode is not self-describing: it cannot encode internally tagged or
+    /// untagged enums, and its payloads cannot be upcast.
+    Bincode,
+}
+
+impl Format {
+    /// Returns the name under which the format is recorded.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::MessagePack => "messagepack",
+            Self::Cbor => "cbor",
+            Self::Bincode => "bincode",
+        }
+    }
+
+    /// Decodes a payload written in this format.
+    ///
+    /// Fails if the feature providing the format is not enabled.
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Self::Json => JsonSerializer.deserialize(bytes),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePackSerializer.deserialize(bytes),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => CborSerializer.deserialize(bytes),
+            #[cfg(feature = "bincode")]
+            Self::Bincode => BincodeSerializer.deserialize(bytes),
+            #[allow(unreachable_patterns)]
+            format => Err(Error::Store(format!(
+                "cannot decode {format} payload: format not enabled"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Self::Json),
+            "messagepack" => Ok(Self::MessagePack),
+            "cbor" => Ok(Self::Cbor),
+            "bincode" => Ok(Self::Bincode),
+            _ => Err(Error::Store(format!("unknown payload format `{s}`"))),
+        }
+    }
+}
+
+/// Encodes and decodes event payloads for a persistent store.
+pub trait EventSerializer: Clone + Send + Sync + 'static {
+    /// The format this serializer writes, recorded alongside every event.
+    fn format(&self) -> Format;
+
+    /// Encodes a value.
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+
+    /// Decodes a value written by [`serialize`](Self::serialize).
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+
+    /// Decodes a payload recorded in `format`, which may have been written by
+    /// a different serializer.
+    fn decode<T: DeserializeOwned>(&self, format: Format, bytes: &[u8]) -> Result<T> {
+        if format == self.format() {
+            self.deserialize(bytes)
+        } else {
+            format.decode(bytes)
+        }
+    }
+}
+
+/// Encodes payloads as JSON. This is the default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JsonSerializer;
+
+impl EventSerializer for JsonSerializer {
+    fn format(&self) -> Format {
+        Format::Json
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| Error::Store(e.to_string()))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|e| Error::Store(e.to_string()))
+    }
+}
+
+/// Encodes payloads as MessagePack.
+///
+/// Structs are written as maps with their field names, so payloads can be
+/// upcast.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessagePackSerializer;
+
+#[cfg(feature = "msgpack")]
+impl EventSerializer for MessagePackSerializer {
+    fn format(&self) -> Format {
+        Format::MessagePack
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(|e| Error::Store(e.to_string()))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(bytes).map_err(|e| Error::Store(e.to_string()))
+    }
+}
+
+/// Encodes payloads as CBOR.
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CborSerializer;
+
+#[cfg(feature = "cbor")]
+impl EventSerializer for CborSerializer {
+    fn format(&self) -> Format {
+        Format::Cbor
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).map_err(|e| Error::Store(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        ciborium::from_reader(bytes).map_err(|e| Error::Store(e.to_string()))
+    }
+}
+
+/// Encodes payloads with bincode's standard configuration.
+///
+/// See [`Format::Bincode`] for its limitations.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BincodeSerializer;
+
+#[cfg(feature = "bincode")]
+impl EventSerializer for BincodeSerializer {
+    fn format(&self) -> Format {
+        Format::Bincode
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        bincode::serde::encode_to_vec(value, bincode::config::standard())
+            .map_err(|e| Error::Store(e.to_string()))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .map(|(value, _)| value)
+            .map_err(|e| Error::Store(e.to_string()))
+    }
+}
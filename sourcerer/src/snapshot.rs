@@ -1,9 +1,22 @@
 //! The snapshot module contains the traits and structs for creating and storing
 //! aggregate snapshots.
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Aggregate, Error, Event, Result, Snapshot};
 
-use crate::{Aggregate, Result, Snapshot};
+/// Returns the schema version of snapshots stored without one.
+fn first_version() -> u16 {
+    1
+}
 
 /// Represents a stored snapshot, including metadata.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,20 +29,43 @@ pub struct StoredSnapshot<S: Snapshot> {
     aggregate_id: String,
     /// The version of the aggregate when this snapshot was taken.
     version: i64,
+    /// The version of the snapshot's schema.
+    #[serde(default = "first_version")]
+    snapshot_version: u16,
+    /// When the snapshot was taken, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    taken_at: Option<DateTime<Utc>>,
     /// The snapshot payload itself.
     snapshot: S,
 }
 
 impl<S: Snapshot> StoredSnapshot<S> {
-    /// Creates a new stored snapshot.
+    /// Creates a new stored snapshot of the current schema version, taken
+    /// now.
     pub fn new(aggregate_id: String, version: i64, snapshot: S) -> Self {
         Self {
             aggregate_id,
             version,
+            snapshot_version: S::snapshot_version(),
+            taken_at: Some(Utc::now()),
             snapshot,
         }
     }
 
+    /// Sets the schema version the snapshot was stored with.
+    #[must_use]
+    pub fn with_snapshot_version(mut self, snapshot_version: u16) -> Self {
+        self.snapshot_version = snapshot_version;
+        self
+    }
+
+    /// Sets when the snapshot was taken, or `None` if that is unknown.
+    #[must_use]
+    pub fn with_taken_at(mut self, taken_at: Option<DateTime<Utc>>) -> Self {
+        self.taken_at = taken_at;
+        self
+    }
+
     /// Returns the aggregate ID.
     pub fn aggregate_id(&self) -> &str {
         &self.aggregate_id
@@ -40,12 +76,121 @@ impl<S: Snapshot> StoredSnapshot<S> {
         self.version
     }
 
+    /// Returns the version of the snapshot's schema it was stored with.
+    pub fn snapshot_version(&self) -> u16 {
+        self.snapshot_version
+    }
+
+    /// Returns when the snapshot was taken, if known.
+    ///
+    /// Snapshots stored before this was recorded have no time.
+    pub fn taken_at(&self) -> Option<DateTime<Utc>> {
+        self.taken_at
+    }
+
     /// Consumes the stored snapshot and returns the inner snapshot.
     pub fn into_snapshot(self) -> S {
         self.snapshot
     }
 }
 
+/// A stored snapshot whose payload has not been deserialized.
+///
+/// Snapshots are loaded in this form so that payloads written by an older
+/// snapshot schema can be upcast before they are deserialized.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RawSnapshot {
+    /// The ID of the aggregate this snapshot belongs to.
+    pub aggregate_id: String,
+    /// The version of the aggregate when this snapshot was taken.
+    pub version: i64,
+    /// The version of the snapshot's schema.
+    #[serde(default = "first_version")]
+    pub snapshot_version: u16,
+    /// When the snapshot was taken, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub taken_at: Option<DateTime<Utc>>,
+    /// The snapshot payload as JSON.
+    #[serde(rename = "snapshot")]
+    pub payload: Value,
+}
+
+impl RawSnapshot {
+    /// Encodes a stored snapshot into its raw form.
+    pub(crate) fn encode<S: Snapshot>(stored: &StoredSnapshot<S>) -> Result<Self> {
+        Ok(Self {
+            aggregate_id: stored.aggregate_id.clone(),
+            version: stored.version,
+            snapshot_version: stored.snapshot_version,
+            taken_at: stored.taken_at,
+            payload: serde_json::to_value(&stored.snapshot)
+                .map_err(|e| Error::Store(e.to_string()))?,
+        })
+    }
+
+    /// Deserializes the payload, keeping the schema version it was stored
+    /// with.
+    pub fn decode<S: Snapshot>(self) -> Result<StoredSnapshot<S>> {
+        let snapshot = serde_json::from_value(self.payload).map_err(|e| {
+            Error::Store(format!(
+                "cannot read version {} snapshot of {}: {e}",
+                self.snapshot_version, self.aggregate_id
+            ))
+        })?;
+        Ok(
+            StoredSnapshot::new(self.aggregate_id, self.version, snapshot)
+                .with_snapshot_version(self.snapshot_version)
+                .with_taken_at(self.taken_at),
+        )
+    }
+}
+
+/// Decides which of an aggregate's snapshots a store keeps.
+///
+/// The latest snapshot is always kept. Stores that keep a single snapshot
+/// per aggregate ignore the retention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotRetention {
+    /// Keeps every snapshot.
+    KeepAll,
+    /// Keeps the latest `K` snapshots, where `0` counts as `1`.
+    KeepLast(usize),
+    /// Keeps the latest snapshot in each span of `N` aggregate versions, where
+    /// `0` counts as `1`.
+    OnePerVersions(i64),
+}
+
+impl Default for SnapshotRetention {
+    /// Keeps only the latest snapshot.
+    fn default() -> Self {
+        Self::KeepLast(1)
+    }
+}
+
+impl SnapshotRetention {
+    /// Returns `true` if snapshots older than the latest may be kept.
+    pub(crate) fn keeps_history(&self) -> bool {
+        !matches!(self, Self::KeepLast(0 | 1))
+    }
+
+    /// Returns the versions to discard out of `versions`, which are in
+    /// ascending order and end with the latest snapshot.
+    pub(crate) fn pruned(&self, versions: &[i64]) -> Vec<i64> {
+        match *self {
+            Self::KeepAll => Vec::new(),
+            Self::KeepLast(k) => versions[..versions.len().saturating_sub(k.max(1))].to_vec(),
+            Self::OnePerVersions(n) => {
+                let n = n.max(1);
+                versions
+                    .windows(2)
+                    .filter(|pair| pair[0] / n == pair[1] / n)
+                    .map(|pair| pair[0])
+                    .collect()
+            }
+        }
+    }
+}
+
 /// A snapshot store is responsible for persisting and loading snapshots.
 ///
 /// Snapshots are an optimization to reduce the time it takes to hydrate an
@@ -56,9 +201,294 @@ impl<S: Snapshot> StoredSnapshot<S> {
 pub trait SnapshotStore<A: Aggregate>: Send + Sync {
     /// Saves a snapshot for a given aggregate.
     ///
-    /// This should overwrite any existing snapshot for the same aggregate.
+    /// Snapshot versions only move forward: a snapshot older than the latest
+    /// one stored is ignored, so a slow writer cannot replace a newer
+    /// snapshot, while one at the same version replaces it. Stores keeping
+    /// a history of snapshots prune it as their [`SnapshotRetention`] says.
     async fn save(&self, aggregate_id: &A::Id, version: i64, snapshot: A::Snapshot) -> Result<()>;
 
     /// Loads the latest snapshot for a given aggregate.
     async fn load(&self, aggregate_id: &A::Id) -> Result<Option<StoredSnapshot<A::Snapshot>>>;
+
+    /// Loads the latest snapshot for a given aggregate without deserializing
+    /// its payload.
+    ///
+    /// The default implementation re-encodes the snapshot returned by
+    /// [`load`](SnapshotStore::load). Stores that keep serialized snapshots
+    /// return them as stored, so snapshots that no longer deserialize can
+    /// still be upcast.
+    async fn load_raw(&self, aggregate_id: &A::Id) -> Result<Option<RawSnapshot>> {
+        self.load(aggregate_id)
+            .await?
+            .as_ref()
+            .map(RawSnapshot::encode)
+            .transpose()
+    }
+
+    /// Loads the latest snapshot for a given aggregate taken at or before
+    /// `version`.
+    ///
+    /// The default implementation only considers the latest snapshot.
+    /// Stores keeping a history of snapshots search it.
+    async fn load_at_or_before(
+        &self,
+        aggregate_id: &A::Id,
+        version: i64,
+    ) -> Result<Option<StoredSnapshot<A::Snapshot>>> {
+        Ok(self
+            .load(aggregate_id)
+            .await?
+            .filter(|snapshot| snapshot.version() <= version))
+    }
+
+    /// Loads the latest snapshot for a given aggregate taken at or before
+    /// `version`, without deserializing its payload.
+    ///
+    /// The default implementation only considers the latest snapshot, as
+    /// returned by [`load_raw`](SnapshotStore::load_raw).
+    async fn load_raw_at_or_before(
+        &self,
+        aggregate_id: &A::Id,
+        version: i64,
+    ) -> Result<Option<RawSnapshot>> {
+        Ok(self
+            .load_raw(aggregate_id)
+            .await?
+            .filter(|snapshot| snapshot.version <= version))
+    }
+}
+
+/// The latest snapshot of an aggregate known to a repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SnapshotMark {
+    /// The aggregate version the snapshot was taken at.
+    pub version: i64,
+    /// When the snapshot was taken, if known.
+    pub taken_at: Option<DateTime<Utc>>,
+}
+
+/// The latest snapshots of the aggregates a repository has recently loaded
+/// or saved.
+///
+/// Once more than `capacity` aggregates are marked, the least recently marked
+/// are forgotten, down to three quarters of the capacity so that the cost of
+/// evicting is spread over many marks. A forgotten aggregate is treated as
+/// having no snapshot until it is loaded again.
+#[derive(Debug)]
+pub(crate) struct SnapshotMarks {
+    marks: DashMap<String, (SnapshotMark, u64)>,
+    capacity: usize,
+    ticks: AtomicU64,
+}
+
+impl SnapshotMarks {
+    /// Creates an empty set of marks for at most `capacity` aggregates.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            marks: DashMap::new(),
+            capacity,
+            ticks: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the latest snapshot marked for the aggregate `id`.
+    pub fn get(&self, id: &str) -> Option<SnapshotMark> {
+        self.marks.get(id).map(|entry| entry.0)
+    }
+
+    /// Records `mark` as the latest snapshot of the aggregate `id`, unless a
+    /// later one is already known.
+    pub fn record(&self, id: String, mark: SnapshotMark) {
+        let tick = self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.marks
+            .entry(id)
+            .and_modify(|(latest, touched)| {
+                if mark.version > latest.version
+                    || (mark.version == latest.version && mark.taken_at.is_some())
+                {
+                    *latest = mark;
+                }
+                *touched = tick;
+            })
+            .or_insert((mark, tick));
+        if self.marks.len() > self.capacity {
+            self.evict();
+        }
+    }
+
+    /// Forgets the least recently marked aggregates.
+    fn evict(&self) {
+        let keep = self.capacity - self.capacity / 4;
+        let mut ticks: Vec<u64> = self.marks.iter().map(|entry| entry.1).collect();
+        let Some(evicted) = ticks.len().checked_sub(keep).filter(|&n| n > 0) else {
+            return;
+        };
+        let newest_evicted = *ticks.select_nth_unstable(evicted - 1).1;
+        self.marks.retain(|_, (_, tick)| *tick > newest_evicted);
+    }
+}
+
+/// What a [`SnapshotPolicy`] is told about a save.
+pub struct SnapshotContext<'a, A: Aggregate> {
+    aggregate: &'a A,
+    version_before: i64,
+    new_events: &'a [A::Event],
+    last_snapshot: Option<SnapshotMark>,
+}
+
+impl<'a, A: Aggregate> SnapshotContext<'a, A> {
+    /// Creates the context of a save.
+    pub(crate) fn new(
+        aggregate: &'a A,
+        version_before: i64,
+        new_events: &'a [A::Event],
+        last_snapshot: Option<SnapshotMark>,
+    ) -> Self {
+        Self {
+            aggregate,
+            version_before,
+            new_events,
+            last_snapshot,
+        }
+    }
+
+    /// Returns the aggregate, with the new events applied.
+    pub fn aggregate(&self) -> &A {
+        self.aggregate
+    }
+
+    /// Returns the version of the aggregate before the new events.
+    pub fn version_before(&self) -> i64 {
+        self.version_before
+    }
+
+    /// Returns the version of the aggregate after the new events.
+    pub fn version_after(&self) -> i64 {
+        self.version_before + self.new_events.len() as i64
+    }
+
+    /// Returns the events being saved.
+    pub fn new_events(&self) -> &[A::Event] {
+        self.new_events
+    }
+
+    /// Returns the version of the latest snapshot the repository has loaded
+    /// or written for the aggregate, if any.
+    pub fn last_snapshot_version(&self) -> Option<i64> {
+        self.last_snapshot.map(|mark| mark.version)
+    }
+
+    /// Returns the time since the latest snapshot the repository has loaded
+    /// or written for the aggregate was taken, if known.
+    pub fn since_last_snapshot(&self) -> Option<Duration> {
+        self.last_snapshot
+            .and_then(|mark| mark.taken_at)
+            .map(|taken_at| (Utc::now() - taken_at).to_std().unwrap_or_default())
+    }
+
+    /// Returns the number of events a load would replay after the new events
+    /// are saved: those since the last snapshot, or the whole stream.
+    pub fn events_since_snapshot(&self) -> i64 {
+        self.version_after() - self.last_snapshot_version().unwrap_or(0)
+    }
+}
+
+/// Decides when a repository snapshots an aggregate after saving events.
+///
+/// Closures taking a [`SnapshotContext`] are policies too.
+pub trait SnapshotPolicy<A: Aggregate>: Send + Sync {
+    /// Returns `true` if the aggregate should be snapshotted after the save
+    /// described by `context`.
+    fn should_snapshot(&self, context: &SnapshotContext<'_, A>) -> bool;
+}
+
+impl<A, F> SnapshotPolicy<A> for F
+where
+    A: Aggregate,
+    F: Fn(&SnapshotContext<'_, A>) -> bool + Send + Sync,
+{
+    fn should_snapshot(&self, context: &SnapshotContext<'_, A>) -> bool {
+        self(context)
+    }
+}
+
+/// Snapshots whenever a save crosses a multiple of `n` events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EveryNEvents(usize);
+
+impl EveryNEvents {
+    /// Creates a policy snapshotting every `n` events, where `0` counts as
+    /// `1`.
+    pub fn new(n: usize) -> Self {
+        Self(n.max(1))
+    }
+}
+
+impl<A: Aggregate> SnapshotPolicy<A> for EveryNEvents {
+    fn should_snapshot(&self, context: &SnapshotContext<'_, A>) -> bool {
+        let n = self.0 as i64;
+        context.version_after() / n > context.version_before() / n
+    }
+}
+
+/// Snapshots when at least `interval` has passed since the aggregate's latest
+/// snapshot was taken, or if the repository knows of none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EveryInterval(Duration);
+
+impl EveryInterval {
+    /// Creates a policy snapshotting at most once per `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Self(interval)
+    }
+}
+
+impl<A: Aggregate> SnapshotPolicy<A> for EveryInterval {
+    fn should_snapshot(&self, context: &SnapshotContext<'_, A>) -> bool {
+        context
+            .since_last_snapshot()
+            .is_none_or(|elapsed| elapsed >= self.0)
+    }
+}
+
+/// Snapshots when a save includes an event of one of the given types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnEventTypes(Vec<String>);
+
+impl OnEventTypes {
+    /// Creates a policy snapshotting on events of the given types.
+    pub fn new<I, T>(event_types: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        Self(event_types.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<A: Aggregate> SnapshotPolicy<A> for OnEventTypes {
+    fn should_snapshot(&self, context: &SnapshotContext<'_, A>) -> bool {
+        context
+            .new_events()
+            .iter()
+            .any(|event| self.0.iter().any(|t| t == event.event_type()))
+    }
+}
+
+/// Snapshots once loading the aggregate would replay at least `max_events`
+/// events past its last snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayCost(i64);
+
+impl ReplayCost {
+    /// Creates a policy bounding replays to about `max_events` events.
+    pub fn new(max_events: usize) -> Self {
+        Self(i64::try_from(max_events).unwrap_or(i64::MAX).max(1))
+    }
+}
+
+impl<A: Aggregate> SnapshotPolicy<A> for ReplayCost {
+    fn should_snapshot(&self, context: &SnapshotContext<'_, A>) -> bool {
+        context.events_since_snapshot() >= self.0
+    }
 }
@@ -0,0 +1,1078 @@
+//! A persistent `EventStore` implementation using append-only segment files.
+//!
+//! Events are written to a directory of segment files, each named after the
+//! global position of its first event. Every append is written as a single
+//! frame:
+//!
+//! ```text
+//! [body length: u32 LE][CRC-32 of body: u32 LE][body]
+//! ```
+//!
+//! where the body holds each event as a length-prefixed record, so an append
+//! is either read back whole or not at all. Once a segment grows
+//! past the configured size, the next append starts a new one.
+//!
+//! On open, the segments are scanned to rebuild the per-stream offset index.
+//! A torn frame at the end of the last segment, left behind by a crash in the
+//! middle of an append, is truncated away. Damage anywhere else, including a
+//! bad frame in the last segment followed by intact ones, is reported as an
+//! error rather than silently dropping events.
+//!
+//! A directory must only be opened by one process at a time.
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::{
+    StreamExt,
+    channel::mpsc,
+    stream::{self, BoxStream},
+};
+use tracing::instrument;
+
+use crate::{
+    Aggregate, Error, Event, EventMetadata, EventStore, ExpectedVersion, Result, StoredEvent,
+    compression::Compression,
+    serializer::{EventSerializer, JsonSerializer},
+    store::{
+        record::{self, Envelope},
+        stream_name, stream_prefix,
+    },
+    subscription::{self, EventSubscription, Page, SubscriptionFilter},
+    upcaster::RawStoredEvent,
+};
+
+/// File extension of segment files.
+const SEGMENT_EXTENSION: &str = "log";
+
+/// Length of a frame header: the body length followed by its checksum.
+const HEADER_LEN: u64 = 8;
+
+/// Maps `std::io::Error` into this crate's `Error`.
+fn to_store_error(e: io::Error) -> Error {
+    Error::Store(e.to_string())
+}
+
+/// Controls when appended events are flushed to disk with `fsync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Every append is synced before it returns.
+    Always,
+    /// Appends are synced once `max_appends` appends are pending or
+    /// `max_delay` has passed since the last sync, whichever comes first.
+    ///
+    /// Both limits are checked when appending, so a quiet store may hold
+    /// unsynced appends until the next append, [`FileLog::sync`] or drop.
+    Batched {
+        /// The maximum number of unsynced appends.
+        max_appends: usize,
+        /// The maximum time since the last sync.
+        max_delay: Duration,
+    },
+    /// Flushing is left to the operating system.
+    OsManaged,
+}
+
+/// Options for opening a [`FileLog`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileOptions {
+    sync_policy: SyncPolicy,
+    segment_size: u64,
+}
+
+impl Default for FileOptions {
+    /// Syncs every append and rolls segments over at 64 MiB.
+    fn default() -> Self {
+        Self {
+            sync_policy: SyncPolicy::Always,
+            segment_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl FileOptions {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets when appends are flushed to disk.
+    #[must_use]
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets the size in bytes past which a new segment is started.
+    ///
+    /// A single append is never split, so a segment can exceed this size by
+    /// up to one append.
+    #[must_use]
+    pub fn with_segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size.max(1);
+        self
+    }
+}
+
+/// A contiguous run of events written by a single append.
+#[derive(Debug, Clone, Copy)]
+struct FrameRef {
+    /// The base position, and so the name, of the segment holding the frame.
+    segment: i64,
+    /// The byte offset of the frame within its segment.
+    offset: u64,
+    /// The stream version of the first event in the frame.
+    first_version: i64,
+    /// The global position of the first event in the frame.
+    first_position: i64,
+    /// The number of events in the frame.
+    count: i64,
+}
+
+impl FrameRef {
+    fn last_version(&self) -> i64 {
+        self.first_version + self.count - 1
+    }
+
+    fn last_position(&self) -> i64 {
+        self.first_position + self.count - 1
+    }
+}
+
+/// An event read back from a segment.
+struct Record {
+    envelope: Envelope,
+    payload: Vec<u8>,
+}
+
+impl Record {
+    fn into_raw(self) -> RawStoredEvent {
+        self.envelope.into_raw(self.payload)
+    }
+
+    fn into_stored<E: Event, S: EventSerializer>(self, serializer: &S) -> Result<StoredEvent<E>> {
+        self.envelope.into_stored(&self.payload, serializer)
+    }
+}
+
+/// The mutable state of a log, guarded by a single lock.
+struct State {
+    /// The segment currently appended to, opened in append mode.
+    active: File,
+    /// The base position of the active segment.
+    active_segment: i64,
+    /// The length of the active segment in bytes.
+    active_len: u64,
+    /// The position the next appended event will get.
+    next_position: i64,
+    /// The frames of every stream, keyed by stream name, in version order.
+    streams: HashMap<String, Vec<FrameRef>>,
+    /// The frames of every aggregate type, in position order.
+    types: HashMap<String, Vec<FrameRef>>,
+    /// The hash of the last event of every stream, keyed by stream name.
+    hashes: HashMap<String, String>,
+    /// The number of appends written since the last sync.
+    unsynced: usize,
+    /// When the log was last synced.
+    last_sync: Instant,
+}
+
+impl State {
+    /// Syncs the active segment if any appends are pending.
+    fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.active.sync_data()?;
+            self.unsynced = 0;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Records a written frame, and the hash of its last event, in the
+    /// indexes.
+    fn index(
+        &mut self,
+        aggregate_type: &str,
+        stream: String,
+        frame: FrameRef,
+        last_hash: Option<String>,
+    ) {
+        match last_hash {
+            Some(hash) => self.hashes.insert(stream.clone(), hash),
+            None => self.hashes.remove(&stream),
+        };
+        self.streams.entry(stream).or_default().push(frame);
+        self.types
+            .entry(aggregate_type.to_string())
+            .or_default()
+            .push(frame);
+    }
+}
+
+struct Inner {
+    dir: PathBuf,
+    options: FileOptions,
+    state: Mutex<State>,
+    /// Live subscriptions, signalled after every append.
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<()>>>,
+}
+
+impl Inner {
+    fn state(&self) -> Result<MutexGuard<'_, State>> {
+        self.state.lock().map_err(|e| Error::Store(e.to_string()))
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if self.options.sync_policy != SyncPolicy::OsManaged
+            && let Ok(state) = self.state.get_mut()
+        {
+            let _ = state.sync();
+        }
+    }
+}
+
+/// A directory of append-only segment files shared by the file-backed event
+/// stores of one or more aggregate types.
+///
+/// Cloning a `FileLog` returns another handle to the same open log.
+#[derive(Clone)]
+pub struct FileLog {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for FileLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileLog")
+            .field("dir", &self.inner.dir)
+            .field("options", &self.inner.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileLog {
+    /// Opens the log in `dir`, creating the directory if needed.
+    ///
+    /// Every segment is scanned to rebuild the index, and a torn frame at the
+    /// end of the last segment is truncated. A bad frame followed by intact
+    /// ones is not a torn write, and fails the open instead.
+    #[instrument(skip(dir), fields(dir = %dir.as_ref().display()))]
+    pub fn open(dir: impl AsRef<Path>, options: FileOptions) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(to_store_error)?;
+
+        let mut segments = list_segments(&dir)?;
+        if segments.is_empty() {
+            File::create(segment_path(&dir, 1)).map_err(to_store_error)?;
+            segments.push(1);
+        }
+
+        let mut streams: HashMap<String, Vec<FrameRef>> = HashMap::new();
+        let mut types: HashMap<String, Vec<FrameRef>> = HashMap::new();
+        let mut hashes: HashMap<String, String> = HashMap::new();
+        let mut next_position = 1;
+        let mut active_len = 0;
+
+        for (i, &segment) in segments.iter().enumerate() {
+            let is_last = i + 1 == segments.len();
+            if segment != next_position {
+                return Err(Error::Store(format!(
+                    "segment {segment} does not follow position {}",
+                    next_position - 1
+                )));
+            }
+
+            let path = segment_path(&dir, segment);
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .map_err(to_store_error)?;
+            let (frames, valid_len) = scan_segment(&mut file, segment);
+
+            let file_len = file.metadata().map_err(to_store_error)?.len();
+            if valid_len < file_len {
+                if !is_last || intact_frame_after(&mut file, valid_len)? {
+                    return Err(Error::Store(format!(
+                        "corrupt frame at offset {valid_len} of segment {}",
+                        path.display()
+                    )));
+                }
+                tracing::warn!(
+                    segment = %path.display(),
+                    offset = valid_len,
+                    discarded = file_len - valid_len,
+                    "truncating torn write"
+                );
+                file.set_len(valid_len).map_err(to_store_error)?;
+                file.sync_all().map_err(to_store_error)?;
+            }
+
+            for ScannedFrame {
+                aggregate_type,
+                stream,
+                frame,
+                last_hash,
+            } in frames
+            {
+                if frame.first_position != next_position {
+                    return Err(Error::Store(format!(
+                        "expected position {next_position} at offset {} of segment {}",
+                        frame.offset,
+                        path.display()
+                    )));
+                }
+                next_position = frame.last_position() + 1;
+                match last_hash {
+                    Some(hash) => hashes.insert(stream.clone(), hash),
+                    None => hashes.remove(&stream),
+                };
+                streams.entry(stream).or_default().push(frame);
+                types.entry(aggregate_type).or_default().push(frame);
+            }
+            active_len = valid_len;
+        }
+
+        let active_segment = *segments.last().unwrap_or(&1);
+        let active = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, active_segment))
+            .map_err(to_store_error)?;
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                dir,
+                options,
+                state: Mutex::new(State {
+                    active,
+                    active_segment,
+                    active_len,
+                    next_position,
+                    streams,
+                    types,
+                    hashes,
+                    unsynced: 0,
+                    last_sync: Instant::now(),
+                }),
+                subscribers: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    /// Returns the directory holding the segment files.
+    pub fn dir(&self) -> &Path {
+        &self.inner.dir
+    }
+
+    /// Flushes every append written so far to disk.
+    pub fn sync(&self) -> Result<()> {
+        self.inner.state()?.sync().map_err(to_store_error)
+    }
+
+    /// Wakes every live subscription, dropping those that have gone away.
+    fn notify_subscribers(&self) {
+        if let Ok(mut subscribers) = self.inner.subscribers.lock() {
+            subscribers.retain(|tx| tx.unbounded_send(()).is_ok());
+        }
+    }
+}
+
+/// A persistent, thread-safe event store using append-only segment files.
+///
+/// Several aggregate types can share one [`FileLog`]; their streams are kept
+/// apart by aggregate type.
+///
+/// Event payloads are encoded by `S`, JSON by default; the rest of each
+/// event is always stored as JSON.
+pub struct FileEventStore<A: Aggregate, S: EventSerializer = JsonSerializer> {
+    log: FileLog,
+    serializer: S,
+    _phantom: PhantomData<A>,
+}
+
+impl<A: Aggregate, S: EventSerializer> Clone for FileEventStore<A, S> {
+    fn clone(&self) -> Self {
+        Self {
+            log: self.log.clone(),
+            serializer: self.serializer.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<A: Aggregate> FileEventStore<A> {
+    /// Creates a new `FileEventStore` on top of an open log, writing JSON
+    /// payloads.
+    pub fn new(log: FileLog) -> Self {
+        Self {
+            log,
+            serializer: JsonSerializer,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<A: Aggregate, S: EventSerializer> FileEventStore<A, S> {
+    /// Sets the serializer used to encode the payloads of appended events.
+    ///
+    /// Events already stored keep the format they were written in.
+    pub fn with_serializer<T: EventSerializer>(self, serializer: T) -> FileEventStore<A, T> {
+        FileEventStore {
+            log: self.log,
+            serializer,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the frames of an aggregate's stream holding events after
+    /// `version`.
+    fn frames_after(&self, id: &A::Id, version: i64) -> Result<Vec<FrameRef>> {
+        let state = self.log.inner.state()?;
+        Ok(match state.streams.get(&stream_name::<A>(id)) {
+            Some(frames) => {
+                let start = frames.partition_point(|f| f.last_version() <= version);
+                frames[start..].to_vec()
+            }
+            None => Vec::new(),
+        })
+    }
+
+    /// Lazily reads an aggregate's events after `version`.
+    fn records_after(
+        &self,
+        id: &A::Id,
+        version: i64,
+    ) -> Result<impl Iterator<Item = Result<Record>> + Send + use<A, S>> {
+        let frames = self.frames_after(id, version)?;
+        Ok(
+            FrameReader::new(self.log.inner.dir.clone(), frames).filter(move |record| {
+                record
+                    .as_ref()
+                    .map_or(true, |r| r.envelope.version > version)
+            }),
+        )
+    }
+}
+
+#[async_trait]
+impl<A, S> EventStore<A> for FileEventStore<A, S>
+where
+    A: Aggregate,
+    S: EventSerializer,
+{
+    #[instrument(skip(self, events, metadata), fields(id = ?id, expected_version))]
+    async fn append(
+        &self,
+        id: &A::Id,
+        expected_version: ExpectedVersion,
+        events: Vec<A::Event>,
+        metadata: EventMetadata,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        if metadata.idempotency_key().is_some() {
+            return Err(Error::Store(
+                "this store cannot deduplicate appends by idempotency key".into(),
+            ));
+        }
+        let aggregate_id = id.to_string();
+        let stream = stream_name::<A>(id);
+        let inner = &self.log.inner;
+        let mut state = inner.state()?;
+
+        let current_version = state
+            .streams
+            .get(&stream)
+            .and_then(|frames| frames.last())
+            .map_or(0, FrameRef::last_version);
+        if !expected_version.matches(current_version) {
+            return Err(Error::Conflict {
+                aggregate_id,
+                expected: expected_version,
+                actual: current_version,
+            });
+        }
+        if events.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let now = Utc::now();
+        let first_position = state.next_position;
+        let mut previous = state.hashes.get(&stream).cloned();
+        let mut records = Vec::with_capacity(events.len());
+        let mut stored_events = Vec::with_capacity(events.len());
+        for (i, event) in (0..).zip(events) {
+            let stored_event = StoredEvent::new(
+                aggregate_id.clone(),
+                current_version + 1 + i,
+                event.event_version(),
+                event.event_type().to_string(),
+                event,
+            )
+            .with_aggregate_type(A::TYPE_NAME)
+            .with_position(first_position + i)
+            .with_metadata(metadata.stamp(now))
+            .chained(previous.as_deref())?;
+            previous = stored_event.hash().map(str::to_owned);
+            records.push(record::encode(
+                &stored_event,
+                &self.serializer,
+                &Compression::none(),
+            )?);
+            stored_events.push(stored_event);
+        }
+        let frame = encode_frame(&records)?;
+
+        if state.active_len > 0
+            && state.active_len + frame.len() as u64 > inner.options.segment_size
+        {
+            roll_segment(&inner.dir, inner.options.sync_policy, &mut state)?;
+        }
+
+        if let Err(e) = state.active.write_all(&frame) {
+            // Cut off whatever part of the frame made it to the file, so the
+            // next append does not land after a torn frame.
+            let _ = state.active.set_len(state.active_len);
+            return Err(to_store_error(e));
+        }
+
+        // The frame is only indexed once it is synced as the policy asks, so
+        // a failed sync leaves the append unwritten rather than readable but
+        // reported as failed.
+        state.unsynced += 1;
+        let due = match inner.options.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Batched {
+                max_appends,
+                max_delay,
+            } => state.unsynced >= max_appends || state.last_sync.elapsed() >= max_delay,
+            SyncPolicy::OsManaged => false,
+        };
+        if due && let Err(e) = state.sync() {
+            state.unsynced -= 1;
+            let _ = state.active.set_len(state.active_len);
+            return Err(to_store_error(e));
+        }
+
+        let written = FrameRef {
+            segment: state.active_segment,
+            offset: state.active_len,
+            first_version: current_version + 1,
+            first_position,
+            count: stored_events.len() as i64,
+        };
+        state.active_len += frame.len() as u64;
+        state.next_position = written.last_position() + 1;
+        state.index(A::TYPE_NAME, stream, written, previous);
+        drop(state);
+
+        self.log.notify_subscribers();
+
+        Ok(stored_events)
+    }
+
+    #[instrument(skip(self), fields(id = ?id))]
+    async fn load(&self, id: &A::Id) -> Result<Vec<StoredEvent<A::Event>>> {
+        self.load_from(id, 0).await
+    }
+
+    #[instrument(skip(self), fields(id = ?id, version))]
+    async fn load_from(&self, id: &A::Id, version: i64) -> Result<Vec<StoredEvent<A::Event>>> {
+        self.records_after(id, version)?
+            .map(|record| record?.into_stored(&self.serializer))
+            .collect()
+    }
+
+    async fn load_raw(&self, id: &A::Id, version: i64) -> Result<Vec<RawStoredEvent>> {
+        self.records_after(id, version)?
+            .map(|record| record.map(Record::into_raw))
+            .collect()
+    }
+
+    fn stream_from<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<StoredEvent<A::Event>>> {
+        match self.records_after(id, version) {
+            Ok(records) => {
+                stream::iter(records.map(|record| record?.into_stored(&self.serializer))).boxed()
+            }
+            Err(e) => stream::once(futures::future::ready(Err(e))).boxed(),
+        }
+    }
+
+    fn stream_raw<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<RawStoredEvent>> {
+        match self.records_after(id, version) {
+            Ok(records) => stream::iter(records.map(|record| record.map(Record::into_raw))).boxed(),
+            Err(e) => stream::once(futures::future::ready(Err(e))).boxed(),
+        }
+    }
+
+    #[instrument(skip(self), fields(from_position, limit))]
+    async fn read_all(
+        &self,
+        from_position: i64,
+        limit: usize,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        let frames = frames_after_position(&self.log.inner, A::TYPE_NAME, from_position, limit)?;
+        FrameReader::new(self.log.inner.dir.clone(), frames)
+            .filter(|record| {
+                record
+                    .as_ref()
+                    .map_or(true, |r| r.envelope.position > from_position)
+            })
+            .take(limit)
+            .map(|record| record?.into_stored(&self.serializer))
+            .collect()
+    }
+
+    #[instrument(skip(self), fields(from_position, limit))]
+    async fn read_all_raw(&self, from_position: i64, limit: usize) -> Result<Vec<RawStoredEvent>> {
+        let frames = frames_after_position(&self.log.inner, A::TYPE_NAME, from_position, limit)?;
+        FrameReader::new(self.log.inner.dir.clone(), frames)
+            .filter(|record| {
+                record
+                    .as_ref()
+                    .map_or(true, |r| r.envelope.position > from_position)
+            })
+            .take(limit)
+            .map(|record| record.map(Record::into_raw))
+            .collect()
+    }
+
+    #[instrument(skip(self))]
+    async fn list_aggregate_ids(&self) -> Result<Vec<String>> {
+        let prefix = stream_prefix::<A>();
+        let state = self.log.inner.state()?;
+        let mut ids: Vec<String> = state
+            .streams
+            .keys()
+            .filter_map(|stream| stream.strip_prefix(&prefix).map(str::to_string))
+            .collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    #[instrument(skip(self, filter), fields(from_position))]
+    async fn subscribe(
+        &self,
+        from_position: i64,
+        filter: SubscriptionFilter,
+    ) -> Result<EventSubscription<A::Event>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.log
+            .inner
+            .subscribers
+            .lock()
+            .map_err(|e| Error::Store(e.to_string()))?
+            .push(tx);
+
+        // Hold the log weakly, so the subscription ends once every handle to
+        // it, and with it the sending half, has been dropped.
+        let inner = Arc::downgrade(&self.log.inner);
+        let serializer = self.serializer.clone();
+        Ok(subscription::catch_up(
+            from_position,
+            move |cursor| {
+                futures::future::ready(match inner.upgrade() {
+                    Some(inner) => read_page(&inner, &serializer, A::TYPE_NAME, cursor, &filter),
+                    None => Ok(Page {
+                        events: Vec::new(),
+                        cursor,
+                    }),
+                })
+            },
+            rx,
+        ))
+    }
+}
+
+/// Returns the frames of an aggregate type holding the first `limit` events
+/// after `from_position`.
+fn frames_after_position(
+    inner: &Inner,
+    aggregate_type: &str,
+    from_position: i64,
+    limit: usize,
+) -> Result<Vec<FrameRef>> {
+    let state = inner.state()?;
+    let Some(frames) = state.types.get(aggregate_type) else {
+        return Ok(Vec::new());
+    };
+    let start = frames.partition_point(|f| f.last_position() <= from_position);
+
+    let mut selected = Vec::new();
+    let mut events = 0;
+    for frame in &frames[start..] {
+        if events >= limit {
+            break;
+        }
+        events +=
+            usize::try_from(frame.last_position() - from_position.max(frame.first_position - 1))
+                .unwrap_or(0);
+        selected.push(*frame);
+    }
+    Ok(selected)
+}
+
+/// Reads the next page of an aggregate type's events after `cursor` for a
+/// subscription.
+fn read_page<E: Event, S: EventSerializer>(
+    inner: &Inner,
+    serializer: &S,
+    aggregate_type: &str,
+    cursor: i64,
+    filter: &SubscriptionFilter,
+) -> Result<Page<E>> {
+    let frames = frames_after_position(inner, aggregate_type, cursor, subscription::BATCH_SIZE)?;
+    let mut page = Page {
+        events: Vec::new(),
+        cursor,
+    };
+    for record in FrameReader::new(inner.dir.clone(), frames) {
+        let record = record?;
+        if record.envelope.position <= cursor {
+            continue;
+        }
+        page.cursor = record.envelope.position;
+        let stored = record.into_stored(serializer)?;
+        if filter.matches(&stored) {
+            page.events.push(stored);
+        }
+    }
+    Ok(page)
+}
+
+/// Lazily reads the records of a list of frames, keeping the current segment
+/// open between frames.
+struct FrameReader {
+    dir: PathBuf,
+    frames: std::vec::IntoIter<FrameRef>,
+    file: Option<(i64, File)>,
+    records: std::vec::IntoIter<Record>,
+}
+
+impl FrameReader {
+    fn new(dir: PathBuf, frames: Vec<FrameRef>) -> Self {
+        Self {
+            dir,
+            frames: frames.into_iter(),
+            file: None,
+            records: Vec::new().into_iter(),
+        }
+    }
+
+    fn read(&mut self, frame: FrameRef) -> Result<Vec<Record>> {
+        let file = match &mut self.file {
+            Some((segment, file)) if *segment == frame.segment => file,
+            slot => {
+                let file =
+                    File::open(segment_path(&self.dir, frame.segment)).map_err(to_store_error)?;
+                &mut slot.insert((frame.segment, file)).1
+            }
+        };
+        let remaining = file
+            .metadata()
+            .map_err(to_store_error)?
+            .len()
+            .saturating_sub(frame.offset);
+        file.seek(SeekFrom::Start(frame.offset))
+            .map_err(to_store_error)?;
+        let body = read_frame(file, remaining)
+            .map_err(to_store_error)?
+            .ok_or_else(|| Error::Store(format!("missing frame at offset {}", frame.offset)))?;
+        split_body(&body)?
+            .into_iter()
+            .map(|bytes| {
+                let (envelope, payload) = record::decode(bytes)?;
+                Ok(Record {
+                    envelope,
+                    payload: payload.into_owned(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Iterator for FrameReader {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.next() {
+                return Some(Ok(record));
+            }
+            let frame = self.frames.next()?;
+            match self.read(frame) {
+                Ok(records) => self.records = records.into_iter(),
+                Err(e) => {
+                    // Stop after the first error.
+                    self.frames = Vec::new().into_iter();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Closes the active segment and starts a new one at the next position.
+fn roll_segment(dir: &Path, sync_policy: SyncPolicy, state: &mut State) -> Result<()> {
+    if sync_policy != SyncPolicy::OsManaged {
+        state.sync().map_err(to_store_error)?;
+    }
+    let segment = state.next_position;
+    let active = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(segment_path(dir, segment))
+        .map_err(to_store_error)?;
+    // Make the new segment's directory entry durable along with its data.
+    if sync_policy != SyncPolicy::OsManaged {
+        sync_dir(dir);
+    }
+    state.active = active;
+    state.active_segment = segment;
+    state.active_len = 0;
+    state.unsynced = 0;
+    Ok(())
+}
+
+/// Syncs a directory so newly created entries survive a crash, where the
+/// platform supports it.
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// Builds the path of the segment whose first event has position `segment`.
+///
+/// Positions are zero-padded so the segments list in log order.
+fn segment_path(dir: &Path, segment: i64) -> PathBuf {
+    dir.join(format!("{segment:020}.{SEGMENT_EXTENSION}"))
+}
+
+/// Lists the base positions of the segments in `dir`, in ascending order.
+fn list_segments(dir: &Path) -> Result<Vec<i64>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir).map_err(to_store_error)? {
+        let path = entry.map_err(to_store_error)?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(segment) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            segments.push(segment);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// A frame found while scanning a segment on open.
+struct ScannedFrame {
+    aggregate_type: String,
+    stream: String,
+    frame: FrameRef,
+    /// The hash of the last event in the frame.
+    last_hash: Option<String>,
+}
+
+/// Scans a segment from the start, returning every intact frame and the
+/// length up to the end of the last one.
+///
+/// Scanning stops at a clean end of the segment or at the first torn or
+/// corrupt frame.
+fn scan_segment(file: &mut File, segment: i64) -> (Vec<ScannedFrame>, u64) {
+    let file_len = file.metadata().map_or(0, |metadata| metadata.len());
+    let mut reader = io::BufReader::new(file);
+    let mut frames = Vec::new();
+    let mut offset = 0;
+
+    while let Ok(Some(body)) = read_frame(&mut reader, file_len - offset) {
+        let Ok(records) = split_body(&body) else {
+            break;
+        };
+        let Some(Ok(first)) = records.first().map(|bytes| record::decode_envelope(bytes)) else {
+            break;
+        };
+        let Some(Ok(last)) = records.last().map(|bytes| record::decode_envelope(bytes)) else {
+            break;
+        };
+        frames.push(ScannedFrame {
+            aggregate_type: first.aggregate_type.clone(),
+            stream: format!("{}/{}", first.aggregate_type, first.aggregate_id),
+            frame: FrameRef {
+                segment,
+                offset,
+                first_version: first.version,
+                first_position: first.position,
+                count: records.len() as i64,
+            },
+            last_hash: last.hash,
+        });
+        offset += HEADER_LEN + body.len() as u64;
+    }
+    (frames, offset)
+}
+
+/// Returns whether an intact frame starts anywhere after the bad frame at
+/// `offset`.
+///
+/// A torn write only ever leaves a partial frame at the very end of the log,
+/// so events after the bad frame mean the segment was damaged instead.
+///
+/// Only offsets holding a header whose length fits in the rest of the tail,
+/// followed by the start of a record, are checksummed, so scanning a large
+/// torn append does not checksum it again from every byte.
+fn intact_frame_after(file: &mut File, offset: u64) -> Result<bool> {
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::Start(offset))
+        .and_then(|_| file.read_to_end(&mut tail))
+        .map_err(to_store_error)?;
+    Ok((1..tail.len()).any(|start| {
+        let Some((header, rest)) = tail[start..].split_first_chunk::<{ HEADER_LEN as usize }>()
+        else {
+            return false;
+        };
+        let [len, checksum] = split_header(header);
+        let Some(body) = rest.get(..len as usize) else {
+            return false;
+        };
+        // Zeroes left by a crash read as empty frames.
+        let starts_with_record = body.split_first_chunk::<4>().is_some_and(|(len, rest)| {
+            rest.get(..u32::from_le_bytes(*len) as usize)
+                .is_some_and(record::starts_like_record)
+        });
+        starts_with_record
+            && crc32(body) == checksum
+            && split_body(body).is_ok_and(|records| {
+                records
+                    .iter()
+                    .all(|bytes| record::decode_envelope(bytes).is_ok())
+            })
+    }))
+}
+
+/// Encodes records as a frame: a length and checksum header followed by a
+/// body of length-prefixed records.
+fn encode_frame(records: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(records.iter().map(|r| 4 + r.len()).sum());
+    for record in records {
+        let len = u32::try_from(record.len())
+            .map_err(|_| Error::Store(format!("event of {} bytes is too large", record.len())))?;
+        body.extend_from_slice(&len.to_le_bytes());
+        body.extend_from_slice(record);
+    }
+    let len = u32::try_from(body.len())
+        .map_err(|_| Error::Store(format!("append of {} bytes is too large", body.len())))?;
+    let mut frame = Vec::with_capacity(HEADER_LEN as usize + body.len());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(&crc32(&body).to_le_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// Splits a frame body into its records.
+fn split_body(mut body: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut records = Vec::new();
+    while let Some((len, rest)) = body.split_first_chunk::<4>() {
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() < len {
+            break;
+        }
+        let (record, rest) = rest.split_at(len);
+        records.push(record);
+        body = rest;
+    }
+    if !body.is_empty() {
+        return Err(Error::Store("corrupt frame body".to_string()));
+    }
+    Ok(records)
+}
+
+/// Splits a frame header into the body length and checksum.
+fn split_header(header: &[u8; HEADER_LEN as usize]) -> [u32; 2] {
+    [
+        u32::from_le_bytes([header[0], header[1], header[2], header[3]]),
+        u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+    ]
+}
+
+/// Reads the frame at the reader's current offset and returns its body.
+///
+/// `remaining` is the number of bytes left from that offset, so a header
+/// claiming a longer body is rejected before anything is read.
+///
+/// Returns `Ok(None)` at a clean end of file, and an `InvalidData` error for
+/// a torn or corrupt frame.
+fn read_frame(reader: &mut impl Read, remaining: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::Error::new(io::ErrorKind::InvalidData, "torn header")),
+            n => filled += n,
+        }
+    }
+    let [len, checksum] = split_header(&header);
+    if u64::from(len) > remaining.saturating_sub(HEADER_LEN) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "torn frame"));
+    }
+
+    let mut body = Vec::with_capacity(len as usize);
+    reader.take(u64::from(len)).read_to_end(&mut body)?;
+    if body.len() != len as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "torn frame"));
+    }
+    if crc32(&body) != checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame checksum mismatch",
+        ));
+    }
+    Ok(Some(body))
+}
+
+/// Computes the CRC-32 (IEEE) checksum of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        TABLE[((crc ^ u32::from(byte)) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
@@ -0,0 +1,113 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use tracing::instrument;
+
+use crate::{
+    Aggregate, Error, Result,
+    snapshot::{RawSnapshot, SnapshotStore, StoredSnapshot},
+    store::stream_name,
+};
+
+/// A persistent, thread-safe snapshot store keeping one file per aggregate.
+///
+/// Each snapshot is written to a temporary file, synced and then renamed over
+/// the previous one, so a crash leaves either the old or the new snapshot in
+/// place.
+#[derive(Debug)]
+pub struct FileSnapshotStore<A: Aggregate> {
+    dir: PathBuf,
+    _phantom: PhantomData<A>,
+}
+
+impl<A: Aggregate> FileSnapshotStore<A> {
+    /// Opens a `FileSnapshotStore` in `dir`, creating the directory if needed.
+    ///
+    /// It is recommended to use a directory separate from the event log.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| Error::Store(e.to_string()))?;
+        Ok(Self {
+            dir,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Builds the path of an aggregate's snapshot file.
+    fn path(&self, aggregate_id: &A::Id) -> PathBuf {
+        self.dir.join(format!(
+            "{}.json",
+            file_name(&stream_name::<A>(aggregate_id))
+        ))
+    }
+}
+
+#[async_trait]
+impl<A> SnapshotStore<A> for FileSnapshotStore<A>
+where
+    A: Aggregate,
+{
+    #[instrument(skip(self, snapshot), fields(aggregate_id = ?aggregate_id, version))]
+    async fn save(&self, aggregate_id: &A::Id, version: i64, snapshot: A::Snapshot) -> Result<()> {
+        let stored_snapshot = StoredSnapshot::new(aggregate_id.to_string(), version, snapshot);
+        let value =
+            serde_json::to_vec(&stored_snapshot).map_err(|e| Error::Store(e.to_string()))?;
+
+        // An unreadable snapshot file is overwritten.
+        if let Ok(Some(latest)) = self.load_raw(aggregate_id).await
+            && latest.version > version
+        {
+            return Ok(());
+        }
+
+        let path = self.path(aggregate_id);
+        let tmp = path.with_extension("json.tmp");
+        let write = || -> io::Result<()> {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&value)?;
+            file.sync_all()?;
+            fs::rename(&tmp, &path)
+        };
+        write().map_err(|e| Error::Store(e.to_string()))
+    }
+
+    #[instrument(skip(self), fields(aggregate_id = ?aggregate_id))]
+    async fn load(&self, aggregate_id: &A::Id) -> Result<Option<StoredSnapshot<A::Snapshot>>> {
+        self.load_raw(aggregate_id)
+            .await?
+            .map(RawSnapshot::decode)
+            .transpose()
+    }
+
+    #[instrument(skip(self), fields(aggregate_id = ?aggregate_id))]
+    async fn load_raw(&self, aggregate_id: &A::Id) -> Result<Option<RawSnapshot>> {
+        match fs::read(self.path(aggregate_id)) {
+            Ok(value) => {
+                let snapshot =
+                    serde_json::from_slice(&value).map_err(|e| Error::Store(e.to_string()))?;
+                Ok(Some(snapshot))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Store(e.to_string())),
+        }
+    }
+}
+
+/// Escapes a stream name into a portable file name, keeping ASCII letters,
+/// digits, `-` and `_` and percent-encoding every other byte.
+fn file_name(stream: &str) -> String {
+    let mut name = String::with_capacity(stream.len());
+    for byte in stream.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            name.push(char::from(byte));
+        } else {
+            name.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    name
+}
@@ -0,0 +1,25 @@
+//! Deduplication records kept by the stores supporting idempotent appends.
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// How long stores remember an idempotency key unless configured otherwise.
+pub(crate) const DEFAULT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The events appended under an idempotency key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Record {
+    /// When the append was made.
+    pub recorded_at: DateTime<Utc>,
+    /// The global positions of the appended events, in append order.
+    pub positions: Vec<i64>,
+}
+
+/// Returns the time before which records kept for `retention` have expired.
+pub(crate) fn cutoff(now: DateTime<Utc>, retention: Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(retention)
+        .ok()
+        .and_then(|retention| now.checked_sub_signed(retention))
+        .unwrap_or(DateTime::<Utc>::MIN_UTC)
+}
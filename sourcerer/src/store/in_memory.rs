@@ -1,20 +1,44 @@
 //! An in-memory event store, useful for testing and development.
 
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock},
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use serde_json;
+use chrono::{DateTime, Utc};
+use futures::{
+    StreamExt,
+    channel::mpsc,
+    stream::{self, BoxStream},
+};
 use tracing::instrument;
 
-use crate::{Aggregate, Event, EventStore, Result, StoredEvent};
+use crate::{
+    Aggregate, Error, Event, EventMetadata, EventStore, ExpectedVersion, Result, StoredEvent,
+    outbox::{Delivery, Outbox, OutboxMessage},
+    serializer::JsonSerializer,
+    store::{
+        idempotency::{self, Record},
+        stream_name, stream_prefix,
+    },
+    subscription::{self, EventSubscription, Page, SubscriptionFilter},
+    upcaster::RawStoredEvent,
+};
 
 use dashmap::DashMap;
 
 // Type aliases to keep complex generic types readable and satisfy clippy::type-complexity.
 type EventStream<E> = Vec<StoredEvent<E>>;
 
-/// Thread-safe map keyed by aggregate_id
-type StoreMap<E> = DashMap<String, EventStream<E>>;
+/// Thread-safe map keyed by stream name (aggregate type and ID). Each stream
+/// has its own lock, so appends can hold several streams at once.
+type StoreMap<E> = DashMap<String, Arc<Mutex<EventStream<E>>>>;
+
+/// The number of events cloned out of a stream per step when streaming, so
+/// the stream's lock is never held across an await point.
+const CHUNK_SIZE: usize = 256;
 
 /// An in-memory, thread-safe event store.
 ///
@@ -22,12 +46,117 @@ type StoreMap<E> = DashMap<String, EventStream<E>>;
 /// persistent event store.
 pub struct InMemoryEventStore<A: Aggregate> {
     events: Arc<StoreMap<A::Event>>,
+    /// Every stored event across all aggregates, indexed by `position - 1`.
+    log: Arc<RwLock<EventStream<A::Event>>>,
+    /// Live subscriptions, signalled after every append.
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<()>>>>,
+    /// The idempotency keys of recent appends.
+    idempotency_keys: Arc<Mutex<IdempotencyKeys>>,
+    idempotency_retention: Duration,
+    /// The outbox, if enabled.
+    outbox: Option<Arc<Mutex<OutboxQueue>>>,
 }
 
 impl<A: Aggregate> Default for InMemoryEventStore<A> {
     fn default() -> Self {
         Self {
             events: Arc::new(DashMap::new()),
+            log: Arc::new(RwLock::new(Vec::new())),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            idempotency_keys: Arc::new(Mutex::new(IdempotencyKeys::default())),
+            idempotency_retention: idempotency::DEFAULT_RETENTION,
+            outbox: None,
+        }
+    }
+}
+
+impl<A: Aggregate> Clone for InMemoryEventStore<A> {
+    /// Returns a handle sharing the same underlying events.
+    fn clone(&self) -> Self {
+        Self {
+            events: Arc::clone(&self.events),
+            log: Arc::clone(&self.log),
+            subscribers: Arc::clone(&self.subscribers),
+            idempotency_keys: Arc::clone(&self.idempotency_keys),
+            idempotency_retention: self.idempotency_retention,
+            outbox: self.outbox.clone(),
+        }
+    }
+}
+
+impl<A: Aggregate> InMemoryEventStore<A> {
+    /// Sets how long the store remembers the idempotency key of an append.
+    ///
+    /// Defaults to 24 hours.
+    #[must_use]
+    pub fn with_idempotency_retention(mut self, retention: Duration) -> Self {
+        self.idempotency_retention = retention;
+        self
+    }
+
+    /// Sets whether appends queue their events in the store's [`Outbox`].
+    ///
+    /// Disabled by default. Enabling it starts an empty queue.
+    #[must_use]
+    pub fn with_outbox(mut self, enabled: bool) -> Self {
+        self.outbox = enabled.then(|| Arc::new(Mutex::new(OutboxQueue::default())));
+        self
+    }
+
+    /// Reads the events of outbox messages from the log.
+    fn outbox_messages(
+        &self,
+        deliveries: Vec<(i64, Delivery)>,
+    ) -> Result<Vec<OutboxMessage<A::Event>>> {
+        let log = self.log.read().map_err(|e| Error::Store(e.to_string()))?;
+        Ok(deliveries
+            .into_iter()
+            .filter_map(|(position, delivery)| {
+                let event = log.get(usize::try_from(position - 1).ok()?)?;
+                Some(delivery.message(event.clone()))
+            })
+            .collect())
+    }
+
+    /// Streams an aggregate's events after `version`, up to and including
+    /// `until`, in chunks of [`CHUNK_SIZE`].
+    fn stream_chunks(
+        &self,
+        id: &A::Id,
+        version: i64,
+        until: i64,
+    ) -> impl futures::Stream<Item = StoredEvent<A::Event>> + Send + use<A> {
+        let events = Arc::clone(&self.events);
+        let stream_name = stream_name::<A>(id);
+
+        stream::unfold(version, move |cursor| {
+            let chunk: Vec<_> = match events.get(&stream_name) {
+                Some(stream) => {
+                    let stream = lock(&stream);
+                    let start = stream.partition_point(|e| e.version() <= cursor);
+                    stream[start..]
+                        .iter()
+                        .take_while(|e| e.version() <= until)
+                        .take(CHUNK_SIZE)
+                        .cloned()
+                        .collect()
+                }
+                None => Vec::new(),
+            };
+            futures::future::ready(
+                chunk
+                    .last()
+                    .map(StoredEvent::version)
+                    .map(|last| (stream::iter(chunk), last)),
+            )
+        })
+        .flatten()
+    }
+
+    /// Wakes every live subscription, dropping those that have gone away.
+    fn notify_subscribers(&self) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|tx| tx.unbounded_send(()).is_ok());
         }
     }
 }
@@ -37,58 +166,144 @@ impl<A> EventStore<A> for InMemoryEventStore<A>
 where
     A: Aggregate,
 {
-    #[instrument(skip(self, events), fields(id = ?id, expected_version))]
+    #[instrument(skip(self, events, metadata), fields(id = ?id, expected_version))]
     async fn append(
         &self,
         id: &A::Id,
-        expected_version: i64,
+        expected_version: ExpectedVersion,
         events: Vec<A::Event>,
+        metadata: EventMetadata,
     ) -> Result<Vec<StoredEvent<A::Event>>> {
-        let aggregate_id = id.to_string();
-
-        let mut stream = self.events.entry(aggregate_id.clone()).or_default();
-
-        let current_version = stream.last().map(|e| e.version()).unwrap_or(0);
-        if current_version != expected_version {
-            return Err(crate::Error::Conflict);
-        }
-
-        let mut stored_events = Vec::new();
-        let mut version = current_version;
-        for event in events {
-            version += 1;
-            let event_version = event.event_version();
-            let event_type = event.event_type().to_string();
-            let stored_event = StoredEvent::new(
-                aggregate_id.clone(),
-                version,
-                event_version,
-                event_type,
-                event,
-            );
-            stream.push(stored_event.clone());
+        self.append_batch(vec![(id.clone(), expected_version, events)], metadata)
+            .await
+    }
+
+    #[instrument(skip(self, batch, metadata), fields(streams = batch.len()))]
+    async fn append_batch(
+        &self,
+        batch: Vec<(A::Id, ExpectedVersion, Vec<A::Event>)>,
+        metadata: EventMetadata,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        // Lock every stream of the batch in name order, then the global log,
+        // so concurrent appends cannot deadlock and positions follow the
+        // per-stream version order.
+        let names: Vec<String> = batch.iter().map(|(id, ..)| stream_name::<A>(id)).collect();
+        let handles: BTreeMap<&str, _> = names
+            .iter()
+            .map(|name| {
+                let stream = Arc::clone(&self.events.entry(name.clone()).or_default());
+                (name.as_str(), stream)
+            })
+            .collect();
+        let mut streams: BTreeMap<&str, _> = handles
+            .iter()
+            .map(|(name, stream)| (*name, lock(stream)))
+            .collect();
+        let mut log = self.log.write().map_err(|e| Error::Store(e.to_string()))?;
+
+        // A repeated idempotency key returns the events it first appended.
+        let now = Utc::now();
+        let mut idempotency_keys = lock(&self.idempotency_keys);
+        let idempotency_key = metadata.idempotency_key();
+        if let Some(key) = idempotency_key {
+            idempotency_keys.prune(idempotency::cutoff(now, self.idempotency_retention));
+            if let Some(record) = idempotency_keys.records.get(key) {
+                return Ok(record
+                    .positions
+                    .iter()
+                    .filter_map(|&position| log.get(position as usize - 1).cloned())
+                    .collect());
+            }
+        }
+
+        // Stage every event before storing any, so a conflict leaves all
+        // streams untouched.
+        let mut heads: HashMap<&str, (i64, Option<String>)> = streams
+            .iter()
+            .map(|(name, stream)| {
+                let head = stream.last();
+                let version = head.map_or(0, StoredEvent::version);
+                (
+                    *name,
+                    (version, head.and_then(|e| e.hash().map(str::to_owned))),
+                )
+            })
+            .collect();
+        let mut position = log.len() as i64;
+        let mut staged = Vec::new();
+        for ((id, expected_version, events), name) in batch.into_iter().zip(&names) {
+            let Some((version, previous)) = heads.get_mut(name.as_str()) else {
+                continue;
+            };
+            if !expected_version.matches(*version) {
+                return Err(Error::Conflict {
+                    aggregate_id: id.to_string(),
+                    expected: expected_version,
+                    actual: *version,
+                });
+            }
+            for event in events {
+                *version += 1;
+                position += 1;
+                let event_version = event.event_version();
+                let event_type = event.event_type().to_string();
+                let stored_event =
+                    StoredEvent::new(id.to_string(), *version, event_version, event_type, event)
+                        .with_aggregate_type(A::TYPE_NAME)
+                        .with_position(position)
+                        .with_metadata(metadata.stamp(now))
+                        .chained(previous.as_deref())?;
+                *previous = stored_event.hash().map(str::to_owned);
+                staged.push((name.as_str(), stored_event));
+            }
+        }
+
+        let mut stored_events = Vec::with_capacity(staged.len());
+        for (name, stored_event) in staged {
+            if let Some(stream) = streams.get_mut(name) {
+                stream.push(stored_event.clone());
+            }
+            log.push(stored_event.clone());
             stored_events.push(stored_event);
         }
+        if let Some(key) = idempotency_key {
+            idempotency_keys.insert(
+                key.to_owned(),
+                Record {
+                    recorded_at: now,
+                    positions: stored_events.iter().map(StoredEvent::position).collect(),
+                },
+            );
+        }
+        if let Some(outbox) = &self.outbox {
+            let mut outbox = lock(outbox);
+            for stored_event in &stored_events {
+                outbox
+                    .pending
+                    .insert(stored_event.position(), Delivery::new(now));
+            }
+        }
+        drop(idempotency_keys);
+        drop(log);
+        drop(streams);
+
+        self.notify_subscribers();
 
         Ok(stored_events)
     }
 
     #[instrument(skip(self), fields(id = ?id))]
     async fn load(&self, id: &A::Id) -> Result<Vec<StoredEvent<A::Event>>> {
-        let aggregate_id = id.to_string();
-
-        match self.events.get(&aggregate_id) {
-            Some(stream) => Ok(stream.clone()),
+        match self.events.get(&stream_name::<A>(id)) {
+            Some(stream) => Ok(lock(&stream).clone()),
             None => Ok(Vec::new()),
         }
     }
 
     #[instrument(skip(self), fields(id = ?id, version))]
     async fn load_from(&self, id: &A::Id, version: i64) -> Result<Vec<StoredEvent<A::Event>>> {
-        let aggregate_id = id.to_string();
-
-        match self.events.get(&aggregate_id) {
-            Some(stream) => Ok(stream
+        match self.events.get(&stream_name::<A>(id)) {
+            Some(stream) => Ok(lock(&stream)
                 .iter()
                 .filter(|e| e.version() > version)
                 .cloned()
@@ -97,30 +312,262 @@ where
         }
     }
 
-    async fn load_raw(
-        &self,
-        id: &A::Id,
-        version: i64,
-    ) -> Result<Vec<crate::upcaster::RawStoredEvent>> {
-        let aggregate_id = id.to_string();
-
-        match self.events.get(&aggregate_id) {
-            Some(stream) => stream
+    async fn load_raw(&self, id: &A::Id, version: i64) -> Result<Vec<RawStoredEvent>> {
+        match self.events.get(&stream_name::<A>(id)) {
+            Some(stream) => lock(&stream)
                 .iter()
                 .filter(|e| e.version() > version)
-                .map(|e| {
-                    serde_json::to_value(e.event())
-                        .map_err(|se| crate::Error::Store(se.to_string()))
-                        .map(|payload| crate::upcaster::RawStoredEvent {
-                            aggregate_id: e.aggregate_id().to_string(),
-                            version: e.version(),
-                            event_version: e.event_version(),
-                            event_type: e.event_type().to_string(),
-                            payload,
-                        })
-                })
+                .map(to_raw)
                 .collect::<Result<Vec<_>>>(),
             None => Ok(Vec::new()),
         }
     }
+
+    fn stream_from<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<StoredEvent<A::Event>>> {
+        self.stream_chunks(id, version, i64::MAX).map(Ok).boxed()
+    }
+
+    fn stream_raw<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<RawStoredEvent>> {
+        self.stream_chunks(id, version, i64::MAX)
+            .map(|e| to_raw(&e))
+            .boxed()
+    }
+
+    fn stream_raw_range<'a>(
+        &'a self,
+        id: &'a A::Id,
+        after_version: i64,
+        until_version: i64,
+    ) -> BoxStream<'a, Result<RawStoredEvent>> {
+        self.stream_chunks(id, after_version, until_version)
+            .map(|e| to_raw(&e))
+            .boxed()
+    }
+
+    #[instrument(skip(self), fields(id = ?id, %timestamp))]
+    async fn version_as_of(&self, id: &A::Id, timestamp: DateTime<Utc>) -> Result<i64> {
+        Ok(self
+            .events
+            .get(&stream_name::<A>(id))
+            .and_then(|stream| {
+                lock(&stream)
+                    .iter()
+                    .filter(|e| e.metadata().recorded_at() <= timestamp)
+                    .map(StoredEvent::version)
+                    .max()
+            })
+            .unwrap_or(0))
+    }
+
+    #[instrument(skip(self), fields(from_position, limit))]
+    async fn read_all(
+        &self,
+        from_position: i64,
+        limit: usize,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        let log = self
+            .log
+            .read()
+            .map_err(|e| crate::Error::Store(e.to_string()))?;
+
+        // Positions are dense and start at 1, so they map directly onto
+        // indices in the log.
+        let start = usize::try_from(from_position.max(0))
+            .unwrap_or(usize::MAX)
+            .min(log.len());
+        Ok(log[start..].iter().take(limit).cloned().collect())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_aggregate_ids(&self) -> Result<Vec<String>> {
+        let prefix = stream_prefix::<A>();
+        let mut ids: Vec<String> = self
+            .events
+            .iter()
+            .filter(|entry| !lock(entry.value()).is_empty())
+            .filter_map(|entry| entry.key().strip_prefix(&prefix).map(str::to_string))
+            .collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    #[instrument(skip(self, filter), fields(from_position))]
+    async fn subscribe(
+        &self,
+        from_position: i64,
+        filter: SubscriptionFilter,
+    ) -> Result<EventSubscription<A::Event>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers
+            .lock()
+            .map_err(|e| crate::Error::Store(e.to_string()))?
+            .push(tx);
+
+        // The subscription only holds the log, so it ends once every handle
+        // to this store, and with it the sending half, has been dropped.
+        let log = Arc::clone(&self.log);
+        Ok(subscription::catch_up(
+            from_position,
+            move |cursor| futures::future::ready(read_page(&log, cursor, &filter)),
+            rx,
+        ))
+    }
+}
+
+#[async_trait]
+impl<A> Outbox<A::Event> for InMemoryEventStore<A>
+where
+    A: Aggregate,
+{
+    #[instrument(skip(self), fields(%now, limit))]
+    async fn pending(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<OutboxMessage<A::Event>>> {
+        let Some(outbox) = &self.outbox else {
+            return Ok(Vec::new());
+        };
+        let due: Vec<_> = lock(outbox)
+            .pending
+            .iter()
+            .filter(|(_, delivery)| delivery.due_at <= now)
+            .take(limit)
+            .map(|(position, delivery)| (*position, delivery.clone()))
+            .collect();
+        self.outbox_messages(due)
+    }
+
+    #[instrument(skip(self), fields(position))]
+    async fn acknowledge(&self, position: i64) -> Result<()> {
+        if let Some(outbox) = &self.outbox {
+            lock(outbox).pending.remove(&position);
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self), fields(position, %retry_at))]
+    async fn retry_later(&self, position: i64, error: &str, retry_at: DateTime<Utc>) -> Result<()> {
+        if let Some(outbox) = &self.outbox
+            && let Some(delivery) = lock(outbox).pending.get_mut(&position)
+        {
+            delivery.failed(error, retry_at);
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self), fields(position))]
+    async fn dead_letter(&self, position: i64, error: &str) -> Result<()> {
+        if let Some(outbox) = &self.outbox {
+            let mut outbox = lock(outbox);
+            if let Some(mut delivery) = outbox.pending.remove(&position) {
+                delivery.failed(error, delivery.due_at);
+                outbox.dead.insert(position, delivery);
+            }
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self), fields(limit))]
+    async fn dead_letters(&self, limit: usize) -> Result<Vec<OutboxMessage<A::Event>>> {
+        let Some(outbox) = &self.outbox else {
+            return Ok(Vec::new());
+        };
+        let dead: Vec<_> = lock(outbox)
+            .dead
+            .iter()
+            .take(limit)
+            .map(|(position, delivery)| (*position, delivery.clone()))
+            .collect();
+        self.outbox_messages(dead)
+    }
+
+    #[instrument(skip(self), fields(position))]
+    async fn requeue(&self, position: i64) -> Result<()> {
+        if let Some(outbox) = &self.outbox {
+            let mut outbox = lock(outbox);
+            if outbox.dead.remove(&position).is_some() {
+                outbox.pending.insert(position, Delivery::new(Utc::now()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Converts a stored event into its raw form for upcasting.
+fn to_raw<E: Event>(e: &StoredEvent<E>) -> Result<RawStoredEvent> {
+    RawStoredEvent::encode(e, &JsonSerializer)
+}
+
+/// Reads the next page of the global log after `cursor` for a subscription.
+fn read_page<E: Event>(
+    log: &RwLock<EventStream<E>>,
+    cursor: i64,
+    filter: &SubscriptionFilter,
+) -> Result<Page<E>> {
+    let log = log.read().map_err(|e| crate::Error::Store(e.to_string()))?;
+    let start = usize::try_from(cursor.max(0))
+        .unwrap_or(usize::MAX)
+        .min(log.len());
+    let scanned = &log[start..log.len().min(start + subscription::BATCH_SIZE)];
+
+    Ok(Page {
+        events: scanned
+            .iter()
+            .filter(|e| filter.matches(e))
+            .cloned()
+            .collect(),
+        cursor: scanned.last().map_or(cursor, |e| e.position()),
+    })
+}
+
+/// The outbox of an in-memory store, keyed by position.
+#[derive(Default)]
+struct OutboxQueue {
+    pending: BTreeMap<i64, Delivery>,
+    dead: BTreeMap<i64, Delivery>,
+}
+
+/// The idempotency keys remembered by an in-memory store, oldest first.
+#[derive(Default)]
+struct IdempotencyKeys {
+    records: HashMap<String, Record>,
+    order: VecDeque<String>,
+}
+
+impl IdempotencyKeys {
+    /// Forgets the keys recorded before `cutoff`.
+    fn prune(&mut self, cutoff: DateTime<Utc>) {
+        while let Some(key) = self.order.front() {
+            if self
+                .records
+                .get(key)
+                .is_some_and(|record| record.recorded_at >= cutoff)
+            {
+                break;
+            }
+            self.records.remove(key);
+            self.order.pop_front();
+        }
+    }
+
+    /// Remembers a key that is not known yet.
+    fn insert(&mut self, key: String, record: Record) {
+        self.order.push_back(key.clone());
+        self.records.insert(key, record);
+    }
+}
+
+/// Locks a stream, the idempotency keys or the outbox, recovering them if a
+/// writer panicked while holding the lock.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
 }
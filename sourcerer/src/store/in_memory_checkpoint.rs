@@ -0,0 +1,38 @@
+//! An in-memory checkpoint store.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tracing::instrument;
+
+use crate::{Result, projection::CheckpointStore};
+
+use dashmap::DashMap;
+
+/// An in-memory, thread-safe checkpoint store.
+///
+/// This is useful for testing or for projections that are rebuilt on every
+/// start.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryCheckpointStore {
+    checkpoints: Arc<DashMap<String, i64>>,
+}
+
+#[async_trait]
+impl CheckpointStore for InMemoryCheckpointStore {
+    #[instrument(skip(self))]
+    async fn load(&self, projection: &str) -> Result<Option<i64>> {
+        Ok(self.checkpoints.get(projection).map(|r| *r))
+    }
+
+    #[instrument(skip(self))]
+    async fn save(&self, projection: &str, position: i64) -> Result<()> {
+        self.checkpoints.insert(projection.to_string(), position);
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn delete(&self, projection: &str) -> Result<()> {
+        self.checkpoints.remove(projection);
+        Ok(())
+    }
+}
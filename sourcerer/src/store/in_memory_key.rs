@@ -0,0 +1,44 @@
+//! An in-memory encryption key store.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tracing::instrument;
+
+use crate::{
+    Result,
+    encryption::{Key, KeyStore},
+};
+
+use dashmap::DashMap;
+
+/// An in-memory, thread-safe encryption key store.
+///
+/// This is useful for testing; keys are lost when the process exits, which
+/// shreds every value they encrypted.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryKeyStore {
+    keys: Arc<DashMap<String, Key>>,
+}
+
+#[async_trait]
+impl KeyStore for InMemoryKeyStore {
+    #[instrument(skip(self))]
+    async fn load(&self, subject: &str) -> Result<Option<Key>> {
+        Ok(self.keys.get(subject).map(|r| r.clone()))
+    }
+
+    #[instrument(skip(self))]
+    async fn load_or_create(&self, subject: &str) -> Result<Key> {
+        Ok(self
+            .keys
+            .entry(subject.to_string())
+            .or_insert_with(Key::generate)
+            .clone())
+    }
+
+    #[instrument(skip(self))]
+    async fn forget(&self, subject: &str) -> Result<()> {
+        self.keys.remove(subject);
+        Ok(())
+    }
+}
@@ -6,7 +6,8 @@ use tracing::instrument;
 
 use crate::{
     Aggregate, Result,
-    snapshot::{SnapshotStore, StoredSnapshot},
+    snapshot::{RawSnapshot, SnapshotRetention, SnapshotStore, StoredSnapshot},
+    store::stream_name,
 };
 
 use dashmap::DashMap;
@@ -14,20 +15,32 @@ use dashmap::DashMap;
 /// An in-memory, thread-safe snapshot store.
 ///
 /// This is useful for testing or for applications that do not require a
-/// persistent snapshot store.
+/// persistent snapshot store. Each aggregate's snapshots are kept in version
+/// order, pruned by the store's [`SnapshotRetention`].
 #[derive(Debug)]
 pub struct InMemorySnapshotStore<A: Aggregate> {
-    snapshots: Arc<DashMap<String, StoredSnapshot<A::Snapshot>>>,
+    snapshots: Arc<DashMap<String, Vec<StoredSnapshot<A::Snapshot>>>>,
+    retention: SnapshotRetention,
 }
 
 impl<A: Aggregate> Default for InMemorySnapshotStore<A> {
     fn default() -> Self {
         Self {
             snapshots: Arc::new(DashMap::new()),
+            retention: SnapshotRetention::default(),
         }
     }
 }
 
+impl<A: Aggregate> InMemorySnapshotStore<A> {
+    /// Sets which snapshots of each aggregate the store keeps.
+    #[must_use]
+    pub fn with_retention(mut self, retention: SnapshotRetention) -> Self {
+        self.retention = retention;
+        self
+    }
+}
+
 #[async_trait]
 impl<A> SnapshotStore<A> for InMemorySnapshotStore<A>
 where
@@ -36,8 +49,22 @@ where
     #[instrument(skip(self, snapshot), fields(aggregate_id = ?aggregate_id, version))]
     async fn save(&self, aggregate_id: &A::Id, version: i64, snapshot: A::Snapshot) -> Result<()> {
         let stored_snapshot = StoredSnapshot::new(aggregate_id.to_string(), version, snapshot);
-        self.snapshots
-            .insert(aggregate_id.to_string(), stored_snapshot);
+        let mut history = self
+            .snapshots
+            .entry(stream_name::<A>(aggregate_id))
+            .or_default();
+        match history.last().map(StoredSnapshot::version) {
+            Some(latest) if latest > version => return Ok(()),
+            Some(latest) if latest == version => {
+                history.pop();
+            }
+            _ => {}
+        }
+        history.push(stored_snapshot);
+
+        let versions: Vec<_> = history.iter().map(StoredSnapshot::version).collect();
+        let pruned = self.retention.pruned(&versions);
+        history.retain(|snapshot| !pruned.contains(&snapshot.version()));
         Ok(())
     }
 
@@ -45,7 +72,38 @@ where
     async fn load(&self, aggregate_id: &A::Id) -> Result<Option<StoredSnapshot<A::Snapshot>>> {
         Ok(self
             .snapshots
-            .get(&aggregate_id.to_string())
-            .map(|r| r.clone()))
+            .get(&stream_name::<A>(aggregate_id))
+            .and_then(|history| history.last().cloned()))
+    }
+
+    #[instrument(skip(self), fields(aggregate_id = ?aggregate_id, version))]
+    async fn load_at_or_before(
+        &self,
+        aggregate_id: &A::Id,
+        version: i64,
+    ) -> Result<Option<StoredSnapshot<A::Snapshot>>> {
+        Ok(self
+            .snapshots
+            .get(&stream_name::<A>(aggregate_id))
+            .and_then(|history| {
+                history
+                    .iter()
+                    .rev()
+                    .find(|snapshot| snapshot.version() <= version)
+                    .cloned()
+            }))
+    }
+
+    #[instrument(skip(self), fields(aggregate_id = ?aggregate_id, version))]
+    async fn load_raw_at_or_before(
+        &self,
+        aggregate_id: &A::Id,
+        version: i64,
+    ) -> Result<Option<RawSnapshot>> {
+        self.load_at_or_before(aggregate_id, version)
+            .await?
+            .as_ref()
+            .map(RawSnapshot::encode)
+            .transpose()
     }
 }
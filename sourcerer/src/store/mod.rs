@@ -1,6 +1,47 @@
 //! The store module contains the implementations of the event and snapshot
 //! stores.
 
+use crate::Aggregate;
+
+/// Builds the name under which a stream or snapshot is stored, namespaced by
+/// the aggregate type.
+pub(crate) fn stream_name<A: Aggregate>(id: &A::Id) -> String {
+    format!("{}{id}", stream_prefix::<A>())
+}
+
+/// Returns the prefix shared by the stream names of every aggregate of type
+/// `A`.
+///
+/// A type name containing the separator could make the streams of two
+/// aggregate types share names, so it fails the build.
+pub(crate) fn stream_prefix<A: Aggregate>() -> String {
+    const {
+        assert!(
+            !contains_separator(A::TYPE_NAME),
+            "Aggregate::TYPE_NAME must not contain '/'"
+        );
+    }
+    format!("{}/", A::TYPE_NAME)
+}
+
+/// Returns whether `type_name` contains the stream name separator.
+const fn contains_separator(type_name: &str) -> bool {
+    let bytes = type_name.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'/' {
+            return true;
+        }
+        i += 1;
+    }
+    false
+}
+
+mod idempotency;
+
+#[cfg(any(feature = "sled-storage", feature = "file-storage"))]
+mod record;
+
 // The in-memory implementations are compiled when the `in-memory` feature is
 // enabled (this is the default).
 #[cfg(feature = "in-memory")]
@@ -11,6 +52,14 @@ pub mod in_memory;
 /// An in-memory snapshot store.
 pub mod in_memory_snapshot;
 
+#[cfg(feature = "in-memory")]
+/// An in-memory projection checkpoint store.
+pub mod in_memory_checkpoint;
+
+#[cfg(all(feature = "in-memory", feature = "encryption"))]
+/// An in-memory encryption key store.
+pub mod in_memory_key;
+
 // The persistent `sled` implementations are compiled when the `sled-storage`
 // feature is enabled.
 #[cfg(feature = "sled-storage")]
@@ -21,7 +70,30 @@ pub mod sled;
 /// A persistent snapshot store using `sled`.
 pub mod sled_snapshot;
 
+#[cfg(feature = "sled-storage")]
+/// A persistent projection checkpoint store using `sled`.
+pub mod sled_checkpoint;
+
+#[cfg(all(feature = "sled-storage", feature = "encryption"))]
+/// A persistent encryption key store using `sled`.
+pub mod sled_key;
+
+// The persistent file implementations are compiled when the `file-storage`
+// feature is enabled.
+#[cfg(feature = "file-storage")]
+/// A persistent event store using append-only segment files.
+pub mod file;
+
+#[cfg(feature = "file-storage")]
+/// A persistent snapshot store using one file per aggregate.
+pub mod file_snapshot;
+
 // SQLx / Postgres implementation compiled when the `postgres-storage` feature
 // is enabled.
 #[cfg(feature = "postgres-storage")]
 pub mod sqlx_postgres;
+
+// SQLx / SQLite implementation compiled when the `sqlite-storage` feature is
+// enabled.
+#[cfg(feature = "sqlite-storage")]
+pub mod sqlx_sqlite;
@@ -0,0 +1,170 @@
+//! The binary layout of events in the embedded stores.
+//!
+//! A record is the event's envelope as JSON, followed by its payload in the
+//! format, and with the compression, named by the envelope:
+//!
+//! ```text
+//! [envelope length: u32 LE][envelope: JSON][payload]
+//! ```
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error, Event, EventMetadata, Result, StoredEvent,
+    compression::{Codec, Compression},
+    serializer::{EventSerializer, Format},
+    upcaster::RawStoredEvent,
+};
+
+/// Everything recorded about an event but its payload.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    pub aggregate_type: String,
+    pub aggregate_id: String,
+    pub version: i64,
+    pub event_version: u16,
+    pub event_type: String,
+    pub format: Format,
+    /// Omitted for uncompressed payloads.
+    #[serde(default, skip_serializing_if = "Codec::is_none")]
+    pub compression: Codec,
+    pub position: i64,
+    #[serde(default)]
+    pub metadata: EventMetadata,
+    /// Omitted for events stored before hashes were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl Envelope {
+    /// Converts the envelope and its payload into a raw stored event.
+    pub fn into_raw(self, payload: Vec<u8>) -> RawStoredEvent {
+        RawStoredEvent {
+            aggregate_type: self.aggregate_type,
+            aggregate_id: self.aggregate_id,
+            version: self.version,
+            event_version: self.event_version,
+            event_type: self.event_type,
+            format: self.format,
+            payload,
+            position: self.position,
+            metadata: self.metadata,
+            hash: self.hash,
+        }
+    }
+
+    /// Decodes the payload and converts the envelope into a stored event.
+    pub fn into_stored<E: Event, S: EventSerializer>(
+        self,
+        payload: &[u8],
+        serializer: &S,
+    ) -> Result<StoredEvent<E>> {
+        let event: E = serializer.decode(self.format, payload)?;
+        Ok(StoredEvent::new(
+            self.aggregate_id,
+            self.version,
+            self.event_version,
+            self.event_type,
+            event,
+        )
+        .with_aggregate_type(self.aggregate_type)
+        .with_position(self.position)
+        .with_metadata(self.metadata)
+        .with_recorded_hash(self.hash))
+    }
+}
+
+/// Encodes a stored event as a record, with its payload written by
+/// `serializer` and then compressed per `compression`.
+pub(crate) fn encode<E: Event, S: EventSerializer>(
+    stored: &StoredEvent<E>,
+    serializer: &S,
+    compression: &Compression,
+) -> Result<Vec<u8>> {
+    let (codec, payload) = compression.compress(serializer.serialize(stored.event())?)?;
+    let envelope = Envelope {
+        aggregate_type: stored.aggregate_type().to_string(),
+        aggregate_id: stored.aggregate_id().to_string(),
+        version: stored.version(),
+        event_version: stored.event_version(),
+        event_type: stored.event_type().to_string(),
+        format: serializer.format(),
+        compression: codec,
+        position: stored.position(),
+        metadata: stored.metadata().clone(),
+        hash: stored.hash().map(str::to_owned),
+    };
+    assemble(&envelope, &payload)
+}
+
+/// Encodes a raw event as a record, with its payload stored as is.
+#[cfg(feature = "sled-storage")]
+pub(crate) fn encode_raw(event: RawStoredEvent) -> Result<Vec<u8>> {
+    let envelope = Envelope {
+        aggregate_type: event.aggregate_type,
+        aggregate_id: event.aggregate_id,
+        version: event.version,
+        event_version: event.event_version,
+        event_type: event.event_type,
+        format: event.format,
+        compression: Codec::None,
+        position: event.position,
+        metadata: event.metadata,
+        hash: event.hash,
+    };
+    assemble(&envelope, &event.payload)
+}
+
+/// Lays out an envelope and its payload as a record.
+fn assemble(envelope: &Envelope, payload: &[u8]) -> Result<Vec<u8>> {
+    let envelope = serde_json::to_vec(envelope).map_err(|e| Error::Store(e.to_string()))?;
+
+    let len = u32::try_from(envelope.len())
+        .map_err(|_| Error::Store("event envelope is too large".to_string()))?;
+    let mut record = Vec::with_capacity(4 + envelope.len() + payload.len());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&envelope);
+    record.extend_from_slice(payload);
+    Ok(record)
+}
+
+/// Returns whether `bytes` start the way every record does: with the length
+/// of an envelope that fits, and the envelope's first field.
+///
+/// This is a cheap filter for where a record may start, not a check that it
+/// decodes.
+#[cfg(feature = "file-storage")]
+pub(crate) fn starts_like_record(bytes: &[u8]) -> bool {
+    bytes.split_first_chunk::<4>().is_some_and(|(len, rest)| {
+        u32::from_le_bytes(*len) as usize <= rest.len()
+            && rest.starts_with(br#"{"aggregate_type":"#)
+    })
+}
+
+/// Splits a record into its envelope and decompressed payload.
+pub(crate) fn decode(record: &[u8]) -> Result<(Envelope, Cow<'_, [u8]>)> {
+    let (mut envelope, payload) = split(record)?;
+    let payload = envelope.compression.decompress(payload)?;
+    // The payload is handed out decompressed.
+    envelope.compression = Codec::None;
+    Ok((envelope, payload))
+}
+
+/// Decodes only the envelope of a record.
+pub(crate) fn decode_envelope(record: &[u8]) -> Result<Envelope> {
+    split(record).map(|(envelope, _)| envelope)
+}
+
+/// Splits a record into its envelope and the payload as stored.
+fn split(record: &[u8]) -> Result<(Envelope, &[u8])> {
+    let corrupt = || Error::Store("corrupt event record".to_string());
+    let (len, rest) = record.split_first_chunk::<4>().ok_or_else(corrupt)?;
+    let len = u32::from_le_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(corrupt());
+    }
+    let (envelope, payload) = rest.split_at(len);
+    let envelope = serde_json::from_slice(envelope).map_err(|e| Error::Store(e.to_string()))?;
+    Ok((envelope, payload))
+}
@@ -1,161 +1,1117 @@
 //! A persistent `EventStore` and `SnapshotStore` implementation using `sled`.
 
-use std::marker::PhantomData;
+use std::{
+    marker::PhantomData,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use serde_json;
+use chrono::{DateTime, Utc};
+use futures::{
+    StreamExt,
+    channel::mpsc,
+    stream::{self, BoxStream},
+};
+use serde::Deserialize;
+use sled::{
+    Transactional,
+    transaction::{ConflictableTransactionError, TransactionError},
+};
 use tracing::instrument;
 
-use crate::{Aggregate, Error, Event, EventStore, Result, StoredEvent};
+use crate::{
+    Aggregate, Error, Event, EventMetadata, EventStore, ExpectedVersion, Result, StoredEvent,
+    compression::Compression,
+    outbox::{Delivery, Outbox, OutboxMessage},
+    serializer::{EventSerializer, Format, JsonSerializer},
+    store::{
+        idempotency::{self, Record},
+        record, stream_name, stream_prefix,
+    },
+    subscription::{self, EventSubscription, Page, SubscriptionFilter},
+    upcaster::RawStoredEvent,
+};
+
+/// Prefix of the trees holding every event of an aggregate type, keyed by its
+/// big-endian global position.
+const GLOBAL_TREE: &str = "__sourcerer_global";
+
+/// Name of the tree holding the last assigned global position.
+const SEQUENCE_TREE: &str = "__sourcerer_sequence";
+
+/// Key under which the last assigned global position is stored.
+const SEQUENCE_KEY: &[u8] = b"position";
+
+/// Prefix of the trees holding the idempotency records of an aggregate type,
+/// keyed by idempotency key.
+const IDEMPOTENCY_TREE: &str = "__sourcerer_idempotency";
+
+/// Prefix of the trees indexing the idempotency keys of an aggregate type by
+/// the big-endian time they were recorded at, followed by the key.
+const IDEMPOTENCY_EXPIRY_TREE: &str = "__sourcerer_idempotency_expiry";
+
+/// Prefix of the trees holding the pending outbox messages of an aggregate
+/// type, keyed by big-endian global position.
+const OUTBOX_TREE: &str = "__sourcerer_outbox";
+
+/// Prefix of the trees holding the dead-lettered outbox messages of an
+/// aggregate type, keyed by big-endian global position.
+const DEAD_LETTER_TREE: &str = "__sourcerer_dead_letters";
+
+/// Name of the tree recording that the database holds no streams in the
+/// layout used before streams were named after their aggregate type.
+const LAYOUT_TREE: &str = "__sourcerer_layout";
+
+/// Key under which the layout of the streams is recorded.
+const LAYOUT_KEY: &[u8] = b"typed_streams";
+
+/// A stream of an append batch, with the version it expects and the events
+/// appended to it.
+type BatchEntry<A> = (
+    <A as Aggregate>::Id,
+    ExpectedVersion,
+    Vec<<A as Aggregate>::Event>,
+);
 
 /// A persistent, thread-safe event store using `sled`.
 ///
 /// This store uses a `sled::Tree` to store events, which is an ordered
 /// key-value store. This allows for efficient scanning of event streams.
-#[derive(Clone)]
-pub struct SledEventStore<A: Aggregate> {
+/// Each stream gets its own tree named after the aggregate type and ID, so
+/// several aggregate types can share one database.
+///
+/// Event payloads are encoded by `S`, JSON by default, and optionally
+/// compressed; the rest of each event is always stored as JSON.
+///
+/// Databases written by earlier versions kept each stream in a tree named
+/// after the aggregate ID alone. The store refuses to read or append while
+/// any such stream remains; assign them to their aggregate type with
+/// [`SledEventStore::adopt_untyped_streams`].
+pub struct SledEventStore<A: Aggregate, S: EventSerializer = JsonSerializer> {
     db: sled::Db,
+    serializer: S,
+    compression: Compression,
+    idempotency_retention: Duration,
+    outbox: bool,
+    layout_checked: Arc<AtomicBool>,
     _phantom: PhantomData<A>,
 }
 
+impl<A: Aggregate, S: EventSerializer> Clone for SledEventStore<A, S> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            serializer: self.serializer.clone(),
+            compression: self.compression,
+            idempotency_retention: self.idempotency_retention,
+            outbox: self.outbox,
+            layout_checked: self.layout_checked.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
 impl<A: Aggregate> SledEventStore<A> {
-    /// Creates a new `SledEventStore`.
+    /// Creates a new `SledEventStore` writing JSON payloads.
     pub fn new(db: sled::Db) -> Self {
         Self {
             db,
+            serializer: JsonSerializer,
+            compression: Compression::none(),
+            idempotency_retention: idempotency::DEFAULT_RETENTION,
+            outbox: false,
+            layout_checked: Arc::default(),
             _phantom: PhantomData,
         }
     }
 }
 
+impl<A: Aggregate, S: EventSerializer> SledEventStore<A, S> {
+    /// Sets the serializer used to encode the payloads of appended events.
+    ///
+    /// Events already stored keep the format they were written in.
+    pub fn with_serializer<T: EventSerializer>(self, serializer: T) -> SledEventStore<A, T> {
+        SledEventStore {
+            db: self.db,
+            serializer,
+            compression: self.compression,
+            idempotency_retention: self.idempotency_retention,
+            outbox: self.outbox,
+            layout_checked: self.layout_checked,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Sets the compression applied to the payloads of appended events.
+    ///
+    /// Events already stored keep the compression they were written with.
+    #[must_use]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets how long the store remembers the idempotency key of an append.
+    ///
+    /// Defaults to 24 hours. Expired keys are removed by later appends with
+    /// an idempotency key.
+    #[must_use]
+    pub fn with_idempotency_retention(mut self, retention: Duration) -> Self {
+        self.idempotency_retention = retention;
+        self
+    }
+
+    /// Sets whether appends queue their events in the store's [`Outbox`], in
+    /// the same transaction as the events themselves.
+    ///
+    /// Disabled by default. Messages already queued stay in the outbox either
+    /// way.
+    #[must_use]
+    pub fn with_outbox(mut self, enabled: bool) -> Self {
+        self.outbox = enabled;
+        self
+    }
+
+    /// Fails if the database holds streams in the layout used before streams
+    /// were named after their aggregate type.
+    ///
+    /// The database is scanned once, after which the result is recorded in
+    /// it and remembered by the store.
+    fn check_layout(&self) -> Result<()> {
+        if self.layout_checked.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let layout = self
+            .db
+            .open_tree(LAYOUT_TREE)
+            .map_err(|e| Error::Store(e.to_string()))?;
+        if !layout
+            .contains_key(LAYOUT_KEY)
+            .map_err(|e| Error::Store(e.to_string()))?
+        {
+            if let Some(name) = untyped_streams(&self.db)?.first() {
+                return Err(Error::Store(format!(
+                    "the database holds streams, such as `{name}`, stored before streams were \
+                     named after their aggregate type; assign them with \
+                     `SledEventStore::adopt_untyped_streams`"
+                )));
+            }
+            layout
+                .insert(LAYOUT_KEY, &[])
+                .map_err(|e| Error::Store(e.to_string()))?;
+        }
+        self.layout_checked.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Assigns the streams stored before streams were named after their
+    /// aggregate type to `A`, if `owns` accepts their aggregate ID, and
+    /// returns how many it assigned.
+    ///
+    /// Run this on the store of every aggregate type the database held
+    /// before. The streams of a database used by a single aggregate type are
+    /// all adopted with `|_| true`. Events keep the global position they
+    /// were recorded at, if any, and are otherwise given the next ones.
+    ///
+    /// Fails if a stream was also appended to under its aggregate type.
+    #[instrument(skip(self, owns))]
+    pub async fn adopt_untyped_streams(&self, owns: impl Fn(&str) -> bool) -> Result<usize> {
+        let open = |name: &str| {
+            self.db
+                .open_tree(name)
+                .map_err(|e| Error::Store(e.to_string()))
+        };
+        let global = open(&format!("{GLOBAL_TREE}/{}", A::TYPE_NAME))?;
+        let sequence = open(SEQUENCE_TREE)?;
+        let mut adopted = 0;
+        for aggregate_id in untyped_streams(&self.db)? {
+            if !owns(&aggregate_id) {
+                continue;
+            }
+            let untyped = open(&aggregate_id)?;
+            let stream = open(&format!("{}{aggregate_id}", stream_prefix::<A>()))?;
+            if !stream.is_empty() {
+                return Err(Error::Store(format!(
+                    "stream `{aggregate_id}` is stored both with and without its aggregate type"
+                )));
+            }
+            let mut events = Vec::new();
+            for entry in &untyped {
+                let (key, value) = entry.map_err(|e| Error::Store(e.to_string()))?;
+                let event: UntypedEvent =
+                    serde_json::from_slice(&value).map_err(|e| Error::Store(e.to_string()))?;
+                events.push((key, event));
+            }
+            events.sort_by_key(|(_, event)| event.version);
+
+            // The untyped tree is emptied in the same transaction, so an
+            // interrupted adoption is not mistaken for a stream stored in
+            // both layouts.
+            (&global, &sequence, &stream, &untyped)
+                .transaction(|(tx_global, tx_sequence, tx_stream, tx_untyped)| {
+                    let mut last = match tx_sequence.get(SEQUENCE_KEY)? {
+                        Some(v) => decode_position(&v),
+                        None => 0,
+                    };
+                    for (key, event) in &events {
+                        let position = if event.position > 0 {
+                            last = last.max(event.position);
+                            event.position
+                        } else {
+                            last += 1;
+                            last
+                        };
+                        let value = event
+                            .clone()
+                            .into_raw(A::TYPE_NAME, position)
+                            .and_then(record::encode_raw)
+                            .map_err(failed)?;
+                        let key_in_stream = stream_key(&aggregate_id, event.version);
+                        tx_stream.insert(key_in_stream.as_bytes(), value.as_slice())?;
+                        tx_global.insert(&position.to_be_bytes(), value)?;
+                        tx_untyped.remove(key)?;
+                    }
+                    tx_sequence.insert(SEQUENCE_KEY, &last.to_be_bytes())?;
+                    Ok(())
+                })
+                .map_err(|e| match e {
+                    TransactionError::Abort(Aborted::Failed(e)) => e,
+                    TransactionError::Abort(_) => Error::Store("adoption aborted".into()),
+                    TransactionError::Storage(e) => Error::Store(e.to_string()),
+                })?;
+            self.db
+                .drop_tree(&aggregate_id)
+                .map_err(|e| Error::Store(e.to_string()))?;
+            adopted += 1;
+        }
+
+        // The global log shared by every stream before aggregate types were
+        // recorded goes once the last of them is adopted.
+        if untyped_streams(&self.db)?.is_empty() {
+            self.db
+                .drop_tree(GLOBAL_TREE)
+                .map_err(|e| Error::Store(e.to_string()))?;
+            open(LAYOUT_TREE)?
+                .insert(LAYOUT_KEY, &[])
+                .map_err(|e| Error::Store(e.to_string()))?;
+            self.layout_checked.store(true, Ordering::Release);
+        }
+        Ok(adopted)
+    }
+
+    /// Opens the tree holding an aggregate's stream.
+    fn stream_tree(&self, id: &A::Id) -> Result<sled::Tree> {
+        self.check_layout()?;
+        self.db
+            .open_tree(stream_name::<A>(id))
+            .map_err(|e| Error::Store(e.to_string()))
+    }
+
+    /// Opens the tree holding the global log of aggregates of type `A`.
+    fn global_tree(&self) -> Result<sled::Tree> {
+        self.check_layout()?;
+        self.db
+            .open_tree(format!("{GLOBAL_TREE}/{}", A::TYPE_NAME))
+            .map_err(|e| Error::Store(e.to_string()))
+    }
+
+    /// Opens the trees holding the idempotency records of aggregates of type
+    /// `A` and indexing them by time.
+    fn idempotency_trees(&self) -> Result<(sled::Tree, sled::Tree)> {
+        let open = |name: &str| {
+            self.db
+                .open_tree(format!("{name}/{}", A::TYPE_NAME))
+                .map_err(|e| Error::Store(e.to_string()))
+        };
+        Ok((open(IDEMPOTENCY_TREE)?, open(IDEMPOTENCY_EXPIRY_TREE)?))
+    }
+
+    /// Removes the idempotency records made before `cutoff`.
+    fn prune_idempotency_keys(
+        &self,
+        keys: &sled::Tree,
+        expiry: &sled::Tree,
+        cutoff: DateTime<Utc>,
+    ) -> Result<()> {
+        let end = cutoff.timestamp_micros().to_be_bytes();
+        for entry in expiry.range(..end) {
+            let (expiry_key, key) = entry.map_err(|e| Error::Store(e.to_string()))?;
+            // The key may have been recorded again since.
+            if let Some(record) = keys.get(&key).map_err(|e| Error::Store(e.to_string()))?
+                && decode_record(&record)?.recorded_at < cutoff
+            {
+                keys.remove(&key).map_err(|e| Error::Store(e.to_string()))?;
+            }
+            expiry
+                .remove(expiry_key)
+                .map_err(|e| Error::Store(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Opens the trees holding the pending and dead-lettered outbox messages
+    /// of aggregates of type `A`.
+    fn outbox_trees(&self) -> Result<(sled::Tree, sled::Tree)> {
+        let open = |name: &str| {
+            self.db
+                .open_tree(format!("{name}/{}", A::TYPE_NAME))
+                .map_err(|e| Error::Store(e.to_string()))
+        };
+        Ok((open(OUTBOX_TREE)?, open(DEAD_LETTER_TREE)?))
+    }
+
+    /// Reads up to `limit` outbox messages from `tree` whose delivery state
+    /// passes `filter`, in position order.
+    fn outbox_messages(
+        &self,
+        tree: &sled::Tree,
+        limit: usize,
+        filter: impl Fn(&Delivery) -> bool,
+    ) -> Result<Vec<OutboxMessage<A::Event>>> {
+        let global = self.global_tree()?;
+        let mut messages = Vec::new();
+        for entry in tree {
+            if messages.len() >= limit {
+                break;
+            }
+            let (position, delivery) = entry.map_err(|e| Error::Store(e.to_string()))?;
+            let delivery: Delivery =
+                serde_json::from_slice(&delivery).map_err(|e| Error::Store(e.to_string()))?;
+            if !filter(&delivery) {
+                continue;
+            }
+            if let Some(v) = global
+                .get(position)
+                .map_err(|e| Error::Store(e.to_string()))?
+            {
+                messages.push(delivery.message(decode_stored(&v, &self.serializer)?));
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Stages a batch on top of the current head of each stream and commits
+    /// it, returning `None` if it must be staged again because a stream
+    /// appended to with [`ExpectedVersion::Any`] moved in between.
+    fn try_append_batch(
+        &self,
+        batch: &[BatchEntry<A>],
+        metadata: &EventMetadata,
+        now: DateTime<Utc>,
+        keys: &sled::Tree,
+        expiry: &sled::Tree,
+    ) -> Result<Option<Vec<StoredEvent<A::Event>>>> {
+        let idempotency_key = metadata.idempotency_key();
+
+        // The streams of the batch, in order of first appearance, with the
+        // version each was read at.
+        let mut streams: Vec<Stream> = Vec::new();
+        let mut events_to_commit = Vec::new();
+
+        for (id, expected_version, events) in batch {
+            let expected_version = *expected_version;
+            let aggregate_id = id.to_string();
+            let index = match streams.iter().position(|s| s.aggregate_id == aggregate_id) {
+                Some(index) => index,
+                None => {
+                    let tree = self.stream_tree(id)?;
+                    let (version, hash) = head(&tree)?;
+                    streams.push(Stream {
+                        aggregate_id: aggregate_id.clone(),
+                        tree,
+                        expected_version,
+                        rebase: true,
+                        read_version: version,
+                        version,
+                        hash,
+                    });
+                    streams.len() - 1
+                }
+            };
+            let stream = &mut streams[index];
+            stream.rebase &= expected_version == ExpectedVersion::Any;
+            if !expected_version.matches(stream.version) {
+                return Err(Error::Conflict {
+                    aggregate_id,
+                    expected: expected_version,
+                    actual: stream.version,
+                });
+            }
+
+            for event in events.iter().cloned() {
+                stream.version += 1;
+                let stored_event = StoredEvent::new(
+                    aggregate_id.clone(),
+                    stream.version,
+                    event.event_version(),
+                    event.event_type().to_string(),
+                    event,
+                )
+                .with_aggregate_type(A::TYPE_NAME)
+                .with_metadata(metadata.stamp(now))
+                .chained(stream.hash.as_deref())?;
+                stream.hash = stored_event.hash().map(str::to_owned);
+                let key = stream_key(&aggregate_id, stream.version);
+                events_to_commit.push((index, key, stored_event));
+            }
+        }
+
+        // The transaction spans the global log, the sequence, the idempotency
+        // trees, the outbox and every stream of the batch, in that order.
+        let mut trees = vec![
+            self.global_tree()?,
+            self.db
+                .open_tree(SEQUENCE_TREE)
+                .map_err(|e| Error::Store(e.to_string()))?,
+            keys.clone(),
+            expiry.clone(),
+            self.outbox_trees()?.0,
+        ];
+        trees.extend(streams.iter().map(|s| s.tree.clone()));
+
+        let outbox_delivery =
+            serde_json::to_vec(&Delivery::new(now)).map_err(|e| Error::Store(e.to_string()))?;
+
+        // Global positions are allocated inside the same transaction as the
+        // stream writes, so the global log is gap-free and follows commit
+        // order.
+        let stored_events = trees.as_slice().transaction(|trees| {
+            let [
+                tx_global,
+                tx_sequence,
+                tx_keys,
+                tx_expiry,
+                tx_outbox,
+                tx_streams @ ..,
+            ] = &trees[..]
+            else {
+                unreachable!("the transaction spans at least five trees");
+            };
+            // Another writer may have used the key since it was checked.
+            if let Some(key) = idempotency_key
+                && let Some(record) = tx_keys.get(key)?
+            {
+                let record = decode_record(&record).map_err(failed)?;
+                return Err(ConflictableTransactionError::Abort(Aborted::Duplicate(
+                    record,
+                )));
+            }
+            // Another writer may have appended since the version check.
+            for (stream, tx_tree) in streams.iter().zip(tx_streams) {
+                let next_key = stream_key(&stream.aggregate_id, stream.read_version + 1);
+                if tx_tree.get(next_key.as_bytes())?.is_some() {
+                    return Err(ConflictableTransactionError::Abort(Aborted::Conflict));
+                }
+            }
+            let mut position = match tx_sequence.get(SEQUENCE_KEY)? {
+                Some(v) => decode_position(&v),
+                None => 0,
+            };
+            let mut stored_events = Vec::with_capacity(events_to_commit.len());
+            for (index, key, stored_event) in &events_to_commit {
+                position += 1;
+                let stored_event = stored_event.clone().with_position(position);
+                let value = record::encode(&stored_event, &self.serializer, &self.compression)
+                    .map_err(failed)?;
+                tx_streams[*index].insert(key.as_bytes(), value.as_slice())?;
+                tx_global.insert(&position.to_be_bytes(), value)?;
+                if self.outbox {
+                    tx_outbox.insert(&position.to_be_bytes(), outbox_delivery.as_slice())?;
+                }
+                stored_events.push(stored_event);
+            }
+            tx_sequence.insert(SEQUENCE_KEY, &position.to_be_bytes())?;
+            if let Some(key) = idempotency_key {
+                let record = Record {
+                    recorded_at: now,
+                    positions: stored_events.iter().map(StoredEvent::position).collect(),
+                };
+                let record =
+                    serde_json::to_vec(&record).map_err(|e| failed(Error::Store(e.to_string())))?;
+                tx_keys.insert(key.as_bytes(), record)?;
+                tx_expiry.insert(expiry_key(now, key), key.as_bytes())?;
+            }
+            Ok(stored_events)
+        });
+
+        match stored_events {
+            Ok(stored_events) => Ok(Some(stored_events)),
+            Err(TransactionError::Abort(Aborted::Duplicate(record))) => {
+                self.events_at(&record.positions).map(Some)
+            }
+            Err(TransactionError::Abort(Aborted::Conflict)) => match conflict(&streams) {
+                Some(e) => Err(e),
+                None => Ok(None),
+            },
+            Err(TransactionError::Abort(Aborted::Failed(e))) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(Error::Store(e.to_string())),
+        }
+    }
+
+    /// Reads the events at the given global positions.
+    fn events_at(&self, positions: &[i64]) -> Result<Vec<StoredEvent<A::Event>>> {
+        let global = self.global_tree()?;
+        positions
+            .iter()
+            .filter_map(|position| global.get(position.to_be_bytes()).transpose())
+            .map(|res| {
+                let v = res.map_err(|e| Error::Store(e.to_string()))?;
+                decode_stored(&v, &self.serializer)
+            })
+            .collect()
+    }
+
+    /// Lazily iterates over an aggregate's events after `version`.
+    fn events_after(
+        &self,
+        id: &A::Id,
+        version: i64,
+    ) -> Result<impl Iterator<Item = Result<StoredEvent<A::Event>>> + Send + use<A, S>> {
+        let aggregate_id = id.to_string();
+        let tree = self.stream_tree(id)?;
+        let start_key = stream_key(&aggregate_id, version + 1);
+        let serializer = self.serializer.clone();
+
+        Ok(tree.range(start_key.as_bytes()..).map(move |res| {
+            let (_, v) = res.map_err(|e| Error::Store(e.to_string()))?;
+            decode_stored(&v, &serializer)
+        }))
+    }
+
+    /// Lazily iterates over an aggregate's raw events after `version`, up to
+    /// and including `until`.
+    fn raw_events_between(
+        &self,
+        id: &A::Id,
+        version: i64,
+        until: i64,
+    ) -> Result<impl Iterator<Item = Result<RawStoredEvent>> + Send + use<A, S>> {
+        let aggregate_id = id.to_string();
+        let tree = self.stream_tree(id)?;
+        let start_key = stream_key(&aggregate_id, version + 1);
+        let end_key = stream_key(&aggregate_id, until.max(version).saturating_add(1));
+
+        Ok(tree.range(start_key..end_key).map(|res| {
+            let (_, v) = res.map_err(|e| Error::Store(e.to_string()))?;
+            let (envelope, payload) = record::decode(&v)?;
+            Ok(envelope.into_raw(payload.into_owned()))
+        }))
+    }
+}
+
 #[async_trait]
-impl<A> EventStore<A> for SledEventStore<A>
+impl<A, S> EventStore<A> for SledEventStore<A, S>
 where
     A: Aggregate,
+    S: EventSerializer,
 {
-    #[instrument(skip(self, events), fields(id = ?id, expected_version))]
+    #[instrument(skip(self, events, metadata), fields(id = ?id, expected_version))]
     async fn append(
         &self,
         id: &A::Id,
-        expected_version: i64,
+        expected_version: ExpectedVersion,
         events: Vec<A::Event>,
+        metadata: EventMetadata,
     ) -> Result<Vec<StoredEvent<A::Event>>> {
-        let aggregate_id = id.to_string();
-        let tree = self
-            .db
-            .open_tree(aggregate_id.as_bytes())
-            .map_err(|e| Error::Store(e.to_string()))?;
+        self.append_batch(vec![(id.clone(), expected_version, events)], metadata)
+            .await
+    }
 
-        let current_version = match tree.last() {
-            Ok(Some((_, v))) => {
-                let e: StoredEvent<A::Event> =
-                    serde_json::from_slice(&v).map_err(|e| Error::Store(e.to_string()))?;
-                e.version()
+    #[instrument(skip(self, batch, metadata), fields(streams = batch.len()))]
+    async fn append_batch(
+        &self,
+        batch: Vec<(A::Id, ExpectedVersion, Vec<A::Event>)>,
+        metadata: EventMetadata,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        // A repeated idempotency key returns the events it first appended.
+        let now = Utc::now();
+        let idempotency_key = metadata.idempotency_key();
+        let (keys, expiry) = self.idempotency_trees()?;
+        if let Some(key) = idempotency_key {
+            let cutoff = idempotency::cutoff(now, self.idempotency_retention);
+            self.prune_idempotency_keys(&keys, &expiry, cutoff)?;
+            if let Some(record) = keys.get(key).map_err(|e| Error::Store(e.to_string()))? {
+                return self.events_at(&decode_record(&record)?.positions);
             }
-            _ => 0,
-        };
-
-        if current_version != expected_version {
-            return Err(crate::Error::Conflict);
         }
 
-        let event_types: Vec<String> = events.iter().map(|e| e.event_type().to_string()).collect();
-        let num_events = events.len();
-
-        let mut stored_events = Vec::new();
-        let mut events_to_commit = Vec::new();
-
-        for (event, (version, event_type)) in events.into_iter().zip(
-            (1..=num_events as i64)
-                .map(|i| expected_version + i)
-                .zip(event_types.into_iter()),
-        ) {
-            let stored_event = StoredEvent::new(
-                aggregate_id.clone(),
-                version,
-                event.event_version(),
-                event_type,
-                event,
-            );
-            let value =
-                serde_json::to_vec(&stored_event).map_err(|e| Error::Store(e.to_string()))?;
-            stored_events.push(stored_event.clone());
-            let key = format!("{aggregate_id}/{version}");
-            events_to_commit.push((key, value));
-        }
-
-        tree.transaction(|tx| {
-            for (key, value) in &events_to_commit {
-                tx.insert(key.as_bytes(), value.as_slice())?;
+        // Streams appended to with `ExpectedVersion::Any` that another writer
+        // moved meanwhile are staged again on top of their new head.
+        loop {
+            if let Some(stored_events) =
+                self.try_append_batch(&batch, &metadata, now, &keys, &expiry)?
+            {
+                return Ok(stored_events);
             }
-            Ok(())
-        })
-        .map_err(|e: sled::transaction::TransactionError| Error::Store(e.to_string()))?;
-
-        Ok(stored_events)
+        }
     }
 
     #[instrument(skip(self), fields(id = ?id))]
     async fn load(&self, id: &A::Id) -> Result<Vec<StoredEvent<A::Event>>> {
         let aggregate_id = id.to_string();
-        let tree = self
-            .db
-            .open_tree(aggregate_id.as_bytes())
-            .map_err(|e| Error::Store(e.to_string()))?;
+        let tree = self.stream_tree(id)?;
         let prefix = format!("{aggregate_id}/");
 
         tree.scan_prefix(prefix.as_bytes())
             .map(|res| {
                 let (_, v) = res.map_err(|e| Error::Store(e.to_string()))?;
-                serde_json::from_slice(&v).map_err(|e| Error::Store(e.to_string()))
+                decode_stored(&v, &self.serializer)
             })
             .collect()
     }
 
     #[instrument(skip(self), fields(id = ?id, version))]
     async fn load_from(&self, id: &A::Id, version: i64) -> Result<Vec<StoredEvent<A::Event>>> {
-        let aggregate_id = id.to_string();
-        let tree = self
-            .db
-            .open_tree(aggregate_id.as_bytes())
-            .map_err(|e| Error::Store(e.to_string()))?;
-        let start_key = format!("{aggregate_id}/{}", version + 1);
+        self.events_after(id, version)?.collect()
+    }
+
+    async fn load_raw(
+        &self,
+        id: &<A as Aggregate>::Id,
+        version: i64,
+    ) -> Result<Vec<RawStoredEvent>> {
+        self.raw_events_between(id, version, i64::MAX)?.collect()
+    }
+
+    fn stream_from<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<StoredEvent<A::Event>>> {
+        match self.events_after(id, version) {
+            Ok(events) => stream::iter(events).boxed(),
+            Err(e) => stream::once(futures::future::ready(Err(e))).boxed(),
+        }
+    }
+
+    fn stream_raw<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<RawStoredEvent>> {
+        match self.raw_events_between(id, version, i64::MAX) {
+            Ok(events) => stream::iter(events).boxed(),
+            Err(e) => stream::once(futures::future::ready(Err(e))).boxed(),
+        }
+    }
+
+    fn stream_raw_range<'a>(
+        &'a self,
+        id: &'a A::Id,
+        after_version: i64,
+        until_version: i64,
+    ) -> BoxStream<'a, Result<RawStoredEvent>> {
+        match self.raw_events_between(id, after_version, until_version) {
+            Ok(events) => stream::iter(events).boxed(),
+            Err(e) => stream::once(futures::future::ready(Err(e))).boxed(),
+        }
+    }
+
+    #[instrument(skip(self), fields(id = ?id, %timestamp))]
+    async fn version_as_of(&self, id: &A::Id, timestamp: DateTime<Utc>) -> Result<i64> {
+        // Only the envelopes are decoded, never the payloads.
+        let mut version = 0;
+        for entry in self.stream_tree(id)?.iter().values() {
+            let entry = entry.map_err(|e| Error::Store(e.to_string()))?;
+            let envelope = record::decode_envelope(&entry)?;
+            if envelope.metadata.recorded_at() <= timestamp {
+                version = version.max(envelope.version);
+            }
+        }
+        Ok(version)
+    }
+
+    #[instrument(skip(self), fields(from_position, limit))]
+    async fn read_all(
+        &self,
+        from_position: i64,
+        limit: usize,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        let global = self.global_tree()?;
+        let start_key = (from_position.max(0) + 1).to_be_bytes();
 
-        tree.range(start_key.as_bytes()..)
+        global
+            .range(start_key..)
+            .take(limit)
             .map(|res| {
                 let (_, v) = res.map_err(|e| Error::Store(e.to_string()))?;
-                serde_json::from_slice(&v).map_err(|e| Error::Store(e.to_string()))
+                decode_stored(&v, &self.serializer)
             })
             .collect()
     }
 
-    async fn load_raw(
-        &self,
-        id: &<A as Aggregate>::Id,
-        version: i64,
-    ) -> Result<Vec<crate::upcaster::RawStoredEvent>> {
-        let aggregate_id = id.to_string();
-        let tree = self
-            .db
-            .open_tree(aggregate_id.as_bytes())
-            .map_err(|e| Error::Store(e.to_string()))?;
-        let start_key = format!("{aggregate_id}/{}", version + 1);
+    #[instrument(skip(self), fields(from_position, limit))]
+    async fn read_all_raw(&self, from_position: i64, limit: usize) -> Result<Vec<RawStoredEvent>> {
+        let global = self.global_tree()?;
+        let start_key = (from_position.max(0) + 1).to_be_bytes();
 
-        tree.range(start_key.as_bytes()..)
+        global
+            .range(start_key..)
+            .take(limit)
             .map(|res| {
                 let (_, v) = res.map_err(|e| Error::Store(e.to_string()))?;
-                let stored: StoredEvent<A::Event> =
-                    serde_json::from_slice(&v).map_err(|e| Error::Store(e.to_string()))?;
-                let payload = serde_json::to_value(stored.event())
-                    .map_err(|e| Error::Store(e.to_string()))?;
-                Ok(crate::upcaster::RawStoredEvent {
-                    aggregate_id: stored.aggregate_id().to_string(),
-                    version: stored.version(),
-                    event_version: stored.event_version(),
-                    event_type: stored.event_type().to_string(),
-                    payload,
-                })
+                let (envelope, payload) = record::decode(&v)?;
+                Ok(envelope.into_raw(payload.into_owned()))
             })
             .collect()
     }
+
+    #[instrument(skip(self))]
+    async fn list_aggregate_ids(&self) -> Result<Vec<String>> {
+        self.check_layout()?;
+        let prefix = stream_prefix::<A>();
+        let mut ids = Vec::new();
+        for name in self.db.tree_names() {
+            let Some(id) = std::str::from_utf8(&name)
+                .ok()
+                .and_then(|name| name.strip_prefix(&prefix))
+            else {
+                continue;
+            };
+            // Reads of unknown IDs leave empty trees behind.
+            let tree = self
+                .db
+                .open_tree(&name)
+                .map_err(|e| Error::Store(e.to_string()))?;
+            if !tree.is_empty() {
+                ids.push(id.to_string());
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    #[instrument(skip(self, filter), fields(from_position))]
+    async fn subscribe(
+        &self,
+        from_position: i64,
+        filter: SubscriptionFilter,
+    ) -> Result<EventSubscription<A::Event>> {
+        let global = self.global_tree()?;
+        let sequence = self
+            .db
+            .open_tree(SEQUENCE_TREE)
+            .map_err(|e| Error::Store(e.to_string()))?;
+
+        let wakeup = watch_appends(&sequence)?;
+        let serializer = self.serializer.clone();
+        Ok(subscription::catch_up(
+            from_position,
+            move |cursor| futures::future::ready(read_page(&global, &serializer, cursor, &filter)),
+            wakeup,
+        ))
+    }
+}
+
+/// Builds the key of an event within its aggregate's tree.
+///
+/// Versions are zero-padded so that the lexicographic key order sled iterates
+/// in matches the numeric version order.
+fn stream_key(aggregate_id: &str, version: i64) -> String {
+    format!("{aggregate_id}/{version:020}")
+}
+
+/// Lists the trees holding a stream in the layout used before streams were
+/// named after their aggregate type: named after the aggregate ID alone, with
+/// keys made of that ID and a version.
+fn untyped_streams(db: &sled::Db) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for name in db.tree_names() {
+        let Ok(name) = String::from_utf8(name.to_vec()) else {
+            continue;
+        };
+        if name.starts_with("__sourcerer") || name.starts_with("__sled") {
+            continue;
+        }
+        let tree = db
+            .open_tree(&name)
+            .map_err(|e| Error::Store(e.to_string()))?;
+        let Some((key, _)) = tree.first().map_err(|e| Error::Store(e.to_string()))? else {
+            continue;
+        };
+        let untyped = key
+            .strip_prefix(format!("{name}/").as_bytes())
+            .is_some_and(|version| !version.is_empty() && version.iter().all(u8::is_ascii_digit));
+        if untyped {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// An event as stored before streams were named after their aggregate type.
+#[derive(Clone, Deserialize)]
+struct UntypedEvent {
+    aggregate_id: String,
+    version: i64,
+    event_version: u16,
+    event_type: String,
+    event: serde_json::Value,
+    /// Absent for events stored before the global log.
+    #[serde(default)]
+    position: i64,
+    /// Absent for events stored before metadata was recorded.
+    #[serde(default)]
+    metadata: EventMetadata,
+}
+
+impl UntypedEvent {
+    /// Converts the event into a raw event of the given aggregate type, at
+    /// the given global position.
+    fn into_raw(self, aggregate_type: &str, position: i64) -> Result<RawStoredEvent> {
+        Ok(RawStoredEvent {
+            aggregate_type: aggregate_type.to_string(),
+            aggregate_id: self.aggregate_id,
+            version: self.version,
+            event_version: self.event_version,
+            event_type: self.event_type,
+            format: Format::Json,
+            payload: serde_json::to_vec(&self.event).map_err(|e| Error::Store(e.to_string()))?,
+            position,
+            metadata: self.metadata,
+            hash: None,
+        })
+    }
+}
+
+/// A stream written by an append.
+struct Stream {
+    aggregate_id: String,
+    tree: sled::Tree,
+    /// The version the first entry of the batch for this stream expected.
+    expected_version: ExpectedVersion,
+    /// Whether every entry of the batch for this stream expected
+    /// [`ExpectedVersion::Any`], so it can be staged again on top of a
+    /// concurrent append.
+    rebase: bool,
+    /// The version of the stream when it was read.
+    read_version: i64,
+    /// The version of the stream once the events staged so far are written.
+    version: i64,
+    /// The hash of the last event staged or stored in the stream.
+    hash: Option<String>,
+}
+
+#[async_trait]
+impl<A, S> Outbox<A::Event> for SledEventStore<A, S>
+where
+    A: Aggregate,
+    S: EventSerializer,
+{
+    #[instrument(skip(self), fields(%now, limit))]
+    async fn pending(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<OutboxMessage<A::Event>>> {
+        let (outbox, _) = self.outbox_trees()?;
+        self.outbox_messages(&outbox, limit, |delivery| delivery.due_at <= now)
+    }
+
+    #[instrument(skip(self), fields(position))]
+    async fn acknowledge(&self, position: i64) -> Result<()> {
+        let (outbox, _) = self.outbox_trees()?;
+        outbox
+            .remove(position.to_be_bytes())
+            .map_err(|e| Error::Store(e.to_string()))?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), fields(position, %retry_at))]
+    async fn retry_later(&self, position: i64, error: &str, retry_at: DateTime<Utc>) -> Result<()> {
+        let (outbox, _) = self.outbox_trees()?;
+        let key = position.to_be_bytes();
+        let Some(delivery) = outbox.get(key).map_err(|e| Error::Store(e.to_string()))? else {
+            return Ok(());
+        };
+        let mut delivery: Delivery =
+            serde_json::from_slice(&delivery).map_err(|e| Error::Store(e.to_string()))?;
+        delivery.failed(error, retry_at);
+        let delivery = serde_json::to_vec(&delivery).map_err(|e| Error::Store(e.to_string()))?;
+        outbox
+            .insert(key, delivery)
+            .map_err(|e| Error::Store(e.to_string()))?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), fields(position))]
+    async fn dead_letter(&self, position: i64, error: &str) -> Result<()> {
+        let (outbox, dead_letters) = self.outbox_trees()?;
+        move_delivery(&outbox, &dead_letters, position, |delivery| {
+            delivery.failed(error, delivery.due_at);
+        })
+    }
+
+    #[instrument(skip(self), fields(limit))]
+    async fn dead_letters(&self, limit: usize) -> Result<Vec<OutboxMessage<A::Event>>> {
+        let (_, dead_letters) = self.outbox_trees()?;
+        self.outbox_messages(&dead_letters, limit, |_| true)
+    }
+
+    #[instrument(skip(self), fields(position))]
+    async fn requeue(&self, position: i64) -> Result<()> {
+        let (outbox, dead_letters) = self.outbox_trees()?;
+        move_delivery(&dead_letters, &outbox, position, |delivery| {
+            *delivery = Delivery::new(Utc::now());
+        })
+    }
+}
+
+/// Moves the outbox message at `position` from one tree to another, updating
+/// its delivery state, atomically. A message not in `from` is left alone.
+fn move_delivery(
+    from: &sled::Tree,
+    to: &sled::Tree,
+    position: i64,
+    update: impl Fn(&mut Delivery),
+) -> Result<()> {
+    let key = position.to_be_bytes();
+    (from, to)
+        .transaction(|(tx_from, tx_to)| {
+            let Some(delivery) = tx_from.remove(&key)? else {
+                return Ok(());
+            };
+            let mut delivery: Delivery = serde_json::from_slice(&delivery)
+                .map_err(|e| failed(Error::Store(e.to_string())))?;
+            update(&mut delivery);
+            let delivery =
+                serde_json::to_vec(&delivery).map_err(|e| failed(Error::Store(e.to_string())))?;
+            tx_to.insert(&key, delivery)?;
+            Ok(())
+        })
+        .map_err(|e| match e {
+            TransactionError::Abort(Aborted::Failed(e)) => e,
+            TransactionError::Abort(_) => Error::Store("outbox update aborted".into()),
+            TransactionError::Storage(e) => Error::Store(e.to_string()),
+        })
+}
+
+/// Why an append transaction was aborted.
+enum Aborted {
+    /// A stream was appended to since it was read.
+    Conflict,
+    /// The idempotency key was recorded since it was checked.
+    Duplicate(Record),
+    /// The append failed.
+    Failed(Error),
+}
+
+/// Aborts an append transaction with an error.
+fn failed(e: Error) -> ConflictableTransactionError<Aborted> {
+    ConflictableTransactionError::Abort(Aborted::Failed(e))
+}
+
+/// Builds the key indexing an idempotency key by the time it was recorded.
+fn expiry_key(recorded_at: DateTime<Utc>, key: &str) -> Vec<u8> {
+    let mut expiry_key = recorded_at.timestamp_micros().to_be_bytes().to_vec();
+    expiry_key.extend_from_slice(key.as_bytes());
+    expiry_key
+}
+
+/// Decodes an idempotency record.
+fn decode_record(bytes: &[u8]) -> Result<Record> {
+    serde_json::from_slice(bytes).map_err(|e| Error::Store(e.to_string()))
+}
+
+/// Builds the error for a batch that lost a race with another writer,
+/// naming the first stream that moved since it was read, or returns `None`
+/// if every stream that moved can be rebased onto its new head.
+fn conflict(streams: &[Stream]) -> Option<Error> {
+    let mut moved = false;
+    for stream in streams {
+        match head(&stream.tree) {
+            Ok((actual, _)) if actual != stream.read_version => {
+                if !stream.rebase {
+                    return Some(Error::Conflict {
+                        aggregate_id: stream.aggregate_id.clone(),
+                        expected: stream.expected_version,
+                        actual,
+                    });
+                }
+                moved = true;
+            }
+            Ok(_) => {}
+            Err(e) => return Some(e),
+        }
+    }
+    (!moved).then(|| Error::Store("append conflicted with a concurrent write".into()))
+}
+
+/// Reads the version and hash of the last event in an aggregate's tree, or
+/// `0` and no hash for a new stream.
+fn head(tree: &sled::Tree) -> Result<(i64, Option<String>)> {
+    match tree.last().map_err(|e| Error::Store(e.to_string()))? {
+        Some((_, v)) => {
+            let envelope = record::decode_envelope(&v)?;
+            Ok((envelope.version, envelope.hash))
+        }
+        None => Ok((0, None)),
+    }
+}
+
+/// Decodes a record into a stored event.
+fn decode_stored<E: Event, S: EventSerializer>(
+    value: &[u8],
+    serializer: &S,
+) -> Result<StoredEvent<E>> {
+    let (envelope, payload) = record::decode(value)?;
+    envelope.into_stored(&payload, serializer)
+}
+
+/// Reads the next page of the global tree after `cursor` for a subscription.
+fn read_page<E: Event, S: EventSerializer>(
+    global: &sled::Tree,
+    serializer: &S,
+    cursor: i64,
+    filter: &SubscriptionFilter,
+) -> Result<Page<E>> {
+    let start_key = (cursor.max(0) + 1).to_be_bytes();
+    let mut page = Page {
+        events: Vec::new(),
+        cursor,
+    };
+
+    for res in global.range(start_key..).take(subscription::BATCH_SIZE) {
+        let (_, v) = res.map_err(|e| Error::Store(e.to_string()))?;
+        let stored: StoredEvent<E> = decode_stored(&v, serializer)?;
+        page.cursor = stored.position();
+        if filter.matches(&stored) {
+            page.events.push(stored);
+        }
+    }
+    Ok(page)
+}
+
+/// Forwards sled's notifications for appends onto an async channel.
+///
+/// sled stalls writers once a watcher falls too far behind, so a dedicated
+/// thread drains the watcher eagerly. The sequence key is written once per
+/// append, which keeps notifications to one per transaction. The thread exits
+/// on the first append after the receiving half has been dropped.
+fn watch_appends(sequence: &sled::Tree) -> Result<mpsc::UnboundedReceiver<()>> {
+    let subscriber = sequence.watch_prefix(SEQUENCE_KEY);
+    let (tx, rx) = mpsc::unbounded();
+
+    std::thread::Builder::new()
+        .name("sourcerer-sled-watch".to_string())
+        .spawn(move || {
+            for _ in subscriber {
+                if tx.unbounded_send(()).is_err() {
+                    break;
+                }
+            }
+        })
+        .map_err(|e| Error::Store(e.to_string()))?;
+    Ok(rx)
+}
+
+/// Decodes a big-endian global position as written by `append`.
+fn decode_position(bytes: &[u8]) -> i64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    i64::from_be_bytes(buf)
 }
@@ -0,0 +1,62 @@
+use async_trait::async_trait;
+use sled::Tree;
+use tracing::instrument;
+
+use crate::{Error, Result, projection::CheckpointStore};
+
+/// A persistent, thread-safe checkpoint store using `sled`.
+///
+/// Each projection's checkpoint is stored as a big-endian integer under a key
+/// corresponding to its name.
+#[derive(Debug, Clone)]
+pub struct SledCheckpointStore {
+    tree: Tree,
+}
+
+impl SledCheckpointStore {
+    /// Creates a new `SledCheckpointStore`.
+    ///
+    /// It is recommended to open a dedicated `sled::Tree` for checkpoints,
+    /// separate from the ones used for events and snapshots.
+    pub fn new(tree: Tree) -> Self {
+        Self { tree }
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for SledCheckpointStore {
+    #[instrument(skip(self))]
+    async fn load(&self, projection: &str) -> Result<Option<i64>> {
+        let result = self
+            .tree
+            .get(projection)
+            .map_err(|e| Error::Store(e.to_string()))?;
+
+        match result {
+            Some(value) => {
+                let bytes: [u8; 8] = value
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| Error::Store(format!("corrupt checkpoint for {projection}")))?;
+                Ok(Some(i64::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn save(&self, projection: &str, position: i64) -> Result<()> {
+        self.tree
+            .insert(projection, &position.to_be_bytes())
+            .map_err(|e| Error::Store(e.to_string()))?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn delete(&self, projection: &str) -> Result<()> {
+        self.tree
+            .remove(projection)
+            .map_err(|e| Error::Store(e.to_string()))?;
+        Ok(())
+    }
+}
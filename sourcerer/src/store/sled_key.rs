@@ -0,0 +1,68 @@
+use async_trait::async_trait;
+use sled::Tree;
+use tracing::instrument;
+
+use crate::{
+    Error, Result,
+    encryption::{Key, KeyStore},
+};
+
+/// A persistent, thread-safe encryption key store using `sled`.
+///
+/// Each subject's key is stored as raw bytes under a key corresponding to the
+/// subject. Forgetting a key removes it from the tree, but, as with any
+/// deletion in `sled`, older copies may linger on disk until the log is
+/// compacted.
+#[derive(Debug, Clone)]
+pub struct SledKeyStore {
+    tree: Tree,
+}
+
+impl SledKeyStore {
+    /// Creates a new `SledKeyStore`.
+    ///
+    /// It is recommended to open a dedicated `sled::Tree` for keys, separate
+    /// from the ones used for events and snapshots.
+    pub fn new(tree: Tree) -> Self {
+        Self { tree }
+    }
+}
+
+#[async_trait]
+impl KeyStore for SledKeyStore {
+    #[instrument(skip(self))]
+    async fn load(&self, subject: &str) -> Result<Option<Key>> {
+        let result = self
+            .tree
+            .get(subject)
+            .map_err(|e| Error::Store(e.to_string()))?;
+        result
+            .map(|value| Key::try_from(value.as_ref()))
+            .transpose()
+    }
+
+    #[instrument(skip(self))]
+    async fn load_or_create(&self, subject: &str) -> Result<Key> {
+        let key = Key::generate();
+        // Only the first of several concurrent callers stores its key.
+        match self
+            .tree
+            .compare_and_swap(subject, None as Option<&[u8]>, Some(&key.as_bytes()[..]))
+            .map_err(|e| Error::Store(e.to_string()))?
+        {
+            Ok(()) => Ok(key),
+            Err(existing) => match existing.current {
+                Some(value) => Key::try_from(value.as_ref()),
+                None => Err(Error::Store(format!("key of {subject} vanished"))),
+            },
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn forget(&self, subject: &str) -> Result<()> {
+        self.tree
+            .remove(subject)
+            .map_err(|e| Error::Store(e.to_string()))?;
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use std::marker::PhantomData;
+use std::{borrow::Cow, marker::PhantomData};
 
 use async_trait::async_trait;
 use sled::Tree;
@@ -6,17 +6,31 @@ use tracing::instrument;
 
 use crate::{
     Aggregate, Error, Result,
-    snapshot::{SnapshotStore, StoredSnapshot},
+    compression::{Codec, Compression},
+    snapshot::{RawSnapshot, SnapshotRetention, SnapshotStore, StoredSnapshot},
+    store::{stream_name, stream_prefix},
 };
 
 /// A persistent, thread-safe snapshot store using `sled`.
 ///
 /// This store uses a `sled::Tree` to store snapshots, which is an ordered
 /// key-value store. Each aggregate's snapshot is stored under a key
-/// corresponding to its ID.
+/// corresponding to its type and ID.
+///
+/// Snapshots are stored as JSON. Compressed snapshots are prefixed with a
+/// byte naming their codec, which a JSON object never starts with.
+///
+/// Older snapshots kept by the store's [`SnapshotRetention`] move to keys
+/// made of the aggregate's key, a zero byte and their big-endian version.
+///
+/// Snapshots saved by earlier versions were keyed by the aggregate ID alone.
+/// They are ignored until assigned to their aggregate type with
+/// [`SledSnapshotStore::adopt_untyped_snapshots`].
 #[derive(Debug)]
 pub struct SledSnapshotStore<A: Aggregate> {
     tree: Tree,
+    compression: Compression,
+    retention: SnapshotRetention,
     _phantom: PhantomData<A>,
 }
 
@@ -28,9 +42,112 @@ impl<A: Aggregate> SledSnapshotStore<A> {
     pub fn new(tree: Tree) -> Self {
         Self {
             tree,
+            compression: Compression::none(),
+            retention: SnapshotRetention::default(),
             _phantom: PhantomData,
         }
     }
+
+    /// Sets the compression applied to saved snapshots.
+    #[must_use]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets which snapshots of each aggregate the store keeps.
+    #[must_use]
+    pub fn with_retention(mut self, retention: SnapshotRetention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Assigns the snapshots saved before snapshots were keyed by aggregate
+    /// type to `A`, if `owns` accepts their aggregate ID, and returns how
+    /// many it assigned.
+    ///
+    /// A snapshot `A` already has for the aggregate is kept, and the untyped
+    /// one dropped.
+    #[instrument(skip(self, owns))]
+    pub async fn adopt_untyped_snapshots(&self, owns: impl Fn(&str) -> bool) -> Result<usize> {
+        let mut adopted = 0;
+        for entry in &self.tree {
+            let (key, value) = entry.map_err(|e| Error::Store(e.to_string()))?;
+            // Untyped snapshots are keyed by the ID they record.
+            let Ok(aggregate_id) = std::str::from_utf8(&key) else {
+                continue;
+            };
+            if !decode(&value).is_ok_and(|raw| raw.aggregate_id == aggregate_id)
+                || !owns(aggregate_id)
+            {
+                continue;
+            }
+            let typed = format!("{}{aggregate_id}", stream_prefix::<A>());
+            let swapped = self
+                .tree
+                .compare_and_swap(typed, None as Option<&[u8]>, Some(value))
+                .map_err(|e| Error::Store(e.to_string()))?;
+            self.tree
+                .remove(&key)
+                .map_err(|e| Error::Store(e.to_string()))?;
+            if swapped.is_ok() {
+                adopted += 1;
+            }
+        }
+        Ok(adopted)
+    }
+
+    /// Moves the snapshot replaced by a newer one into the history, then
+    /// prunes the history.
+    fn archive(&self, key: &str, replaced: Option<(i64, sled::IVec)>, latest: i64) -> Result<()> {
+        if !self.retention.keeps_history() {
+            return Ok(());
+        }
+        if let Some((version, value)) = replaced {
+            self.tree
+                .insert(history_key(key, version), value)
+                .map_err(|e| Error::Store(e.to_string()))?;
+        }
+
+        let mut versions = Vec::new();
+        for entry in self.tree.scan_prefix(history_prefix(key)).keys() {
+            let entry = entry.map_err(|e| Error::Store(e.to_string()))?;
+            if let Ok(version) = entry[key.len() + 1..].try_into() {
+                versions.push(i64::from_be_bytes(version));
+            }
+        }
+        versions.push(latest);
+        for version in self.retention.pruned(&versions) {
+            self.tree
+                .remove(history_key(key, version))
+                .map_err(|e| Error::Store(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds the prefix of the keys of an aggregate's older snapshots.
+fn history_prefix(key: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(key.len() + 1);
+    prefix.extend_from_slice(key.as_bytes());
+    prefix.push(0);
+    prefix
+}
+
+/// Builds the key of an aggregate's older snapshot at `version`.
+fn history_key(key: &str, version: i64) -> Vec<u8> {
+    let mut history_key = history_prefix(key);
+    history_key.extend_from_slice(&version.to_be_bytes());
+    history_key
+}
+
+/// Reads a stored snapshot, decompressing it if needed.
+fn decode(value: &[u8]) -> Result<RawSnapshot> {
+    let value = match value.split_first() {
+        Some((&tag, compressed)) if tag != b'{' => Codec::from_tag(tag)?.decompress(compressed)?,
+        _ => Cow::Borrowed(value),
+    };
+    serde_json::from_slice(&value).map_err(|e| Error::Store(e.to_string()))
 }
 
 #[async_trait]
@@ -43,27 +160,91 @@ where
         let stored_snapshot = StoredSnapshot::new(aggregate_id.to_string(), version, snapshot);
         let value =
             serde_json::to_vec(&stored_snapshot).map_err(|e| Error::Store(e.to_string()))?;
-        self.tree
-            .insert(aggregate_id.to_string().as_bytes(), value)
-            .map_err(|e| Error::Store(e.to_string()))?;
-        Ok(())
+        let value = match self.compression.compress(value)? {
+            (Codec::None, value) => value,
+            (codec, compressed) => {
+                let mut value = Vec::with_capacity(1 + compressed.len());
+                value.push(codec.tag());
+                value.extend_from_slice(&compressed);
+                value
+            }
+        };
+
+        // Swap the new snapshot in unless a newer one was saved meanwhile.
+        let key = stream_name::<A>(aggregate_id);
+        let replaced = loop {
+            let current = self
+                .tree
+                .get(&key)
+                .map_err(|e| Error::Store(e.to_string()))?;
+            // An unreadable snapshot is overwritten.
+            let current_version = current
+                .as_ref()
+                .and_then(|current| decode(current).ok())
+                .map(|raw| raw.version);
+            if current_version.is_some_and(|current| current > version) {
+                return Ok(());
+            }
+            let swapped = self
+                .tree
+                .compare_and_swap(&key, current.clone(), Some(value.clone()))
+                .map_err(|e| Error::Store(e.to_string()))?;
+            if swapped.is_ok() {
+                break current_version.zip(current);
+            }
+        };
+        self.archive(&key, replaced.filter(|(v, _)| *v < version), version)
     }
 
     #[instrument(skip(self), fields(aggregate_id = ?aggregate_id))]
     async fn load(&self, aggregate_id: &A::Id) -> Result<Option<StoredSnapshot<A::Snapshot>>> {
-        let key = aggregate_id.to_string();
+        self.load_raw(aggregate_id)
+            .await?
+            .map(RawSnapshot::decode)
+            .transpose()
+    }
+
+    #[instrument(skip(self), fields(aggregate_id = ?aggregate_id))]
+    async fn load_raw(&self, aggregate_id: &A::Id) -> Result<Option<RawSnapshot>> {
+        let key = stream_name::<A>(aggregate_id);
         let result = self
             .tree
             .get(key)
             .map_err(|e| Error::Store(e.to_string()))?;
 
-        match result {
-            Some(value) => {
-                let snapshot =
-                    serde_json::from_slice(&value).map_err(|e| Error::Store(e.to_string()))?;
-                Ok(Some(snapshot))
-            }
-            None => Ok(None),
+        result.map(|value| decode(&value)).transpose()
+    }
+
+    #[instrument(skip(self), fields(aggregate_id = ?aggregate_id, version))]
+    async fn load_at_or_before(
+        &self,
+        aggregate_id: &A::Id,
+        version: i64,
+    ) -> Result<Option<StoredSnapshot<A::Snapshot>>> {
+        self.load_raw_at_or_before(aggregate_id, version)
+            .await?
+            .map(RawSnapshot::decode)
+            .transpose()
+    }
+
+    #[instrument(skip(self), fields(aggregate_id = ?aggregate_id, version))]
+    async fn load_raw_at_or_before(
+        &self,
+        aggregate_id: &A::Id,
+        version: i64,
+    ) -> Result<Option<RawSnapshot>> {
+        if let Some(latest) = self.load_raw(aggregate_id).await?
+            && latest.version <= version
+        {
+            return Ok(Some(latest));
         }
+        let key = stream_name::<A>(aggregate_id);
+        let older = self
+            .tree
+            .range(history_prefix(&key)..=history_key(&key, version))
+            .next_back()
+            .transpose()
+            .map_err(|e| Error::Store(e.to_string()))?;
+        older.map(|(_, value)| decode(&value)).transpose()
     }
 }
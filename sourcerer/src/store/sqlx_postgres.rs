@@ -1,20 +1,36 @@
 //! A `sqlx` implementation of the `sourcerer` store traits.
 //!
-//! This module provides `sqlx`-based implementations of the `EventStore` and
-//! `SnapshotStore` traits, designed for PostgreSQL. Compile it with the
-//! `postgres-storage` cargo feature.
+//! This module provides `sqlx`-based implementations of the `EventStore`,
+//! `SnapshotStore` and `CheckpointStore` traits, designed for PostgreSQL.
+//! Compile it with the `postgres-storage` cargo feature.
 #![allow(clippy::missing_errors_doc)]
 
-use std::marker::PhantomData;
+use std::{marker::PhantomData, time::Duration};
+
+use std::collections::BTreeMap;
 
 use crate::{
-    Aggregate, Error, Event, EventStore, Result, StoredEvent,
-    snapshot::{SnapshotStore, StoredSnapshot},
+    Aggregate, Error, Event, EventMetadata, EventStore, ExpectedVersion, Result, Snapshot,
+    StoredEvent,
+    compression::{Codec, Compression},
+    metadata::legacy_event_id,
+    outbox::{Delivery, Outbox, OutboxMessage},
+    projection::CheckpointStore,
+    serializer::{EventSerializer, Format, JsonSerializer},
+    snapshot::{RawSnapshot, SnapshotRetention, SnapshotStore, StoredSnapshot},
+    store::idempotency,
+    subscription::{self, EventSubscription, Page, SubscriptionFilter, Wakeup},
     upcaster,
 };
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, stream::BoxStream};
 use serde::{Serialize, de::DeserializeOwned};
-use sqlx::PgPool;
+use sqlx::{PgPool, postgres::PgListener, types::Json};
 use tracing::instrument;
+use uuid::Uuid;
+
+#[cfg(feature = "encryption")]
+use crate::encryption::{Key, KeyStore};
 
 /// Maps `sqlx::Error` into this crate's `Error`.
 fn to_store_error(e: sqlx::Error) -> Error {
@@ -26,202 +42,1244 @@ fn to_serde_error(e: serde_json::Error) -> Error {
     Error::Store(e.to_string())
 }
 
+/// Key of the transaction-level advisory lock taken by every append.
+///
+/// Holding it until commit serialises writers, so `position` values become
+/// visible in the order they were assigned and `read_all` never skips an
+/// event that commits late.
+const APPEND_LOCK_KEY: i64 = 0x736f_7572_6365_7272;
+
+/// Channel notified by the `events` insert trigger installed by `setup`.
+const NOTIFY_CHANNEL: &str = "sourcerer_events";
+
+/// Expands to the columns selected for every event read, matching
+/// [`EventRow`].
+macro_rules! event_columns {
+    () => {
+        "aggregate_type, aggregate_id, version, event_version, event_type, payload_format, \
+         payload, payload_bytes, payload_compression, position, event_id, created_at, \
+         correlation_id, causation_id, headers, hash"
+    };
+}
+
+/// Selects an aggregate's events after a version, in version order.
+const STREAM_QUERY: &str = concat!(
+    "SELECT ",
+    event_columns!(),
+    " FROM events WHERE aggregate_type = $1 AND aggregate_id = $2 AND version > $3 \
+     ORDER BY version"
+);
+
+/// Selects an aggregate's events after a version, up to and including
+/// another, in version order.
+const STREAM_RANGE_QUERY: &str = concat!(
+    "SELECT ",
+    event_columns!(),
+    " FROM events WHERE aggregate_type = $1 AND aggregate_id = $2 AND version > $3 \
+     AND version <= $4 ORDER BY version"
+);
+
+/// Selects a page of an aggregate type's events after a position, in
+/// position order.
+const READ_ALL_QUERY: &str = concat!(
+    "SELECT ",
+    event_columns!(),
+    " FROM events WHERE aggregate_type = $1 AND position > $2 ORDER BY position LIMIT $3"
+);
+
+/// Selects the events at the given positions, in position order.
+const EVENTS_AT_QUERY: &str = concat!(
+    "SELECT ",
+    event_columns!(),
+    " FROM events WHERE position = ANY($1) ORDER BY position"
+);
+
+/// The position, attempts and last error of a row of the `outbox` table.
+type OutboxRow = (i64, i32, Option<String>);
+
+/// A row of the `events` table.
+#[derive(sqlx::FromRow)]
+struct EventRow {
+    aggregate_type: String,
+    aggregate_id: String,
+    version: i64,
+    event_version: i16,
+    event_type: String,
+    payload_format: String,
+    payload: Option<serde_json::Value>,
+    payload_bytes: Option<Vec<u8>>,
+    payload_compression: Option<String>,
+    position: i64,
+    event_id: Uuid,
+    created_at: DateTime<Utc>,
+    correlation_id: Option<String>,
+    causation_id: Option<String>,
+    headers: Json<BTreeMap<String, String>>,
+    hash: Option<String>,
+}
+
+impl EventRow {
+    /// Splits the metadata envelope off the row.
+    fn metadata(&mut self) -> EventMetadata {
+        EventMetadata::from_parts(
+            self.event_id,
+            self.created_at,
+            self.correlation_id.take(),
+            self.causation_id.take(),
+            std::mem::take(&mut self.headers.0),
+        )
+    }
+
+    /// Splits the encoded payload off the row.
+    ///
+    /// Uncompressed JSON payloads are kept in the `payload` column, and every
+    /// other payload in `payload_bytes`, compressed with the codec named by
+    /// `payload_compression`.
+    fn encoded_payload(&mut self) -> Result<(Format, Vec<u8>)> {
+        match (self.payload.take(), self.payload_bytes.take()) {
+            (Some(payload), _) => Ok((
+                Format::Json,
+                serde_json::to_vec(&payload).map_err(to_serde_error)?,
+            )),
+            (None, Some(bytes)) => {
+                let codec = match self.payload_compression.as_deref() {
+                    Some(codec) => codec.parse()?,
+                    None => Codec::None,
+                };
+                let bytes = if codec.is_none() {
+                    bytes
+                } else {
+                    codec.decompress(&bytes)?.into_owned()
+                };
+                Ok((self.payload_format.parse()?, bytes))
+            }
+            (None, None) => Err(Error::Store(format!(
+                "event {} of {} has no payload",
+                self.version, self.aggregate_id
+            ))),
+        }
+    }
+
+    /// Deserializes the row into a stored event.
+    fn into_stored<E: Event, S: EventSerializer>(
+        mut self,
+        serializer: &S,
+    ) -> Result<StoredEvent<E>> {
+        let metadata = self.metadata();
+        let event: E = match self.payload.take() {
+            Some(payload) => serde_json::from_value(payload).map_err(to_serde_error)?,
+            None => {
+                let (format, bytes) = self.encoded_payload()?;
+                serializer.decode(format, &bytes)?
+            }
+        };
+        Ok(StoredEvent::new(
+            self.aggregate_id,
+            self.version,
+            self.event_version as u16,
+            self.event_type,
+            event,
+        )
+        .with_aggregate_type(self.aggregate_type)
+        .with_position(self.position)
+        .with_metadata(metadata)
+        .with_recorded_hash(self.hash))
+    }
+
+    /// Converts the row into a raw stored event for upcasting.
+    fn into_raw(mut self) -> Result<upcaster::RawStoredEvent> {
+        let metadata = self.metadata();
+        let (format, payload) = self.encoded_payload()?;
+        Ok(upcaster::RawStoredEvent {
+            aggregate_type: self.aggregate_type,
+            aggregate_id: self.aggregate_id,
+            version: self.version,
+            event_version: self.event_version as u16,
+            event_type: self.event_type,
+            format,
+            payload,
+            position: self.position,
+            metadata,
+            hash: self.hash,
+        })
+    }
+}
+
+/// The `payload`, `payload_bytes` and `payload_compression` columns of a row.
+type PayloadColumns = (
+    Option<serde_json::Value>,
+    Option<Vec<u8>>,
+    Option<&'static str>,
+);
+
 /// A `sqlx`-backed event store for PostgreSQL.
-#[derive(Debug, Clone)]
-pub struct SqlxEventStore<A: Aggregate> {
+///
+/// Event payloads are encoded by `S`, JSON by default. JSON payloads are
+/// stored as `JSONB`, and every other format, or any compressed payload, as
+/// `BYTEA`.
+///
+/// # Throughput
+///
+/// Every append takes one database-wide advisory lock until it commits, so
+/// `position` values become visible in order and `read_all` never skips an
+/// event that commits late. Appends therefore run one at a time across every
+/// stream and aggregate type sharing the database, even when they touch
+/// unrelated streams: write throughput is bounded by the latency of a single
+/// append transaction. Reads are not affected.
+#[derive(Debug)]
+pub struct SqlxEventStore<A: Aggregate, S: EventSerializer = JsonSerializer> {
     pool: PgPool,
+    serializer: S,
+    compression: Compression,
+    idempotency_retention: Duration,
+    outbox: bool,
     _phantom: PhantomData<A>,
 }
 
+impl<A: Aggregate, S: EventSerializer> Clone for SqlxEventStore<A, S> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            serializer: self.serializer.clone(),
+            compression: self.compression,
+            idempotency_retention: self.idempotency_retention,
+            outbox: self.outbox,
+            _phantom: PhantomData,
+        }
+    }
+}
+
 impl<A: Aggregate> SqlxEventStore<A> {
-    /// Creates a new `SqlxEventStore`.
+    /// Creates a new `SqlxEventStore` writing JSON payloads.
     pub fn new(pool: PgPool) -> Self {
         Self {
             pool,
+            serializer: JsonSerializer,
+            compression: Compression::none(),
+            idempotency_retention: idempotency::DEFAULT_RETENTION,
+            outbox: false,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<A: Aggregate, S: EventSerializer> SqlxEventStore<A, S> {
+    /// Sets the serializer used to encode the payloads of appended events.
+    ///
+    /// Events already stored keep the format they were written in.
+    pub fn with_serializer<T: EventSerializer>(self, serializer: T) -> SqlxEventStore<A, T> {
+        SqlxEventStore {
+            pool: self.pool,
+            serializer,
+            compression: self.compression,
+            idempotency_retention: self.idempotency_retention,
+            outbox: self.outbox,
             _phantom: PhantomData,
         }
     }
 
-    /// Ensures the `events` table exists.
+    /// Sets the compression applied to the payloads of appended events.
+    ///
+    /// Events already stored keep the compression they were written with.
+    #[must_use]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets how long the store remembers the idempotency key of an append.
+    ///
+    /// Defaults to 24 hours. Expired keys are removed by later appends with
+    /// an idempotency key.
+    #[must_use]
+    pub fn with_idempotency_retention(mut self, retention: Duration) -> Self {
+        self.idempotency_retention = retention;
+        self
+    }
+
+    /// Sets whether appends queue their events in the store's [`Outbox`], the
+    /// `outbox` table, in the same transaction as the events themselves.
+    ///
+    /// Disabled by default. Messages already queued stay in the outbox either
+    /// way.
+    #[must_use]
+    pub fn with_outbox(mut self, enabled: bool) -> Self {
+        self.outbox = enabled;
+        self
+    }
+
+    /// Reads the events of the outbox messages in `deliveries`, which are in
+    /// position order.
+    async fn outbox_messages(
+        &self,
+        deliveries: Vec<OutboxRow>,
+    ) -> Result<Vec<OutboxMessage<A::Event>>> {
+        let positions: Vec<i64> = deliveries.iter().map(|row| row.0).collect();
+        let rows: Vec<EventRow> = sqlx::query_as(EVENTS_AT_QUERY)
+            .bind(&positions)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+        let mut rows = rows.into_iter().peekable();
+        let mut messages = Vec::with_capacity(deliveries.len());
+        for (position, attempts, last_error) in deliveries {
+            // Both lists are in position order.
+            let Some(row) = rows.next_if(|row| row.position == position) else {
+                continue;
+            };
+            let delivery = Delivery {
+                attempts: u32::try_from(attempts).unwrap_or_default(),
+                due_at: Utc::now(),
+                last_error,
+            };
+            messages.push(delivery.message(row.into_stored(&self.serializer)?));
+        }
+        Ok(messages)
+    }
+
+    /// Encodes an event payload into its `payload`, `payload_bytes` and
+    /// `payload_compression` columns.
+    fn encode_payload(&self, event: &A::Event) -> Result<PayloadColumns> {
+        let json = self.serializer.format() == Format::Json;
+        if json && self.compression.codec().is_none() {
+            let payload = serde_json::to_value(event).map_err(to_serde_error)?;
+            return Ok((Some(payload), None, None));
+        }
+        let (codec, bytes) = self
+            .compression
+            .compress(self.serializer.serialize(event)?)?;
+        Ok(match codec {
+            Codec::None if json => (
+                Some(serde_json::from_slice(&bytes).map_err(to_serde_error)?),
+                None,
+                None,
+            ),
+            Codec::None => (None, Some(bytes), None),
+            codec => (None, Some(bytes), Some(codec.as_str())),
+        })
+    }
+
+    /// Checks the version of one stream and appends events to it inside an
+    /// append transaction, returning the stored events in version order.
+    async fn append_to_stream(
+        &self,
+        conn: &mut sqlx::PgConnection,
+        aggregate_id: String,
+        expected_version: ExpectedVersion,
+        events: Vec<A::Event>,
+        metadata: &EventMetadata,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        let format = self.serializer.format();
+        let mut payloads = Vec::with_capacity(events.len());
+        let mut payload_bytes = Vec::with_capacity(events.len());
+        let mut compressions = Vec::with_capacity(events.len());
+        for event in &events {
+            let (payload, bytes, compression) = self.encode_payload(event)?;
+            payloads.push(payload);
+            payload_bytes.push(bytes);
+            compressions.push(compression);
+        }
+        let event_types: Vec<String> = events.iter().map(|e| e.event_type().to_owned()).collect();
+        let event_versions: Vec<i16> = events.iter().map(|e| e.event_version() as i16).collect();
+        let metadata: Vec<EventMetadata> = events.iter().map(|_| metadata.stamp(now)).collect();
+        let event_ids: Vec<Uuid> = metadata.iter().map(EventMetadata::event_id).collect();
+
+        // Optimistic concurrency check, reading the hash the new events chain
+        // on to. A new stream has no rows.
+        let head: Option<(i64, Option<String>)> = sqlx::query_as(
+            "SELECT version, hash FROM events WHERE aggregate_type = $1 AND aggregate_id = $2 \
+             ORDER BY version DESC LIMIT 1",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(&aggregate_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(to_store_error)?;
+
+        let (current_version, mut previous) = head.unwrap_or((0, None));
+        if !expected_version.matches(current_version) {
+            return Err(Error::Conflict {
+                aggregate_id,
+                expected: expected_version,
+                actual: current_version,
+            });
+        }
+        if events.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Everything but the event ID is shared by all events of the append.
+        let shared = &metadata[0];
+        let versions: Vec<i64> = (1..=events.len() as i64)
+            .map(|i| current_version + i)
+            .collect();
+        let mut stored_events = Vec::with_capacity(events.len());
+        let mut hashes = Vec::with_capacity(events.len());
+        for (((event, event_type), metadata), &version) in events
+            .into_iter()
+            .zip(&event_types)
+            .zip(&metadata)
+            .zip(&versions)
+        {
+            let stored_event = StoredEvent::new(
+                aggregate_id.clone(),
+                version,
+                event.event_version(),
+                event_type.clone(),
+                event,
+            )
+            .with_aggregate_type(A::TYPE_NAME)
+            .with_metadata(metadata.clone())
+            .chained(previous.as_deref())?;
+            previous = stored_event.hash().map(str::to_owned);
+            hashes.push(previous.clone());
+            stored_events.push(stored_event);
+        }
+
+        // Bulk insert.
+        let mut positions: Vec<(i64, i64)> = sqlx::query_as(
+            r#"
+            INSERT INTO events (
+                aggregate_type, aggregate_id, version, payload_format, payload, payload_bytes,
+                payload_compression, event_type, event_version, event_id, created_at,
+                correlation_id, causation_id, headers, hash
+            )
+            SELECT $1, $2, v, $3, p, pb, pc, t, ev, id, $11, $12, $13, $14, h
+            FROM UNNEST(
+                $4::BIGINT[], $5::JSONB[], $6::BYTEA[], $7::TEXT[], $8::TEXT[], $9::SMALLINT[],
+                $10::UUID[], $15::TEXT[]
+            ) AS x(v, p, pb, pc, t, ev, id, h)
+            ORDER BY v
+            RETURNING version, position
+            "#,
+        )
+        .bind(A::TYPE_NAME)
+        .bind(&aggregate_id)
+        .bind(format.as_str())
+        .bind(&versions)
+        .bind(&payloads)
+        .bind(&payload_bytes)
+        .bind(&compressions)
+        .bind(&event_types)
+        .bind(&event_versions)
+        .bind(&event_ids)
+        .bind(shared.recorded_at())
+        .bind(shared.correlation_id())
+        .bind(shared.causation_id())
+        .bind(Json(shared.headers()))
+        .bind(&hashes)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(to_store_error)?;
+
+        positions.sort_unstable();
+
+        Ok(positions
+            .into_iter()
+            .zip(stored_events)
+            .map(|((_, position), stored_event)| stored_event.with_position(position))
+            .collect())
+    }
+
+    /// Ensures the `events`, `idempotency_keys` and `outbox` tables exist,
+    /// along with the trigger that wakes subscriptions on insert, migrating
+    /// tables created by earlier versions.
+    ///
+    /// Streams are keyed by aggregate type and ID, so every aggregate type can
+    /// share the one table. Streams recorded before aggregate types were
+    /// cannot be told apart, so this fails while any is left: assign them to
+    /// their aggregate type with [`SqlxEventStore::adopt_untyped_streams`],
+    /// then run `setup` again.
     #[instrument(skip(self))]
-    pub async fn setup(&self) -> sqlx::Result<()> {
+    pub async fn setup(&self) -> Result<()> {
+        self.create_tables().await.map_err(to_store_error)?;
+        let untyped: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM events WHERE aggregate_type = '')")
+                .fetch_one(&self.pool)
+                .await
+                .map_err(to_store_error)?;
+        if untyped {
+            return Err(Error::Store(
+                "the events table holds streams recorded before aggregate types were; assign \
+                 them with `SqlxEventStore::adopt_untyped_streams`"
+                    .into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Assigns the streams recorded before aggregate types were to `A`, if
+    /// `owns` accepts their aggregate ID, and returns how many it assigned.
+    ///
+    /// Run this on the store of every aggregate type the table held before
+    /// [`SqlxEventStore::setup`]. The streams of a table used by a single
+    /// aggregate type are all adopted with `|_| true`.
+    #[instrument(skip(self, owns))]
+    pub async fn adopt_untyped_streams(&self, owns: impl Fn(&str) -> bool) -> Result<usize> {
+        let untyped: Vec<String> = sqlx::query_scalar(
+            "SELECT DISTINCT aggregate_id FROM events WHERE aggregate_type = ''",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        let owned: Vec<String> = untyped.into_iter().filter(|id| owns(id)).collect();
+        sqlx::query(
+            "UPDATE events SET aggregate_type = $1 \
+             WHERE aggregate_type = '' AND aggregate_id = ANY($2)",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(&owned)
+        .execute(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        self.backfill_event_ids().await.map_err(to_store_error)?;
+        Ok(owned.len())
+    }
+
+    /// Gives the events recorded before metadata a stable event ID, derived
+    /// from their aggregate type, aggregate ID and version as
+    /// [`CloudEvent::from_stored`](crate::CloudEvent::from_stored) does for
+    /// events without one, and makes the column required and unique.
+    ///
+    /// Events of streams recorded before aggregate types were are left
+    /// without an ID until they are adopted, so that it is derived from the
+    /// type they are read back with. The column stays optional until then.
+    async fn backfill_event_ids(&self) -> sqlx::Result<()> {
+        let nullable: bool = sqlx::query_scalar(
+            "SELECT is_nullable = 'YES' FROM information_schema.columns \
+             WHERE table_schema = current_schema() \
+             AND table_name = 'events' AND column_name = 'event_id'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if !nullable {
+            return Ok(());
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let missing: Vec<(String, String, i64)> = sqlx::query_as(
+            "SELECT aggregate_type, aggregate_id, version FROM events \
+             WHERE event_id IS NULL AND aggregate_type <> ''",
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let mut types = Vec::with_capacity(missing.len());
+        let mut ids = Vec::with_capacity(missing.len());
+        let mut versions = Vec::with_capacity(missing.len());
+        let mut event_ids = Vec::with_capacity(missing.len());
+        for (aggregate_type, aggregate_id, version) in missing {
+            event_ids.push(legacy_event_id(&aggregate_type, &aggregate_id, version));
+            types.push(aggregate_type);
+            ids.push(aggregate_id);
+            versions.push(version);
+        }
+        sqlx::query(
+            r#"
+                UPDATE events SET event_id = x.event_id
+                FROM UNNEST($1::TEXT[], $2::TEXT[], $3::BIGINT[], $4::UUID[])
+                    AS x(aggregate_type, aggregate_id, version, event_id)
+                WHERE events.aggregate_type = x.aggregate_type
+                    AND events.aggregate_id = x.aggregate_id
+                    AND events.version = x.version
+            "#,
+        )
+        .bind(&types)
+        .bind(&ids)
+        .bind(&versions)
+        .bind(&event_ids)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS events_event_id_key ON events (event_id)")
+            .execute(&mut *tx)
+            .await?;
+        let untyped: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM events WHERE event_id IS NULL)")
+                .fetch_one(&mut *tx)
+                .await?;
+        if !untyped {
+            sqlx::query("ALTER TABLE events ALTER COLUMN event_id SET NOT NULL")
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    /// Creates or migrates the tables, indexes and trigger of the store.
+    async fn create_tables(&self) -> sqlx::Result<()> {
         sqlx::query(
             r#"
                 CREATE TABLE IF NOT EXISTS events (
+                    aggregate_type TEXT NOT NULL,
                     aggregate_id TEXT NOT NULL,
                     version BIGINT NOT NULL,
                     event_version SMALLINT NOT NULL,
                     event_type TEXT NOT NULL,
-                    payload JSONB NOT NULL,
+                    payload_format TEXT NOT NULL DEFAULT 'json',
+                    payload JSONB,
+                    payload_bytes BYTEA,
+                    payload_compression TEXT,
                     created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
-                    PRIMARY KEY (aggregate_id, version)
+                    position BIGSERIAL NOT NULL UNIQUE,
+                    event_id UUID NOT NULL UNIQUE,
+                    correlation_id TEXT,
+                    causation_id TEXT,
+                    headers JSONB NOT NULL DEFAULT '{}',
+                    hash TEXT,
+                    PRIMARY KEY (aggregate_type, aggregate_id, version)
+                );
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // Tables created before aggregate types were recorded are keyed by
+        // aggregate ID alone. Their streams are left untyped, with an empty
+        // type, until adopted.
+        sqlx::query(
+            r#"
+                DO $$
+                BEGIN
+                    IF NOT EXISTS (
+                        SELECT 1 FROM information_schema.columns
+                        WHERE table_schema = current_schema()
+                            AND table_name = 'events' AND column_name = 'aggregate_type'
+                    ) THEN
+                        ALTER TABLE events ADD COLUMN aggregate_type TEXT NOT NULL DEFAULT '';
+                        ALTER TABLE events ALTER COLUMN aggregate_type DROP DEFAULT;
+                        ALTER TABLE events DROP CONSTRAINT events_pkey;
+                        ALTER TABLE events ADD PRIMARY KEY (aggregate_type, aggregate_id, version);
+                    END IF;
+                END
+                $$;
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // Tables created before payload formats were recorded hold only
+        // uncompressed JSON, those created before metadata was recorded lack
+        // the metadata columns, and those created before events were hashed
+        // lack the `hash` column.
+        sqlx::query(
+            r#"
+                ALTER TABLE events
+                    ADD COLUMN IF NOT EXISTS payload_format TEXT NOT NULL DEFAULT 'json',
+                    ADD COLUMN IF NOT EXISTS payload_bytes BYTEA,
+                    ADD COLUMN IF NOT EXISTS payload_compression TEXT,
+                    ADD COLUMN IF NOT EXISTS event_id UUID,
+                    ADD COLUMN IF NOT EXISTS correlation_id TEXT,
+                    ADD COLUMN IF NOT EXISTS causation_id TEXT,
+                    ADD COLUMN IF NOT EXISTS headers JSONB NOT NULL DEFAULT '{}',
+                    ADD COLUMN IF NOT EXISTS hash TEXT,
+                    ALTER COLUMN payload DROP NOT NULL;
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        self.backfill_event_ids().await?;
+        // Tables created before the global log have no `position`. Existing
+        // events are numbered in the order they were recorded, which keeps
+        // each stream in version order.
+        sqlx::query(
+            r#"
+                DO $$
+                BEGIN
+                    IF NOT EXISTS (
+                        SELECT 1 FROM information_schema.columns
+                        WHERE table_schema = current_schema()
+                            AND table_name = 'events' AND column_name = 'position'
+                    ) THEN
+                        ALTER TABLE events ADD COLUMN position BIGINT;
+                        UPDATE events SET position = numbered.position
+                        FROM (
+                            SELECT ctid, ROW_NUMBER() OVER (
+                                ORDER BY created_at, aggregate_id, version
+                            ) AS position
+                            FROM events
+                        ) AS numbered
+                        WHERE events.ctid = numbered.ctid;
+                        CREATE SEQUENCE events_position_seq OWNED BY events.position;
+                        PERFORM setval('events_position_seq', COALESCE(MAX(position), 0) + 1, false)
+                        FROM events;
+                        ALTER TABLE events
+                            ALTER COLUMN position SET DEFAULT nextval('events_position_seq'),
+                            ALTER COLUMN position SET NOT NULL;
+                    END IF;
+                END
+                $$;
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS events_position_key ON events (position)")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS events_type_position ON events (aggregate_type, position)",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS idempotency_keys (
+                    aggregate_type TEXT NOT NULL,
+                    idempotency_key TEXT NOT NULL,
+                    recorded_at TIMESTAMPTZ NOT NULL,
+                    positions BIGINT[] NOT NULL,
+                    PRIMARY KEY (aggregate_type, idempotency_key)
+                );
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idempotency_keys_type_recorded_at \
+             ON idempotency_keys (aggregate_type, recorded_at)",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS outbox (
+                    position BIGINT PRIMARY KEY,
+                    aggregate_type TEXT NOT NULL,
+                    attempts INTEGER NOT NULL DEFAULT 0,
+                    due_at TIMESTAMPTZ NOT NULL,
+                    last_error TEXT,
+                    dead_lettered BOOLEAN NOT NULL DEFAULT FALSE
                 );
             "#,
         )
         .execute(&self.pool)
         .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS outbox_type_due \
+             ON outbox (aggregate_type, dead_lettered, due_at)",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+                CREATE OR REPLACE FUNCTION sourcerer_notify_events() RETURNS trigger AS $$
+                BEGIN
+                    PERFORM pg_notify('sourcerer_events', '');
+                    RETURN NULL;
+                END;
+                $$ LANGUAGE plpgsql;
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DROP TRIGGER IF EXISTS events_notify ON events")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+                CREATE TRIGGER events_notify
+                AFTER INSERT ON events
+                FOR EACH STATEMENT EXECUTE FUNCTION sourcerer_notify_events();
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
         Ok(())
     }
 }
 
 #[async_trait::async_trait]
-impl<A> EventStore<A> for SqlxEventStore<A>
+impl<A, S> Outbox<A::Event> for SqlxEventStore<A, S>
 where
     A: Aggregate,
+    S: EventSerializer,
+    A::Event: Serialize + DeserializeOwned + Send + Sync,
+{
+    #[instrument(skip(self), fields(%now, limit))]
+    async fn pending(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<OutboxMessage<A::Event>>> {
+        let deliveries: Vec<OutboxRow> = sqlx::query_as(
+            "SELECT position, attempts, last_error FROM outbox \
+             WHERE aggregate_type = $1 AND NOT dead_lettered AND due_at <= $2 \
+             ORDER BY position LIMIT $3",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(now)
+        .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        self.outbox_messages(deliveries).await
+    }
+
+    #[instrument(skip(self), fields(position))]
+    async fn acknowledge(&self, position: i64) -> Result<()> {
+        sqlx::query("DELETE FROM outbox WHERE aggregate_type = $1 AND position = $2")
+            .bind(A::TYPE_NAME)
+            .bind(position)
+            .execute(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), fields(position, %retry_at))]
+    async fn retry_later(&self, position: i64, error: &str, retry_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query(
+            "UPDATE outbox SET attempts = attempts + 1, last_error = $3, due_at = $4 \
+             WHERE aggregate_type = $1 AND position = $2 AND NOT dead_lettered",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(position)
+        .bind(error)
+        .bind(retry_at)
+        .execute(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), fields(position))]
+    async fn dead_letter(&self, position: i64, error: &str) -> Result<()> {
+        sqlx::query(
+            "UPDATE outbox SET attempts = attempts + 1, last_error = $3, dead_lettered = TRUE \
+             WHERE aggregate_type = $1 AND position = $2 AND NOT dead_lettered",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(position)
+        .bind(error)
+        .execute(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), fields(limit))]
+    async fn dead_letters(&self, limit: usize) -> Result<Vec<OutboxMessage<A::Event>>> {
+        let deliveries: Vec<OutboxRow> = sqlx::query_as(
+            "SELECT position, attempts, last_error FROM outbox \
+             WHERE aggregate_type = $1 AND dead_lettered ORDER BY position LIMIT $2",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        self.outbox_messages(deliveries).await
+    }
+
+    #[instrument(skip(self), fields(position))]
+    async fn requeue(&self, position: i64) -> Result<()> {
+        sqlx::query(
+            "UPDATE outbox SET attempts = 0, last_error = NULL, due_at = $3, \
+             dead_lettered = FALSE WHERE aggregate_type = $1 AND position = $2 AND dead_lettered",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(position)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        Ok(())
+    }
+}
+
+/// Reads the next page of the global log after `cursor` for a subscription,
+/// applying the filter in the query.
+async fn read_page<E: Event, S: EventSerializer>(
+    pool: &PgPool,
+    serializer: &S,
+    aggregate_type: &str,
+    cursor: i64,
+    filter: &SubscriptionFilter,
+) -> Result<Page<E>> {
+    let rows: Vec<EventRow> = sqlx::query_as(concat!(
+        "SELECT ",
+        event_columns!(),
+        r#"
+        FROM events
+        WHERE aggregate_type = $1
+          AND position > $2
+          AND ($3::TEXT[] IS NULL OR event_type = ANY($3))
+          AND ($4::TEXT[] IS NULL OR aggregate_id = ANY($4))
+        ORDER BY position
+        LIMIT $5
+        "#
+    ))
+    .bind(aggregate_type)
+    .bind(cursor)
+    .bind(filter.event_types())
+    .bind(filter.aggregate_ids())
+    .bind(subscription::BATCH_SIZE as i64)
+    .fetch_all(pool)
+    .await
+    .map_err(to_store_error)?;
+
+    let events = rows
+        .into_iter()
+        .map(|row| row.into_stored(serializer))
+        .collect::<Result<Vec<StoredEvent<E>>>>()?;
+    Ok(Page {
+        cursor: events.last().map_or(cursor, StoredEvent::position),
+        events,
+    })
+}
+
+/// Wakes a subscription whenever the `events` insert trigger fires.
+struct ListenerWakeup(PgListener);
+
+#[async_trait::async_trait]
+impl Wakeup for ListenerWakeup {
+    async fn wait(&mut self) -> Result<bool> {
+        // `None` means the connection dropped and has been re-established;
+        // notifications sent in between are lost, so wake up regardless and
+        // let the subscription catch up from its last position.
+        self.0.try_recv().await.map_err(to_store_error)?;
+        while self.0.next_buffered().is_some() {}
+        Ok(true)
+    }
+}
+
+#[async_trait::async_trait]
+impl<A, S> EventStore<A> for SqlxEventStore<A, S>
+where
+    A: Aggregate,
+    S: EventSerializer,
     A::Event: Serialize + DeserializeOwned + Send + Sync,
     A::Id: Clone + Serialize + Send + Sync,
 {
-    #[instrument(skip(self, events), fields(id = ?id))]
+    #[instrument(skip(self, events, metadata), fields(id = ?id))]
     async fn append(
         &self,
         id: &A::Id,
-        expected_version: i64,
+        expected_version: ExpectedVersion,
         events: Vec<A::Event>,
+        metadata: EventMetadata,
     ) -> Result<Vec<StoredEvent<A::Event>>> {
-        if events.is_empty() {
+        self.append_batch(vec![(id.clone(), expected_version, events)], metadata)
+            .await
+    }
+
+    #[instrument(skip(self, batch, metadata), fields(streams = batch.len()))]
+    async fn append_batch(
+        &self,
+        batch: Vec<(A::Id, ExpectedVersion, Vec<A::Event>)>,
+        metadata: EventMetadata,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        if batch.is_empty() {
             return Ok(Vec::new());
         }
 
-        let aggregate_id = id.to_string();
-        let versions: Vec<i64> = (1..=events.len() as i64)
-            .map(|i| expected_version + i)
-            .collect();
-
-        let payloads: Vec<serde_json::Value> = events
-            .iter()
-            .map(|e| serde_json::to_value(e).map_err(to_serde_error))
-            .collect::<Result<_>>()?;
-        let event_types: Vec<String> = events.iter().map(|e| e.event_type().to_owned()).collect();
-        let event_versions: Vec<i16> = events.iter().map(|e| e.event_version() as i16).collect();
-
+        let now = Utc::now();
         let mut tx = self.pool.begin().await.map_err(to_store_error)?;
 
-        // Optimistic concurrency check.
-        let current_version: Option<i64> =
-            sqlx::query_scalar("SELECT MAX(version) FROM events WHERE aggregate_id = $1")
-                .bind(&aggregate_id)
-                .fetch_optional(&mut *tx)
-                .await
-                .map_err(to_store_error)?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(APPEND_LOCK_KEY)
+            .execute(&mut *tx)
+            .await
+            .map_err(to_store_error)?;
 
-        if current_version.unwrap_or(0) != expected_version {
-            return Err(Error::Conflict);
+        // A repeated idempotency key returns the events it first appended.
+        let idempotency_key = metadata.idempotency_key();
+        if let Some(key) = idempotency_key {
+            sqlx::query(
+                "DELETE FROM idempotency_keys WHERE aggregate_type = $1 AND recorded_at < $2",
+            )
+            .bind(A::TYPE_NAME)
+            .bind(idempotency::cutoff(now, self.idempotency_retention))
+            .execute(&mut *tx)
+            .await
+            .map_err(to_store_error)?;
+            let positions: Option<Vec<i64>> = sqlx::query_scalar(
+                "SELECT positions FROM idempotency_keys \
+                 WHERE aggregate_type = $1 AND idempotency_key = $2",
+            )
+            .bind(A::TYPE_NAME)
+            .bind(key)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(to_store_error)?;
+            if let Some(positions) = positions {
+                let rows: Vec<EventRow> = sqlx::query_as(EVENTS_AT_QUERY)
+                    .bind(&positions)
+                    .fetch_all(&mut *tx)
+                    .await
+                    .map_err(to_store_error)?;
+                tx.commit().await.map_err(to_store_error)?;
+                return rows
+                    .into_iter()
+                    .map(|row| row.into_stored(&self.serializer))
+                    .collect();
+            }
         }
 
-        // Bulk insert.
-        sqlx::query(
-            r#"
-            INSERT INTO events (aggregate_id, version, payload, event_type, event_version)
-            SELECT $1, v, p, t, ev
-            FROM UNNEST($2::BIGINT[], $3::JSONB[], $4::TEXT[], $5::SMALLINT[]) AS x(v, p, t, ev)
-            "#,
-        )
-        .bind(&aggregate_id)
-        .bind(&versions)
-        .bind(&payloads)
-        .bind(&event_types)
-        .bind(&event_versions)
-        .execute(&mut *tx)
-        .await
-        .map_err(to_store_error)?;
+        // Dropping the transaction on an error rolls back every entry.
+        let mut stored_events = Vec::new();
+        for (id, expected_version, events) in batch {
+            stored_events.extend(
+                self.append_to_stream(
+                    &mut tx,
+                    id.to_string(),
+                    expected_version,
+                    events,
+                    &metadata,
+                    now,
+                )
+                .await?,
+            );
+        }
+
+        let positions: Vec<i64> = stored_events.iter().map(StoredEvent::position).collect();
+        if self.outbox {
+            sqlx::query(
+                "INSERT INTO outbox (position, aggregate_type, due_at) \
+                 SELECT UNNEST($1::BIGINT[]), $2, $3",
+            )
+            .bind(&positions)
+            .bind(A::TYPE_NAME)
+            .bind(now)
+            .execute(&mut *tx)
+            .await
+            .map_err(to_store_error)?;
+        }
+        if let Some(key) = idempotency_key {
+            sqlx::query(
+                "INSERT INTO idempotency_keys (aggregate_type, idempotency_key, recorded_at, \
+                 positions) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(A::TYPE_NAME)
+            .bind(key)
+            .bind(now)
+            .bind(&positions)
+            .execute(&mut *tx)
+            .await
+            .map_err(to_store_error)?;
+        }
 
         tx.commit().await.map_err(to_store_error)?;
 
-        Ok(versions
-            .into_iter()
-            .zip(events.into_iter())
-            .zip(event_types.into_iter())
-            .map(|((version, event), event_type)| {
-                StoredEvent::new(
-                    aggregate_id.clone(),
-                    version,
-                    event.event_version(),
-                    event_type,
-                    event,
-                )
-            })
-            .collect())
+        Ok(stored_events)
     }
 
     #[instrument(skip(self), fields(id = ?id))]
     async fn load(&self, id: &A::Id) -> Result<Vec<StoredEvent<A::Event>>> {
-        let rows: Vec<(i64, i16, String, serde_json::Value)> = sqlx::query_as(
-            "SELECT version, event_version, event_type, payload FROM events WHERE aggregate_id = $1 ORDER BY version",
-        )
-        .bind(id.to_string())
-        .fetch_all(&self.pool)
-        .await
-        .map_err(to_store_error)?;
+        self.load_from(id, 0).await
+    }
+
+    #[instrument(skip(self), fields(id = ?id, version))]
+    async fn load_from(&self, id: &A::Id, version: i64) -> Result<Vec<StoredEvent<A::Event>>> {
+        let rows: Vec<EventRow> = sqlx::query_as(STREAM_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(id.to_string())
+            .bind(version)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(to_store_error)?;
 
         rows.into_iter()
-            .map(|(version, ev_version, ev_type, payload)| {
-                let event: A::Event = serde_json::from_value(payload).map_err(to_serde_error)?;
-                Ok(StoredEvent::new(
-                    id.to_string(),
-                    version,
-                    ev_version as u16,
-                    ev_type,
-                    event,
-                ))
-            })
+            .map(|row| row.into_stored(&self.serializer))
             .collect()
     }
 
-    #[instrument(skip(self), fields(id = ?id, version))]
-    async fn load_from(&self, id: &A::Id, version: i64) -> Result<Vec<StoredEvent<A::Event>>> {
-        let rows: Vec<(i64, i16, String, serde_json::Value)> = sqlx::query_as(
-            "SELECT version, event_version, event_type, payload FROM events WHERE aggregate_id = $1 AND version > $2 ORDER BY version",
+    fn stream_from<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<StoredEvent<A::Event>>> {
+        sqlx::query_as::<_, EventRow>(STREAM_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(id.to_string())
+            .bind(version)
+            .fetch(&self.pool)
+            .map(|row| row.map_err(to_store_error)?.into_stored(&self.serializer))
+            .boxed()
+    }
+
+    fn stream_raw<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<upcaster::RawStoredEvent>> {
+        sqlx::query_as::<_, EventRow>(STREAM_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(id.to_string())
+            .bind(version)
+            .fetch(&self.pool)
+            .map(|row| row.map_err(to_store_error)?.into_raw())
+            .boxed()
+    }
+
+    fn stream_raw_range<'a>(
+        &'a self,
+        id: &'a A::Id,
+        after_version: i64,
+        until_version: i64,
+    ) -> BoxStream<'a, Result<upcaster::RawStoredEvent>> {
+        sqlx::query_as::<_, EventRow>(STREAM_RANGE_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(id.to_string())
+            .bind(after_version)
+            .bind(until_version)
+            .fetch(&self.pool)
+            .map(|row| row.map_err(to_store_error)?.into_raw())
+            .boxed()
+    }
+
+    #[instrument(skip(self), fields(id = ?id, %timestamp))]
+    async fn version_as_of(&self, id: &A::Id, timestamp: DateTime<Utc>) -> Result<i64> {
+        sqlx::query_scalar(
+            "SELECT COALESCE(MAX(version), 0) FROM events \
+             WHERE aggregate_type = $1 AND aggregate_id = $2 AND created_at <= $3",
         )
+        .bind(A::TYPE_NAME)
         .bind(id.to_string())
-        .bind(version)
-        .fetch_all(&self.pool)
+        .bind(timestamp)
+        .fetch_one(&self.pool)
         .await
-        .map_err(to_store_error)?;
+        .map_err(to_store_error)
+    }
+
+    #[instrument(skip(self), fields(id = ?id, version))]
+    async fn load_raw(&self, id: &A::Id, version: i64) -> Result<Vec<upcaster::RawStoredEvent>> {
+        let rows: Vec<EventRow> = sqlx::query_as(STREAM_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(id.to_string())
+            .bind(version)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+
+        rows.into_iter().map(EventRow::into_raw).collect()
+    }
+
+    #[instrument(skip(self), fields(from_position, limit))]
+    async fn read_all(
+        &self,
+        from_position: i64,
+        limit: usize,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        let rows: Vec<EventRow> = sqlx::query_as(READ_ALL_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(from_position)
+            .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(to_store_error)?;
 
         rows.into_iter()
-            .map(|(version, ev_version, ev_type, payload)| {
-                let event: A::Event = serde_json::from_value(payload).map_err(to_serde_error)?;
-                Ok(StoredEvent::new(
-                    id.to_string(),
-                    version,
-                    ev_version as u16,
-                    ev_type,
-                    event,
-                ))
-            })
+            .map(|row| row.into_stored(&self.serializer))
             .collect()
     }
 
-    #[instrument(skip(self), fields(id = ?id, version))]
-    async fn load_raw(&self, id: &A::Id, version: i64) -> Result<Vec<upcaster::RawStoredEvent>> {
-        let rows: Vec<(i64, i16, String, serde_json::Value)> = sqlx::query_as(
-            "SELECT version, event_version, event_type, payload FROM events WHERE aggregate_id = $1 AND version > $2 ORDER BY version",
+    #[instrument(skip(self), fields(from_position, limit))]
+    async fn read_all_raw(
+        &self,
+        from_position: i64,
+        limit: usize,
+    ) -> Result<Vec<upcaster::RawStoredEvent>> {
+        let rows: Vec<EventRow> = sqlx::query_as(READ_ALL_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(from_position)
+            .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+
+        rows.into_iter().map(EventRow::into_raw).collect()
+    }
+
+    #[instrument(skip(self))]
+    async fn list_aggregate_ids(&self) -> Result<Vec<String>> {
+        sqlx::query_scalar(
+            "SELECT DISTINCT aggregate_id FROM events WHERE aggregate_type = $1 ORDER BY aggregate_id",
         )
-        .bind(id.to_string())
-        .bind(version)
+        .bind(A::TYPE_NAME)
         .fetch_all(&self.pool)
         .await
-        .map_err(to_store_error)?;
+        .map_err(to_store_error)
+    }
 
-        Ok(rows
-            .into_iter()
-            .map(
-                |(version, ev_version, ev_type, payload)| upcaster::RawStoredEvent {
-                    aggregate_id: id.to_string(),
-                    version,
-                    event_version: ev_version as u16,
-                    event_type: ev_type,
-                    payload,
-                },
-            )
-            .collect())
+    #[instrument(skip(self, filter), fields(from_position))]
+    async fn subscribe(
+        &self,
+        from_position: i64,
+        filter: SubscriptionFilter,
+    ) -> Result<EventSubscription<A::Event>> {
+        let mut listener = PgListener::connect_with(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+        listener
+            .listen(NOTIFY_CHANNEL)
+            .await
+            .map_err(to_store_error)?;
+
+        let pool = self.pool.clone();
+        let serializer = self.serializer.clone();
+        Ok(subscription::catch_up(
+            from_position,
+            move |cursor| {
+                let pool = pool.clone();
+                let serializer = serializer.clone();
+                let filter = filter.clone();
+                async move { read_page(&pool, &serializer, A::TYPE_NAME, cursor, &filter).await }
+            },
+            ListenerWakeup(listener),
+        ))
+    }
+}
+
+/// A row of the `snapshots` or `snapshot_history` table.
+#[derive(sqlx::FromRow)]
+struct SnapshotRow {
+    version: i64,
+    snapshot_version: i16,
+    payload: Option<serde_json::Value>,
+    payload_bytes: Option<Vec<u8>>,
+    payload_compression: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl SnapshotRow {
+    /// Decompresses the row's payload into a raw snapshot of `aggregate_id`.
+    fn into_raw(self, aggregate_id: String) -> Result<RawSnapshot> {
+        let payload = match (self.payload, self.payload_bytes) {
+            (Some(payload), _) => payload,
+            (None, Some(bytes)) => {
+                let codec = match self.payload_compression.as_deref() {
+                    Some(codec) => codec.parse()?,
+                    None => Codec::None,
+                };
+                serde_json::from_slice(&codec.decompress(&bytes)?).map_err(to_serde_error)?
+            }
+            (None, None) => {
+                return Err(Error::Store(format!(
+                    "snapshot of {aggregate_id} has no payload"
+                )));
+            }
+        };
+        Ok(RawSnapshot {
+            aggregate_id,
+            version: self.version,
+            snapshot_version: self.snapshot_version as u16,
+            taken_at: Some(self.created_at),
+            payload,
+        })
     }
 }
 
 /// A `sqlx`-backed snapshot store for PostgreSQL.
+///
+/// Snapshots are stored as `JSONB`, or as `BYTEA` once compressed. The
+/// `snapshots` table holds the latest snapshot of each aggregate, and
+/// `snapshot_history` the older ones kept by the store's
+/// [`SnapshotRetention`].
 #[derive(Debug, Clone)]
 pub struct SqlxSnapshotStore<A: Aggregate> {
     pool: PgPool,
+    compression: Compression,
+    retention: SnapshotRetention,
     _phantom: PhantomData<A>,
 }
 
@@ -230,20 +1288,98 @@ impl<A: Aggregate> SqlxSnapshotStore<A> {
     pub fn new(pool: PgPool) -> Self {
         Self {
             pool,
+            compression: Compression::none(),
+            retention: SnapshotRetention::default(),
             _phantom: PhantomData,
         }
     }
 
-    /// Ensures the `snapshots` table exists.
+    /// Sets the compression applied to saved snapshots.
+    #[must_use]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets which snapshots of each aggregate the store keeps.
+    #[must_use]
+    pub fn with_retention(mut self, retention: SnapshotRetention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Ensures the `snapshots` and `snapshot_history` tables exist, migrating
+    /// tables created by earlier versions.
+    ///
+    /// Snapshots taken before aggregate types were recorded are ignored until
+    /// assigned to their aggregate type with
+    /// [`SqlxSnapshotStore::adopt_untyped_snapshots`].
     #[instrument(skip(self))]
     pub async fn setup(&self) -> sqlx::Result<()> {
         sqlx::query(
             r#"
                 CREATE TABLE IF NOT EXISTS snapshots (
-                    aggregate_id TEXT PRIMARY KEY,
+                    aggregate_type TEXT NOT NULL,
+                    aggregate_id TEXT NOT NULL,
                     version BIGINT NOT NULL,
-                    payload JSONB NOT NULL,
-                    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+                    snapshot_version SMALLINT NOT NULL DEFAULT 1,
+                    payload JSONB,
+                    payload_bytes BYTEA,
+                    payload_compression TEXT,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                    PRIMARY KEY (aggregate_type, aggregate_id)
+                );
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // Tables created before aggregate types were recorded are keyed by
+        // aggregate ID alone.
+        sqlx::query(
+            r#"
+                DO $$
+                BEGIN
+                    IF NOT EXISTS (
+                        SELECT 1 FROM information_schema.columns
+                        WHERE table_schema = current_schema()
+                            AND table_name = 'snapshots' AND column_name = 'aggregate_type'
+                    ) THEN
+                        ALTER TABLE snapshots ADD COLUMN aggregate_type TEXT NOT NULL DEFAULT '';
+                        ALTER TABLE snapshots ALTER COLUMN aggregate_type DROP DEFAULT;
+                        ALTER TABLE snapshots DROP CONSTRAINT snapshots_pkey;
+                        ALTER TABLE snapshots ADD PRIMARY KEY (aggregate_type, aggregate_id);
+                    END IF;
+                END
+                $$;
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // Tables created before snapshots could be compressed hold only JSON,
+        // and those created before snapshots were versioned hold version 1.
+        sqlx::query(
+            r#"
+                ALTER TABLE snapshots
+                    ADD COLUMN IF NOT EXISTS snapshot_version SMALLINT NOT NULL DEFAULT 1,
+                    ADD COLUMN IF NOT EXISTS payload_bytes BYTEA,
+                    ADD COLUMN IF NOT EXISTS payload_compression TEXT,
+                    ALTER COLUMN payload DROP NOT NULL;
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS snapshot_history (
+                    aggregate_type TEXT NOT NULL,
+                    aggregate_id TEXT NOT NULL,
+                    version BIGINT NOT NULL,
+                    snapshot_version SMALLINT NOT NULL DEFAULT 1,
+                    payload JSONB,
+                    payload_bytes BYTEA,
+                    payload_compression TEXT,
+                    created_at TIMESTAMPTZ NOT NULL,
+                    PRIMARY KEY (aggregate_type, aggregate_id, version)
                 );
             "#,
         )
@@ -251,6 +1387,29 @@ impl<A: Aggregate> SqlxSnapshotStore<A> {
         .await?;
         Ok(())
     }
+
+    /// Assigns the snapshots taken before aggregate types were recorded to
+    /// `A`, if `owns` accepts their aggregate ID, and returns how many it
+    /// assigned.
+    #[instrument(skip(self, owns))]
+    pub async fn adopt_untyped_snapshots(&self, owns: impl Fn(&str) -> bool) -> Result<usize> {
+        let untyped: Vec<String> =
+            sqlx::query_scalar("SELECT aggregate_id FROM snapshots WHERE aggregate_type = ''")
+                .fetch_all(&self.pool)
+                .await
+                .map_err(to_store_error)?;
+        let owned: Vec<String> = untyped.into_iter().filter(|id| owns(id)).collect();
+        sqlx::query(
+            "UPDATE snapshots SET aggregate_type = $1 \
+             WHERE aggregate_type = '' AND aggregate_id = ANY($2)",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(&owned)
+        .execute(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        Ok(owned.len())
+    }
 }
 
 #[async_trait::async_trait]
@@ -263,45 +1422,327 @@ where
     #[instrument(skip(self, snapshot), fields(id = ?aggregate_id))]
     async fn save(&self, aggregate_id: &A::Id, version: i64, snapshot: A::Snapshot) -> Result<()> {
         let payload = serde_json::to_value(snapshot).map_err(to_serde_error)?;
+        let (payload, payload_bytes, compression) = if self.compression.codec().is_none() {
+            (Some(payload), None, None)
+        } else {
+            let bytes = serde_json::to_vec(&payload).map_err(to_serde_error)?;
+            match self.compression.compress(bytes)? {
+                (Codec::None, _) => (Some(payload), None, None),
+                (codec, bytes) => (None, Some(bytes), Some(codec.as_str())),
+            }
+        };
 
+        let aggregate_id = aggregate_id.to_string();
+        let mut tx = self.pool.begin().await.map_err(to_store_error)?;
+        let latest: Option<i64> = sqlx::query_scalar(
+            "SELECT version FROM snapshots \
+             WHERE aggregate_type = $1 AND aggregate_id = $2 FOR UPDATE",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(&aggregate_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(to_store_error)?;
+        if latest.is_some_and(|latest| latest > version) {
+            return Ok(());
+        }
+
+        let keeps_history = self.retention.keeps_history();
+        if keeps_history && latest.is_some_and(|latest| latest < version) {
+            sqlx::query(
+                r#"
+                INSERT INTO snapshot_history (
+                    aggregate_type, aggregate_id, version, snapshot_version, payload,
+                    payload_bytes, payload_compression, created_at
+                )
+                SELECT aggregate_type, aggregate_id, version, snapshot_version, payload,
+                    payload_bytes, payload_compression, created_at
+                FROM snapshots WHERE aggregate_type = $1 AND aggregate_id = $2
+                ON CONFLICT DO NOTHING;
+                "#,
+            )
+            .bind(A::TYPE_NAME)
+            .bind(&aggregate_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(to_store_error)?;
+        }
+
+        // The condition guards against a newer snapshot inserted by a
+        // concurrent first save.
         sqlx::query(
             r#"
-            INSERT INTO snapshots (aggregate_id, version, payload)
-            VALUES ($1, $2, $3)
-            ON CONFLICT (aggregate_id) DO UPDATE
+            INSERT INTO snapshots (
+                aggregate_type, aggregate_id, version, payload, payload_bytes, payload_compression,
+                snapshot_version
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (aggregate_type, aggregate_id) DO UPDATE
             SET version = EXCLUDED.version,
-                payload = EXCLUDED.payload;
+                snapshot_version = EXCLUDED.snapshot_version,
+                payload = EXCLUDED.payload,
+                payload_bytes = EXCLUDED.payload_bytes,
+                payload_compression = EXCLUDED.payload_compression,
+                created_at = NOW()
+            WHERE snapshots.version <= EXCLUDED.version;
             "#,
         )
-        .bind(aggregate_id.to_string())
+        .bind(A::TYPE_NAME)
+        .bind(&aggregate_id)
         .bind(version)
         .bind(payload)
-        .execute(&self.pool)
+        .bind(payload_bytes)
+        .bind(compression)
+        .bind(A::Snapshot::snapshot_version() as i16)
+        .execute(&mut *tx)
         .await
         .map_err(to_store_error)?;
+
+        if keeps_history {
+            let mut versions: Vec<i64> = sqlx::query_scalar(
+                "SELECT version FROM snapshot_history \
+                 WHERE aggregate_type = $1 AND aggregate_id = $2 AND version < $3 \
+                 ORDER BY version",
+            )
+            .bind(A::TYPE_NAME)
+            .bind(&aggregate_id)
+            .bind(version)
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(to_store_error)?;
+            versions.push(version);
+            sqlx::query(
+                "DELETE FROM snapshot_history \
+                 WHERE aggregate_type = $1 AND aggregate_id = $2 AND version = ANY($3)",
+            )
+            .bind(A::TYPE_NAME)
+            .bind(&aggregate_id)
+            .bind(self.retention.pruned(&versions))
+            .execute(&mut *tx)
+            .await
+            .map_err(to_store_error)?;
+        }
+
+        tx.commit().await.map_err(to_store_error)?;
         Ok(())
     }
 
     #[instrument(skip(self), fields(id = ?aggregate_id))]
     async fn load(&self, aggregate_id: &A::Id) -> Result<Option<StoredSnapshot<A::Snapshot>>> {
-        let row: Option<(i64, serde_json::Value)> =
-            sqlx::query_as("SELECT version, payload FROM snapshots WHERE aggregate_id = $1")
-                .bind(aggregate_id.to_string())
+        self.load_raw(aggregate_id)
+            .await?
+            .map(RawSnapshot::decode)
+            .transpose()
+    }
+
+    #[instrument(skip(self), fields(id = ?aggregate_id))]
+    async fn load_raw(&self, aggregate_id: &A::Id) -> Result<Option<RawSnapshot>> {
+        let row: Option<SnapshotRow> = sqlx::query_as(
+            "SELECT version, snapshot_version, payload, payload_bytes, payload_compression, \
+             created_at FROM snapshots WHERE aggregate_type = $1 AND aggregate_id = $2",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(aggregate_id.to_string())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+
+        row.map(|row| row.into_raw(aggregate_id.to_string()))
+            .transpose()
+    }
+
+    #[instrument(skip(self), fields(id = ?aggregate_id, version))]
+    async fn load_at_or_before(
+        &self,
+        aggregate_id: &A::Id,
+        version: i64,
+    ) -> Result<Option<StoredSnapshot<A::Snapshot>>> {
+        self.load_raw_at_or_before(aggregate_id, version)
+            .await?
+            .map(RawSnapshot::decode)
+            .transpose()
+    }
+
+    #[instrument(skip(self), fields(id = ?aggregate_id, version))]
+    async fn load_raw_at_or_before(
+        &self,
+        aggregate_id: &A::Id,
+        version: i64,
+    ) -> Result<Option<RawSnapshot>> {
+        let row: Option<SnapshotRow> = sqlx::query_as(
+            r#"
+            SELECT version, snapshot_version, payload, payload_bytes, payload_compression,
+                created_at
+            FROM snapshots
+            WHERE aggregate_type = $1 AND aggregate_id = $2 AND version <= $3
+            UNION ALL
+            SELECT version, snapshot_version, payload, payload_bytes, payload_compression,
+                created_at
+            FROM snapshot_history
+            WHERE aggregate_type = $1 AND aggregate_id = $2 AND version <= $3
+            ORDER BY version DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(A::TYPE_NAME)
+        .bind(aggregate_id.to_string())
+        .bind(version)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+
+        row.map(|row| row.into_raw(aggregate_id.to_string()))
+            .transpose()
+    }
+}
+
+/// A `sqlx`-backed projection checkpoint store for PostgreSQL.
+#[derive(Debug, Clone)]
+pub struct SqlxCheckpointStore {
+    pool: PgPool,
+}
+
+impl SqlxCheckpointStore {
+    /// Creates a new `SqlxCheckpointStore`.
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Ensures the `projection_checkpoints` table exists.
+    #[instrument(skip(self))]
+    pub async fn setup(&self) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS projection_checkpoints (
+                    projection TEXT PRIMARY KEY,
+                    position BIGINT NOT NULL,
+                    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+                );
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl CheckpointStore for SqlxCheckpointStore {
+    #[instrument(skip(self))]
+    async fn load(&self, projection: &str) -> Result<Option<i64>> {
+        sqlx::query_scalar("SELECT position FROM projection_checkpoints WHERE projection = $1")
+            .bind(projection)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(to_store_error)
+    }
+
+    #[instrument(skip(self))]
+    async fn save(&self, projection: &str, position: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO projection_checkpoints (projection, position)
+            VALUES ($1, $2)
+            ON CONFLICT (projection) DO UPDATE
+            SET position = EXCLUDED.position,
+                updated_at = NOW();
+            "#,
+        )
+        .bind(projection)
+        .bind(position)
+        .execute(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn delete(&self, projection: &str) -> Result<()> {
+        sqlx::query("DELETE FROM projection_checkpoints WHERE projection = $1")
+            .bind(projection)
+            .execute(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+        Ok(())
+    }
+}
+
+/// A `sqlx`-backed encryption key store for PostgreSQL.
+///
+/// Forgetting a key deletes its row; it may survive in backups and in the
+/// write-ahead log until those are rotated.
+#[cfg(feature = "encryption")]
+#[derive(Debug, Clone)]
+pub struct SqlxKeyStore {
+    pool: PgPool,
+}
+
+#[cfg(feature = "encryption")]
+impl SqlxKeyStore {
+    /// Creates a new `SqlxKeyStore`.
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Ensures the `encryption_keys` table exists.
+    #[instrument(skip(self))]
+    pub async fn setup(&self) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS encryption_keys (
+                    subject TEXT PRIMARY KEY,
+                    key BYTEA NOT NULL,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+                );
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "encryption")]
+#[async_trait::async_trait]
+impl KeyStore for SqlxKeyStore {
+    #[instrument(skip(self))]
+    async fn load(&self, subject: &str) -> Result<Option<Key>> {
+        let key: Option<Vec<u8>> =
+            sqlx::query_scalar("SELECT key FROM encryption_keys WHERE subject = $1")
+                .bind(subject)
                 .fetch_optional(&self.pool)
                 .await
                 .map_err(to_store_error)?;
+        key.map(|key| Key::try_from(key.as_slice())).transpose()
+    }
 
-        match row {
-            Some((version, payload)) => {
-                let snapshot: A::Snapshot =
-                    serde_json::from_value(payload).map_err(to_serde_error)?;
-                Ok(Some(StoredSnapshot::new(
-                    aggregate_id.to_string(),
-                    version,
-                    snapshot,
-                )))
-            }
-            None => Ok(None),
-        }
+    #[instrument(skip(self))]
+    async fn load_or_create(&self, subject: &str) -> Result<Key> {
+        // The no-op update makes a conflicting insert return the stored key.
+        let key: Vec<u8> = sqlx::query_scalar(
+            r#"
+            INSERT INTO encryption_keys (subject, key)
+            VALUES ($1, $2)
+            ON CONFLICT (subject) DO UPDATE
+            SET key = encryption_keys.key
+            RETURNING key;
+            "#,
+        )
+        .bind(subject)
+        .bind(&Key::generate().as_bytes()[..])
+        .fetch_one(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        Key::try_from(key.as_slice())
+    }
+
+    #[instrument(skip(self))]
+    async fn forget(&self, subject: &str) -> Result<()> {
+        sqlx::query("DELETE FROM encryption_keys WHERE subject = $1")
+            .bind(subject)
+            .execute(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+        Ok(())
     }
 }
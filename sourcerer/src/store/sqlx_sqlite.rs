@@ -0,0 +1,858 @@
+//! A `sqlx` implementation of the `sourcerer` store traits for SQLite.
+//!
+//! This module provides `sqlx`-based implementations of the `EventStore`,
+//! `SnapshotStore` and `CheckpointStore` traits, designed for SQLite and
+//! mirroring the Postgres stores. Compile it with the `sqlite-storage` cargo
+//! feature.
+#![allow(clippy::missing_errors_doc)]
+
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    Aggregate, Error, Event, EventMetadata, EventStore, ExpectedVersion, Result, Snapshot,
+    StoredEvent,
+    projection::CheckpointStore,
+    serializer::{EventSerializer, Format, JsonSerializer},
+    snapshot::{RawSnapshot, SnapshotStore, StoredSnapshot},
+    store::idempotency,
+    subscription::{self, EventSubscription, Page, SubscriptionFilter},
+    upcaster,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::{StreamExt, channel::mpsc, stream::BoxStream};
+use serde::{Serialize, de::DeserializeOwned};
+use sqlx::{SqlitePool, types::Json};
+use tracing::instrument;
+use uuid::Uuid;
+
+/// Maps `sqlx::Error` into this crate's `Error`.
+fn to_store_error(e: sqlx::Error) -> Error {
+    Error::Store(e.to_string())
+}
+
+/// Maps `serde_json::Error` into this crate's `Error`.
+fn to_serde_error(e: serde_json::Error) -> Error {
+    Error::Store(e.to_string())
+}
+
+/// Expands to the columns selected for every event read, matching
+/// [`EventRow`].
+macro_rules! event_columns {
+    () => {
+        "aggregate_type, aggregate_id, version, event_version, event_type, payload_format, \
+         payload, position, event_id, created_at, correlation_id, causation_id, headers, hash"
+    };
+}
+
+/// Selects an aggregate's events after a version, in version order.
+const STREAM_QUERY: &str = concat!(
+    "SELECT ",
+    event_columns!(),
+    " FROM events WHERE aggregate_type = $1 AND aggregate_id = $2 AND version > $3 \
+     ORDER BY version"
+);
+
+/// Selects a page of an aggregate type's events after a position, in
+/// position order.
+const READ_ALL_QUERY: &str = concat!(
+    "SELECT ",
+    event_columns!(),
+    " FROM events WHERE aggregate_type = $1 AND position > $2 ORDER BY position LIMIT $3"
+);
+
+/// Selects the events at the positions listed in a JSON array, in position
+/// order.
+const EVENTS_AT_QUERY: &str = concat!(
+    "SELECT ",
+    event_columns!(),
+    " FROM events WHERE position IN (SELECT value FROM json_each($1)) ORDER BY position"
+);
+
+/// A row of the `events` table.
+#[derive(sqlx::FromRow)]
+struct EventRow {
+    aggregate_type: String,
+    aggregate_id: String,
+    version: i64,
+    event_version: i64,
+    event_type: String,
+    payload_format: String,
+    payload: Vec<u8>,
+    position: i64,
+    event_id: Uuid,
+    created_at: DateTime<Utc>,
+    correlation_id: Option<String>,
+    causation_id: Option<String>,
+    headers: Json<BTreeMap<String, String>>,
+    hash: Option<String>,
+}
+
+impl EventRow {
+    /// Splits the metadata envelope off the row.
+    fn metadata(&mut self) -> EventMetadata {
+        EventMetadata::from_parts(
+            self.event_id,
+            self.created_at,
+            self.correlation_id.take(),
+            self.causation_id.take(),
+            std::mem::take(&mut self.headers.0),
+        )
+    }
+
+    /// Deserializes the row into a stored event.
+    fn into_stored<E: Event, S: EventSerializer>(
+        mut self,
+        serializer: &S,
+    ) -> Result<StoredEvent<E>> {
+        let metadata = self.metadata();
+        let format: Format = self.payload_format.parse()?;
+        let event: E = serializer.decode(format, &self.payload)?;
+        Ok(StoredEvent::new(
+            self.aggregate_id,
+            self.version,
+            self.event_version as u16,
+            self.event_type,
+            event,
+        )
+        .with_aggregate_type(self.aggregate_type)
+        .with_position(self.position)
+        .with_metadata(metadata)
+        .with_recorded_hash(self.hash))
+    }
+
+    /// Converts the row into a raw stored event for upcasting.
+    fn into_raw(mut self) -> Result<upcaster::RawStoredEvent> {
+        let metadata = self.metadata();
+        Ok(upcaster::RawStoredEvent {
+            aggregate_type: self.aggregate_type,
+            aggregate_id: self.aggregate_id,
+            version: self.version,
+            event_version: self.event_version as u16,
+            event_type: self.event_type,
+            format: self.payload_format.parse()?,
+            payload: self.payload,
+            position: self.position,
+            metadata,
+            hash: self.hash,
+        })
+    }
+}
+
+/// A `sqlx`-backed event store for SQLite.
+///
+/// SQLite has no `LISTEN`/`NOTIFY`, so subscriptions are woken in-process:
+/// they only follow appends made through this store or one of its clones.
+///
+/// Event payloads are encoded by `S`, JSON by default. JSON payloads are
+/// stored as text, and every other format as a blob.
+pub struct SqliteEventStore<A: Aggregate, S: EventSerializer = JsonSerializer> {
+    pool: SqlitePool,
+    serializer: S,
+    /// Live subscriptions, signalled after every append.
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<()>>>>,
+    idempotency_retention: Duration,
+    _phantom: PhantomData<A>,
+}
+
+impl<A: Aggregate, S: EventSerializer> Clone for SqliteEventStore<A, S> {
+    /// Returns a handle sharing the same pool and subscriptions.
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            serializer: self.serializer.clone(),
+            subscribers: Arc::clone(&self.subscribers),
+            idempotency_retention: self.idempotency_retention,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<A: Aggregate> SqliteEventStore<A> {
+    /// Creates a new `SqliteEventStore` writing JSON payloads.
+    pub fn new(pool: SqlitePool) -> Self {
+        Self {
+            pool,
+            serializer: JsonSerializer,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            idempotency_retention: idempotency::DEFAULT_RETENTION,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<A: Aggregate, S: EventSerializer> SqliteEventStore<A, S> {
+    /// Sets the serializer used to encode the payloads of appended events.
+    ///
+    /// Events already stored keep the format they were written in.
+    pub fn with_serializer<T: EventSerializer>(self, serializer: T) -> SqliteEventStore<A, T> {
+        SqliteEventStore {
+            pool: self.pool,
+            serializer,
+            subscribers: self.subscribers,
+            idempotency_retention: self.idempotency_retention,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Sets how long the store remembers the idempotency key of an append.
+    ///
+    /// Defaults to 24 hours. Expired keys are removed by later appends with
+    /// an idempotency key.
+    #[must_use]
+    pub fn with_idempotency_retention(mut self, retention: Duration) -> Self {
+        self.idempotency_retention = retention;
+        self
+    }
+
+    /// Ensures the `events` and `idempotency_keys` tables exist.
+    ///
+    /// Streams are keyed by aggregate type and ID, so every aggregate type can
+    /// share the one table.
+    #[instrument(skip(self))]
+    pub async fn setup(&self) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS events (
+                    position INTEGER PRIMARY KEY AUTOINCREMENT,
+                    aggregate_type TEXT NOT NULL,
+                    aggregate_id TEXT NOT NULL,
+                    version INTEGER NOT NULL,
+                    event_version INTEGER NOT NULL,
+                    event_type TEXT NOT NULL,
+                    payload_format TEXT NOT NULL DEFAULT 'json',
+                    payload BLOB NOT NULL,
+                    event_id BLOB NOT NULL UNIQUE,
+                    created_at TEXT NOT NULL,
+                    correlation_id TEXT,
+                    causation_id TEXT,
+                    headers TEXT NOT NULL DEFAULT '{}',
+                    hash TEXT,
+                    UNIQUE (aggregate_type, aggregate_id, version)
+                );
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // Tables created before events were hashed lack the `hash` column.
+        let hashed: bool = sqlx::query_scalar(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('events') WHERE name = 'hash'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if !hashed {
+            sqlx::query("ALTER TABLE events ADD COLUMN hash TEXT")
+                .execute(&self.pool)
+                .await?;
+        }
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS events_type_position ON events (aggregate_type, position)",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS idempotency_keys (
+                    aggregate_type TEXT NOT NULL,
+                    idempotency_key TEXT NOT NULL,
+                    recorded_at TEXT NOT NULL,
+                    positions TEXT NOT NULL,
+                    PRIMARY KEY (aggregate_type, idempotency_key)
+                );
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idempotency_keys_type_recorded_at \
+             ON idempotency_keys (aggregate_type, recorded_at)",
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Checks the version of one stream and appends events to it inside an
+    /// append transaction, returning the stored events in version order.
+    async fn append_to_stream(
+        &self,
+        conn: &mut sqlx::SqliteConnection,
+        aggregate_id: String,
+        expected_version: ExpectedVersion,
+        events: Vec<A::Event>,
+        metadata: &EventMetadata,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        // Optimistic concurrency check, reading the hash the new events chain
+        // on to.
+        let head: Option<(i64, Option<String>)> = sqlx::query_as(
+            "SELECT version, hash FROM events WHERE aggregate_type = $1 AND aggregate_id = $2 \
+             ORDER BY version DESC LIMIT 1",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(&aggregate_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(to_store_error)?;
+
+        let (current_version, mut previous) = head.unwrap_or((0, None));
+        if !expected_version.matches(current_version) {
+            return Err(Error::Conflict {
+                aggregate_id,
+                expected: expected_version,
+                actual: current_version,
+            });
+        }
+
+        let format = self.serializer.format();
+        let mut stored_events = Vec::with_capacity(events.len());
+        let mut version = current_version;
+        for event in events {
+            version += 1;
+            let stored_event = StoredEvent::new(
+                aggregate_id.clone(),
+                version,
+                event.event_version(),
+                event.event_type().to_string(),
+                event,
+            )
+            .with_aggregate_type(A::TYPE_NAME)
+            .with_metadata(metadata.stamp(now))
+            .chained(previous.as_deref())?;
+            previous = stored_event.hash().map(str::to_owned);
+
+            let payload = self.serializer.serialize(stored_event.event())?;
+            // JSON payloads are stored as text, so they stay readable.
+            let (json_payload, binary_payload) = if format == Format::Json {
+                let json = String::from_utf8(payload).map_err(|e| Error::Store(e.to_string()))?;
+                (Some(json), None)
+            } else {
+                (None, Some(payload))
+            };
+            let metadata = stored_event.metadata();
+            let result = sqlx::query(
+                r#"
+                INSERT INTO events (
+                    aggregate_type, aggregate_id, version, payload_format, payload, event_type,
+                    event_version, event_id, created_at, correlation_id, causation_id, headers,
+                    hash
+                )
+                VALUES ($1, $2, $3, $4, COALESCE($5, $6), $7, $8, $9, $10, $11, $12, $13, $14)
+                "#,
+            )
+            .bind(A::TYPE_NAME)
+            .bind(&aggregate_id)
+            .bind(version)
+            .bind(format.as_str())
+            .bind(json_payload)
+            .bind(binary_payload)
+            .bind(stored_event.event_type())
+            .bind(i64::from(stored_event.event_version()))
+            .bind(metadata.event_id())
+            .bind(metadata.recorded_at())
+            .bind(metadata.correlation_id())
+            .bind(metadata.causation_id())
+            .bind(Json(metadata.headers()))
+            .bind(stored_event.hash())
+            .execute(&mut *conn)
+            .await
+            .map_err(to_store_error)?;
+
+            stored_events.push(stored_event.with_position(result.last_insert_rowid()));
+        }
+
+        Ok(stored_events)
+    }
+
+    /// Wakes every live subscription, dropping those that have gone away.
+    fn notify_subscribers(&self) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|tx| tx.unbounded_send(()).is_ok());
+        }
+    }
+}
+
+/// Reads the next page of the global log after `cursor` for a subscription,
+/// applying the filter in the query.
+async fn read_page<E: Event, S: EventSerializer>(
+    pool: &SqlitePool,
+    serializer: &S,
+    aggregate_type: &str,
+    cursor: i64,
+    filter: &SubscriptionFilter,
+) -> Result<Page<E>> {
+    // SQLite has no array parameters, so the filter lists are passed as JSON.
+    let event_types = filter.event_types().map(Json);
+    let aggregate_ids = filter.aggregate_ids().map(Json);
+    let rows: Vec<EventRow> = sqlx::query_as(concat!(
+        "SELECT ",
+        event_columns!(),
+        r#"
+        FROM events
+        WHERE aggregate_type = $1
+          AND position > $2
+          AND ($3 IS NULL OR event_type IN (SELECT value FROM json_each($3)))
+          AND ($4 IS NULL OR aggregate_id IN (SELECT value FROM json_each($4)))
+        ORDER BY position
+        LIMIT $5
+        "#
+    ))
+    .bind(aggregate_type)
+    .bind(cursor)
+    .bind(event_types)
+    .bind(aggregate_ids)
+    .bind(subscription::BATCH_SIZE as i64)
+    .fetch_all(pool)
+    .await
+    .map_err(to_store_error)?;
+
+    let events = rows
+        .into_iter()
+        .map(|row| row.into_stored(serializer))
+        .collect::<Result<Vec<StoredEvent<E>>>>()?;
+    Ok(Page {
+        cursor: events.last().map_or(cursor, StoredEvent::position),
+        events,
+    })
+}
+
+#[async_trait::async_trait]
+impl<A, S> EventStore<A> for SqliteEventStore<A, S>
+where
+    A: Aggregate,
+    S: EventSerializer,
+    A::Event: Serialize + DeserializeOwned + Send + Sync,
+    A::Id: Clone + Serialize + Send + Sync,
+{
+    #[instrument(skip(self, events, metadata), fields(id = ?id))]
+    async fn append(
+        &self,
+        id: &A::Id,
+        expected_version: ExpectedVersion,
+        events: Vec<A::Event>,
+        metadata: EventMetadata,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        self.append_batch(vec![(id.clone(), expected_version, events)], metadata)
+            .await
+    }
+
+    #[instrument(skip(self, batch, metadata), fields(streams = batch.len()))]
+    async fn append_batch(
+        &self,
+        batch: Vec<(A::Id, ExpectedVersion, Vec<A::Event>)>,
+        metadata: EventMetadata,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        if batch.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let now = Utc::now();
+
+        // `BEGIN IMMEDIATE` takes SQLite's write lock up front, so the version
+        // checks and the inserts cannot interleave with another writer.
+        let mut tx = self
+            .pool
+            .begin_with("BEGIN IMMEDIATE")
+            .await
+            .map_err(to_store_error)?;
+
+        // A repeated idempotency key returns the events it first appended.
+        let idempotency_key = metadata.idempotency_key();
+        if let Some(key) = idempotency_key {
+            sqlx::query(
+                "DELETE FROM idempotency_keys WHERE aggregate_type = $1 AND recorded_at < $2",
+            )
+            .bind(A::TYPE_NAME)
+            .bind(idempotency::cutoff(now, self.idempotency_retention))
+            .execute(&mut *tx)
+            .await
+            .map_err(to_store_error)?;
+            let positions: Option<String> = sqlx::query_scalar(
+                "SELECT positions FROM idempotency_keys \
+                 WHERE aggregate_type = $1 AND idempotency_key = $2",
+            )
+            .bind(A::TYPE_NAME)
+            .bind(key)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(to_store_error)?;
+            if let Some(positions) = positions {
+                let rows: Vec<EventRow> = sqlx::query_as(EVENTS_AT_QUERY)
+                    .bind(positions)
+                    .fetch_all(&mut *tx)
+                    .await
+                    .map_err(to_store_error)?;
+                tx.commit().await.map_err(to_store_error)?;
+                return rows
+                    .into_iter()
+                    .map(|row| row.into_stored(&self.serializer))
+                    .collect();
+            }
+        }
+
+        // Dropping the transaction on an error rolls back every entry.
+        let mut stored_events = Vec::new();
+        for (id, expected_version, events) in batch {
+            stored_events.extend(
+                self.append_to_stream(
+                    &mut tx,
+                    id.to_string(),
+                    expected_version,
+                    events,
+                    &metadata,
+                    now,
+                )
+                .await?,
+            );
+        }
+
+        if let Some(key) = idempotency_key {
+            let positions: Vec<i64> = stored_events.iter().map(StoredEvent::position).collect();
+            sqlx::query(
+                "INSERT INTO idempotency_keys (aggregate_type, idempotency_key, recorded_at, \
+                 positions) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(A::TYPE_NAME)
+            .bind(key)
+            .bind(now)
+            .bind(Json(positions))
+            .execute(&mut *tx)
+            .await
+            .map_err(to_store_error)?;
+        }
+
+        tx.commit().await.map_err(to_store_error)?;
+
+        self.notify_subscribers();
+
+        Ok(stored_events)
+    }
+
+    #[instrument(skip(self), fields(id = ?id))]
+    async fn load(&self, id: &A::Id) -> Result<Vec<StoredEvent<A::Event>>> {
+        self.load_from(id, 0).await
+    }
+
+    #[instrument(skip(self), fields(id = ?id, version))]
+    async fn load_from(&self, id: &A::Id, version: i64) -> Result<Vec<StoredEvent<A::Event>>> {
+        let rows: Vec<EventRow> = sqlx::query_as(STREAM_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(id.to_string())
+            .bind(version)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+
+        rows.into_iter()
+            .map(|row| row.into_stored(&self.serializer))
+            .collect()
+    }
+
+    fn stream_from<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<StoredEvent<A::Event>>> {
+        sqlx::query_as::<_, EventRow>(STREAM_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(id.to_string())
+            .bind(version)
+            .fetch(&self.pool)
+            .map(|row| row.map_err(to_store_error)?.into_stored(&self.serializer))
+            .boxed()
+    }
+
+    fn stream_raw<'a>(
+        &'a self,
+        id: &'a A::Id,
+        version: i64,
+    ) -> BoxStream<'a, Result<upcaster::RawStoredEvent>> {
+        sqlx::query_as::<_, EventRow>(STREAM_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(id.to_string())
+            .bind(version)
+            .fetch(&self.pool)
+            .map(|row| row.map_err(to_store_error)?.into_raw())
+            .boxed()
+    }
+
+    #[instrument(skip(self), fields(id = ?id, version))]
+    async fn load_raw(&self, id: &A::Id, version: i64) -> Result<Vec<upcaster::RawStoredEvent>> {
+        let rows: Vec<EventRow> = sqlx::query_as(STREAM_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(id.to_string())
+            .bind(version)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+
+        rows.into_iter().map(EventRow::into_raw).collect()
+    }
+
+    #[instrument(skip(self), fields(from_position, limit))]
+    async fn read_all(
+        &self,
+        from_position: i64,
+        limit: usize,
+    ) -> Result<Vec<StoredEvent<A::Event>>> {
+        let rows: Vec<EventRow> = sqlx::query_as(READ_ALL_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(from_position)
+            .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+
+        rows.into_iter()
+            .map(|row| row.into_stored(&self.serializer))
+            .collect()
+    }
+
+    #[instrument(skip(self), fields(from_position, limit))]
+    async fn read_all_raw(
+        &self,
+        from_position: i64,
+        limit: usize,
+    ) -> Result<Vec<upcaster::RawStoredEvent>> {
+        let rows: Vec<EventRow> = sqlx::query_as(READ_ALL_QUERY)
+            .bind(A::TYPE_NAME)
+            .bind(from_position)
+            .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+
+        rows.into_iter().map(EventRow::into_raw).collect()
+    }
+
+    #[instrument(skip(self))]
+    async fn list_aggregate_ids(&self) -> Result<Vec<String>> {
+        sqlx::query_scalar(
+            "SELECT DISTINCT aggregate_id FROM events WHERE aggregate_type = $1 ORDER BY aggregate_id",
+        )
+        .bind(A::TYPE_NAME)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(to_store_error)
+    }
+
+    #[instrument(skip(self, filter), fields(from_position))]
+    async fn subscribe(
+        &self,
+        from_position: i64,
+        filter: SubscriptionFilter,
+    ) -> Result<EventSubscription<A::Event>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers
+            .lock()
+            .map_err(|e| Error::Store(e.to_string()))?
+            .push(tx);
+
+        let pool = self.pool.clone();
+        let serializer = self.serializer.clone();
+        Ok(subscription::catch_up(
+            from_position,
+            move |cursor| {
+                let pool = pool.clone();
+                let serializer = serializer.clone();
+                let filter = filter.clone();
+                async move { read_page(&pool, &serializer, A::TYPE_NAME, cursor, &filter).await }
+            },
+            rx,
+        ))
+    }
+}
+
+/// A `sqlx`-backed snapshot store for SQLite.
+#[derive(Debug, Clone)]
+pub struct SqliteSnapshotStore<A: Aggregate> {
+    pool: SqlitePool,
+    _phantom: PhantomData<A>,
+}
+
+impl<A: Aggregate> SqliteSnapshotStore<A> {
+    /// Creates a new `SqliteSnapshotStore`.
+    pub fn new(pool: SqlitePool) -> Self {
+        Self {
+            pool,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Ensures the `snapshots` table exists.
+    #[instrument(skip(self))]
+    pub async fn setup(&self) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS snapshots (
+                    aggregate_type TEXT NOT NULL,
+                    aggregate_id TEXT NOT NULL,
+                    version INTEGER NOT NULL,
+                    snapshot_version INTEGER NOT NULL DEFAULT 1,
+                    payload TEXT NOT NULL,
+                    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (aggregate_type, aggregate_id)
+                );
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // Tables created before snapshots were versioned hold version 1.
+        let versioned: bool = sqlx::query_scalar(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('snapshots') \
+             WHERE name = 'snapshot_version'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if !versioned {
+            sqlx::query(
+                "ALTER TABLE snapshots ADD COLUMN snapshot_version INTEGER NOT NULL DEFAULT 1",
+            )
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<A> SnapshotStore<A> for SqliteSnapshotStore<A>
+where
+    A: Aggregate,
+    A::Snapshot: Serialize + DeserializeOwned + Send + Sync,
+    A::Id: Clone + Serialize + Send + Sync,
+{
+    #[instrument(skip(self, snapshot), fields(id = ?aggregate_id))]
+    async fn save(&self, aggregate_id: &A::Id, version: i64, snapshot: A::Snapshot) -> Result<()> {
+        let payload = serde_json::to_value(snapshot).map_err(to_serde_error)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO snapshots (aggregate_type, aggregate_id, version, payload, snapshot_version)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (aggregate_type, aggregate_id) DO UPDATE
+            SET version = excluded.version,
+                snapshot_version = excluded.snapshot_version,
+                payload = excluded.payload,
+                created_at = CURRENT_TIMESTAMP
+            WHERE snapshots.version <= excluded.version;
+            "#,
+        )
+        .bind(A::TYPE_NAME)
+        .bind(aggregate_id.to_string())
+        .bind(version)
+        .bind(Json(payload))
+        .bind(i64::from(A::Snapshot::snapshot_version()))
+        .execute(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), fields(id = ?aggregate_id))]
+    async fn load(&self, aggregate_id: &A::Id) -> Result<Option<StoredSnapshot<A::Snapshot>>> {
+        self.load_raw(aggregate_id)
+            .await?
+            .map(RawSnapshot::decode)
+            .transpose()
+    }
+
+    #[instrument(skip(self), fields(id = ?aggregate_id))]
+    async fn load_raw(&self, aggregate_id: &A::Id) -> Result<Option<RawSnapshot>> {
+        let row: Option<(i64, i64, Json<serde_json::Value>, NaiveDateTime)> = sqlx::query_as(
+            "SELECT version, snapshot_version, payload, created_at FROM snapshots \
+             WHERE aggregate_type = $1 AND aggregate_id = $2",
+        )
+        .bind(A::TYPE_NAME)
+        .bind(aggregate_id.to_string())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+
+        match row {
+            Some((version, snapshot_version, payload, created_at)) => Ok(Some(RawSnapshot {
+                aggregate_id: aggregate_id.to_string(),
+                version,
+                snapshot_version: snapshot_version as u16,
+                taken_at: Some(created_at.and_utc()),
+                payload: payload.0,
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A `sqlx`-backed projection checkpoint store for SQLite.
+#[derive(Debug, Clone)]
+pub struct SqliteCheckpointStore {
+    pool: SqlitePool,
+}
+
+impl SqliteCheckpointStore {
+    /// Creates a new `SqliteCheckpointStore`.
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    /// Ensures the `projection_checkpoints` table exists.
+    #[instrument(skip(self))]
+    pub async fn setup(&self) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS projection_checkpoints (
+                    projection TEXT PRIMARY KEY,
+                    position INTEGER NOT NULL,
+                    updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+                );
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl CheckpointStore for SqliteCheckpointStore {
+    #[instrument(skip(self))]
+    async fn load(&self, projection: &str) -> Result<Option<i64>> {
+        sqlx::query_scalar("SELECT position FROM projection_checkpoints WHERE projection = $1")
+            .bind(projection)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(to_store_error)
+    }
+
+    #[instrument(skip(self))]
+    async fn save(&self, projection: &str, position: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO projection_checkpoints (projection, position)
+            VALUES ($1, $2)
+            ON CONFLICT (projection) DO UPDATE
+            SET position = excluded.position,
+                updated_at = CURRENT_TIMESTAMP;
+            "#,
+        )
+        .bind(projection)
+        .bind(position)
+        .execute(&self.pool)
+        .await
+        .map_err(to_store_error)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn delete(&self, projection: &str) -> Result<()> {
+        sqlx::query("DELETE FROM projection_checkpoints WHERE projection = $1")
+            .bind(projection)
+            .execute(&self.pool)
+            .await
+            .map_err(to_store_error)?;
+        Ok(())
+    }
+}
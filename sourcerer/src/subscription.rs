@@ -0,0 +1,182 @@
+//! Catch-up subscriptions over the global event log.
+//!
+//! A subscription first replays every stored event after a given position and
+//! then keeps yielding new events as they are appended. Stores only signal
+//! that *something* was appended; the subscription always re-reads the log
+//! from the last position it delivered. Missed or coalesced signals, dropped
+//! connections and slow consumers therefore never cause events to be skipped
+//! or delivered out of order.
+use std::{collections::VecDeque, future::Future};
+
+use async_trait::async_trait;
+use futures::{StreamExt, channel::mpsc, stream::BoxStream};
+
+use crate::{Event, Result, StoredEvent};
+
+/// A live stream of stored events returned by
+/// [`EventStore::subscribe`](crate::EventStore::subscribe).
+///
+/// The stream ends after yielding an error. Resubscribe from the position of
+/// the last event you processed to resume.
+pub type EventSubscription<E> = BoxStream<'static, Result<StoredEvent<E>>>;
+
+/// The maximum number of log entries read per round-trip while catching up.
+pub(crate) const BATCH_SIZE: usize = 256;
+
+/// Server-side filter applied to a subscription.
+///
+/// An empty filter matches every event. Setting both event types and
+/// aggregate IDs only matches events satisfying both.
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionFilter {
+    event_types: Option<Vec<String>>,
+    aggregate_ids: Option<Vec<String>>,
+}
+
+impl SubscriptionFilter {
+    /// Creates a filter that matches every event.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restricts the subscription to the given event types.
+    #[must_use]
+    pub fn with_event_types<I, S>(mut self, event_types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.event_types = Some(event_types.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Restricts the subscription to the given aggregates.
+    #[must_use]
+    pub fn with_aggregate_ids<I, T>(mut self, aggregate_ids: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: ToString,
+    {
+        self.aggregate_ids = Some(aggregate_ids.into_iter().map(|id| id.to_string()).collect());
+        self
+    }
+
+    /// Returns the event types this filter is restricted to, if any.
+    pub fn event_types(&self) -> Option<&[String]> {
+        self.event_types.as_deref()
+    }
+
+    /// Returns the aggregate IDs this filter is restricted to, if any.
+    pub fn aggregate_ids(&self) -> Option<&[String]> {
+        self.aggregate_ids.as_deref()
+    }
+
+    /// Returns `true` if the given event passes this filter.
+    pub fn matches<E: Event>(&self, event: &StoredEvent<E>) -> bool {
+        self.event_types
+            .as_ref()
+            .is_none_or(|types| types.iter().any(|t| t == event.event_type()))
+            && self
+                .aggregate_ids
+                .as_ref()
+                .is_none_or(|ids| ids.iter().any(|id| id == event.aggregate_id()))
+    }
+}
+
+/// A page of the global log read while catching up.
+pub(crate) struct Page<E: Event> {
+    /// The events on this page that passed the subscription filter.
+    pub events: Vec<StoredEvent<E>>,
+    /// The last position scanned, including events rejected by the filter.
+    pub cursor: i64,
+}
+
+/// Waits for a store to signal that new events may have been appended.
+#[async_trait]
+pub(crate) trait Wakeup: Send + 'static {
+    /// Resolves once new events may be available.
+    ///
+    /// Returns `Ok(false)` when no further appends can ever be observed, for
+    /// example because the store was dropped.
+    async fn wait(&mut self) -> Result<bool>;
+}
+
+#[async_trait]
+impl Wakeup for mpsc::UnboundedReceiver<()> {
+    async fn wait(&mut self) -> Result<bool> {
+        if self.next().await.is_none() {
+            return Ok(false);
+        }
+        // Coalesce signals that queued up while the consumer was busy; the
+        // next read covers all of them.
+        while let Ok(Some(())) = self.try_next() {}
+        Ok(true)
+    }
+}
+
+struct State<R, W, E: Event> {
+    cursor: i64,
+    buffer: VecDeque<StoredEvent<E>>,
+    caught_up: bool,
+    done: bool,
+    read_page: R,
+    wakeup: W,
+}
+
+/// Builds a subscription from a page reader and a wakeup source.
+///
+/// The wakeup source must be registered before this is called so no append
+/// between the initial read and the first wait goes unnoticed.
+pub(crate) fn catch_up<E, R, Fut, W>(
+    from_position: i64,
+    read_page: R,
+    wakeup: W,
+) -> EventSubscription<E>
+where
+    E: Event + 'static,
+    R: Fn(i64) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Page<E>>> + Send,
+    W: Wakeup,
+{
+    let state = State {
+        cursor: from_position,
+        buffer: VecDeque::new(),
+        caught_up: false,
+        done: false,
+        read_page,
+        wakeup,
+    };
+
+    futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if state.done {
+                return None;
+            }
+            if let Some(event) = state.buffer.pop_front() {
+                return Some((Ok(event), state));
+            }
+            if state.caught_up {
+                match state.wakeup.wait().await {
+                    Ok(true) => state.caught_up = false,
+                    Ok(false) => return None,
+                    Err(e) => {
+                        state.done = true;
+                        return Some((Err(e), state));
+                    }
+                }
+            }
+            match (state.read_page)(state.cursor).await {
+                Ok(page) => {
+                    state.caught_up = page.cursor <= state.cursor;
+                    state.cursor = page.cursor.max(state.cursor);
+                    state.buffer.extend(page.events);
+                }
+                Err(e) => {
+                    state.done = true;
+                    return Some((Err(e), state));
+                }
+            }
+        }
+    })
+    .boxed()
+}
@@ -1,11 +1,19 @@
-//! Defines the upcasting mechanism for handling event schema versioning.
+//! Defines the upcasting mechanism for handling event and snapshot schema
+//! versioning.
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 
-use crate::{Event, Result};
+use crate::{
+    Error, Event, EventMetadata, Result, Snapshot, StoredEvent,
+    serializer::{EventSerializer, Format, JsonSerializer},
+    snapshot::{RawSnapshot, StoredSnapshot},
+};
 
 /// A raw, stored event, used for upcasting before deserialization.
 #[derive(Debug)]
 pub struct RawStoredEvent {
+    /// The type name of the aggregate this event belongs to.
+    pub aggregate_type: String,
     /// The ID of the aggregate this event belongs to.
     pub aggregate_id: String,
     /// The version of the aggregate after this event was applied.
@@ -14,8 +22,60 @@ pub struct RawStoredEvent {
     pub event_version: u16,
     /// The type of the event.
     pub event_type: String,
-    /// The event payload itself.
-    pub payload: Value,
+    /// The format the payload is encoded in.
+    pub format: Format,
+    /// The encoded event payload.
+    pub payload: Vec<u8>,
+    /// The position of the event in the store's global log.
+    pub position: i64,
+    /// The metadata recorded alongside the event.
+    pub metadata: EventMetadata,
+    /// The hash of the event, chained to the previous event in the stream.
+    pub hash: Option<String>,
+}
+
+impl RawStoredEvent {
+    /// Encodes a stored event into its raw form with `serializer`.
+    pub(crate) fn encode<E: Event, S: EventSerializer>(
+        stored: &StoredEvent<E>,
+        serializer: &S,
+    ) -> Result<Self> {
+        Ok(Self {
+            aggregate_type: stored.aggregate_type().to_string(),
+            aggregate_id: stored.aggregate_id().to_string(),
+            version: stored.version(),
+            event_version: stored.event_version(),
+            event_type: stored.event_type().to_string(),
+            format: serializer.format(),
+            payload: serializer.serialize(stored.event())?,
+            position: stored.position(),
+            metadata: stored.metadata().clone(),
+            hash: stored.hash().map(str::to_owned),
+        })
+    }
+
+    /// Decodes the payload, for example into the event type or a JSON
+    /// [`Value`].
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T> {
+        self.format.decode(&self.payload)
+    }
+
+    /// Decodes the payload into the event type and converts the envelope into
+    /// a stored event, without upcasting it.
+    pub fn into_stored<E: Event>(self) -> Result<StoredEvent<E>> {
+        let event: E = self.decode()?;
+        Ok(StoredEvent::new(
+            self.aggregate_id,
+            self.version,
+            self.event_version,
+            self.event_type,
+            event,
+        )
+        .with_aggregate_type(self.aggregate_type)
+        .with_position(self.position)
+        .with_metadata(self.metadata)
+        .with_recorded_hash(self.hash))
+    }
 }
 
 /// Defines the interface for an upcaster.
@@ -67,26 +127,142 @@ impl<E: Event> UpcasterChain<E> {
     ///
     /// It will continue to apply upcasters until the event's version matches
     /// the latest version known to the application.
+    /// The payload is only decoded into JSON if an upcaster applies, in which
+    /// case the upcast event is re-encoded as JSON.
     pub(crate) fn upcast(&self, event: RawStoredEvent) -> Result<RawStoredEvent> {
-        let mut current_version = event.event_version;
-        let mut payload = event.payload;
-        let event_type = event.event_type.clone();
-
-        while let Some(upcaster) = self
-            .upcasters
-            .iter()
-            .find(|u| u.event_type() == event_type && u.source_version() == current_version)
-        {
+        let find = |version: u16| {
+            self.upcasters
+                .iter()
+                .find(|u| u.event_type() == event.event_type && u.source_version() == version)
+        };
+        let Some(mut upcaster) = find(event.event_version) else {
+            return Ok(event);
+        };
+
+        let mut payload: Value = event.decode().map_err(|e| {
+            Error::Store(format!(
+                "cannot upcast {} payload of {}: {e}",
+                event.format, event.event_type
+            ))
+        })?;
+        let current_version = loop {
+            let version = upcaster.target_version();
+            if version <= upcaster.source_version() {
+                return Err(Error::Store(format!(
+                    "upcaster of {} from version {} does not move forward",
+                    event.event_type,
+                    upcaster.source_version()
+                )));
+            }
             payload = upcaster.upcast(payload)?;
-            current_version = upcaster.target_version();
-        }
+            match find(version) {
+                Some(next) => upcaster = next,
+                None => break version,
+            }
+        };
 
         Ok(RawStoredEvent {
-            aggregate_id: event.aggregate_id,
-            version: event.version,
             event_version: current_version,
-            event_type,
-            payload,
+            format: Format::Json,
+            payload: JsonSerializer.serialize(&payload)?,
+            ..event
         })
     }
+
+    /// Upcasts a raw event to the latest version known to the chain and
+    /// deserializes it into a stored event.
+    ///
+    /// This is how events read from elsewhere, such as inbound CloudEvents
+    /// converted into a [`RawStoredEvent`], go through the same schema
+    /// evolution as the events of the application's own stores.
+    pub fn decode(&self, event: RawStoredEvent) -> Result<StoredEvent<E>> {
+        self.upcast(event)?.into_stored()
+    }
+}
+
+/// Defines the interface for a snapshot upcaster.
+///
+/// A snapshot upcaster transforms the payload of a snapshot stored with an
+/// older version of the snapshot's schema into the next version.
+pub trait SnapshotUpcaster<S: Snapshot>: Send + Sync {
+    /// The version of the snapshot schema this upcaster can transform from.
+    fn source_version(&self) -> u16;
+
+    /// The version of the snapshot schema this upcaster transforms to.
+    fn target_version(&self) -> u16 {
+        self.source_version() + 1
+    }
+
+    /// Transforms a JSON snapshot payload into its next version.
+    fn upcast(&self, payload: Value) -> Result<Value>;
+}
+
+/// A chain of upcasters that can be applied sequentially to a snapshot.
+pub struct SnapshotUpcasterChain<S: Snapshot> {
+    upcasters: Vec<Box<dyn SnapshotUpcaster<S>>>,
+}
+
+impl<S: Snapshot> Default for SnapshotUpcasterChain<S> {
+    fn default() -> Self {
+        Self {
+            upcasters: Vec::new(),
+        }
+    }
+}
+
+impl<S: Snapshot> SnapshotUpcasterChain<S> {
+    /// Creates a new, empty snapshot upcaster chain.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an upcaster to the chain.
+    pub fn with<U: SnapshotUpcaster<S> + 'static>(mut self, upcaster: U) -> Self {
+        self.upcasters.push(Box::new(upcaster));
+        self
+    }
+
+    /// Applies the upcasting chain to a raw snapshot.
+    ///
+    /// Upcasters are applied until the snapshot reaches the current
+    /// [`Snapshot::snapshot_version`] or no upcaster applies.
+    pub(crate) fn upcast(&self, mut snapshot: RawSnapshot) -> Result<RawSnapshot> {
+        let current = S::snapshot_version();
+        while snapshot.snapshot_version < current {
+            let Some(upcaster) = self
+                .upcasters
+                .iter()
+                .find(|u| u.source_version() == snapshot.snapshot_version)
+            else {
+                break;
+            };
+            let target = upcaster.target_version();
+            if target <= snapshot.snapshot_version {
+                return Err(Error::Store(format!(
+                    "snapshot upcaster from version {} does not move forward",
+                    snapshot.snapshot_version
+                )));
+            }
+            snapshot.payload = upcaster.upcast(snapshot.payload)?;
+            snapshot.snapshot_version = target;
+        }
+        Ok(snapshot)
+    }
+
+    /// Upcasts a raw snapshot to the current schema version and deserializes
+    /// it.
+    ///
+    /// Fails if the snapshot cannot be brought to the current version, or
+    /// does not deserialize once it is.
+    pub(crate) fn decode(&self, snapshot: RawSnapshot) -> Result<StoredSnapshot<S>> {
+        let snapshot = self.upcast(snapshot)?;
+        let current = S::snapshot_version();
+        if snapshot.snapshot_version != current {
+            return Err(Error::Store(format!(
+                "snapshot of {} has schema version {}, expected {current}",
+                snapshot.aggregate_id, snapshot.snapshot_version
+            )));
+        }
+        snapshot.decode()
+    }
 }
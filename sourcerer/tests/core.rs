@@ -1,18 +1,37 @@
 //! Integration tests for Sourcerer core components.
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use sourcerer::{
-    Aggregate, Event, EventStore, Snapshot, async_trait,
-    repository::GenericRepository,
-    repository::Repository,
+    Aggregate, CloudEvent, CommandError, Event, EventMetadata, EventStore, ExpectedVersion,
+    Snapshot, StoredEvent, async_trait,
+    repository::{GenericRepository, Repository, RetryPolicy},
     store::{in_memory::InMemoryEventStore, in_memory_snapshot::InMemorySnapshotStore},
 };
 
-use sourcerer::snapshot::SnapshotStore;
+use futures::{StreamExt, future::BoxFuture, stream::BoxStream};
+use sourcerer::outbox::{EventPublisher, Outbox, OutboxRelay};
+use sourcerer::projection::{CheckpointStore, Projection, ProjectionRunner};
+use sourcerer::repository::SnapshotFailure;
+use sourcerer::snapshot::{
+    EveryInterval, EveryNEvents, OnEventTypes, RawSnapshot, ReplayCost, SnapshotContext,
+    SnapshotPolicy, SnapshotRetention, SnapshotStore, StoredSnapshot,
+};
+use sourcerer::store::in_memory_checkpoint::InMemoryCheckpointStore;
+use sourcerer::subscription::{EventSubscription, SubscriptionFilter};
+use sourcerer::upcaster::{
+    RawStoredEvent, SnapshotUpcaster, SnapshotUpcasterChain, Upcaster, UpcasterChain,
+};
 
 /// Simple event used for testing.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -44,7 +63,33 @@ struct TestSnap {
     version: i64,
 }
 
-impl Snapshot for TestSnap {}
+/// Version 1 of the snapshot named its only field `v`.
+impl Snapshot for TestSnap {
+    fn snapshot_version() -> u16 {
+        2
+    }
+}
+
+/// Commands accepted by [`TestAggregate`].
+#[derive(Clone, Debug)]
+enum TestCommand {
+    /// Creates the aggregate, or updates it if it already exists.
+    Touch,
+    /// Always rejected by the aggregate.
+    Reject,
+}
+
+/// Domain error returned by [`TestAggregate`] for [`TestCommand::Reject`].
+#[derive(Debug, PartialEq)]
+struct Rejected;
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("rejected by the aggregate")
+    }
+}
+
+impl std::error::Error for Rejected {}
 
 /// A minimal aggregate implementation used solely for testing store behaviour.
 #[derive(Default, Debug)]
@@ -55,11 +100,12 @@ struct TestAggregate {
 
 #[async_trait]
 impl Aggregate for TestAggregate {
+    const TYPE_NAME: &str = "test";
     type Id = Uuid;
     type Event = TestEvent;
-    type Command = ();
+    type Command = TestCommand;
     type Snapshot = TestSnap;
-    type Error = std::convert::Infallible;
+    type Error = Rejected;
 
     fn id(&self) -> &Self::Id {
         &self.id
@@ -79,9 +125,66 @@ impl Aggregate for TestAggregate {
 
     async fn handle(
         &self,
-        _command: Self::Command,
+        command: Self::Command,
+    ) -> std::result::Result<Vec<Self::Event>, Self::Error> {
+        match command {
+            TestCommand::Touch if self.version == 0 => Ok(vec![TestEvent::Created]),
+            TestCommand::Touch => Ok(vec![TestEvent::Updated]),
+            TestCommand::Reject => Err(Rejected),
+        }
+    }
+
+    fn from_snapshot(snapshot: Self::Snapshot) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            version: snapshot.version,
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        TestSnap {
+            version: self.version,
+        }
+    }
+}
+
+/// A command that cannot be cloned, like one carrying a reply channel.
+#[derive(Debug)]
+struct Emit(Vec<TestEvent>);
+
+/// An aggregate whose commands cannot be cloned.
+#[derive(Default, Debug)]
+struct Emitter {
+    id: Uuid,
+    version: i64,
+}
+
+#[async_trait]
+impl Aggregate for Emitter {
+    const TYPE_NAME: &str = "emitter";
+    type Id = Uuid;
+    type Event = TestEvent;
+    type Command = Emit;
+    type Snapshot = TestSnap;
+    type Error = Rejected;
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+
+    fn version(&self) -> i64 {
+        self.version
+    }
+
+    fn apply(&mut self, _event: &Self::Event) {
+        self.version += 1;
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
     ) -> std::result::Result<Vec<Self::Event>, Self::Error> {
-        Ok(Vec::new())
+        Ok(command.0)
     }
 
     fn from_snapshot(snapshot: Self::Snapshot) -> Self {
@@ -98,6 +201,249 @@ impl Aggregate for TestAggregate {
     }
 }
 
+/// Projection counting events per type, used to test the projection runner.
+#[derive(Default)]
+struct EventCounter {
+    created: usize,
+    updated: usize,
+}
+
+#[async_trait]
+impl Projection<TestEvent> for EventCounter {
+    fn name(&self) -> &str {
+        "event-counter"
+    }
+
+    async fn handle(&mut self, event: &sourcerer::StoredEvent<TestEvent>) -> sourcerer::Result<()> {
+        match event.event() {
+            TestEvent::Created => self.created += 1,
+            TestEvent::Updated => self.updated += 1,
+        }
+        Ok(())
+    }
+
+    async fn reset(&mut self) -> sourcerer::Result<()> {
+        *self = Self::default();
+        Ok(())
+    }
+}
+
+/// Event store that lets another writer append to the same stream just
+/// before each of the next `interference` appends, forcing conflicts.
+#[derive(Default)]
+struct ContendedStore {
+    inner: InMemoryEventStore<TestAggregate>,
+    interference: AtomicUsize,
+}
+
+#[async_trait]
+impl EventStore<TestAggregate> for ContendedStore {
+    async fn append(
+        &self,
+        id: &Uuid,
+        expected_version: ExpectedVersion,
+        events: Vec<TestEvent>,
+        metadata: EventMetadata,
+    ) -> sourcerer::Result<Vec<StoredEvent<TestEvent>>> {
+        if self
+            .interference
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            self.inner
+                .append(
+                    id,
+                    ExpectedVersion::Any,
+                    vec![TestEvent::Updated],
+                    EventMetadata::default(),
+                )
+                .await?;
+        }
+        self.inner
+            .append(id, expected_version, events, metadata)
+            .await
+    }
+
+    async fn load(&self, id: &Uuid) -> sourcerer::Result<Vec<StoredEvent<TestEvent>>> {
+        self.inner.load(id).await
+    }
+
+    async fn load_from(
+        &self,
+        id: &Uuid,
+        version: i64,
+    ) -> sourcerer::Result<Vec<StoredEvent<TestEvent>>> {
+        self.inner.load_from(id, version).await
+    }
+
+    async fn load_raw(&self, id: &Uuid, version: i64) -> sourcerer::Result<Vec<RawStoredEvent>> {
+        self.inner.load_raw(id, version).await
+    }
+
+    fn stream_from<'a>(
+        &'a self,
+        id: &'a Uuid,
+        version: i64,
+    ) -> BoxStream<'a, sourcerer::Result<StoredEvent<TestEvent>>> {
+        self.inner.stream_from(id, version)
+    }
+
+    fn stream_raw<'a>(
+        &'a self,
+        id: &'a Uuid,
+        version: i64,
+    ) -> BoxStream<'a, sourcerer::Result<RawStoredEvent>> {
+        self.inner.stream_raw(id, version)
+    }
+
+    async fn read_all(
+        &self,
+        from_position: i64,
+        limit: usize,
+    ) -> sourcerer::Result<Vec<StoredEvent<TestEvent>>> {
+        self.inner.read_all(from_position, limit).await
+    }
+
+    async fn list_aggregate_ids(&self) -> sourcerer::Result<Vec<String>> {
+        self.inner.list_aggregate_ids().await
+    }
+
+    async fn subscribe(
+        &self,
+        from_position: i64,
+        filter: SubscriptionFilter,
+    ) -> sourcerer::Result<EventSubscription<TestEvent>> {
+        self.inner.subscribe(from_position, filter).await
+    }
+}
+
+/// Snapshot store holding raw snapshots as they were written, including
+/// those of older snapshot schemas, and counting saves.
+#[derive(Default)]
+struct RawSnapshots {
+    snapshots: Mutex<HashMap<String, RawSnapshot>>,
+    saves: AtomicUsize,
+}
+
+impl RawSnapshots {
+    fn insert(&self, raw: RawSnapshot) {
+        self.snapshots
+            .lock()
+            .unwrap()
+            .insert(raw.aggregate_id.clone(), raw);
+    }
+}
+
+#[async_trait]
+impl SnapshotStore<TestAggregate> for RawSnapshots {
+    async fn save(&self, id: &Uuid, version: i64, snapshot: TestSnap) -> sourcerer::Result<()> {
+        self.saves.fetch_add(1, Ordering::SeqCst);
+        self.insert(RawSnapshot {
+            aggregate_id: id.to_string(),
+            version,
+            snapshot_version: TestSnap::snapshot_version(),
+            taken_at: None,
+            payload: serde_json::to_value(snapshot).unwrap(),
+        });
+        Ok(())
+    }
+
+    async fn load(&self, id: &Uuid) -> sourcerer::Result<Option<StoredSnapshot<TestSnap>>> {
+        self.load_raw(id)
+            .await?
+            .map(RawSnapshot::decode)
+            .transpose()
+    }
+
+    async fn load_raw(&self, id: &Uuid) -> sourcerer::Result<Option<RawSnapshot>> {
+        Ok(self.snapshots.lock().unwrap().get(&id.to_string()).cloned())
+    }
+}
+
+/// Renames the `v` field of version 1 snapshots to `version`.
+struct RenameV;
+
+impl SnapshotUpcaster<TestSnap> for RenameV {
+    fn source_version(&self) -> u16 {
+        1
+    }
+
+    fn upcast(&self, mut payload: serde_json::Value) -> sourcerer::Result<serde_json::Value> {
+        let v = payload["v"].take();
+        Ok(serde_json::json!({ "version": v }))
+    }
+}
+
+/// Saves three events for a new aggregate and stores `raw` as its snapshot,
+/// returning the aggregate's ID.
+fn save_with_raw_snapshot(
+    events: &Arc<InMemoryEventStore<TestAggregate>>,
+    snapshots: &RawSnapshots,
+    snapshot_version: u16,
+    payload: serde_json::Value,
+) -> Uuid {
+    let id = Uuid::new_v4();
+    futures::executor::block_on(events.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    snapshots.insert(RawSnapshot {
+        aggregate_id: id.to_string(),
+        version: 2,
+        snapshot_version,
+        taken_at: None,
+        payload,
+    });
+    id
+}
+
+/// Snapshot store whose saves always fail.
+struct FailingSnapshots;
+
+#[async_trait]
+impl SnapshotStore<TestAggregate> for FailingSnapshots {
+    async fn save(&self, _id: &Uuid, _version: i64, _snapshot: TestSnap) -> sourcerer::Result<()> {
+        Err(sourcerer::Error::Store("snapshot store unavailable".into()))
+    }
+
+    async fn load(&self, _id: &Uuid) -> sourcerer::Result<Option<StoredSnapshot<TestSnap>>> {
+        Ok(None)
+    }
+}
+
+/// Saves `batches` of events for a new aggregate under `policy`, returning
+/// the versions snapshots were taken at.
+fn snapshot_versions(
+    policy: impl SnapshotPolicy<TestAggregate> + 'static,
+    batches: &[&[TestEvent]],
+) -> Vec<i64> {
+    let events = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let snapshots = Arc::new(RawSnapshots::default());
+    let repo = GenericRepository::new(events, Some(snapshots.clone())).with_snapshot_policy(policy);
+
+    let mut agg = TestAggregate {
+        id: Uuid::new_v4(),
+        version: 0,
+    };
+    let mut versions = Vec::new();
+    for batch in batches {
+        for event in *batch {
+            agg.apply(event);
+        }
+        futures::executor::block_on(repo.save(&agg, batch.to_vec())).expect("save");
+        if let Some(snapshot) =
+            futures::executor::block_on(snapshots.load(&agg.id)).expect("load snapshot")
+            && versions.last() != Some(&snapshot.version())
+        {
+            versions.push(snapshot.version());
+        }
+    }
+    versions
+}
+
 // -- Tests ---------------------------------------------------------------
 
 #[test]
@@ -106,8 +452,13 @@ fn in_memory_event_store_append_and_load() {
     let id = Uuid::new_v4();
 
     // Append one event.
-    let stored = futures::executor::block_on(store.append(&id, 0, vec![TestEvent::Created]))
-        .expect("append should succeed");
+    let stored = futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append should succeed");
     assert_eq!(stored.len(), 1, "one event should be stored");
 
     // Loading should return same event.
@@ -120,13 +471,803 @@ fn in_memory_event_store_append_and_load() {
 fn in_memory_event_store_conflict() {
     let store = InMemoryEventStore::<TestAggregate>::default();
     let id = Uuid::new_v4();
-    let _ = futures::executor::block_on(store.append(&id, 0, vec![TestEvent::Created]))
-        .expect("initial append");
+    let _ = futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("initial append");
 
     // Appending with wrong expected_version should yield conflict.
-    let err = futures::executor::block_on(store.append(&id, 0, vec![TestEvent::Updated]))
-        .expect_err("should conflict");
-    assert!(matches!(err, sourcerer::Error::Conflict));
+    let err = futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect_err("should conflict");
+    match err {
+        sourcerer::Error::Conflict {
+            aggregate_id,
+            expected,
+            actual,
+        } => {
+            assert_eq!(aggregate_id, id.to_string());
+            assert_eq!(expected, ExpectedVersion::NoStream);
+            assert_eq!(actual, 1);
+        }
+        other => panic!("expected a conflict, got {other:?}"),
+    }
+}
+
+#[test]
+fn in_memory_event_store_expected_version_variants() {
+    let store = InMemoryEventStore::<TestAggregate>::default();
+    let id = Uuid::new_v4();
+    let append = |expected| {
+        futures::executor::block_on(store.append(
+            &id,
+            expected,
+            vec![TestEvent::Updated],
+            EventMetadata::default(),
+        ))
+    };
+
+    // A stream that does not exist yet fails `StreamExists` and `Exact(1)`.
+    assert!(matches!(
+        append(ExpectedVersion::StreamExists),
+        Err(sourcerer::Error::Conflict { .. })
+    ));
+    assert!(matches!(
+        append(ExpectedVersion::Exact(1)),
+        Err(sourcerer::Error::Conflict { .. })
+    ));
+
+    // `Any` creates the stream and numbers events from the current version.
+    assert_eq!(append(ExpectedVersion::Any).expect("any")[0].version(), 1);
+    assert_eq!(append(ExpectedVersion::Any).expect("any")[0].version(), 2);
+    assert_eq!(
+        append(ExpectedVersion::StreamExists).expect("exists")[0].version(),
+        3
+    );
+    assert_eq!(append(3.into()).expect("exact")[0].version(), 4);
+    assert!(matches!(
+        append(ExpectedVersion::NoStream),
+        Err(sourcerer::Error::Conflict { .. })
+    ));
+}
+
+#[test]
+fn repository_rejects_creating_an_existing_aggregate() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let repo: GenericRepository<_, _, InMemorySnapshotStore<TestAggregate>> =
+        GenericRepository::new(store, None);
+    let id = Uuid::new_v4();
+
+    let create = || {
+        let mut agg = TestAggregate { id, version: 0 };
+        agg.apply(&TestEvent::Created);
+        futures::executor::block_on(repo.save(&agg, vec![TestEvent::Created]))
+    };
+
+    create().expect("first creation");
+    let err = create().expect_err("second creation should conflict");
+    assert!(matches!(err, sourcerer::Error::Conflict { .. }));
+}
+
+#[test]
+fn in_memory_event_store_appends_batches_atomically() {
+    let store = InMemoryEventStore::<TestAggregate>::default();
+    let first = Uuid::new_v4();
+    let second = Uuid::new_v4();
+
+    // A stream may appear twice, the second entry expecting the first's
+    // version.
+    let stored = futures::executor::block_on(store.append_batch(
+        vec![
+            (first, ExpectedVersion::NoStream, vec![TestEvent::Created]),
+            (second, ExpectedVersion::NoStream, vec![TestEvent::Created]),
+            (first, ExpectedVersion::Exact(1), vec![TestEvent::Updated]),
+        ],
+        EventMetadata::default(),
+    ))
+    .expect("append batch");
+    let written: Vec<_> = stored
+        .iter()
+        .map(|e| (e.aggregate_id().to_owned(), e.version(), e.position()))
+        .collect();
+    assert_eq!(
+        written,
+        [
+            (first.to_string(), 1, 1),
+            (second.to_string(), 1, 2),
+            (first.to_string(), 2, 3),
+        ]
+    );
+    assert_eq!(
+        futures::executor::block_on(store.verify_stream(&first)).expect("verify"),
+        None,
+        "the entries of one stream chain onto each other"
+    );
+
+    // One stale entry rejects the whole batch.
+    let err = futures::executor::block_on(store.append_batch(
+        vec![
+            (first, ExpectedVersion::Exact(2), vec![TestEvent::Updated]),
+            (second, ExpectedVersion::NoStream, vec![TestEvent::Created]),
+        ],
+        EventMetadata::default(),
+    ))
+    .expect_err("the second entry should conflict");
+    match err {
+        sourcerer::Error::Conflict {
+            aggregate_id,
+            actual,
+            ..
+        } => {
+            assert_eq!(aggregate_id, second.to_string());
+            assert_eq!(actual, 1);
+        }
+        other => panic!("expected a conflict, got {other:?}"),
+    }
+    let first_events = futures::executor::block_on(store.load(&first)).expect("load first");
+    assert_eq!(first_events.len(), 2, "the first entry was not written");
+    let all = futures::executor::block_on(store.read_all(0, 10)).expect("read all");
+    assert_eq!(all.len(), 3);
+}
+
+#[test]
+fn in_memory_event_store_deduplicates_appends_by_idempotency_key() {
+    let store = InMemoryEventStore::<TestAggregate>::default();
+    let id = Uuid::new_v4();
+    let append = |expected, key: &str| {
+        futures::executor::block_on(store.append(
+            &id,
+            expected,
+            vec![TestEvent::Created],
+            EventMetadata::new().with_idempotency_key(key),
+        ))
+    };
+
+    let first = append(ExpectedVersion::NoStream, "create-1").expect("append");
+    assert_eq!(
+        first[0].metadata().idempotency_key(),
+        None,
+        "keys are not stored"
+    );
+
+    // A retry returns the original events, even though its expected version
+    // no longer matches.
+    let retried = append(ExpectedVersion::NoStream, "create-1").expect("retry");
+    assert_eq!(retried.len(), 1);
+    assert_eq!(
+        retried[0].metadata().event_id(),
+        first[0].metadata().event_id()
+    );
+    assert_eq!(
+        futures::executor::block_on(store.load(&id))
+            .expect("load")
+            .len(),
+        1
+    );
+
+    // Another key is a new append.
+    assert!(matches!(
+        append(ExpectedVersion::NoStream, "create-2"),
+        Err(sourcerer::Error::Conflict { .. })
+    ));
+}
+
+#[test]
+fn in_memory_event_store_forgets_idempotency_keys_after_retention() {
+    let store =
+        InMemoryEventStore::<TestAggregate>::default().with_idempotency_retention(Duration::ZERO);
+    let id = Uuid::new_v4();
+    let append = |expected| {
+        futures::executor::block_on(store.append(
+            &id,
+            expected,
+            vec![TestEvent::Updated],
+            EventMetadata::new().with_idempotency_key("touch"),
+        ))
+    };
+
+    append(ExpectedVersion::NoStream).expect("append");
+    std::thread::sleep(Duration::from_millis(2));
+    assert!(matches!(
+        append(ExpectedVersion::NoStream),
+        Err(sourcerer::Error::Conflict { .. })
+    ));
+    assert_eq!(
+        append(ExpectedVersion::Exact(1)).expect("append again")[0].version(),
+        2
+    );
+}
+
+#[test]
+fn repository_skips_snapshots_of_deduplicated_saves() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let snapshot_store = Arc::new(InMemorySnapshotStore::<TestAggregate>::default());
+    let repo = GenericRepository::new(store.clone(), Some(snapshot_store.clone()))
+        .with_snapshot_frequency(Some(1));
+    let id = Uuid::new_v4();
+    let metadata = EventMetadata::new().with_idempotency_key("request-1");
+
+    let mut agg = TestAggregate { id, version: 0 };
+    agg.apply(&TestEvent::Created);
+    futures::executor::block_on(repo.save_with_metadata(
+        &agg,
+        vec![TestEvent::Created],
+        metadata.clone(),
+    ))
+    .expect("save");
+
+    // The retried request reloads the aggregate and handles its command
+    // again, producing different events.
+    let mut retried = futures::executor::block_on(repo.load(&id)).expect("load");
+    retried.apply(&TestEvent::Updated);
+    futures::executor::block_on(repo.save_with_metadata(
+        &retried,
+        vec![TestEvent::Updated],
+        metadata,
+    ))
+    .expect("retried save");
+
+    assert_eq!(
+        futures::executor::block_on(store.load(&id))
+            .expect("load")
+            .len(),
+        1
+    );
+    let snapshot = futures::executor::block_on(snapshot_store.load(&id))
+        .expect("load snapshot")
+        .expect("snapshot of the first save");
+    assert_eq!(snapshot.version(), 1);
+}
+
+/// A publisher that fails while `failures` is positive, recording the IDs of
+/// the CloudEvents it publishes.
+#[derive(Default)]
+struct FlakyPublisher {
+    failures: AtomicUsize,
+    published: Mutex<Vec<String>>,
+}
+
+#[async_trait]
+impl EventPublisher for FlakyPublisher {
+    async fn publish(&self, event: CloudEvent) -> sourcerer::Result<()> {
+        if self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return Err(sourcerer::Error::Store("broker unavailable".into()));
+        }
+        self.published
+            .lock()
+            .expect("published")
+            .push(cloudevents::AttributesReader::id(&event.0).to_owned());
+        Ok(())
+    }
+}
+
+#[test]
+fn outbox_relay_publishes_retries_and_dead_letters_events() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default().with_outbox(true));
+    let id = Uuid::new_v4();
+    let stored = futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::new(),
+    ))
+    .expect("append");
+    let pending =
+        futures::executor::block_on(store.pending(chrono::Utc::now(), 10)).expect("pending");
+    assert_eq!(pending.len(), 2, "appends queue their events");
+
+    // Every attempt fails: the first pass retries, the second gives up.
+    let relay = OutboxRelay::new(
+        Arc::clone(&store),
+        FlakyPublisher {
+            failures: AtomicUsize::new(usize::MAX),
+            ..FlakyPublisher::default()
+        },
+    )
+    .with_retry(
+        RetryPolicy::new(2).with_backoff(Duration::from_secs(3600), Duration::from_secs(3600)),
+    );
+    let report = futures::executor::block_on(relay.run_once()).expect("relay");
+    assert_eq!(report.retried, 2);
+    assert_eq!(report.handled(), 2, "retries wait for their backoff");
+    let later = chrono::Utc::now() + chrono::Duration::hours(2);
+    let retried = futures::executor::block_on(store.pending(later, 10)).expect("pending");
+    assert_eq!(retried[0].attempts(), 1);
+    assert!(
+        retried[0]
+            .last_error()
+            .expect("error")
+            .contains("broker unavailable")
+    );
+
+    let relay = OutboxRelay::new(
+        Arc::clone(&store),
+        FlakyPublisher {
+            failures: AtomicUsize::new(usize::MAX),
+            ..FlakyPublisher::default()
+        },
+    )
+    .with_retry(RetryPolicy::new(2).with_backoff(Duration::ZERO, Duration::ZERO));
+    for message in &retried {
+        futures::executor::block_on(store.retry_later(
+            message.position(),
+            "again",
+            chrono::Utc::now(),
+        ))
+        .expect("due now");
+    }
+    let report = futures::executor::block_on(relay.run_once()).expect("relay");
+    assert_eq!(report.dead_lettered, 2);
+    let dead = futures::executor::block_on(store.dead_letters(10)).expect("dead letters");
+    assert_eq!(dead.len(), 2);
+    assert!(
+        futures::executor::block_on(store.pending(later, 10))
+            .expect("pending")
+            .is_empty()
+    );
+
+    // Requeued dead letters are published, identified by their event IDs.
+    for message in &dead {
+        futures::executor::block_on(store.requeue(message.position())).expect("requeue");
+    }
+    let relay = OutboxRelay::new(
+        Arc::clone(&store),
+        FlakyPublisher {
+            failures: AtomicUsize::new(1),
+            ..FlakyPublisher::default()
+        },
+    )
+    .with_retry(RetryPolicy::new(3).with_backoff(Duration::ZERO, Duration::ZERO));
+    let report = futures::executor::block_on(relay.run_once()).expect("relay");
+    assert_eq!((report.published, report.retried), (2, 1));
+    let mut published = relay
+        .publisher()
+        .published
+        .lock()
+        .expect("published")
+        .clone();
+    published.sort();
+    let mut expected: Vec<String> = stored
+        .iter()
+        .map(|event| event.metadata().event_id().to_string())
+        .collect();
+    expected.sort();
+    assert_eq!(published, expected);
+    assert!(
+        futures::executor::block_on(store.dead_letters(10))
+            .expect("dead letters")
+            .is_empty()
+    );
+}
+
+/// An outbox whose clock runs `ahead` of the real one, standing in for the
+/// time passing between relay passes.
+struct Ahead<O> {
+    outbox: Arc<O>,
+    ahead: Mutex<chrono::Duration>,
+}
+
+impl<O> Ahead<O> {
+    fn shift(&self, by: chrono::Duration) -> chrono::Duration {
+        let mut ahead = self.ahead.lock().expect("ahead");
+        *ahead += by;
+        *ahead
+    }
+}
+
+#[async_trait]
+impl<O: Outbox<TestEvent>> Outbox<TestEvent> for Ahead<O> {
+    async fn pending(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        limit: usize,
+    ) -> sourcerer::Result<Vec<sourcerer::outbox::OutboxMessage<TestEvent>>> {
+        let now = now + self.shift(chrono::Duration::zero());
+        self.outbox.pending(now, limit).await
+    }
+
+    async fn acknowledge(&self, position: i64) -> sourcerer::Result<()> {
+        self.outbox.acknowledge(position).await
+    }
+
+    async fn retry_later(
+        &self,
+        position: i64,
+        error: &str,
+        retry_at: chrono::DateTime<chrono::Utc>,
+    ) -> sourcerer::Result<()> {
+        let retry_at = retry_at + self.shift(chrono::Duration::zero());
+        self.outbox.retry_later(position, error, retry_at).await
+    }
+
+    async fn dead_letter(&self, position: i64, error: &str) -> sourcerer::Result<()> {
+        self.outbox.dead_letter(position, error).await
+    }
+
+    async fn dead_letters(
+        &self,
+        limit: usize,
+    ) -> sourcerer::Result<Vec<sourcerer::outbox::OutboxMessage<TestEvent>>> {
+        self.outbox.dead_letters(limit).await
+    }
+
+    async fn requeue(&self, position: i64) -> sourcerer::Result<()> {
+        self.outbox.requeue(position).await
+    }
+}
+
+#[test]
+fn outbox_relay_rides_out_a_brief_outage_by_default() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default().with_outbox(true));
+    futures::executor::block_on(store.append(
+        &Uuid::new_v4(),
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::new(),
+    ))
+    .expect("append");
+    let outbox = Arc::new(Ahead {
+        outbox: Arc::clone(&store),
+        ahead: Mutex::new(chrono::Duration::zero()),
+    });
+    let relay = OutboxRelay::new(
+        Arc::clone(&outbox),
+        FlakyPublisher {
+            failures: AtomicUsize::new(usize::MAX),
+            ..FlakyPublisher::default()
+        },
+    );
+
+    // The publisher is down for ten minutes, with a pass every minute.
+    for _ in 0..10 {
+        let report = futures::executor::block_on(relay.run_once()).expect("relay");
+        assert_eq!(report.dead_lettered, 0);
+        outbox.shift(chrono::Duration::minutes(1));
+    }
+    let later = chrono::Utc::now() + chrono::Duration::days(1);
+    let attempts =
+        futures::executor::block_on(store.pending(later, 10)).expect("pending")[0].attempts();
+    assert!(attempts >= 5, "the message was retried during the outage");
+
+    relay.publisher().failures.store(0, Ordering::SeqCst);
+    outbox.shift(chrono::Duration::minutes(5));
+    let report = futures::executor::block_on(relay.run_once()).expect("relay");
+    assert_eq!(report.published, 1);
+    assert!(
+        futures::executor::block_on(store.dead_letters(10))
+            .expect("dead letters")
+            .is_empty()
+    );
+}
+
+#[test]
+fn cloud_events_from_stored_events_carry_their_stream_context() {
+    use cloudevents::{AttributesReader, event::ExtensionValue};
+    use sourcerer::cloudevent::CloudEventOptions;
+
+    let store = InMemoryEventStore::<TestAggregate>::default();
+    let id = Uuid::new_v4();
+    let stored = futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::new(),
+    ))
+    .expect("append");
+    let updated = &stored[1];
+
+    let ce = CloudEvent::from_stored(updated)
+        .expect("convert")
+        .into_inner();
+    assert_eq!(ce.id(), updated.metadata().event_id().to_string());
+    assert_eq!(
+        CloudEvent::from_stored(updated).expect("convert").0.id(),
+        ce.id(),
+        "ids are deterministic"
+    );
+    assert_eq!(ce.ty(), "Updated");
+    assert_eq!(ce.source().as_str(), "urn:sourcerer:test");
+    assert_eq!(ce.subject(), Some(id.to_string().as_str()));
+    assert_eq!(ce.time(), Some(&updated.metadata().recorded_at()));
+    assert_eq!(ce.dataschema(), None);
+    assert_eq!(
+        ce.extension("aggregatetype"),
+        Some(&ExtensionValue::String("test".into()))
+    );
+    assert_eq!(
+        ce.extension("aggregateversion"),
+        Some(&ExtensionValue::Integer(2))
+    );
+    assert_eq!(
+        ce.extension("eventversion"),
+        Some(&ExtensionValue::Integer(1))
+    );
+
+    let options = CloudEventOptions::new()
+        .with_source("https://orders.example.com".parse().expect("source"))
+        .with_dataschema(
+            "https://schemas.example.com/updated/v1"
+                .parse()
+                .expect("dataschema"),
+        );
+    let ce = CloudEvent::from_stored_with(updated, &options)
+        .expect("convert")
+        .into_inner();
+    assert_eq!(ce.source().as_str(), "https://orders.example.com/");
+    assert_eq!(
+        ce.dataschema().map(url::Url::as_str),
+        Some("https://schemas.example.com/updated/v1")
+    );
+
+    // Events stored without an event ID get one derived from their place
+    // in their stream.
+    let legacy = |version| {
+        let event = StoredEvent::new(
+            id.to_string(),
+            version,
+            1,
+            "Updated".into(),
+            TestEvent::Updated,
+        )
+        .with_aggregate_type("test");
+        CloudEvent::from_stored(&event)
+            .expect("convert")
+            .0
+            .id()
+            .to_owned()
+    };
+    assert_eq!(legacy(2), legacy(2), "derived ids are deterministic");
+    assert_ne!(legacy(2), legacy(3));
+    assert_ne!(legacy(2), Uuid::nil().to_string());
+}
+
+/// Upcasts version 0 of `Created`, which was a `{"kind": ...}` object.
+struct UnwrapKind;
+
+impl Upcaster<TestEvent> for UnwrapKind {
+    fn event_type(&self) -> &'static str {
+        "Created"
+    }
+
+    fn source_version(&self) -> u16 {
+        0
+    }
+
+    fn upcast(&self, payload: serde_json::Value) -> sourcerer::Result<serde_json::Value> {
+        Ok(payload["kind"].clone())
+    }
+}
+
+#[test]
+fn inbound_cloud_events_convert_into_stored_events() {
+    use cloudevents::{AttributesWriter, EventBuilder, EventBuilderV10};
+
+    let store = InMemoryEventStore::<TestAggregate>::default();
+    let id = Uuid::new_v4();
+    let stored = futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::new(),
+    ))
+    .expect("append");
+
+    // Our own CloudEvents round-trip.
+    let ce = CloudEvent::from_stored(&stored[1]).expect("convert");
+    let received = StoredEvent::<TestEvent>::try_from(ce).expect("receive");
+    assert_eq!(received.event(), &TestEvent::Updated);
+    assert_eq!(received.aggregate_type(), "test");
+    assert_eq!(received.aggregate_id(), id.to_string());
+    assert_eq!((received.version(), received.event_version()), (2, 1));
+    assert_eq!(
+        received.metadata().event_id(),
+        stored[1].metadata().event_id()
+    );
+    assert_eq!(
+        received.metadata().recorded_at(),
+        stored[1].metadata().recorded_at()
+    );
+
+    // A foreign event on an older schema, with binary-mode string extensions,
+    // is upcast like a historical one.
+    let event_id = Uuid::new_v4();
+    let foreign = |event_version: &str| {
+        CloudEvent(
+            EventBuilderV10::new()
+                .id(event_id.to_string())
+                .ty("Created")
+                .source("https://billing.example.com")
+                .subject("invoice-7")
+                .extension("eventversion", event_version)
+                .data("application/json", serde_json::json!({ "kind": "Created" }))
+                .build()
+                .expect("build"),
+        )
+    };
+    let raw = RawStoredEvent::try_from(foreign("0")).expect("raw");
+    assert_eq!((raw.event_version, raw.version), (0, 0));
+    let received = UpcasterChain::new()
+        .with(UnwrapKind)
+        .decode(raw)
+        .expect("upcast");
+    assert_eq!(received.event(), &TestEvent::Created);
+    assert_eq!(received.event_version(), 1);
+    assert_eq!(received.aggregate_id(), "invoice-7");
+    assert_eq!(received.metadata().event_id(), event_id);
+    assert!(
+        StoredEvent::<TestEvent>::try_from(foreign("0")).is_err(),
+        "without upcasters the old payload does not deserialize"
+    );
+
+    // Malformed events name the offending attribute.
+    let rejection = |ce: CloudEvent| match RawStoredEvent::try_from(ce) {
+        Err(sourcerer::Error::Validation(message)) => message,
+        other => panic!("expected a validation error, got {other:?}"),
+    };
+    assert!(rejection(foreign("one")).contains("`eventversion`"));
+    assert!(rejection(foreign("70000")).contains("`eventversion`"));
+    let mut ce = foreign("1").into_inner();
+    ce.set_subject(None::<String>);
+    assert!(rejection(CloudEvent(ce)).contains("`subject`"));
+    let mut ce = foreign("1").into_inner();
+    ce.set_id("not-a-uuid");
+    assert!(rejection(CloudEvent(ce)).contains("`id`"));
+    let mut ce = foreign("1").into_inner();
+    ce.set_data("text/plain", "Created");
+    assert!(rejection(CloudEvent(ce)).contains("`datacontenttype`"));
+    let mut ce = foreign("1").into_inner();
+    ce.remove_extension("eventversion");
+    assert!(rejection(CloudEvent(ce)).contains("`eventversion`"));
+}
+
+/// Claims to upcast version 1 of `Updated` to version 1 again.
+struct Stuck;
+
+impl Upcaster<TestEvent> for Stuck {
+    fn event_type(&self) -> &'static str {
+        "Updated"
+    }
+
+    fn source_version(&self) -> u16 {
+        1
+    }
+
+    fn target_version(&self) -> u16 {
+        1
+    }
+
+    fn upcast(&self, payload: serde_json::Value) -> sourcerer::Result<serde_json::Value> {
+        Ok(payload)
+    }
+}
+
+#[test]
+fn upcasters_that_do_not_move_forward_are_rejected() {
+    let raw = RawStoredEvent {
+        aggregate_type: "test".into(),
+        aggregate_id: Uuid::new_v4().to_string(),
+        version: 1,
+        event_version: 1,
+        event_type: "Updated".into(),
+        format: sourcerer::serializer::Format::Json,
+        payload: b"\"Updated\"".to_vec(),
+        position: 1,
+        metadata: EventMetadata::new(),
+        hash: None,
+    };
+    match UpcasterChain::new().with(Stuck).decode(raw) {
+        Err(sourcerer::Error::Store(message)) => {
+            assert!(message.contains("does not move forward"), "{message}");
+        }
+        other => panic!("expected a store error, got {other:?}"),
+    }
+}
+
+#[test]
+fn repository_unit_of_work_commits_saves_together() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let snapshot_store = Arc::new(InMemorySnapshotStore::<TestAggregate>::default());
+    let repo = GenericRepository::new(store.clone(), Some(snapshot_store.clone()))
+        .with_snapshot_frequency(Some(2));
+    let mut from = TestAggregate {
+        id: Uuid::new_v4(),
+        version: 0,
+    };
+    let mut to = TestAggregate {
+        id: Uuid::new_v4(),
+        version: 0,
+    };
+
+    let mut unit = repo
+        .unit_of_work()
+        .with_metadata(EventMetadata::default().with_correlation_id("transfer-1"));
+    from.apply(&TestEvent::Created);
+    unit.save(&from, vec![TestEvent::Created]);
+    to.apply(&TestEvent::Created);
+    unit.save(&to, vec![TestEvent::Created]);
+    from.apply(&TestEvent::Updated);
+    unit.save(&from, vec![TestEvent::Updated]);
+    unit.save(&to, Vec::new());
+    assert_eq!(unit.len(), 3, "empty saves are not recorded");
+    futures::executor::block_on(unit.commit()).expect("commit");
+
+    assert_eq!(
+        futures::executor::block_on(repo.load(&from.id))
+            .expect("load from")
+            .version(),
+        2
+    );
+    assert_eq!(
+        futures::executor::block_on(repo.load(&to.id))
+            .expect("load to")
+            .version(),
+        1
+    );
+    let events = futures::executor::block_on(store.load(&to.id)).expect("load to events");
+    assert_eq!(events[0].metadata().correlation_id(), Some("transfer-1"));
+    let snapshot = futures::executor::block_on(snapshot_store.load(&from.id))
+        .expect("load snapshot")
+        .expect("the policy snapshots the second save of `from`");
+    assert_eq!(snapshot.version(), 2);
+
+    // A stale aggregate fails the whole unit.
+    let mut unit = repo.unit_of_work();
+    to.apply(&TestEvent::Updated);
+    unit.save(&to, vec![TestEvent::Updated]);
+    let stale = TestAggregate {
+        id: from.id,
+        version: 2,
+    };
+    unit.save(&stale, vec![TestEvent::Updated]);
+    let err = futures::executor::block_on(unit.commit()).expect_err("stale save");
+    assert!(matches!(err, sourcerer::Error::Conflict { .. }));
+    assert_eq!(
+        futures::executor::block_on(repo.load(&to.id))
+            .expect("load to")
+            .version(),
+        1
+    );
+}
+
+#[test]
+fn in_memory_event_store_lists_aggregate_ids() {
+    let store = InMemoryEventStore::<TestAggregate>::default();
+    let mut ids = vec![Uuid::new_v4(), Uuid::new_v4()];
+    for id in &ids {
+        let stored = futures::executor::block_on(store.append(
+            id,
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created],
+            EventMetadata::default(),
+        ))
+        .expect("append");
+        assert_eq!(stored[0].aggregate_type(), TestAggregate::TYPE_NAME);
+    }
+    // A rejected append does not create a stream.
+    let _ = futures::executor::block_on(store.append(
+        &Uuid::new_v4(),
+        ExpectedVersion::StreamExists,
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ));
+
+    ids.sort();
+    let expected: Vec<String> = ids.iter().map(Uuid::to_string).collect();
+    assert_eq!(
+        futures::executor::block_on(store.list_aggregate_ids()).expect("list ids"),
+        expected
+    );
 }
 
 #[test]
@@ -142,6 +1283,85 @@ fn snapshot_store_save_and_load() {
     assert_eq!(loaded.unwrap().version(), 1);
 }
 
+#[test]
+fn in_memory_snapshot_store_only_moves_forward() {
+    let snaps = InMemorySnapshotStore::<TestAggregate>::default();
+    let id = Uuid::new_v4();
+    for version in [1, 3, 2] {
+        futures::executor::block_on(snaps.save(&id, version, TestSnap { version }))
+            .expect("save snapshot");
+    }
+
+    let latest = futures::executor::block_on(snaps.load(&id))
+        .expect("load")
+        .expect("snapshot exists");
+    assert_eq!(latest.version(), 3, "the older snapshot is ignored");
+    assert!(
+        futures::executor::block_on(snaps.load_at_or_before(&id, 2))
+            .expect("load at or before")
+            .is_none(),
+        "only the latest snapshot is kept by default"
+    );
+}
+
+#[test]
+fn in_memory_snapshot_store_keeps_history_by_retention() {
+    let id = Uuid::new_v4();
+    let at_or_before = |retention, versions: &[i64], at: i64| {
+        let snaps = InMemorySnapshotStore::<TestAggregate>::default().with_retention(retention);
+        for &version in versions {
+            futures::executor::block_on(snaps.save(&id, version, TestSnap { version }))
+                .expect("save snapshot");
+        }
+        futures::executor::block_on(snaps.load_at_or_before(&id, at))
+            .expect("load at or before")
+            .map(|snapshot| snapshot.version())
+    };
+
+    let versions = [5, 8, 12, 15, 21];
+    assert_eq!(
+        at_or_before(SnapshotRetention::KeepAll, &versions, 14),
+        Some(12)
+    );
+    assert_eq!(at_or_before(SnapshotRetention::KeepAll, &versions, 4), None);
+    assert_eq!(
+        at_or_before(SnapshotRetention::KeepLast(2), &versions, 20),
+        Some(15)
+    );
+    assert_eq!(
+        at_or_before(SnapshotRetention::KeepLast(2), &versions, 14),
+        None
+    );
+    assert_eq!(
+        at_or_before(SnapshotRetention::KeepLast(2), &versions, 30),
+        Some(21)
+    );
+    assert_eq!(
+        at_or_before(SnapshotRetention::OnePerVersions(10), &versions, 14),
+        Some(8),
+        "the latest of versions 0 to 9 is kept"
+    );
+    assert_eq!(
+        at_or_before(SnapshotRetention::OnePerVersions(10), &versions, 20),
+        Some(15)
+    );
+    assert_eq!(
+        at_or_before(SnapshotRetention::OnePerVersions(10), &versions, 7),
+        None
+    );
+
+    let raw = InMemorySnapshotStore::<TestAggregate>::default()
+        .with_retention(SnapshotRetention::KeepAll);
+    for version in [1, 2] {
+        futures::executor::block_on(raw.save(&id, version, TestSnap { version }))
+            .expect("save snapshot");
+    }
+    let snapshot = futures::executor::block_on(raw.load_raw_at_or_before(&id, 1))
+        .expect("load raw")
+        .expect("snapshot exists");
+    assert_eq!(snapshot.payload, serde_json::json!({ "version": 1 }));
+}
+
 #[test]
 fn repository_load_and_save_with_snapshot() {
     let event_store = Arc::new(InMemoryEventStore::<TestAggregate>::default());
@@ -170,3 +1390,825 @@ fn repository_load_and_save_with_snapshot() {
         .expect("snapshot exists");
     assert_eq!(snap.version(), 1);
 }
+
+#[test]
+fn snapshot_policies_decide_when_save_snapshots() {
+    use TestEvent::{Created, Updated};
+    let batches: &[&[TestEvent]] = &[&[Created], &[Updated, Updated], &[Updated], &[Updated]];
+
+    assert_eq!(snapshot_versions(EveryNEvents::new(2), batches), [3, 4]);
+    assert_eq!(
+        snapshot_versions(OnEventTypes::new(["Created"]), batches),
+        [1]
+    );
+    assert_eq!(snapshot_versions(ReplayCost::new(2), batches), [3, 5]);
+    assert_eq!(
+        snapshot_versions(EveryInterval::new(Duration::from_secs(3600)), batches),
+        [1],
+        "only the first save, as no snapshot was written before it"
+    );
+    assert_eq!(
+        snapshot_versions(EveryInterval::new(Duration::ZERO), batches),
+        [1, 3, 4, 5]
+    );
+    assert_eq!(
+        snapshot_versions(
+            |context: &SnapshotContext<'_, TestAggregate>| context.new_events().len() > 1,
+            batches
+        ),
+        [3]
+    );
+}
+
+#[test]
+fn every_interval_counts_from_snapshots_taken_before_a_restart() {
+    let events = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let snapshots = Arc::new(InMemorySnapshotStore::<TestAggregate>::default());
+    let hourly = || {
+        GenericRepository::new(events.clone(), Some(snapshots.clone()))
+            .with_snapshot_policy(EveryInterval::new(Duration::from_secs(3600)))
+    };
+    let id = Uuid::new_v4();
+
+    futures::executor::block_on(hourly().execute(&id, TestCommand::Touch, RetryPolicy::no_retry()))
+        .expect("create");
+    let snapshot = futures::executor::block_on(snapshots.load(&id))
+        .expect("load snapshot")
+        .expect("snapshot exists");
+    assert_eq!(snapshot.version(), 1);
+    assert!(snapshot.taken_at().is_some());
+
+    // A new repository learns when the snapshot was taken from the snapshot
+    // itself.
+    futures::executor::block_on(hourly().execute(&id, TestCommand::Touch, RetryPolicy::no_retry()))
+        .expect("update");
+    let snapshot = futures::executor::block_on(snapshots.load(&id))
+        .expect("load snapshot")
+        .expect("snapshot exists");
+    assert_eq!(
+        snapshot.version(),
+        1,
+        "the snapshot is less than an hour old"
+    );
+}
+
+#[test]
+fn repository_forgets_the_snapshots_of_least_recently_saved_aggregates() {
+    let snapshots = Arc::new(InMemorySnapshotStore::<TestAggregate>::default());
+    let repo = GenericRepository::new(
+        Arc::new(InMemoryEventStore::<TestAggregate>::default()),
+        Some(snapshots.clone()),
+    )
+    .with_snapshot_policy(EveryInterval::new(Duration::from_secs(3600)))
+    .with_snapshot_mark_capacity(1);
+    // Saving without loading first leaves the repository only its marks to
+    // go by.
+    let save = |agg: &mut TestAggregate, event: TestEvent| {
+        agg.apply(&event);
+        futures::executor::block_on(repo.save(agg, vec![event])).expect("save");
+        futures::executor::block_on(snapshots.load(&agg.id))
+            .expect("load snapshot")
+            .map(|snapshot| snapshot.version())
+    };
+    let mut first = TestAggregate {
+        id: Uuid::new_v4(),
+        version: 0,
+    };
+    let mut second = TestAggregate {
+        id: Uuid::new_v4(),
+        version: 0,
+    };
+
+    assert_eq!(save(&mut first, TestEvent::Created), Some(1));
+    assert_eq!(save(&mut first, TestEvent::Updated), Some(1));
+    assert_eq!(save(&mut second, TestEvent::Created), Some(1));
+    assert_eq!(
+        save(&mut first, TestEvent::Updated),
+        Some(3),
+        "the first aggregate's snapshot was forgotten"
+    );
+}
+
+#[test]
+fn repository_reports_snapshot_failures_without_failing_saves() {
+    let events = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let failures = Arc::new(Mutex::new(Vec::<SnapshotFailure>::new()));
+    let repo = GenericRepository::new(events.clone(), Some(Arc::new(FailingSnapshots)))
+        .with_snapshot_frequency(Some(1))
+        .with_snapshot_failure_handler({
+            let failures = failures.clone();
+            move |failure| failures.lock().unwrap().push(failure)
+        });
+
+    let mut agg = TestAggregate {
+        id: Uuid::new_v4(),
+        version: 0,
+    };
+    agg.apply(&TestEvent::Created);
+    futures::executor::block_on(repo.save(&agg, vec![TestEvent::Created]))
+        .expect("the save succeeds");
+    assert_eq!(
+        futures::executor::block_on(events.load(&agg.id))
+            .expect("load")
+            .len(),
+        1
+    );
+
+    let failures = failures.lock().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].aggregate_id, agg.id.to_string());
+    assert_eq!(failures[0].version, 1);
+    assert!(matches!(failures[0].error, sourcerer::Error::Store(_)));
+}
+
+#[test]
+fn repository_writes_snapshots_in_the_background() {
+    let events = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let snapshots = Arc::new(InMemorySnapshotStore::<TestAggregate>::default());
+    let tasks = Arc::new(Mutex::new(Vec::<BoxFuture<'static, ()>>::new()));
+    let repo = GenericRepository::new(events, Some(snapshots.clone()))
+        .with_snapshot_frequency(Some(1))
+        .with_background_snapshots({
+            let tasks = tasks.clone();
+            move |task| tasks.lock().unwrap().push(task)
+        });
+
+    let mut agg = TestAggregate {
+        id: Uuid::new_v4(),
+        version: 0,
+    };
+    agg.apply(&TestEvent::Created);
+    futures::executor::block_on(repo.save(&agg, vec![TestEvent::Created])).expect("save");
+    assert!(
+        futures::executor::block_on(snapshots.load(&agg.id))
+            .expect("load snapshot")
+            .is_none(),
+        "the snapshot is left to the spawned task"
+    );
+
+    let spawned: Vec<_> = tasks.lock().unwrap().drain(..).collect();
+    assert_eq!(spawned.len(), 1);
+    futures::executor::block_on(futures::future::join_all(spawned));
+    let snapshot = futures::executor::block_on(snapshots.load(&agg.id))
+        .expect("load snapshot")
+        .expect("snapshot written");
+    assert_eq!(snapshot.version(), 1);
+}
+
+#[test]
+fn repository_loads_aggregates_at_past_versions() {
+    let events = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let snapshots = Arc::new(
+        InMemorySnapshotStore::<TestAggregate>::default()
+            .with_retention(SnapshotRetention::KeepAll),
+    );
+    let id = Uuid::new_v4();
+    futures::executor::block_on(events.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    futures::executor::block_on(events.append(
+        &id,
+        ExpectedVersion::Exact(3),
+        vec![TestEvent::Updated, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    // The snapshots claim versions the events do not reach, so the loaded
+    // version shows which snapshot was used.
+    for (version, claimed) in [(2, 20), (4, 40)] {
+        futures::executor::block_on(snapshots.save(&id, version, TestSnap { version: claimed }))
+            .expect("save snapshot");
+    }
+
+    let repo = GenericRepository::new(events, Some(snapshots));
+    let at_version = |version| {
+        futures::executor::block_on(repo.load_at_version(&id, version)).map(|agg| agg.version())
+    };
+    assert_eq!(at_version(1).expect("load"), 1, "no snapshot is that old");
+    assert_eq!(
+        at_version(3).expect("load"),
+        21,
+        "replays past the snapshot at 2"
+    );
+    assert_eq!(at_version(4).expect("load"), 40);
+    assert_eq!(
+        at_version(9).expect("load"),
+        41,
+        "later versions load the current state"
+    );
+    assert!(matches!(at_version(0), Err(sourcerer::Error::NotFound)));
+    assert!(matches!(
+        futures::executor::block_on(repo.load_at_version(&Uuid::new_v4(), 3)),
+        Err(sourcerer::Error::NotFound)
+    ));
+}
+
+#[test]
+fn repository_loads_aggregates_as_of_a_point_in_time() {
+    let events = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let repo: GenericRepository<_, _, InMemorySnapshotStore<TestAggregate>> =
+        GenericRepository::new(events.clone(), None);
+    let id = Uuid::new_v4();
+    let before = chrono::Utc::now();
+    std::thread::sleep(Duration::from_millis(5));
+    futures::executor::block_on(events.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    std::thread::sleep(Duration::from_millis(5));
+    let between = chrono::Utc::now();
+    std::thread::sleep(Duration::from_millis(5));
+    futures::executor::block_on(events.append(
+        &id,
+        ExpectedVersion::Exact(2),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+
+    assert_eq!(
+        futures::executor::block_on(events.version_as_of(&id, between)).expect("version"),
+        2
+    );
+    let loaded = futures::executor::block_on(repo.load_as_of(&id, between)).expect("load");
+    assert_eq!(loaded.version(), 2);
+    let loaded =
+        futures::executor::block_on(repo.load_as_of(&id, chrono::Utc::now())).expect("load");
+    assert_eq!(loaded.version(), 3);
+    assert!(matches!(
+        futures::executor::block_on(repo.load_as_of(&id, before)),
+        Err(sourcerer::Error::NotFound)
+    ));
+}
+
+#[test]
+fn in_memory_event_store_chains_event_hashes() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let id = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    let first = futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    let second = futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::Exact(2),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append more");
+    futures::executor::block_on(store.append(
+        &other,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append other");
+
+    // Identical events get distinct hashes, as each covers its predecessor.
+    let hashes: Vec<&str> = first
+        .iter()
+        .chain(&second)
+        .map(|e| e.hash().expect("hashed"))
+        .collect();
+    assert_eq!(hashes[0].len(), 64);
+    assert_ne!(hashes[1], hashes[2]);
+
+    assert_eq!(
+        futures::executor::block_on(store.verify_stream(&id)).expect("verify"),
+        None
+    );
+    assert_eq!(
+        futures::executor::block_on(store.verify_all()).expect("verify all"),
+        None
+    );
+
+    // Verifying loads still replay only what the snapshot does not cover.
+    let snapshot_store = Arc::new(InMemorySnapshotStore::<TestAggregate>::default());
+    futures::executor::block_on(snapshot_store.save(&id, 2, TestSnap { version: 2 }))
+        .expect("save snapshot");
+    let repo = GenericRepository::new(store, Some(snapshot_store)).with_hash_verification(true);
+    let loaded = futures::executor::block_on(repo.load(&id)).expect("load verified");
+    assert_eq!(loaded.version(), 3);
+}
+
+#[test]
+fn repository_upcasts_snapshots_of_older_schemas() {
+    let events = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let snapshots = Arc::new(RawSnapshots::default());
+    // The snapshot claims a version the events do not reach, so the loaded
+    // version shows whether it was used.
+    let id = save_with_raw_snapshot(&events, &snapshots, 1, serde_json::json!({ "v": 10 }));
+
+    let repo = GenericRepository::new(events, Some(snapshots.clone()))
+        .with_snapshot_upcasters(SnapshotUpcasterChain::new().with(RenameV));
+    let loaded = futures::executor::block_on(repo.load(&id)).expect("load");
+    assert_eq!(loaded.version(), 11, "the upcast snapshot is used");
+    assert_eq!(snapshots.saves.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn repository_replays_the_full_stream_past_unusable_snapshots() {
+    let events = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let snapshots = Arc::new(RawSnapshots::default());
+    let stale = save_with_raw_snapshot(&events, &snapshots, 1, serde_json::json!({ "v": 10 }));
+    let unreadable = save_with_raw_snapshot(&events, &snapshots, 2, serde_json::json!({ "v": 10 }));
+    let newer = save_with_raw_snapshot(&events, &snapshots, 3, serde_json::json!({}));
+
+    let repo = GenericRepository::new(events, Some(snapshots.clone()));
+    for id in [stale, unreadable, newer] {
+        let loaded = futures::executor::block_on(repo.load(&id)).expect("load");
+        assert_eq!(loaded.version(), 3, "the stream is replayed from the start");
+
+        // The unusable snapshot is replaced with a current one.
+        let snapshot = futures::executor::block_on(snapshots.load(&id))
+            .expect("load snapshot")
+            .expect("snapshot replaced");
+        assert_eq!(snapshot.version(), 3);
+        assert_eq!(snapshot.snapshot_version(), 2);
+    }
+    assert_eq!(snapshots.saves.load(Ordering::SeqCst), 3);
+
+    // The replacement is used from then on.
+    futures::executor::block_on(repo.load(&stale)).expect("reload");
+    assert_eq!(snapshots.saves.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn in_memory_event_store_read_all_orders_across_aggregates() {
+    let store = InMemoryEventStore::<TestAggregate>::default();
+    let first = Uuid::new_v4();
+    let second = Uuid::new_v4();
+
+    futures::executor::block_on(store.append(
+        &first,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append first");
+    futures::executor::block_on(store.append(
+        &second,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append second");
+    let stored = futures::executor::block_on(store.append(
+        &first,
+        ExpectedVersion::Exact(1),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append first again");
+    assert_eq!(stored[0].position(), 3, "positions are global");
+
+    let all = futures::executor::block_on(store.read_all(0, 10)).expect("read all");
+    let positions: Vec<i64> = all.iter().map(|e| e.position()).collect();
+    assert_eq!(positions, vec![1, 2, 3]);
+    assert_eq!(all[1].aggregate_id(), second.to_string());
+
+    // Resuming after a position honours the limit.
+    let page = futures::executor::block_on(store.read_all(1, 1)).expect("read page");
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].position(), 2);
+}
+
+#[test]
+fn in_memory_subscription_catches_up_then_follows_appends() {
+    let store = InMemoryEventStore::<TestAggregate>::default();
+    let first = Uuid::new_v4();
+    let second = Uuid::new_v4();
+    futures::executor::block_on(store.append(
+        &first,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append first");
+
+    let mut all = futures::executor::block_on(store.subscribe(0, SubscriptionFilter::new()))
+        .expect("subscribe");
+    let mut updates = futures::executor::block_on(
+        store.subscribe(0, SubscriptionFilter::new().with_event_types(["Updated"])),
+    )
+    .expect("subscribe filtered");
+
+    // Historical events are replayed first.
+    let replayed = futures::executor::block_on(all.next())
+        .expect("stream open")
+        .expect("replayed event");
+    assert_eq!(replayed.position(), 1);
+
+    // New appends are pushed once the subscription has caught up.
+    futures::executor::block_on(store.append(
+        &second,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append second");
+    futures::executor::block_on(store.append(
+        &first,
+        ExpectedVersion::Exact(1),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append update");
+
+    let live: Vec<i64> = futures::executor::block_on(all.by_ref().take(2).collect::<Vec<_>>())
+        .into_iter()
+        .map(|e| e.expect("live event").position())
+        .collect();
+    assert_eq!(live, vec![2, 3]);
+
+    let update = futures::executor::block_on(updates.next())
+        .expect("stream open")
+        .expect("filtered event");
+    assert_eq!(update.event_type(), "Updated");
+    assert_eq!(update.position(), 3);
+}
+
+#[test]
+fn in_memory_streams_long_event_streams_in_order() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let id = Uuid::new_v4();
+    let mut events = vec![TestEvent::Created];
+    events.extend(std::iter::repeat_n(TestEvent::Updated, 599));
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        events,
+        EventMetadata::default(),
+    ))
+    .expect("append");
+
+    let versions: Vec<i64> =
+        futures::executor::block_on(store.stream_from(&id, 100).collect::<Vec<_>>())
+            .into_iter()
+            .map(|e| e.expect("streamed event").version())
+            .collect();
+    assert_eq!(versions, (101..=600).collect::<Vec<_>>());
+
+    // The repository folds the same stream incrementally.
+    let repo: GenericRepository<_, _, InMemorySnapshotStore<TestAggregate>> =
+        GenericRepository::new(store, None);
+    let loaded = futures::executor::block_on(repo.load(&id)).expect("load");
+    assert_eq!(loaded.version(), 600);
+}
+
+#[test]
+fn projection_runner_checkpoints_and_rebuilds() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let checkpoints = Arc::new(InMemoryCheckpointStore::default());
+    let id = Uuid::new_v4();
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+
+    let mut runner =
+        ProjectionRunner::new(store.clone(), checkpoints.clone(), EventCounter::default())
+            .with_batch_size(1);
+    let handled = futures::executor::block_on(runner.run_once()).expect("run once");
+    assert_eq!(handled, 2);
+    assert_eq!(
+        futures::executor::block_on(checkpoints.load("event-counter")).expect("load checkpoint"),
+        Some(2)
+    );
+
+    // A fresh runner resumes from the checkpoint instead of replaying.
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::Exact(2),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    let mut resumed =
+        ProjectionRunner::new(store.clone(), checkpoints.clone(), EventCounter::default());
+    assert_eq!(
+        futures::executor::block_on(resumed.run_once()).expect("resume"),
+        1
+    );
+    assert_eq!(resumed.projection().updated, 1);
+    assert_eq!(resumed.projection().created, 0);
+
+    // Rebuilding resets the projection and replays from position zero.
+    assert_eq!(
+        futures::executor::block_on(resumed.rebuild()).expect("rebuild"),
+        3
+    );
+    assert_eq!(resumed.projection().created, 1);
+    assert_eq!(resumed.projection().updated, 2);
+    assert_eq!(
+        futures::executor::block_on(resumed.position()).expect("position"),
+        3
+    );
+}
+
+#[test]
+fn repository_records_event_metadata() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let repo: GenericRepository<_, _, InMemorySnapshotStore<TestAggregate>> =
+        GenericRepository::new(store.clone(), None);
+    let id = Uuid::new_v4();
+
+    let mut agg = TestAggregate { id, version: 0 };
+    let events = vec![TestEvent::Created, TestEvent::Updated];
+    for event in &events {
+        agg.apply(event);
+    }
+    let metadata = EventMetadata::new()
+        .with_correlation_id("request-1")
+        .with_causation_id("command-1")
+        .with_header("tenant", "acme");
+    futures::executor::block_on(repo.save_with_metadata(&agg, events, metadata))
+        .expect("save events");
+
+    let loaded = futures::executor::block_on(store.load(&id)).expect("load");
+    let (first, second) = (loaded[0].metadata(), loaded[1].metadata());
+    assert_eq!(first.correlation_id(), Some("request-1"));
+    assert_eq!(first.causation_id(), Some("command-1"));
+    assert_eq!(
+        first.headers().get("tenant").map(String::as_str),
+        Some("acme")
+    );
+    assert_ne!(
+        first.event_id(),
+        second.event_id(),
+        "each event has its own ID"
+    );
+    assert_eq!(first.recorded_at(), second.recorded_at());
+
+    let raw = futures::executor::block_on(store.load_raw(&id, 0)).expect("load raw");
+    assert_eq!(raw[1].metadata, *second);
+}
+
+#[test]
+fn events_stored_without_metadata_read_back_the_same_every_time() {
+    let legacy = serde_json::json!({
+        "aggregate_id": "legacy-1",
+        "version": 1,
+        "event_version": 1,
+        "event_type": "Created",
+        "event": "Created",
+    });
+    let read = || {
+        serde_json::from_value::<StoredEvent<TestEvent>>(legacy.clone())
+            .expect("deserialize")
+            .metadata()
+            .clone()
+    };
+    let metadata = read();
+    assert_eq!(read(), metadata);
+    assert!(metadata.event_id().is_nil());
+    assert_eq!(metadata.recorded_at(), chrono::DateTime::UNIX_EPOCH);
+
+    assert_ne!(
+        EventMetadata::new().event_id(),
+        EventMetadata::new().event_id()
+    );
+}
+
+#[test]
+fn repository_execute_creates_missing_aggregates() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let repo: GenericRepository<_, _, InMemorySnapshotStore<TestAggregate>> =
+        GenericRepository::new(store.clone(), None);
+    let id = Uuid::new_v4();
+
+    let agg =
+        futures::executor::block_on(repo.execute(&id, TestCommand::Touch, RetryPolicy::default()))
+            .expect("execute on a new aggregate");
+    assert_eq!(agg.version(), 1);
+
+    let agg =
+        futures::executor::block_on(repo.execute(&id, TestCommand::Touch, RetryPolicy::default()))
+            .expect("execute on an existing aggregate");
+    assert_eq!(agg.version(), 2);
+
+    let loaded = futures::executor::block_on(store.load(&id)).expect("load");
+    let types: Vec<_> = loaded.iter().map(StoredEvent::event_type).collect();
+    assert_eq!(types, ["Created", "Updated"]);
+}
+
+#[test]
+fn repository_execute_retries_on_conflict() {
+    let store = Arc::new(ContendedStore::default());
+    let repo: GenericRepository<_, _, InMemorySnapshotStore<TestAggregate>> =
+        GenericRepository::new(store.clone(), None);
+    let id = Uuid::new_v4();
+    let no_delay = RetryPolicy::new(3).with_backoff(Duration::ZERO, Duration::ZERO);
+
+    futures::executor::block_on(repo.execute(&id, TestCommand::Touch, no_delay)).expect("create");
+
+    // Two competing writes are absorbed by the third attempt.
+    store.interference.store(2, Ordering::SeqCst);
+    let agg = futures::executor::block_on(repo.execute(&id, TestCommand::Touch, no_delay))
+        .expect("execute should succeed after retrying");
+    assert_eq!(agg.version(), 4);
+
+    // Without retries the conflict reaches the caller.
+    store.interference.store(1, Ordering::SeqCst);
+    let err =
+        futures::executor::block_on(repo.execute(&id, TestCommand::Touch, RetryPolicy::no_retry()))
+            .expect_err("execute should conflict");
+    match err {
+        CommandError::Concurrency {
+            expected, actual, ..
+        } => {
+            assert_eq!(expected, ExpectedVersion::Exact(4));
+            assert_eq!(actual, 5);
+        }
+        other => panic!("expected a conflict, got {other:?}"),
+    }
+}
+
+#[test]
+fn repository_handle_keeps_domain_errors() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let repo: GenericRepository<_, _, InMemorySnapshotStore<TestAggregate>> =
+        GenericRepository::new(store.clone(), None);
+    let id = Uuid::new_v4();
+
+    let agg = futures::executor::block_on(repo.handle(&id, TestCommand::Touch)).expect("create");
+    assert_eq!(agg.version(), 1);
+
+    let err = futures::executor::block_on(repo.handle(&id, TestCommand::Reject))
+        .expect_err("command should be rejected");
+    assert!(matches!(err, CommandError::Domain(Rejected)));
+
+    let loaded = futures::executor::block_on(store.load(&id)).expect("load");
+    assert_eq!(loaded.len(), 1, "a rejected command appends nothing");
+}
+
+#[test]
+fn repository_handle_takes_commands_that_cannot_be_cloned() {
+    let repo: GenericRepository<_, _, InMemorySnapshotStore<Emitter>> =
+        GenericRepository::new(Arc::new(InMemoryEventStore::<Emitter>::default()), None);
+    let id = Uuid::new_v4();
+
+    let command = Emit(vec![TestEvent::Created, TestEvent::Updated]);
+    let agg = futures::executor::block_on(repo.handle(&id, command)).expect("handle");
+    assert_eq!(agg.version(), 2);
+}
+
+#[test]
+fn repository_execute_returns_the_original_events_of_a_retried_request() {
+    let store = Arc::new(InMemoryEventStore::<TestAggregate>::default());
+    let repo: GenericRepository<_, _, InMemorySnapshotStore<TestAggregate>> =
+        GenericRepository::new(store.clone(), None);
+    let id = Uuid::new_v4();
+    futures::executor::block_on(repo.execute(&id, TestCommand::Touch, RetryPolicy::no_retry()))
+        .expect("create");
+
+    let metadata = EventMetadata::new()
+        .with_idempotency_key("request-1")
+        .with_correlation_id("corr-1");
+    let first = futures::executor::block_on(repo.execute_with_metadata(
+        &id,
+        TestCommand::Touch,
+        RetryPolicy::no_retry(),
+        metadata.clone(),
+    ))
+    .expect("first");
+    let retried = futures::executor::block_on(repo.execute_with_metadata(
+        &id,
+        TestCommand::Touch,
+        RetryPolicy::no_retry(),
+        metadata,
+    ))
+    .expect("retried");
+    assert_eq!(first.version(), 2);
+    assert_eq!(retried.version(), 2, "the retry stores nothing");
+
+    let events = futures::executor::block_on(store.load(&id)).expect("load");
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[1].metadata().correlation_id(), Some("corr-1"));
+}
+
+#[test]
+fn retry_policy_backs_off_exponentially_up_to_the_cap() {
+    let policy = RetryPolicy::new(5)
+        .with_backoff(Duration::from_millis(10), Duration::from_millis(50))
+        .with_multiplier(2);
+    let delays: Vec<_> = (1..=4).map(|attempt| policy.backoff(attempt)).collect();
+    assert_eq!(
+        delays,
+        [10, 20, 40, 50].map(Duration::from_millis),
+        "delays double until capped"
+    );
+}
+
+#[test]
+fn payload_formats_round_trip_their_names() {
+    use sourcerer::serializer::Format;
+
+    for format in [
+        Format::Json,
+        Format::MessagePack,
+        Format::Cbor,
+        Format::Bincode,
+    ] {
+        assert_eq!(format.to_string().parse::<Format>().expect("parse"), format);
+    }
+    assert!("yaml".parse::<Format>().is_err());
+
+    let value: serde_json::Value = Format::Json.decode(br#"{"a":1}"#).expect("decode json");
+    assert_eq!(value, serde_json::json!({ "a": 1 }));
+    #[cfg(not(feature = "cbor"))]
+    assert!(
+        Format::Cbor.decode::<serde_json::Value>(&[0xa0]).is_err(),
+        "formats whose feature is disabled are reported, not misread"
+    );
+}
+
+/// Checks that appending no events still checks the expected version.
+async fn assert_empty_appends_check_the_expected_version(store: &impl EventStore<TestAggregate>) {
+    let (id, new) = (Uuid::new_v4(), Uuid::new_v4());
+    store
+        .append(
+            &id,
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created],
+            EventMetadata::new(),
+        )
+        .await
+        .expect("append");
+
+    let empty = |id, expected| store.append(id, expected, Vec::new(), EventMetadata::new());
+    for (id, expected) in [
+        (&id, ExpectedVersion::NoStream),
+        (&id, ExpectedVersion::Exact(0)),
+        (&new, ExpectedVersion::StreamExists),
+    ] {
+        assert!(
+            matches!(
+                empty(id, expected).await,
+                Err(sourcerer::Error::Conflict { .. })
+            ),
+            "an empty append expecting {expected:?} conflicts"
+        );
+    }
+    for (id, expected) in [
+        (&id, ExpectedVersion::Exact(1)),
+        (&id, ExpectedVersion::Any),
+        (&new, ExpectedVersion::NoStream),
+    ] {
+        let stored = empty(id, expected).await.expect("empty append");
+        assert!(stored.is_empty());
+    }
+    assert_eq!(store.load(&id).await.expect("load").len(), 1);
+}
+
+#[tokio::test]
+async fn empty_appends_check_the_expected_version_in_every_store() {
+    assert_empty_appends_check_the_expected_version(&InMemoryEventStore::default()).await;
+
+    #[cfg(feature = "sled-storage")]
+    {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .expect("open temporary sled db");
+        let store = sourcerer::store::sled::SledEventStore::new(db);
+        assert_empty_appends_check_the_expected_version(&store).await;
+    }
+
+    #[cfg(feature = "file-storage")]
+    {
+        use sourcerer::store::file::{FileEventStore, FileLog, FileOptions};
+
+        let dir = tempfile::tempdir().expect("temp dir");
+        let log = FileLog::open(dir.path(), FileOptions::new()).expect("open log");
+        assert_empty_appends_check_the_expected_version(&FileEventStore::new(log)).await;
+    }
+
+    #[cfg(feature = "sqlite-storage")]
+    {
+        use sourcerer::store::sqlx_sqlite::SqliteEventStore;
+
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("open in-memory sqlite db");
+        let store = SqliteEventStore::new(pool);
+        store.setup().await.expect("set up events");
+        assert_empty_appends_check_the_expected_version(&store).await;
+    }
+}
@@ -1,7 +1,7 @@
 #![allow(missing_docs)]
 use serde::{Deserialize, Serialize};
-use sourcerer::Event;
-use sourcerer_derive::Event as DeriveEvent;
+use sourcerer::{Event, Snapshot};
+use sourcerer_derive::{Event as DeriveEvent, Snapshot as DeriveSnapshot};
 
 #[derive(Clone, Debug, Serialize, Deserialize, DeriveEvent)]
 #[event(version = 7, source = "urn:custom")]
@@ -22,3 +22,18 @@ fn derive_macro_configurable_version_and_source() {
     assert_eq!(CustomEvent::Else.event_source(), "urn:variant");
     assert_eq!(CustomEvent::Else.event_type(), "Else");
 }
+
+#[derive(Clone, Debug, Serialize, Deserialize, DeriveSnapshot)]
+struct DefaultSnapshot;
+
+#[derive(Clone, Debug, Serialize, Deserialize, DeriveSnapshot)]
+#[snapshot(version = 3)]
+struct VersionedSnapshot {
+    balance: i64,
+}
+
+#[test]
+fn derive_macro_configurable_snapshot_version() {
+    assert_eq!(DefaultSnapshot::snapshot_version(), 1);
+    assert_eq!(VersionedSnapshot::snapshot_version(), 3);
+}
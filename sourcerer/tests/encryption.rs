@@ -0,0 +1,184 @@
+//! Integration tests for crypto-shredding.
+#![cfg(all(feature = "encryption", feature = "in-memory"))]
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use sourcerer::{
+    Aggregate, Event, EventStore, KeyStore, Snapshot, async_trait,
+    encryption::{Encrypted, Encryption},
+    repository::{GenericRepository, Repository},
+    store::{
+        in_memory::InMemoryEventStore, in_memory_key::InMemoryKeyStore,
+        in_memory_snapshot::InMemorySnapshotStore,
+    },
+};
+
+/// Events carrying personal data.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+enum PersonEvent {
+    Registered { name: Encrypted<String>, age: u8 },
+    Erased,
+}
+
+impl Event for PersonEvent {
+    fn event_type(&self) -> &'static str {
+        match self {
+            Self::Registered { .. } => "Registered",
+            Self::Erased => "Erased",
+        }
+    }
+
+    fn event_version(&self) -> u16 {
+        1
+    }
+
+    fn event_source(&self) -> &'static str {
+        "urn:sourcerer:test"
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct PersonSnap;
+
+impl Snapshot for PersonSnap {}
+
+/// An aggregate remembering the name it was registered with.
+#[derive(Default, Debug)]
+struct Person {
+    id: Uuid,
+    name: Option<String>,
+    age: u8,
+    erased: bool,
+    version: i64,
+}
+
+#[async_trait]
+impl Aggregate for Person {
+    const TYPE_NAME: &str = "person";
+    type Id = Uuid;
+    type Event = PersonEvent;
+    type Command = ();
+    type Snapshot = PersonSnap;
+    type Error = std::convert::Infallible;
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+
+    fn version(&self) -> i64 {
+        self.version
+    }
+
+    fn apply(&mut self, event: &Self::Event) {
+        match event {
+            PersonEvent::Registered { name, age } => {
+                self.name = name.value().cloned();
+                self.age = *age;
+            }
+            PersonEvent::Erased => self.erased = true,
+        }
+        self.version += 1;
+    }
+
+    async fn handle(
+        &self,
+        _command: Self::Command,
+    ) -> std::result::Result<Vec<Self::Event>, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn from_snapshot(_snapshot: Self::Snapshot) -> Self {
+        Self::default()
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        PersonSnap
+    }
+}
+
+type Repo = GenericRepository<Person, InMemoryEventStore<Person>, InMemorySnapshotStore<Person>>;
+
+/// Registers a person named Ada through `repo` and returns their ID.
+fn register(repo: &Repo) -> Uuid {
+    let id = Uuid::new_v4();
+    let event = PersonEvent::Registered {
+        name: Encrypted::new(id.to_string(), "Ada".to_string()),
+        age: 36,
+    };
+    let mut person = Person {
+        id,
+        ..Person::default()
+    };
+    person.apply(&event);
+    futures::executor::block_on(repo.save(&person, vec![event])).expect("save person");
+    id
+}
+
+// -- Tests ---------------------------------------------------------------
+
+#[test]
+fn repository_encrypts_personal_data_and_decrypts_it_on_load() {
+    let store = Arc::new(InMemoryEventStore::<Person>::default());
+    let keys = Arc::new(InMemoryKeyStore::default());
+    let repo = Repo::new(store.clone(), None).with_encryption(Encryption::new(keys));
+    let id = register(&repo);
+
+    let raw = futures::executor::block_on(store.load_raw(&id, 0)).expect("load raw");
+    let payload = String::from_utf8(raw[0].payload.clone()).expect("JSON payload");
+    assert!(payload.contains("$sealed"), "the name is stored sealed");
+    assert!(!payload.contains("Ada"), "the name is not stored in clear");
+    assert!(payload.contains("36"), "other fields stay readable");
+
+    let stored = futures::executor::block_on(store.load(&id)).expect("load");
+    match stored[0].event() {
+        PersonEvent::Registered { name, .. } => assert_eq!(name.value(), None),
+        other => panic!("unexpected event {other:?}"),
+    }
+
+    let person = futures::executor::block_on(repo.load(&id)).expect("load person");
+    assert_eq!(person.name.as_deref(), Some("Ada"));
+    assert_eq!(person.age, 36);
+}
+
+#[test]
+fn forgetting_a_subject_shreds_their_events() {
+    let store = Arc::new(InMemoryEventStore::<Person>::default());
+    let keys = Arc::new(InMemoryKeyStore::default());
+    let repo = Repo::new(store.clone(), None).with_encryption(Encryption::new(keys.clone()));
+    let forgotten = register(&repo);
+    let remembered = register(&repo);
+
+    futures::executor::block_on(keys.forget(&forgotten.to_string())).expect("forget");
+
+    let person = futures::executor::block_on(repo.load(&forgotten)).expect("load shredded");
+    assert_eq!(person.name, None, "the name is shredded");
+    assert_eq!(person.age, 36, "the rest of the event still loads");
+    assert_eq!(person.version, 1);
+
+    let person = futures::executor::block_on(repo.load(&remembered)).expect("load other");
+    assert_eq!(person.name.as_deref(), Some("Ada"));
+
+    let placeholder = Repo::new(store, None)
+        .with_encryption(Encryption::new(keys).with_placeholder(|_raw| PersonEvent::Erased));
+    let person = futures::executor::block_on(placeholder.load(&forgotten)).expect("load");
+    assert!(person.erased, "the placeholder replaces the shredded event");
+    let person = futures::executor::block_on(placeholder.load(&remembered)).expect("load");
+    assert!(!person.erased);
+}
+
+#[test]
+fn unsealed_personal_data_never_serializes_or_prints() {
+    let event = PersonEvent::Registered {
+        name: Encrypted::new("subject", "Ada".to_string()),
+        age: 36,
+    };
+
+    assert!(
+        serde_json::to_string(&event).is_err(),
+        "personal data is only written once sealed"
+    );
+    assert!(!format!("{event:?}").contains("Ada"));
+}
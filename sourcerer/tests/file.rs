@@ -0,0 +1,654 @@
+//! Integration tests for the file-backed stores.
+#![cfg(feature = "file-storage")]
+
+use std::{fs, io::Write, path::Path, time::Duration};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use sourcerer::{
+    Aggregate, Event, EventMetadata, EventStore, ExpectedVersion, Snapshot, SnapshotStore,
+    async_trait,
+    store::{
+        file::{FileEventStore, FileLog, FileOptions, SyncPolicy},
+        file_snapshot::FileSnapshotStore,
+    },
+    subscription::SubscriptionFilter,
+};
+
+/// Simple event used for testing.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+enum TestEvent {
+    Created,
+    Updated,
+}
+
+impl Event for TestEvent {
+    fn event_type(&self) -> &'static str {
+        match self {
+            Self::Created => "Created",
+            Self::Updated => "Updated",
+        }
+    }
+
+    fn event_version(&self) -> u16 {
+        1
+    }
+
+    fn event_source(&self) -> &'static str {
+        "urn:sourcerer:test"
+    }
+}
+
+/// Snapshot payload for [`TestAggregate`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct TestSnap {
+    version: i64,
+}
+
+impl Snapshot for TestSnap {}
+
+/// A minimal aggregate implementation used solely for testing store behaviour.
+#[derive(Default, Debug)]
+struct TestAggregate {
+    id: Uuid,
+    version: i64,
+}
+
+#[async_trait]
+impl Aggregate for TestAggregate {
+    const TYPE_NAME: &str = "test";
+    type Id = Uuid;
+    type Event = TestEvent;
+    type Command = ();
+    type Snapshot = TestSnap;
+    type Error = std::convert::Infallible;
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+
+    fn version(&self) -> i64 {
+        self.version
+    }
+
+    fn apply(&mut self, _event: &Self::Event) {
+        self.version += 1;
+    }
+
+    async fn handle(
+        &self,
+        _command: Self::Command,
+    ) -> std::result::Result<Vec<Self::Event>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    fn from_snapshot(snapshot: Self::Snapshot) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            version: snapshot.version,
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        TestSnap {
+            version: self.version,
+        }
+    }
+}
+
+/// A second aggregate type sharing IDs and events with [`TestAggregate`],
+/// used to test that stores keep aggregate types apart.
+#[derive(Default, Debug)]
+struct OtherAggregate(TestAggregate);
+
+#[async_trait]
+impl Aggregate for OtherAggregate {
+    const TYPE_NAME: &str = "other";
+    type Id = Uuid;
+    type Event = TestEvent;
+    type Command = ();
+    type Snapshot = TestSnap;
+    type Error = std::convert::Infallible;
+
+    fn id(&self) -> &Self::Id {
+        self.0.id()
+    }
+
+    fn version(&self) -> i64 {
+        self.0.version()
+    }
+
+    fn apply(&mut self, event: &Self::Event) {
+        self.0.apply(event);
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+    ) -> std::result::Result<Vec<Self::Event>, Self::Error> {
+        self.0.handle(command).await
+    }
+
+    fn from_snapshot(snapshot: Self::Snapshot) -> Self {
+        Self(TestAggregate::from_snapshot(snapshot))
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.0.snapshot()
+    }
+}
+
+fn open_store(dir: &Path, options: FileOptions) -> FileEventStore<TestAggregate> {
+    FileEventStore::new(FileLog::open(dir, options).expect("open file log"))
+}
+
+/// Lists the segment files in `dir`, in log order.
+fn segments(dir: &Path) -> Vec<std::path::PathBuf> {
+    let mut segments: Vec<_> = fs::read_dir(dir)
+        .expect("read dir")
+        .map(|entry| entry.expect("dir entry").path())
+        .filter(|path| path.extension().is_some_and(|e| e == "log"))
+        .collect();
+    segments.sort();
+    segments
+}
+
+// -- Tests ---------------------------------------------------------------
+
+#[test]
+fn file_event_store_survives_reopen() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let first = Uuid::new_v4();
+    let second = Uuid::new_v4();
+    let metadata = EventMetadata::new().with_correlation_id("request-1");
+
+    let store = open_store(dir.path(), FileOptions::new());
+    futures::executor::block_on(store.append(
+        &first,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        metadata,
+    ))
+    .expect("append first");
+    futures::executor::block_on(store.append(
+        &second,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append second");
+    drop(store);
+
+    let store = open_store(dir.path(), FileOptions::new());
+    let loaded = futures::executor::block_on(store.load(&first)).expect("load first");
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded[0].aggregate_type(), "test");
+    assert_eq!(loaded[0].metadata().correlation_id(), Some("request-1"));
+
+    let all = futures::executor::block_on(store.read_all(0, 10)).expect("read all");
+    let positions: Vec<i64> = all.iter().map(|e| e.position()).collect();
+    assert_eq!(positions, vec![1, 2, 3]);
+    let page = futures::executor::block_on(store.read_all(2, 10)).expect("read page");
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].event_type(), "Updated");
+
+    let mut ids = vec![first.to_string(), second.to_string()];
+    ids.sort();
+    assert_eq!(
+        futures::executor::block_on(store.list_aggregate_ids()).expect("list ids"),
+        ids
+    );
+
+    // The reopened log carries on where it left off.
+    let stored = futures::executor::block_on(store.append(
+        &second,
+        ExpectedVersion::Exact(2),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append after reopen");
+    assert_eq!(stored[0].version(), 3);
+    assert_eq!(stored[0].position(), 4);
+
+    let versions: Vec<i64> =
+        futures::executor::block_on(store.stream_from(&second, 1).collect::<Vec<_>>())
+            .into_iter()
+            .map(|e| e.expect("streamed event").version())
+            .collect();
+    assert_eq!(versions, vec![2, 3]);
+    let raw = futures::executor::block_on(store.load_raw(&second, 0)).expect("load raw");
+    assert_eq!(raw.len(), 3);
+    assert_eq!(
+        raw[2].decode::<serde_json::Value>().expect("decode"),
+        serde_json::json!("Updated")
+    );
+
+    // The hash chain carries on across the reopen too.
+    assert_eq!(raw[2].hash.as_deref(), stored[0].hash());
+    assert_eq!(
+        futures::executor::block_on(store.verify_all()).expect("verify all"),
+        None
+    );
+}
+
+#[test]
+fn file_event_store_rejects_idempotency_keys() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let id = Uuid::new_v4();
+
+    let store = open_store(dir.path(), FileOptions::new());
+    let result = futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::new().with_idempotency_key("request-1"),
+    ));
+    assert!(
+        matches!(result, Err(sourcerer::Error::Store(_))),
+        "an append it cannot deduplicate is refused"
+    );
+    let loaded = futures::executor::block_on(store.load(&id)).expect("load");
+    assert!(loaded.is_empty());
+}
+
+#[test]
+fn file_event_store_truncates_torn_writes() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let id = Uuid::new_v4();
+
+    let store = open_store(dir.path(), FileOptions::new());
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    drop(store);
+
+    // Simulate a crash in the middle of the next append: a frame header
+    // promising more bytes than were written.
+    let segment = segments(dir.path()).pop().expect("segment");
+    let intact_len = fs::metadata(&segment).expect("metadata").len();
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(&segment)
+        .expect("open segment");
+    file.write_all(&[200, 0, 0, 0, 1, 2, 3, 4, b'[', b'{'])
+        .expect("write torn frame");
+    drop(file);
+
+    let store = open_store(dir.path(), FileOptions::new());
+    assert_eq!(
+        fs::metadata(&segment).expect("metadata").len(),
+        intact_len,
+        "the torn frame is truncated on open"
+    );
+    let loaded = futures::executor::block_on(store.load(&id)).expect("load");
+    assert_eq!(loaded.len(), 1);
+
+    let stored = futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::Exact(1),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append after recovery");
+    assert_eq!(stored[0].position(), 2);
+    drop(store);
+
+    let store = open_store(dir.path(), FileOptions::new());
+    let loaded = futures::executor::block_on(store.load(&id)).expect("load");
+    assert_eq!(loaded.len(), 2);
+}
+
+#[test]
+fn file_event_store_recovers_from_a_large_torn_append_quickly() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let id = Uuid::new_v4();
+
+    let store = open_store(dir.path(), FileOptions::new());
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    let segment = segments(dir.path()).pop().expect("segment");
+    let intact_len = fs::metadata(&segment).expect("metadata").len();
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::Exact(1),
+        vec![TestEvent::Updated; 20_000],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    drop(store);
+
+    // Cut the multi-megabyte append short, as a crash in its middle would.
+    let len = fs::metadata(&segment).expect("metadata").len();
+    assert!(len - intact_len > 4 << 20, "the append spans megabytes");
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&segment)
+        .and_then(|file| file.set_len(len - 100))
+        .expect("tear append");
+
+    let started = std::time::Instant::now();
+    let store = open_store(dir.path(), FileOptions::new());
+    assert!(
+        started.elapsed() < std::time::Duration::from_secs(10),
+        "recovery took {:?}",
+        started.elapsed()
+    );
+    assert_eq!(fs::metadata(&segment).expect("metadata").len(), intact_len);
+    let loaded = futures::executor::block_on(store.load(&id)).expect("load");
+    assert_eq!(loaded.len(), 1);
+}
+
+#[test]
+fn file_event_store_refuses_damage_before_intact_frames() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let id = Uuid::new_v4();
+
+    let store = open_store(dir.path(), FileOptions::new());
+    let segment = segments(dir.path()).pop().expect("segment");
+    let mut frame_ends = Vec::new();
+    for (expected, event) in [
+        (ExpectedVersion::NoStream, TestEvent::Created),
+        (ExpectedVersion::Exact(1), TestEvent::Updated),
+        (ExpectedVersion::Exact(2), TestEvent::Updated),
+    ] {
+        futures::executor::block_on(store.append(
+            &id,
+            expected,
+            vec![event],
+            EventMetadata::default(),
+        ))
+        .expect("append");
+        frame_ends.push(fs::metadata(&segment).expect("metadata").len());
+    }
+    drop(store);
+
+    // Flip a byte in the body of the middle frame.
+    let mut bytes = fs::read(&segment).expect("read segment");
+    let damaged = frame_ends[0] as usize + 12;
+    bytes[damaged] ^= 0xff;
+    fs::write(&segment, &bytes).expect("write segment");
+
+    assert!(
+        matches!(
+            FileLog::open(dir.path(), FileOptions::new()),
+            Err(sourcerer::Error::Store(_))
+        ),
+        "the damaged frame is not mistaken for a torn write"
+    );
+    assert_eq!(
+        fs::metadata(&segment).expect("metadata").len(),
+        frame_ends[2],
+        "the intact frame after it is kept"
+    );
+}
+
+#[test]
+fn file_event_store_rolls_segments_over() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let options = FileOptions::new()
+        .with_segment_size(1)
+        .with_sync_policy(SyncPolicy::Batched {
+            max_appends: 2,
+            max_delay: Duration::from_secs(60),
+        });
+    let id = Uuid::new_v4();
+
+    let store = open_store(dir.path(), options);
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    for version in 2..5 {
+        futures::executor::block_on(store.append(
+            &id,
+            ExpectedVersion::Exact(version),
+            vec![TestEvent::Updated],
+            EventMetadata::default(),
+        ))
+        .expect("append");
+    }
+    drop(store);
+
+    // Every append after the first starts a new segment named after the
+    // position of its first event.
+    let names: Vec<String> = segments(dir.path())
+        .iter()
+        .map(|path| path.file_stem().unwrap().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(
+        names,
+        [1, 3, 4, 5]
+            .iter()
+            .map(|position| format!("{position:020}"))
+            .collect::<Vec<_>>()
+    );
+
+    let store = open_store(dir.path(), options);
+    let versions: Vec<i64> = futures::executor::block_on(store.load_from(&id, 1))
+        .expect("load")
+        .iter()
+        .map(|e| e.version())
+        .collect();
+    assert_eq!(versions, vec![2, 3, 4, 5]);
+    let page = futures::executor::block_on(store.read_all(1, 2)).expect("read page");
+    let positions: Vec<i64> = page.iter().map(|e| e.position()).collect();
+    assert_eq!(positions, vec![2, 3]);
+}
+
+#[test]
+fn file_stores_keep_aggregate_types_apart() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let log = FileLog::open(dir.path(), FileOptions::new()).expect("open file log");
+    let tests = FileEventStore::<TestAggregate>::new(log.clone());
+    let others = FileEventStore::<OtherAggregate>::new(log);
+    let id = Uuid::new_v4();
+
+    futures::executor::block_on(tests.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append test stream");
+    futures::executor::block_on(others.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append other stream");
+
+    let err = futures::executor::block_on(tests.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect_err("stream exists");
+    match err {
+        sourcerer::Error::Conflict {
+            aggregate_id,
+            expected,
+            actual,
+        } => {
+            assert_eq!(aggregate_id, id.to_string());
+            assert_eq!(expected, ExpectedVersion::NoStream);
+            assert_eq!(actual, 1);
+        }
+        other => panic!("expected a conflict, got {other:?}"),
+    }
+
+    let loaded = futures::executor::block_on(others.load(&id)).expect("load other stream");
+    assert_eq!(loaded.len(), 2);
+    assert_eq!(loaded[0].aggregate_type(), "other");
+
+    let positions: Vec<i64> = futures::executor::block_on(others.read_all(0, 10))
+        .expect("read all")
+        .iter()
+        .map(|e| e.position())
+        .collect();
+    assert_eq!(positions, vec![2, 3]);
+}
+
+#[test]
+fn file_subscription_catches_up_then_follows_appends() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let store = open_store(dir.path(), FileOptions::new());
+    let first = Uuid::new_v4();
+    let second = Uuid::new_v4();
+    futures::executor::block_on(store.append(
+        &first,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append first");
+
+    let filter = SubscriptionFilter::new().with_aggregate_ids([second]);
+    let mut all = futures::executor::block_on(store.subscribe(0, SubscriptionFilter::new()))
+        .expect("subscribe");
+    let mut filtered = futures::executor::block_on(store.subscribe(0, filter)).expect("subscribe");
+
+    let replayed = futures::executor::block_on(all.next())
+        .expect("stream open")
+        .expect("replayed event");
+    assert_eq!(replayed.aggregate_id(), first.to_string());
+
+    futures::executor::block_on(store.append(
+        &second,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append second");
+
+    let live = futures::executor::block_on(all.next())
+        .expect("stream open")
+        .expect("live event");
+    assert_eq!(live.position(), 2);
+
+    let only_second = futures::executor::block_on(filtered.next())
+        .expect("stream open")
+        .expect("filtered event");
+    assert_eq!(only_second.aggregate_id(), second.to_string());
+}
+
+#[test]
+fn file_snapshot_store_save_and_load() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let id = Uuid::new_v4();
+
+    let snapshots = FileSnapshotStore::<TestAggregate>::open(dir.path()).expect("open");
+    assert!(
+        futures::executor::block_on(snapshots.load(&id))
+            .expect("load")
+            .is_none()
+    );
+    futures::executor::block_on(snapshots.save(&id, 3, TestSnap { version: 3 })).expect("save");
+    futures::executor::block_on(snapshots.save(&id, 7, TestSnap { version: 7 })).expect("save");
+    futures::executor::block_on(snapshots.save(&id, 5, TestSnap { version: 5 }))
+        .expect("stale saves are ignored");
+
+    let others = FileSnapshotStore::<OtherAggregate>::open(dir.path()).expect("open");
+    assert!(
+        futures::executor::block_on(others.load(&id))
+            .expect("load")
+            .is_none(),
+        "snapshots are kept apart by aggregate type"
+    );
+
+    let snapshots = FileSnapshotStore::<TestAggregate>::open(dir.path()).expect("reopen");
+    let loaded = futures::executor::block_on(snapshots.load(&id))
+        .expect("load")
+        .expect("snapshot");
+    assert_eq!(loaded.version(), 7);
+    assert_eq!(loaded.snapshot_version(), 1);
+    assert_eq!(loaded.into_snapshot().version, 7);
+}
+
+#[test]
+fn file_snapshot_store_loads_snapshots_it_cannot_deserialize_raw() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let id = Uuid::new_v4();
+    let snapshots = FileSnapshotStore::<TestAggregate>::open(dir.path()).expect("open");
+    futures::executor::block_on(snapshots.save(&id, 3, TestSnap { version: 3 })).expect("save");
+
+    // Rewrite the snapshot as an older release would have, without a schema
+    // version and with another shape.
+    let file = fs::read_dir(dir.path())
+        .expect("list snapshots")
+        .next()
+        .expect("snapshot file")
+        .expect("dir entry")
+        .path();
+    let legacy = serde_json::json!({
+        "aggregate_id": id.to_string(),
+        "version": 3,
+        "snapshot": { "v": 3 },
+    });
+    fs::write(&file, legacy.to_string()).expect("rewrite snapshot");
+
+    assert!(futures::executor::block_on(snapshots.load(&id)).is_err());
+    let raw = futures::executor::block_on(snapshots.load_raw(&id))
+        .expect("load raw")
+        .expect("snapshot");
+    assert_eq!(raw.version, 3);
+    assert_eq!(raw.snapshot_version, 1);
+    assert_eq!(raw.payload, serde_json::json!({ "v": 3 }));
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn file_event_store_reads_payloads_in_their_recorded_format() {
+    use sourcerer::serializer::{Format, MessagePackSerializer};
+
+    let dir = tempfile::tempdir().expect("temp dir");
+    let id = Uuid::new_v4();
+
+    let store = open_store(dir.path(), FileOptions::new());
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append json");
+    let msgpack = store.with_serializer(MessagePackSerializer);
+    futures::executor::block_on(msgpack.append(
+        &id,
+        ExpectedVersion::Exact(1),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append msgpack");
+    drop(msgpack);
+
+    let store = open_store(dir.path(), FileOptions::new());
+    let events: Vec<TestEvent> = futures::executor::block_on(store.load(&id))
+        .expect("load")
+        .into_iter()
+        .map(|e| e.into_event())
+        .collect();
+    assert_eq!(events, vec![TestEvent::Created, TestEvent::Updated]);
+
+    let raw = futures::executor::block_on(store.load_raw(&id, 1)).expect("load raw");
+    assert_eq!(raw[0].format, Format::MessagePack);
+    assert_eq!(
+        raw[0].decode::<serde_json::Value>().expect("decode"),
+        serde_json::json!("Updated")
+    );
+}
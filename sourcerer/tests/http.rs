@@ -0,0 +1,197 @@
+//! Integration tests for the CloudEvents HTTP protocol binding.
+#![cfg(feature = "http")]
+
+use axum::{
+    Router,
+    body::{Body, to_bytes},
+    http::{Request, StatusCode, header::CONTENT_TYPE},
+    response::Response,
+    routing::post,
+};
+use cloudevents::AttributesReader;
+use serde::{Deserialize, Serialize};
+use tower::ServiceExt;
+
+use sourcerer::{
+    CloudEvent, Event, EventMetadata, StoredEvent,
+    http::{self, Batch, Binary, Mode, Structured},
+};
+
+/// Simple event used for testing.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+enum TestEvent {
+    Shipped { parcels: u32 },
+}
+
+impl Event for TestEvent {
+    fn event_type(&self) -> &'static str {
+        "Shipped"
+    }
+
+    fn event_version(&self) -> u16 {
+        1
+    }
+
+    fn event_source(&self) -> &'static str {
+        "urn:sourcerer:test"
+    }
+}
+
+/// A stored event as another service would emit it.
+fn shipped(parcels: u32) -> StoredEvent<TestEvent> {
+    StoredEvent::new(
+        "order-1".into(),
+        3,
+        1,
+        "Shipped".into(),
+        TestEvent::Shipped { parcels },
+    )
+    .with_aggregate_type("order")
+    .with_metadata(EventMetadata::new())
+}
+
+fn router() -> Router {
+    Router::new()
+        .route("/echo", post(|event: CloudEvent| async move { event }))
+        .route(
+            "/binary",
+            post(|Binary(event): Binary| async move { Binary(event) }),
+        )
+        .route(
+            "/structured",
+            post(|Structured(event): Structured| async move { Structured(event) }),
+        )
+        .route(
+            "/batch",
+            post(|Batch(mut events): Batch| async move {
+                events.reverse();
+                Batch(events)
+            }),
+        )
+}
+
+async fn send(uri: &str, (headers, body): (axum::http::HeaderMap, Vec<u8>)) -> Response {
+    let mut request = Request::post(uri).body(Body::from(body)).expect("request");
+    *request.headers_mut() = headers;
+    router().oneshot(request).await.expect("response")
+}
+
+async fn receive(response: Response) -> CloudEvent {
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers().clone();
+    let body = to_bytes(response.into_body(), usize::MAX)
+        .await
+        .expect("body");
+    http::decode(&headers, body.to_vec()).expect("decode")
+}
+
+/// Returns the ID and the decoded data of an event, which compare equal
+/// whether the data was read as JSON or as bytes.
+fn contents(event: CloudEvent) -> (String, TestEvent) {
+    let id = event.0.id().to_owned();
+    let stored = StoredEvent::<TestEvent>::try_from(event).expect("stored");
+    (id, stored.event().clone())
+}
+
+#[tokio::test]
+async fn binary_mode_carries_attributes_in_headers() {
+    let stored = shipped(2);
+    let event = CloudEvent::from_stored(&stored).expect("convert");
+    let encoded = http::encode(&event, Mode::Binary).expect("encode");
+    assert_eq!(
+        encoded.0["ce-id"],
+        stored.metadata().event_id().to_string().as_str()
+    );
+    assert_eq!(encoded.0["ce-subject"], "order-1");
+    assert_eq!(encoded.0["ce-aggregateversion"], "3");
+    assert_eq!(encoded.0[CONTENT_TYPE], "application/json");
+
+    for uri in ["/echo", "/binary"] {
+        let response = send(uri, encoded.clone()).await;
+        assert!(response.headers().contains_key("ce-specversion"));
+        let received = receive(response).await;
+        assert_eq!(received.0.id(), event.0.id());
+
+        // String extensions from the headers read back as stored events.
+        let received = StoredEvent::<TestEvent>::try_from(received).expect("stored");
+        assert_eq!(received.event(), &TestEvent::Shipped { parcels: 2 });
+        assert_eq!(received.version(), 3);
+        assert_eq!(received.aggregate_type(), "order");
+    }
+}
+
+#[tokio::test]
+async fn structured_mode_carries_the_event_as_json() {
+    let event = CloudEvent::from_stored(&shipped(5)).expect("convert");
+    let encoded = http::encode(&event, Mode::Structured).expect("encode");
+    assert_eq!(encoded.0[CONTENT_TYPE], http::STRUCTURED_CONTENT_TYPE);
+
+    let response = send("/structured", encoded.clone()).await;
+    assert_eq!(
+        response.headers()[CONTENT_TYPE],
+        http::STRUCTURED_CONTENT_TYPE
+    );
+    assert_eq!(contents(receive(response).await), contents(event.clone()));
+
+    // The plain extractor takes either single-event mode, and answers in
+    // binary mode.
+    let response = send("/echo", encoded).await;
+    assert!(response.headers().contains_key("ce-id"));
+    assert_eq!(contents(receive(response).await), contents(event));
+}
+
+#[tokio::test]
+async fn batch_mode_carries_a_list_of_events() {
+    let events: Vec<CloudEvent> = [1, 2, 3]
+        .map(|parcels| CloudEvent::from_stored(&shipped(parcels)).expect("convert"))
+        .into();
+    let response = send("/batch", http::encode_batch(&events).expect("encode")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers().clone();
+    let body = to_bytes(response.into_body(), usize::MAX)
+        .await
+        .expect("body");
+    let received = http::decode_batch(&headers, &body).expect("decode");
+    let ids: Vec<&str> = received.iter().map(|event| event.0.id()).collect();
+    let expected: Vec<&str> = events.iter().rev().map(|event| event.0.id()).collect();
+    assert_eq!(ids, expected);
+}
+
+#[tokio::test]
+async fn requests_in_other_modes_or_malformed_are_rejected() {
+    let event = CloudEvent::from_stored(&shipped(1)).expect("convert");
+    let status = |uri: &'static str, mode| {
+        let encoded = http::encode(&event, mode).expect("encode");
+        async move { send(uri, encoded).await.status() }
+    };
+    assert_eq!(
+        status("/echo", Mode::Batch).await,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+    assert_eq!(
+        status("/structured", Mode::Binary).await,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+    assert_eq!(
+        status("/binary", Mode::Structured).await,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+    assert_eq!(
+        status("/batch", Mode::Structured).await,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+
+    let (mut headers, body) = http::encode(&event, Mode::Binary).expect("encode");
+    headers.remove("ce-source");
+    assert_eq!(
+        send("/binary", (headers, body)).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    let (headers, _) = http::encode(&event, Mode::Structured).expect("encode");
+    assert_eq!(
+        send("/structured", (headers, b"{\"id\":".to_vec()))
+            .await
+            .status(),
+        StatusCode::BAD_REQUEST
+    );
+}
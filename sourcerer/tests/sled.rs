@@ -0,0 +1,1037 @@
+//! Integration tests for the `sled`-backed stores.
+#![cfg(feature = "sled-storage")]
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use sourcerer::{
+    Aggregate, CheckpointStore, Event, EventMetadata, EventStore, ExpectedVersion, Snapshot,
+    SnapshotStore, async_trait,
+    store::{
+        sled::SledEventStore, sled_checkpoint::SledCheckpointStore,
+        sled_snapshot::SledSnapshotStore,
+    },
+    subscription::SubscriptionFilter,
+};
+
+/// Simple event used for testing.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+enum TestEvent {
+    Created,
+    Updated,
+    Noted(String),
+}
+
+impl Event for TestEvent {
+    fn event_type(&self) -> &'static str {
+        match self {
+            Self::Created => "Created",
+            Self::Updated => "Updated",
+            Self::Noted(_) => "Noted",
+        }
+    }
+
+    fn event_version(&self) -> u16 {
+        1
+    }
+
+    fn event_source(&self) -> &'static str {
+        "urn:sourcerer:test"
+    }
+}
+
+/// Snapshot payload for [`TestAggregate`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct TestSnap {
+    version: i64,
+}
+
+impl Snapshot for TestSnap {}
+
+/// A minimal aggregate implementation used solely for testing store behaviour.
+#[derive(Default, Debug)]
+struct TestAggregate {
+    id: Uuid,
+    version: i64,
+}
+
+#[async_trait]
+impl Aggregate for TestAggregate {
+    const TYPE_NAME: &str = "test";
+    type Id = Uuid;
+    type Event = TestEvent;
+    type Command = ();
+    type Snapshot = TestSnap;
+    type Error = std::convert::Infallible;
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+
+    fn version(&self) -> i64 {
+        self.version
+    }
+
+    fn apply(&mut self, _event: &Self::Event) {
+        self.version += 1;
+    }
+
+    async fn handle(
+        &self,
+        _command: Self::Command,
+    ) -> std::result::Result<Vec<Self::Event>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    fn from_snapshot(snapshot: Self::Snapshot) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            version: snapshot.version,
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        TestSnap {
+            version: self.version,
+        }
+    }
+}
+
+/// A second aggregate type sharing IDs and events with [`TestAggregate`],
+/// used to test that stores keep aggregate types apart.
+#[derive(Default, Debug)]
+struct OtherAggregate(TestAggregate);
+
+#[async_trait]
+impl Aggregate for OtherAggregate {
+    const TYPE_NAME: &str = "other";
+    type Id = Uuid;
+    type Event = TestEvent;
+    type Command = ();
+    type Snapshot = TestSnap;
+    type Error = std::convert::Infallible;
+
+    fn id(&self) -> &Self::Id {
+        self.0.id()
+    }
+
+    fn version(&self) -> i64 {
+        self.0.version()
+    }
+
+    fn apply(&mut self, event: &Self::Event) {
+        self.0.apply(event);
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+    ) -> std::result::Result<Vec<Self::Event>, Self::Error> {
+        self.0.handle(command).await
+    }
+
+    fn from_snapshot(snapshot: Self::Snapshot) -> Self {
+        Self(TestAggregate::from_snapshot(snapshot))
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.0.snapshot()
+    }
+}
+
+fn temporary_store() -> SledEventStore<TestAggregate> {
+    let db = sled::Config::new()
+        .temporary(true)
+        .open()
+        .expect("open temporary sled db");
+    SledEventStore::new(db)
+}
+
+// -- Tests ---------------------------------------------------------------
+
+#[test]
+fn sled_event_store_read_all_orders_across_aggregates() {
+    let store = temporary_store();
+    let first = Uuid::new_v4();
+    let second = Uuid::new_v4();
+
+    futures::executor::block_on(store.append(
+        &first,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append first");
+    futures::executor::block_on(store.append(
+        &second,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append second");
+
+    let all = futures::executor::block_on(store.read_all(0, 10)).expect("read all");
+    let positions: Vec<i64> = all.iter().map(|e| e.position()).collect();
+    assert_eq!(positions, vec![1, 2, 3]);
+    assert_eq!(all[0].aggregate_id(), first.to_string());
+
+    let loaded = futures::executor::block_on(store.load(&second)).expect("load second");
+    assert_eq!(
+        loaded[1].position(),
+        3,
+        "stream reads carry the global position"
+    );
+
+    let page = futures::executor::block_on(store.read_all(2, 10)).expect("read page");
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].event_type(), "Updated");
+}
+
+#[test]
+fn sled_event_store_appends_batches_atomically() {
+    let store = temporary_store();
+    let first = Uuid::new_v4();
+    let second = Uuid::new_v4();
+
+    let stored = futures::executor::block_on(store.append_batch(
+        vec![
+            (first, ExpectedVersion::NoStream, vec![TestEvent::Created]),
+            (second, ExpectedVersion::NoStream, vec![TestEvent::Created]),
+            (first, ExpectedVersion::Exact(1), vec![TestEvent::Updated]),
+        ],
+        EventMetadata::default(),
+    ))
+    .expect("append batch");
+    let written: Vec<_> = stored.iter().map(|e| (e.version(), e.position())).collect();
+    assert_eq!(written, [(1, 1), (1, 2), (2, 3)]);
+    assert_eq!(
+        futures::executor::block_on(store.verify_stream(&first)).expect("verify"),
+        None
+    );
+
+    // One stale entry rejects the whole batch.
+    let err = futures::executor::block_on(store.append_batch(
+        vec![
+            (first, ExpectedVersion::Exact(2), vec![TestEvent::Updated]),
+            (second, ExpectedVersion::Exact(2), vec![TestEvent::Updated]),
+        ],
+        EventMetadata::default(),
+    ))
+    .expect_err("the second entry should conflict");
+    assert!(matches!(err, sourcerer::Error::Conflict { actual: 1, .. }));
+    let loaded = futures::executor::block_on(store.load(&first)).expect("load first");
+    assert_eq!(loaded.len(), 2, "the first entry was not written");
+    let all = futures::executor::block_on(store.read_all(0, 10)).expect("read all");
+    assert_eq!(all.len(), 3);
+
+    // Positions carry on without gaps after the rejected batch.
+    let stored = futures::executor::block_on(store.append(
+        &second,
+        ExpectedVersion::Exact(1),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append second");
+    assert_eq!(stored[0].position(), 4);
+}
+
+#[test]
+fn sled_event_store_deduplicates_appends_by_idempotency_key() {
+    let store = temporary_store();
+    let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+    let batch = || {
+        futures::executor::block_on(store.append_batch(
+            vec![
+                (first, ExpectedVersion::NoStream, vec![TestEvent::Created]),
+                (second, ExpectedVersion::NoStream, vec![TestEvent::Created]),
+            ],
+            EventMetadata::new().with_idempotency_key("transfer-1"),
+        ))
+    };
+
+    let stored = batch().expect("append batch");
+    let retried = batch().expect("retried batch");
+    let ids = |events: &[sourcerer::StoredEvent<TestEvent>]| {
+        events
+            .iter()
+            .map(|e| e.metadata().event_id())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ids(&retried), ids(&stored));
+    assert_eq!(
+        futures::executor::block_on(store.read_all(0, 10))
+            .expect("read all")
+            .len(),
+        2
+    );
+
+    // Once the key expires, the retry is a new append and conflicts.
+    let store = store.with_idempotency_retention(std::time::Duration::ZERO);
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    let err = futures::executor::block_on(store.append(
+        &first,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::new().with_idempotency_key("transfer-1"),
+    ))
+    .expect_err("the key has expired");
+    assert!(matches!(err, sourcerer::Error::Conflict { .. }));
+}
+
+#[test]
+fn sled_subscription_catches_up_then_follows_appends() {
+    let store = temporary_store();
+    let first = Uuid::new_v4();
+    let second = Uuid::new_v4();
+    futures::executor::block_on(store.append(
+        &first,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append first");
+
+    let filter = SubscriptionFilter::new().with_aggregate_ids([second]);
+    let mut all = futures::executor::block_on(store.subscribe(0, SubscriptionFilter::new()))
+        .expect("subscribe");
+    let mut filtered = futures::executor::block_on(store.subscribe(0, filter)).expect("subscribe");
+
+    let replayed = futures::executor::block_on(all.next())
+        .expect("stream open")
+        .expect("replayed event");
+    assert_eq!(replayed.aggregate_id(), first.to_string());
+
+    futures::executor::block_on(store.append(
+        &second,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append second");
+
+    let live = futures::executor::block_on(all.next())
+        .expect("stream open")
+        .expect("live event");
+    assert_eq!(live.position(), 2);
+
+    let only_second = futures::executor::block_on(filtered.next())
+        .expect("stream open")
+        .expect("filtered event");
+    assert_eq!(only_second.aggregate_id(), second.to_string());
+}
+
+#[test]
+fn sled_streams_events_in_version_order() {
+    let store = temporary_store();
+    let id = Uuid::new_v4();
+    let mut events = vec![TestEvent::Created];
+    events.extend(std::iter::repeat_n(TestEvent::Updated, 11));
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        events,
+        EventMetadata::default(),
+    ))
+    .expect("append");
+
+    // More than nine events exercise the ordering of version keys.
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::Exact(12),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append after twelve events");
+
+    let versions: Vec<i64> =
+        futures::executor::block_on(store.stream_from(&id, 8).collect::<Vec<_>>())
+            .into_iter()
+            .map(|e| e.expect("streamed event").version())
+            .collect();
+    assert_eq!(versions, (9..=13).collect::<Vec<_>>());
+
+    let raw = futures::executor::block_on(store.stream_raw(&id, 0).collect::<Vec<_>>());
+    assert_eq!(raw.len(), 13);
+}
+
+#[test]
+fn sled_checkpoint_store_save_load_and_delete() {
+    let db = sled::Config::new()
+        .temporary(true)
+        .open()
+        .expect("open temporary sled db");
+    let checkpoints = SledCheckpointStore::new(db.open_tree("checkpoints").expect("open tree"));
+
+    assert_eq!(
+        futures::executor::block_on(checkpoints.load("balances")).expect("load"),
+        None
+    );
+    futures::executor::block_on(checkpoints.save("balances", 42)).expect("save");
+    assert_eq!(
+        futures::executor::block_on(checkpoints.load("balances")).expect("load"),
+        Some(42)
+    );
+    futures::executor::block_on(checkpoints.delete("balances")).expect("delete");
+    assert_eq!(
+        futures::executor::block_on(checkpoints.load("balances")).expect("load"),
+        None
+    );
+}
+
+#[test]
+fn sled_event_store_persists_metadata() {
+    let store = temporary_store();
+    let id = Uuid::new_v4();
+    let metadata = EventMetadata::new()
+        .with_correlation_id("request-1")
+        .with_header("tenant", "acme");
+
+    let stored = futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        metadata,
+    ))
+    .expect("append");
+    let loaded = futures::executor::block_on(store.load(&id)).expect("load");
+
+    assert_eq!(loaded[0].metadata(), stored[0].metadata());
+    assert_eq!(loaded[0].metadata().correlation_id(), Some("request-1"));
+}
+
+#[test]
+fn sled_event_store_concurrent_creates_conflict() {
+    let store = std::sync::Arc::new(temporary_store());
+    let id = Uuid::new_v4();
+    let start = std::sync::Arc::new(std::sync::Barrier::new(16));
+
+    let writers: Vec<_> = (0..16)
+        .map(|_| {
+            let store = store.clone();
+            let start = start.clone();
+            std::thread::spawn(move || {
+                start.wait();
+                futures::executor::block_on(store.append(
+                    &id,
+                    ExpectedVersion::NoStream,
+                    vec![TestEvent::Created],
+                    EventMetadata::default(),
+                ))
+            })
+        })
+        .collect();
+    let results: Vec<_> = writers
+        .into_iter()
+        .map(|writer| writer.join().expect("writer thread"))
+        .collect();
+
+    assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+    for err in results.into_iter().filter_map(Result::err) {
+        match err {
+            sourcerer::Error::Conflict {
+                aggregate_id,
+                expected,
+                actual,
+            } => {
+                assert_eq!(aggregate_id, id.to_string());
+                assert_eq!(expected, ExpectedVersion::NoStream);
+                assert_eq!(actual, 1);
+            }
+            other => panic!("expected a conflict, got {other:?}"),
+        }
+    }
+    let loaded = futures::executor::block_on(store.load(&id)).expect("load");
+    assert_eq!(loaded.len(), 1);
+}
+
+#[test]
+fn sled_event_store_concurrent_appends_to_any_version_all_land() {
+    let store = std::sync::Arc::new(temporary_store());
+    let id = Uuid::new_v4();
+    let start = std::sync::Arc::new(std::sync::Barrier::new(16));
+
+    let writers: Vec<_> = (0..16)
+        .map(|_| {
+            let store = store.clone();
+            let start = start.clone();
+            std::thread::spawn(move || {
+                start.wait();
+                futures::executor::block_on(store.append(
+                    &id,
+                    ExpectedVersion::Any,
+                    vec![TestEvent::Updated, TestEvent::Updated],
+                    EventMetadata::default(),
+                ))
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().expect("writer thread").expect("append");
+    }
+
+    let loaded = futures::executor::block_on(store.load(&id)).expect("load");
+    let versions: Vec<i64> = loaded.iter().map(|e| e.version()).collect();
+    assert_eq!(versions, (1..=32).collect::<Vec<_>>());
+    assert_eq!(
+        futures::executor::block_on(store.verify_stream(&id)).expect("verify"),
+        None,
+        "the hash chain follows the rebased versions"
+    );
+}
+
+#[test]
+fn sled_stores_keep_aggregate_types_apart() {
+    let db = sled::Config::new()
+        .temporary(true)
+        .open()
+        .expect("open temporary sled db");
+    let tests = SledEventStore::<TestAggregate>::new(db.clone());
+    let others = SledEventStore::<OtherAggregate>::new(db.clone());
+    let shared = Uuid::new_v4();
+    let only_other = Uuid::new_v4();
+
+    futures::executor::block_on(tests.append(
+        &shared,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append test stream");
+    // The same ID is a separate, new stream for another aggregate type.
+    futures::executor::block_on(others.append(
+        &shared,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append other stream");
+    futures::executor::block_on(others.append(
+        &only_other,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append second other stream");
+
+    let loaded = futures::executor::block_on(tests.load(&shared)).expect("load test stream");
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded[0].aggregate_type(), "test");
+    let loaded = futures::executor::block_on(others.load(&shared)).expect("load other stream");
+    assert_eq!(loaded.len(), 2);
+    assert_eq!(loaded[0].aggregate_type(), "other");
+
+    // Each type reads only its own events from the shared global sequence.
+    let positions: Vec<i64> = futures::executor::block_on(others.read_all(0, 10))
+        .expect("read all")
+        .iter()
+        .map(|e| e.position())
+        .collect();
+    assert_eq!(positions, vec![2, 3, 4]);
+
+    assert_eq!(
+        futures::executor::block_on(tests.list_aggregate_ids()).expect("list test ids"),
+        vec![shared.to_string()]
+    );
+    let mut expected = vec![shared.to_string(), only_other.to_string()];
+    expected.sort();
+    assert_eq!(
+        futures::executor::block_on(others.list_aggregate_ids()).expect("list other ids"),
+        expected
+    );
+
+    let snapshots = db.open_tree("snapshots").expect("open snapshot tree");
+    let test_snapshots = SledSnapshotStore::<TestAggregate>::new(snapshots.clone());
+    let other_snapshots = SledSnapshotStore::<OtherAggregate>::new(snapshots);
+    futures::executor::block_on(test_snapshots.save(&shared, 1, TestSnap { version: 1 }))
+        .expect("save snapshot");
+    assert!(
+        futures::executor::block_on(other_snapshots.load(&shared))
+            .expect("load snapshot")
+            .is_none(),
+        "snapshots are namespaced by aggregate type"
+    );
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn sled_event_store_reads_payloads_in_their_recorded_format() {
+    use sourcerer::serializer::BincodeSerializer;
+
+    let store = temporary_store();
+    let bincode = store.clone().with_serializer(BincodeSerializer);
+    let id = Uuid::new_v4();
+
+    futures::executor::block_on(bincode.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append bincode");
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::Exact(1),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append json");
+
+    for loaded in [
+        futures::executor::block_on(store.load(&id)),
+        futures::executor::block_on(bincode.load(&id)),
+    ] {
+        let events: Vec<TestEvent> = loaded
+            .expect("load")
+            .into_iter()
+            .map(|e| e.into_event())
+            .collect();
+        assert_eq!(events, vec![TestEvent::Created, TestEvent::Updated]);
+    }
+}
+
+/// Appends an uncompressed event and then a large one through a store
+/// compressing with `compression`, and checks both load from either store.
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn assert_compressed_events_round_trip(compression: sourcerer::compression::Compression) {
+    let db = sled::Config::new()
+        .temporary(true)
+        .open()
+        .expect("open temporary sled db");
+    let store = SledEventStore::<TestAggregate>::new(db.clone());
+    let compressed = store.clone().with_compression(compression);
+    let id = Uuid::new_v4();
+    let note = "a very repetitive note ".repeat(100);
+
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created],
+        EventMetadata::default(),
+    ))
+    .expect("append uncompressed");
+    futures::executor::block_on(compressed.append(
+        &id,
+        ExpectedVersion::Exact(1),
+        vec![TestEvent::Noted(note.clone())],
+        EventMetadata::default(),
+    ))
+    .expect("append compressed");
+
+    let stream = db
+        .open_tree(format!("test/{id}"))
+        .expect("open stream tree");
+    let (_, record) = stream
+        .last()
+        .expect("read stream tree")
+        .expect("stream has records");
+    assert!(
+        record.len() < note.len(),
+        "the large payload is stored compressed"
+    );
+
+    for loaded in [
+        futures::executor::block_on(store.load(&id)),
+        futures::executor::block_on(compressed.load(&id)),
+    ] {
+        let events: Vec<TestEvent> = loaded
+            .expect("load")
+            .into_iter()
+            .map(|e| e.into_event())
+            .collect();
+        assert_eq!(
+            events,
+            vec![TestEvent::Created, TestEvent::Noted(note.clone())]
+        );
+    }
+
+    let snapshots = db.open_tree("snapshots").expect("open snapshot tree");
+    let plain = SledSnapshotStore::<TestAggregate>::new(snapshots.clone());
+    futures::executor::block_on(plain.save(&id, 1, TestSnap { version: 1 }))
+        .expect("save uncompressed snapshot");
+    let snapshots = SledSnapshotStore::<TestAggregate>::new(snapshots)
+        .with_compression(compression.with_threshold(0));
+    let loaded = futures::executor::block_on(snapshots.load(&id))
+        .expect("load uncompressed snapshot")
+        .expect("snapshot exists");
+    assert_eq!(loaded.version(), 1);
+    futures::executor::block_on(snapshots.save(&id, 2, TestSnap { version: 2 }))
+        .expect("save snapshot");
+    let loaded = futures::executor::block_on(plain.load(&id))
+        .expect("load snapshot")
+        .expect("snapshot exists");
+    assert_eq!(loaded.version(), 2);
+}
+
+#[test]
+fn sled_event_store_reads_bounded_ranges_and_past_versions() {
+    let store = temporary_store();
+    let id = Uuid::new_v4();
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    let between = chrono::Utc::now();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::Exact(2),
+        vec![TestEvent::Updated, TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+
+    let range = |after, until| -> Vec<i64> {
+        futures::executor::block_on(
+            store
+                .stream_raw_range(&id, after, until)
+                .map(|e| e.expect("event").version)
+                .collect(),
+        )
+    };
+    assert_eq!(range(1, 3), [2, 3]);
+    assert_eq!(range(0, 10), [1, 2, 3, 4]);
+    assert_eq!(range(3, 3), Vec::<i64>::new());
+    assert_eq!(range(3, 1), Vec::<i64>::new());
+
+    let version_as_of = |timestamp| {
+        futures::executor::block_on(store.version_as_of(&id, timestamp)).expect("version")
+    };
+    assert_eq!(version_as_of(between), 2);
+    assert_eq!(version_as_of(chrono::Utc::now()), 4);
+}
+
+#[test]
+fn sled_snapshot_store_keeps_a_monotonic_history() {
+    use sourcerer::snapshot::SnapshotRetention;
+
+    let db = sled::Config::new()
+        .temporary(true)
+        .open()
+        .expect("open temporary sled db");
+    let tree = db.open_tree("snapshots").expect("open snapshot tree");
+    let snapshots = SledSnapshotStore::<TestAggregate>::new(tree.clone())
+        .with_retention(SnapshotRetention::OnePerVersions(10));
+    let id = Uuid::new_v4();
+    for version in [5, 8, 12, 15, 21, 9] {
+        futures::executor::block_on(snapshots.save(&id, version, TestSnap { version }))
+            .expect("save snapshot");
+    }
+
+    let at_or_before = |version| {
+        futures::executor::block_on(snapshots.load_at_or_before(&id, version))
+            .expect("load at or before")
+            .map(|snapshot| snapshot.version())
+    };
+    assert_eq!(at_or_before(30), Some(21));
+    assert_eq!(at_or_before(20), Some(15));
+    assert_eq!(at_or_before(14), Some(8), "the stale save of 9 is ignored");
+    assert_eq!(at_or_before(7), None, "5 is pruned in favour of 8");
+
+    // Stores that keep no history still read the latest snapshot.
+    let latest = SledSnapshotStore::<TestAggregate>::new(tree);
+    let loaded = futures::executor::block_on(latest.load(&id))
+        .expect("load")
+        .expect("snapshot exists");
+    assert_eq!(loaded.version(), 21);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn sled_stores_round_trip_zstd_compressed_payloads() {
+    assert_compressed_events_round_trip(sourcerer::compression::Compression::zstd(0));
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn sled_stores_round_trip_lz4_compressed_payloads() {
+    assert_compressed_events_round_trip(sourcerer::compression::Compression::lz4());
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn sled_key_store_creates_keys_once_and_forgets_them() {
+    use sourcerer::{KeyStore, store::sled_key::SledKeyStore};
+
+    let db = sled::Config::new()
+        .temporary(true)
+        .open()
+        .expect("open temporary sled db");
+    let keys = SledKeyStore::new(db.open_tree("keys").expect("open key tree"));
+
+    assert!(
+        futures::executor::block_on(keys.load("ada"))
+            .expect("load missing key")
+            .is_none()
+    );
+    let created = futures::executor::block_on(keys.load_or_create("ada")).expect("create key");
+    let again = futures::executor::block_on(keys.load_or_create("ada")).expect("load key");
+    assert_eq!(created, again, "an existing key is reused");
+    assert_eq!(
+        futures::executor::block_on(keys.load("ada")).expect("load key"),
+        Some(created.clone())
+    );
+
+    futures::executor::block_on(keys.forget("ada")).expect("forget");
+    assert!(
+        futures::executor::block_on(keys.load("ada"))
+            .expect("load forgotten key")
+            .is_none()
+    );
+    let renewed = futures::executor::block_on(keys.load_or_create("ada")).expect("create key");
+    assert_ne!(created, renewed, "a forgotten key is never restored");
+}
+
+#[test]
+fn sled_verification_finds_tampered_events() {
+    let db = sled::Config::new()
+        .temporary(true)
+        .open()
+        .expect("open temporary sled db");
+    let store = SledEventStore::<TestAggregate>::new(db.clone());
+    let id = Uuid::new_v4();
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::NoStream,
+        vec![TestEvent::Created, TestEvent::Noted("hello".into())],
+        EventMetadata::default(),
+    ))
+    .expect("append");
+    futures::executor::block_on(store.append(
+        &id,
+        ExpectedVersion::Exact(2),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    ))
+    .expect("append more");
+
+    let stored = futures::executor::block_on(store.load(&id)).expect("load");
+    assert!(stored.iter().all(|e| e.hash().is_some()));
+    assert_eq!(
+        futures::executor::block_on(store.verify_stream(&id)).expect("verify"),
+        None
+    );
+    assert_eq!(
+        futures::executor::block_on(store.verify_all()).expect("verify all"),
+        None
+    );
+
+    // Rewrite the note in place, leaving the record otherwise intact.
+    let tree = db
+        .open_tree(format!("test/{id}"))
+        .expect("open stream tree");
+    let (key, value) = tree
+        .iter()
+        .nth(1)
+        .expect("second event")
+        .expect("read record");
+    let mut value = value.to_vec();
+    let at = value.len() - "hello\"}".len();
+    value[at..at + 5].copy_from_slice(b"jello");
+    tree.insert(key, value).expect("tamper");
+
+    let broken = futures::executor::block_on(store.verify_stream(&id))
+        .expect("verify")
+        .expect("tampering is detected");
+    assert_eq!(broken.version, 2);
+    assert!(matches!(
+        broken.problem,
+        sourcerer::integrity::LinkProblem::Mismatch { .. }
+    ));
+}
+
+#[test]
+fn sled_event_store_queues_appended_events_in_its_outbox() {
+    use sourcerer::outbox::Outbox;
+
+    let db = sled::Config::new()
+        .temporary(true)
+        .open()
+        .expect("open temporary sled db");
+    let id = Uuid::new_v4();
+    futures::executor::block_on(
+        SledEventStore::<TestAggregate>::new(db.clone())
+            .with_outbox(true)
+            .append(
+                &id,
+                ExpectedVersion::NoStream,
+                vec![TestEvent::Created, TestEvent::Updated],
+                EventMetadata::new(),
+            ),
+    )
+    .expect("append");
+
+    // The outbox lives in the database, not in the store handle.
+    let store = SledEventStore::<TestAggregate>::new(db);
+    let now = chrono::Utc::now();
+    let pending = futures::executor::block_on(store.pending(now, 10)).expect("pending");
+    assert_eq!(pending.len(), 2, "appends queue their events");
+    let (first, second) = (pending[0].position(), pending[1].position());
+
+    futures::executor::block_on(store.acknowledge(first)).expect("acknowledge");
+    let later = now + chrono::Duration::minutes(5);
+    futures::executor::block_on(store.retry_later(second, "broker unavailable", later))
+        .expect("retry later");
+    assert!(
+        futures::executor::block_on(store.pending(now, 10))
+            .expect("pending")
+            .is_empty(),
+        "retries wait until due"
+    );
+    let retried = futures::executor::block_on(store.pending(later, 10)).expect("pending");
+    assert_eq!(retried.len(), 1);
+    assert_eq!(retried[0].attempts(), 1);
+    assert_eq!(retried[0].last_error(), Some("broker unavailable"));
+
+    futures::executor::block_on(store.dead_letter(second, "still unavailable"))
+        .expect("dead letter");
+    let dead = futures::executor::block_on(store.dead_letters(10)).expect("dead letters");
+    assert_eq!((dead.len(), dead[0].attempts()), (1, 2));
+    assert!(
+        futures::executor::block_on(store.pending(later, 10))
+            .expect("pending")
+            .is_empty()
+    );
+
+    futures::executor::block_on(store.requeue(second)).expect("requeue");
+    let requeued =
+        futures::executor::block_on(store.pending(chrono::Utc::now(), 10)).expect("pending");
+    assert_eq!((requeued.len(), requeued[0].attempts()), (1, 0));
+    assert_eq!(requeued[0].event().event(), &TestEvent::Updated);
+}
+
+/// Writes a stream the way stores did before streams were named after their
+/// aggregate type: in a tree named after the ID, under unpadded version keys,
+/// as whole JSON events.
+fn write_untyped_stream(db: &sled::Db, id: &Uuid, events: &[TestEvent]) {
+    let tree = db.open_tree(id.to_string()).expect("open stream tree");
+    for (version, event) in (1..).zip(events) {
+        let stored = serde_json::json!({
+            "aggregate_id": id.to_string(),
+            "version": version,
+            "event_version": 1,
+            "event_type": event.event_type(),
+            "event": event,
+        });
+        tree.insert(
+            format!("{id}/{version}"),
+            serde_json::to_vec(&stored).expect("encode"),
+        )
+        .expect("insert");
+    }
+}
+
+#[test]
+fn sled_event_store_refuses_streams_stored_before_aggregate_types() {
+    let db = sled::Config::new()
+        .temporary(true)
+        .open()
+        .expect("open temporary sled db");
+    let id = Uuid::new_v4();
+    write_untyped_stream(&db, &id, &[TestEvent::Created, TestEvent::Updated]);
+
+    let store = SledEventStore::<TestAggregate>::new(db);
+    let refused = |error: Option<sourcerer::Error>| matches!(error, Some(sourcerer::Error::Store(message)) if message.contains(&id.to_string()));
+    assert!(refused(futures::executor::block_on(store.load(&id)).err()));
+    assert!(refused(
+        futures::executor::block_on(store.read_all(0, 10)).err()
+    ));
+    assert!(refused(
+        futures::executor::block_on(store.list_aggregate_ids()).err()
+    ));
+    assert!(refused(
+        futures::executor::block_on(store.append(
+            &Uuid::new_v4(),
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created],
+            EventMetadata::new(),
+        ))
+        .err()
+    ));
+}
+
+#[test]
+fn sled_stores_adopt_streams_and_snapshots_stored_before_aggregate_types() {
+    let db = sled::Config::new()
+        .temporary(true)
+        .open()
+        .expect("open temporary sled db");
+    let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+    // Enough events for unpadded keys to sort out of version order.
+    let events: Vec<TestEvent> = (0..11).map(|n| TestEvent::Noted(n.to_string())).collect();
+    write_untyped_stream(&db, &first, &events);
+    write_untyped_stream(&db, &second, &[TestEvent::Created]);
+    let untyped_snapshot = serde_json::json!({
+        "aggregate_id": first.to_string(),
+        "version": 11,
+        "snapshot": { "version": 11 },
+    });
+    let snapshot_tree = db.open_tree("snapshots").expect("open snapshot tree");
+    snapshot_tree
+        .insert(
+            first.to_string(),
+            serde_json::to_vec(&untyped_snapshot).expect("encode"),
+        )
+        .expect("insert");
+
+    let store = SledEventStore::<TestAggregate>::new(db.clone());
+    let other = SledEventStore::<OtherAggregate>::new(db.clone());
+    let first_id = first.to_string();
+    let adopted = futures::executor::block_on(store.adopt_untyped_streams(|id| id == first_id))
+        .expect("adopt");
+    assert_eq!(adopted, 1);
+    assert!(
+        futures::executor::block_on(store.load(&first)).is_err(),
+        "the database is refused while any untyped stream is left"
+    );
+    let adopted =
+        futures::executor::block_on(other.adopt_untyped_streams(|_| true)).expect("adopt");
+    assert_eq!(adopted, 1);
+
+    let loaded = futures::executor::block_on(store.load(&first)).expect("load");
+    assert_eq!(
+        loaded.iter().map(|e| e.event().clone()).collect::<Vec<_>>(),
+        events
+    );
+    assert_eq!(
+        loaded.iter().map(|e| e.version()).collect::<Vec<_>>(),
+        (1..=11).collect::<Vec<_>>()
+    );
+    assert!(loaded.iter().all(|e| e.aggregate_type() == "test"));
+    let all = futures::executor::block_on(store.read_all(0, 100)).expect("read all");
+    assert_eq!(
+        all.iter().map(|e| e.position()).collect::<Vec<_>>(),
+        (1..=11).collect::<Vec<_>>()
+    );
+    let all = futures::executor::block_on(other.read_all(0, 100)).expect("read all");
+    assert_eq!((all.len(), all[0].position()), (1, 12));
+
+    // A new store handle reads and appends without adopting again.
+    let store = SledEventStore::<TestAggregate>::new(db);
+    let appended = futures::executor::block_on(store.append(
+        &first,
+        ExpectedVersion::Exact(11),
+        vec![TestEvent::Updated],
+        EventMetadata::new(),
+    ))
+    .expect("append");
+    assert_eq!((appended[0].version(), appended[0].position()), (12, 13));
+
+    // Untyped snapshots are ignored until adopted.
+    let snapshots = SledSnapshotStore::<TestAggregate>::new(snapshot_tree);
+    assert!(
+        futures::executor::block_on(snapshots.load(&first))
+            .expect("load")
+            .is_none()
+    );
+    let adopted =
+        futures::executor::block_on(snapshots.adopt_untyped_snapshots(|_| true)).expect("adopt");
+    assert_eq!(adopted, 1);
+    let loaded = futures::executor::block_on(snapshots.load(&first))
+        .expect("load")
+        .expect("snapshot exists");
+    assert_eq!(loaded.version(), 11);
+}
@@ -0,0 +1,556 @@
+//! Integration tests for the `sqlx`-backed SQLite stores.
+#![cfg(feature = "sqlite-storage")]
+
+use std::sync::Arc;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, sqlite::SqlitePoolOptions};
+use uuid::Uuid;
+
+use sourcerer::{
+    Aggregate, CheckpointStore, Event, EventMetadata, EventStore, ExpectedVersion, Repository,
+    Snapshot, SnapshotStore, async_trait,
+    repository::GenericRepository,
+    store::sqlx_sqlite::{SqliteCheckpointStore, SqliteEventStore, SqliteSnapshotStore},
+    subscription::SubscriptionFilter,
+};
+
+/// Simple event used for testing.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+enum TestEvent {
+    Created,
+    Updated,
+}
+
+impl Event for TestEvent {
+    fn event_type(&self) -> &'static str {
+        match self {
+            Self::Created => "Created",
+            Self::Updated => "Updated",
+        }
+    }
+
+    fn event_version(&self) -> u16 {
+        1
+    }
+
+    fn event_source(&self) -> &'static str {
+        "urn:sourcerer:test"
+    }
+}
+
+/// Snapshot payload for [`TestAggregate`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct TestSnap {
+    version: i64,
+}
+
+impl Snapshot for TestSnap {}
+
+/// A minimal aggregate implementation used solely for testing store behaviour.
+#[derive(Default, Debug)]
+struct TestAggregate {
+    id: Uuid,
+    version: i64,
+}
+
+#[async_trait]
+impl Aggregate for TestAggregate {
+    const TYPE_NAME: &str = "test";
+    type Id = Uuid;
+    type Event = TestEvent;
+    type Command = ();
+    type Snapshot = TestSnap;
+    type Error = std::convert::Infallible;
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+
+    fn version(&self) -> i64 {
+        self.version
+    }
+
+    fn apply(&mut self, _event: &Self::Event) {
+        self.version += 1;
+    }
+
+    async fn handle(
+        &self,
+        _command: Self::Command,
+    ) -> std::result::Result<Vec<Self::Event>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    fn from_snapshot(snapshot: Self::Snapshot) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            version: snapshot.version,
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        TestSnap {
+            version: self.version,
+        }
+    }
+}
+
+/// Opens a fresh in-memory database with every table set up.
+///
+/// Each connection to `sqlite::memory:` is a separate database, so the pool
+/// is limited to a single connection.
+async fn memory_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("open in-memory sqlite db");
+    SqliteEventStore::<TestAggregate>::new(pool.clone())
+        .setup()
+        .await
+        .expect("set up events");
+    SqliteSnapshotStore::<TestAggregate>::new(pool.clone())
+        .setup()
+        .await
+        .expect("set up snapshots");
+    SqliteCheckpointStore::new(pool.clone())
+        .setup()
+        .await
+        .expect("set up checkpoints");
+    pool
+}
+
+// -- Tests ---------------------------------------------------------------
+
+#[tokio::test]
+async fn sqlite_event_store_append_load_and_conflict() {
+    let store = SqliteEventStore::<TestAggregate>::new(memory_pool().await);
+    let id = Uuid::new_v4();
+
+    let metadata = EventMetadata::new()
+        .with_correlation_id("request-1")
+        .with_header("tenant", "acme");
+    let stored = store
+        .append(
+            &id,
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created, TestEvent::Updated],
+            metadata,
+        )
+        .await
+        .expect("append");
+    assert_eq!(stored[1].version(), 2);
+
+    let loaded = store.load(&id).await.expect("load");
+    assert_eq!(loaded.len(), 2);
+    assert_eq!(loaded[0].event(), &TestEvent::Created);
+    assert_eq!(loaded[0].aggregate_type(), "test");
+    assert_eq!(loaded[1].metadata(), stored[1].metadata());
+    assert_eq!(loaded[1].metadata().correlation_id(), Some("request-1"));
+
+    let raw = store.load_raw(&id, 1).await.expect("load raw");
+    assert_eq!(raw.len(), 1);
+    assert_eq!(raw[0].event_type, "Updated");
+    assert_eq!(raw[0].position, stored[1].position());
+
+    let err = store
+        .append(
+            &id,
+            ExpectedVersion::Exact(1),
+            vec![TestEvent::Updated],
+            EventMetadata::default(),
+        )
+        .await
+        .expect_err("stale append should conflict");
+    match err {
+        sourcerer::Error::Conflict {
+            expected, actual, ..
+        } => {
+            assert_eq!(expected, ExpectedVersion::Exact(1));
+            assert_eq!(actual, 2);
+        }
+        other => panic!("expected a conflict, got {other:?}"),
+    }
+    assert_eq!(store.load(&id).await.expect("reload").len(), 2);
+}
+
+#[tokio::test]
+async fn sqlite_event_store_appends_batches_atomically() {
+    let store = SqliteEventStore::<TestAggregate>::new(memory_pool().await);
+    let first = Uuid::new_v4();
+    let second = Uuid::new_v4();
+
+    let stored = store
+        .append_batch(
+            vec![
+                (first, ExpectedVersion::NoStream, vec![TestEvent::Created]),
+                (second, ExpectedVersion::NoStream, vec![TestEvent::Created]),
+                (first, ExpectedVersion::Exact(1), vec![TestEvent::Updated]),
+            ],
+            EventMetadata::default().with_correlation_id("batch-1"),
+        )
+        .await
+        .expect("append batch");
+    let written: Vec<_> = stored.iter().map(|e| (e.version(), e.position())).collect();
+    assert_eq!(written, [(1, 1), (1, 2), (2, 3)]);
+    let loaded = store.load(&second).await.expect("load second");
+    assert_eq!(loaded[0].metadata().correlation_id(), Some("batch-1"));
+
+    // One stale entry rolls back the whole batch.
+    let err = store
+        .append_batch(
+            vec![
+                (first, ExpectedVersion::Exact(2), vec![TestEvent::Updated]),
+                (second, ExpectedVersion::NoStream, vec![TestEvent::Created]),
+            ],
+            EventMetadata::default(),
+        )
+        .await
+        .expect_err("the second entry should conflict");
+    assert!(matches!(err, sourcerer::Error::Conflict { actual: 1, .. }));
+    assert_eq!(store.load(&first).await.expect("reload").len(), 2);
+    assert_eq!(store.read_all(0, 10).await.expect("read all").len(), 3);
+}
+
+#[tokio::test]
+async fn sqlite_event_store_deduplicates_appends_by_idempotency_key() {
+    let store = SqliteEventStore::<TestAggregate>::new(memory_pool().await);
+    let id = Uuid::new_v4();
+    let metadata = EventMetadata::new().with_idempotency_key("create-1");
+
+    let stored = store
+        .append(
+            &id,
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created, TestEvent::Updated],
+            metadata.clone(),
+        )
+        .await
+        .expect("append");
+    let retried = store
+        .append(
+            &id,
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created],
+            metadata.clone(),
+        )
+        .await
+        .expect("retry");
+    let event_ids = |events: &[sourcerer::StoredEvent<TestEvent>]| {
+        events
+            .iter()
+            .map(|e| e.metadata().event_id())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(event_ids(&retried), event_ids(&stored));
+    assert_eq!(store.load(&id).await.expect("load").len(), 2);
+
+    // Once the key expires, the retry is a new append and conflicts.
+    let store = store.with_idempotency_retention(std::time::Duration::ZERO);
+    tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+    let err = store
+        .append(
+            &id,
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created],
+            metadata,
+        )
+        .await
+        .expect_err("the key has expired");
+    assert!(matches!(err, sourcerer::Error::Conflict { .. }));
+}
+
+#[tokio::test]
+async fn sqlite_event_store_read_all_and_list_ids() {
+    let store = SqliteEventStore::<TestAggregate>::new(memory_pool().await);
+    let first = Uuid::new_v4();
+    let second = Uuid::new_v4();
+
+    store
+        .append(
+            &first,
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created],
+            EventMetadata::default(),
+        )
+        .await
+        .expect("append first");
+    store
+        .append(
+            &second,
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created, TestEvent::Updated],
+            EventMetadata::default(),
+        )
+        .await
+        .expect("append second");
+
+    let all = store.read_all(0, 10).await.expect("read all");
+    let positions: Vec<i64> = all.iter().map(|e| e.position()).collect();
+    assert_eq!(positions, vec![1, 2, 3]);
+    assert_eq!(all[0].aggregate_id(), first.to_string());
+
+    let page = store.read_all(2, 10).await.expect("read page");
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].event_type(), "Updated");
+
+    let mut expected = vec![first.to_string(), second.to_string()];
+    expected.sort();
+    assert_eq!(
+        store.list_aggregate_ids().await.expect("list ids"),
+        expected
+    );
+}
+
+#[tokio::test]
+async fn sqlite_subscription_catches_up_then_follows_appends() {
+    let store = SqliteEventStore::<TestAggregate>::new(memory_pool().await);
+    let first = Uuid::new_v4();
+    let second = Uuid::new_v4();
+    store
+        .append(
+            &first,
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created],
+            EventMetadata::default(),
+        )
+        .await
+        .expect("append first");
+
+    let mut all = store
+        .subscribe(0, SubscriptionFilter::new())
+        .await
+        .expect("subscribe");
+    let mut filtered = store
+        .subscribe(0, SubscriptionFilter::new().with_event_types(["Updated"]))
+        .await
+        .expect("subscribe");
+
+    let replayed = all.next().await.expect("stream open").expect("replayed");
+    assert_eq!(replayed.aggregate_id(), first.to_string());
+
+    store
+        .append(
+            &second,
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created, TestEvent::Updated],
+            EventMetadata::default(),
+        )
+        .await
+        .expect("append second");
+
+    let live = all.next().await.expect("stream open").expect("live event");
+    assert_eq!(live.position(), 2);
+    let only_updated = filtered
+        .next()
+        .await
+        .expect("stream open")
+        .expect("filtered event");
+    assert_eq!(only_updated.position(), 3);
+}
+
+#[tokio::test]
+async fn sqlite_repository_loads_from_snapshots() {
+    let pool = memory_pool().await;
+    let store = Arc::new(SqliteEventStore::<TestAggregate>::new(pool.clone()));
+    let snapshots = Arc::new(SqliteSnapshotStore::<TestAggregate>::new(pool));
+    let repo =
+        GenericRepository::new(store, Some(snapshots.clone())).with_snapshot_frequency(Some(2));
+    let id = Uuid::new_v4();
+
+    let mut agg = TestAggregate { id, version: 0 };
+    let events = vec![TestEvent::Created, TestEvent::Updated, TestEvent::Updated];
+    for event in &events {
+        agg.apply(event);
+    }
+    repo.save(&agg, events).await.expect("save");
+
+    let snapshot = snapshots
+        .load(&id)
+        .await
+        .expect("load snapshot")
+        .expect("snapshot taken");
+    assert_eq!(snapshot.version(), 3);
+    assert_eq!(snapshot.snapshot_version(), 1);
+    let age = chrono::Utc::now() - snapshot.taken_at().expect("taken at");
+    assert!(age < chrono::Duration::minutes(1), "taken {age} ago");
+
+    snapshots
+        .save(&id, 2, TestSnap { version: 2 })
+        .await
+        .expect("stale saves are ignored");
+    let snapshot = snapshots
+        .load(&id)
+        .await
+        .expect("load snapshot")
+        .expect("snapshot kept");
+    assert_eq!(snapshot.version(), 3);
+
+    let loaded = repo.load(&id).await.expect("load aggregate");
+    assert_eq!(loaded.version(), 3);
+}
+
+#[tokio::test]
+async fn sqlite_verification_finds_tampered_events() {
+    use sourcerer::integrity::LinkProblem;
+
+    let pool = memory_pool().await;
+    let store = Arc::new(SqliteEventStore::<TestAggregate>::new(pool.clone()));
+    let id = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    for id in [id, other] {
+        store
+            .append(
+                &id,
+                ExpectedVersion::NoStream,
+                vec![TestEvent::Created, TestEvent::Updated, TestEvent::Updated],
+                EventMetadata::default(),
+            )
+            .await
+            .expect("append");
+    }
+    assert_eq!(store.verify_stream(&id).await.expect("verify"), None);
+    assert_eq!(store.verify_all().await.expect("verify all"), None);
+
+    sqlx::query(
+        "UPDATE events SET payload = '\"Created\"' WHERE aggregate_id = $1 AND version = 2",
+    )
+    .bind(id.to_string())
+    .execute(&pool)
+    .await
+    .expect("tamper");
+
+    let broken = store
+        .verify_stream(&id)
+        .await
+        .expect("verify")
+        .expect("tampering is detected");
+    assert_eq!(broken.aggregate_id, id.to_string());
+    assert_eq!(broken.version, 2);
+    assert!(matches!(broken.problem, LinkProblem::Mismatch { .. }));
+    assert_eq!(store.verify_stream(&other).await.expect("verify"), None);
+    assert_eq!(
+        store.verify_all().await.expect("verify all"),
+        Some(broken.clone())
+    );
+
+    let repo = GenericRepository::<TestAggregate, _, SqliteSnapshotStore<TestAggregate>>::new(
+        store.clone(),
+        None,
+    );
+    assert_eq!(repo.load(&id).await.expect("load unverified").version(), 3);
+    let repo = repo.with_hash_verification(true);
+    match repo.load(&id).await {
+        Err(sourcerer::Error::Integrity(link)) => assert_eq!(*link, broken),
+        other => panic!("expected a broken link, got {other:?}"),
+    }
+    assert_eq!(repo.load(&other).await.expect("load verified").version(), 3);
+
+    sqlx::query("UPDATE events SET hash = NULL WHERE aggregate_id = $1 AND version = 1")
+        .bind(other.to_string())
+        .execute(&pool)
+        .await
+        .expect("drop hash");
+    let unhashed = store
+        .verify_stream(&other)
+        .await
+        .expect("verify")
+        .expect("missing hashes are reported");
+    assert_eq!(unhashed.version, 1);
+    assert_eq!(unhashed.problem, LinkProblem::Unhashed);
+}
+
+#[tokio::test]
+async fn sqlite_checkpoint_store_save_load_and_delete() {
+    let checkpoints = SqliteCheckpointStore::new(memory_pool().await);
+
+    assert_eq!(checkpoints.load("counter").await.expect("load"), None);
+    checkpoints.save("counter", 3).await.expect("save");
+    checkpoints.save("counter", 5).await.expect("overwrite");
+    assert_eq!(checkpoints.load("counter").await.expect("load"), Some(5));
+    checkpoints.delete("counter").await.expect("delete");
+    assert_eq!(checkpoints.load("counter").await.expect("load"), None);
+}
+
+/// Passes `Updated` payloads through unchanged, forcing them to be decoded
+/// for upcasting.
+#[cfg(feature = "cbor")]
+struct PassThrough;
+
+#[cfg(feature = "cbor")]
+impl sourcerer::upcaster::Upcaster<TestEvent> for PassThrough {
+    fn event_type(&self) -> &'static str {
+        "Updated"
+    }
+
+    fn source_version(&self) -> u16 {
+        1
+    }
+
+    fn upcast(&self, payload: serde_json::Value) -> sourcerer::Result<serde_json::Value> {
+        Ok(payload)
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[tokio::test]
+async fn sqlite_loads_streams_mixing_payload_formats() {
+    use sourcerer::{
+        serializer::{CborSerializer, Format},
+        upcaster::UpcasterChain,
+    };
+
+    let store = SqliteEventStore::<TestAggregate>::new(memory_pool().await);
+    let cbor = store.clone().with_serializer(CborSerializer);
+    let id = Uuid::new_v4();
+
+    store
+        .append(
+            &id,
+            ExpectedVersion::NoStream,
+            vec![TestEvent::Created],
+            EventMetadata::default(),
+        )
+        .await
+        .expect("append json");
+    cbor.append(
+        &id,
+        ExpectedVersion::Exact(1),
+        vec![TestEvent::Updated],
+        EventMetadata::default(),
+    )
+    .await
+    .expect("append cbor");
+
+    // Either store reads every event in the format it was written in.
+    let expected = vec![TestEvent::Created, TestEvent::Updated];
+    for loaded in [store.load(&id).await, cbor.load(&id).await] {
+        let events: Vec<TestEvent> = loaded
+            .expect("load")
+            .into_iter()
+            .map(|e| e.into_event())
+            .collect();
+        assert_eq!(events, expected);
+    }
+    let formats: Vec<Format> = store
+        .load_raw(&id, 0)
+        .await
+        .expect("load raw")
+        .iter()
+        .map(|e| e.format)
+        .collect();
+    assert_eq!(formats, vec![Format::Json, Format::Cbor]);
+
+    let repo = GenericRepository::<TestAggregate, _, SqliteSnapshotStore<TestAggregate>>::new(
+        Arc::new(cbor),
+        None,
+    )
+    .with_upcasters(UpcasterChain::new().with(PassThrough));
+    let aggregate = repo.load(&id).await.expect("load through upcaster");
+    assert_eq!(aggregate.version(), 2);
+
+    // The hash chain does not depend on the payload format.
+    assert_eq!(store.verify_stream(&id).await.expect("verify"), None);
+}